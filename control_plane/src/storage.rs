@@ -402,10 +402,31 @@ impl PageServerNode {
                     .map(|x| x.parse::<u64>())
                     .transpose()?,
                 checkpoint_timeout: settings.get("checkpoint_timeout").map(|x| x.to_string()),
+                checkpoint_max_entries: settings
+                    .get("checkpoint_max_entries")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'checkpoint_max_entries' as an integer")?,
                 compaction_target_size: settings
                     .get("compaction_target_size")
                     .map(|x| x.parse::<u64>())
                     .transpose()?,
+                compaction_target_file_size: settings
+                    .get("compaction_target_file_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
+                compaction_target_file_size_relation: settings
+                    .get("compaction_target_file_size_relation")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
+                compaction_target_file_size_slru: settings
+                    .get("compaction_target_file_size_slru")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
+                compaction_target_file_size_metadata: settings
+                    .get("compaction_target_file_size_metadata")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
                 compaction_period: settings.get("compaction_period").map(|x| x.to_string()),
                 compaction_threshold: settings
                     .get("compaction_threshold")
@@ -430,6 +451,43 @@ impl PageServerNode {
                     .map(|x| x.parse::<NonZeroU64>())
                     .transpose()
                     .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+                materialized_cache_enabled: settings
+                    .get("materialized_cache_enabled")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'materialized_cache_enabled' as bool")?,
+                maintenance_window: settings.get("maintenance_window").map(|x| x.to_string()),
+                walredo_max_records_size: settings
+                    .get("walredo_max_records_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'walredo_max_records_size' as an integer")?,
+                compaction_concurrency: settings
+                    .get("compaction_concurrency")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_concurrency' as an integer")?,
+                compaction_max_input_layers: settings
+                    .get("compaction_max_input_layers")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_max_input_layers' as an integer")?,
+                compaction_strategy: settings.get("compaction_strategy").map(|x| x.to_string()),
+                compaction_min_benefit: settings
+                    .get("compaction_min_benefit")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_min_benefit' as an integer")?,
+                get_requests_per_second: settings
+                    .get("get_requests_per_second")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'get_requests_per_second' as an integer")?,
+                get_bytes_per_second: settings
+                    .get("get_bytes_per_second")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'get_bytes_per_second' as an integer")?,
             })
             .send()?
             .error_from_body()?
@@ -457,11 +515,40 @@ impl PageServerNode {
                     .transpose()
                     .context("Failed to parse 'checkpoint_distance' as an integer")?,
                 checkpoint_timeout: settings.get("checkpoint_timeout").map(|x| x.to_string()),
+                checkpoint_max_entries: settings
+                    .get("checkpoint_max_entries")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'checkpoint_max_entries' as an integer")?,
                 compaction_target_size: settings
                     .get("compaction_target_size")
                     .map(|x| x.parse::<u64>())
                     .transpose()
                     .context("Failed to parse 'compaction_target_size' as an integer")?,
+                compaction_target_file_size: settings
+                    .get("compaction_target_file_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_target_file_size' as an integer")?,
+                compaction_target_file_size_relation: settings
+                    .get("compaction_target_file_size_relation")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context(
+                        "Failed to parse 'compaction_target_file_size_relation' as an integer",
+                    )?,
+                compaction_target_file_size_slru: settings
+                    .get("compaction_target_file_size_slru")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_target_file_size_slru' as an integer")?,
+                compaction_target_file_size_metadata: settings
+                    .get("compaction_target_file_size_metadata")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context(
+                        "Failed to parse 'compaction_target_file_size_metadata' as an integer",
+                    )?,
                 compaction_period: settings.get("compaction_period").map(|x| x.to_string()),
                 compaction_threshold: settings
                     .get("compaction_threshold")
@@ -489,6 +576,43 @@ impl PageServerNode {
                     .map(|x| x.parse::<NonZeroU64>())
                     .transpose()
                     .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+                materialized_cache_enabled: settings
+                    .get("materialized_cache_enabled")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'materialized_cache_enabled' as bool")?,
+                maintenance_window: settings.get("maintenance_window").map(|x| x.to_string()),
+                walredo_max_records_size: settings
+                    .get("walredo_max_records_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'walredo_max_records_size' as an integer")?,
+                compaction_concurrency: settings
+                    .get("compaction_concurrency")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_concurrency' as an integer")?,
+                compaction_max_input_layers: settings
+                    .get("compaction_max_input_layers")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_max_input_layers' as an integer")?,
+                compaction_strategy: settings.get("compaction_strategy").map(|x| x.to_string()),
+                compaction_min_benefit: settings
+                    .get("compaction_min_benefit")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'compaction_min_benefit' as an integer")?,
+                get_requests_per_second: settings
+                    .get("get_requests_per_second")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'get_requests_per_second' as an integer")?,
+                get_bytes_per_second: settings
+                    .get("get_bytes_per_second")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()
+                    .context("Failed to parse 'get_bytes_per_second' as an integer")?,
             })
             .send()?
             .error_from_body()?;
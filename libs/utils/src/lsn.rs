@@ -240,6 +240,17 @@ impl MonotonicCounter<Lsn> for RecordLsn {
     }
 }
 
+/// Expose the LSN itself as counter, to be able to use it in SeqWait
+impl MonotonicCounter<Lsn> for Lsn {
+    fn cnt_advance(&mut self, lsn: Lsn) {
+        assert!(*self <= lsn);
+        *self = lsn;
+    }
+    fn cnt_value(&self) -> Lsn {
+        *self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,6 +224,18 @@ where
         old_value
     }
 
+    /// Move the current value back to 'num'.
+    ///
+    /// This is the only way to go backwards. Waiters are all waiting for a
+    /// number above the current value, so none of them is woken.
+    ///
+    /// Panics if 'num' is ahead of the current value.
+    pub fn rewind(&self, num: S) {
+        let mut internal = self.internal.lock().unwrap();
+        assert!(num.cnt_value() <= internal.current.cnt_value());
+        internal.current = num;
+    }
+
     /// Read the current value, without waiting.
     pub fn load(&self) -> S {
         self.internal.lock().unwrap().current
@@ -275,6 +287,18 @@ mod tests {
         seq.shutdown();
     }
 
+    #[test]
+    fn seqwait_rewind() {
+        let seq = SeqWait::new(0);
+        seq.advance(100);
+        seq.rewind(50);
+        assert_eq!(seq.load(), 50);
+        seq.wait_for(50).expect("wait_for 50");
+        seq.wait_for_timeout(60, Duration::from_millis(1))
+            .expect_err("no 60 after rewind");
+        assert_eq!(seq.advance(60), 50);
+    }
+
     #[test]
     fn seqwait_timeout() {
         let seq = Arc::new(SeqWait::new(0));
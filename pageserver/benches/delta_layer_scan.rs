@@ -0,0 +1,92 @@
+//!
+//! Compares range scans at a single LSN over delta layers written with and
+//! without the key index (the 'delta_key_index' option).
+//!
+//! Run with `cargo bench -p pageserver --bench delta_layer_scan`.
+//!
+use std::fs;
+use std::path::Path;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pageserver::config::PageServerConf;
+use pageserver::layered_repository::{DeltaLayer, DeltaLayerWriter};
+use pageserver::repository::{Key, Value};
+use pageserver::{page_cache, virtual_file};
+use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+const NUM_KEYS: u32 = 10_000;
+const VERSIONS_PER_KEY: u64 = 20;
+const SCAN_KEYS: u32 = 1_000;
+
+fn key(blknum: u32) -> Key {
+    Key::from_hex(&format!("0100000000333333334444444455{blknum:08X}")).unwrap()
+}
+
+fn conf(workdir: &Path, delta_key_index: bool) -> &'static PageServerConf {
+    // parse_and_validate() insists on a postgres binary, even though nothing
+    // here runs one.
+    let pg_distrib_dir = workdir.join("pg_distrib");
+    fs::create_dir_all(pg_distrib_dir.join("bin")).unwrap();
+    fs::write(pg_distrib_dir.join("bin/postgres"), b"").unwrap();
+
+    let toml = format!(
+        "id = 1\npg_distrib_dir = '{}'\ndelta_key_index = {delta_key_index}\n",
+        pg_distrib_dir.display()
+    );
+    let conf = PageServerConf::parse_and_validate(&toml.parse().unwrap(), workdir).unwrap();
+    Box::leak(Box::new(conf))
+}
+
+/// Write a delta layer with VERSIONS_PER_KEY page images of each of NUM_KEYS keys.
+fn write_layer(conf: &'static PageServerConf) -> DeltaLayer {
+    let tenantid = ZTenantId::generate();
+    let timelineid = ZTimelineId::generate();
+    fs::create_dir_all(conf.timeline_path(&timelineid, &tenantid)).unwrap();
+
+    let lsn_end = Lsn(0x10 * (VERSIONS_PER_KEY + 1));
+    let mut writer =
+        DeltaLayerWriter::new(conf, timelineid, tenantid, key(0), Lsn(0x10)..lsn_end).unwrap();
+    let img = Bytes::from(vec![0u8; 128]);
+    for blknum in 0..NUM_KEYS {
+        for version in 1..=VERSIONS_PER_KEY {
+            writer
+                .put_value(key(blknum), Lsn(0x10 * version), Value::Image(img.clone()))
+                .unwrap();
+        }
+    }
+    writer.finish(key(NUM_KEYS)).unwrap()
+}
+
+fn bench_scan_at_lsn(c: &mut Criterion) {
+    page_cache::init(10_000);
+    virtual_file::init(100);
+
+    let workdir = tempfile::tempdir().unwrap();
+    let layers = [
+        (
+            "without_key_index",
+            write_layer(conf(workdir.path(), false)),
+        ),
+        ("with_key_index", write_layer(conf(workdir.path(), true))),
+    ];
+
+    let range = key(NUM_KEYS / 2)..key(NUM_KEYS / 2 + SCAN_KEYS);
+    let mut group = c.benchmark_group("delta_layer_scan_at_lsn");
+    for (name, layer) in layers.iter() {
+        // At the end of the layer, the newest version of every key is visible
+        group.bench_function(BenchmarkId::new(*name, "newest"), |b| {
+            b.iter(|| layer.scan_at_lsn(&range, layer.lsn_range.end).unwrap())
+        });
+        // In the middle, the key index doesn't help
+        let mid_lsn = Lsn(0x10 * (VERSIONS_PER_KEY / 2));
+        group.bench_function(BenchmarkId::new(*name, "middle"), |b| {
+            b.iter(|| layer.scan_at_lsn(&range, mid_lsn).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan_at_lsn);
+criterion_main!(benches);
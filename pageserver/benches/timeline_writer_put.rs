@@ -0,0 +1,114 @@
+//!
+//! Compares ingesting values into a timeline with a put() call per value, and
+//! with put_batch().
+//!
+//! Run with `cargo bench -p pageserver --bench timeline_writer_put`.
+//!
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pageserver::config::PageServerConf;
+use pageserver::layered_repository::LayeredRepository;
+use pageserver::repository::{Key, Repository, Timeline, TimelineWriter, Value};
+use pageserver::storage_sync::index::RemoteIndex;
+use pageserver::tenant_config::TenantConfOpt;
+use pageserver::walredo::DummyRedoManager;
+use pageserver::{page_cache, virtual_file, CheckpointConfig};
+use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+const BATCH_SIZES: [u32; 3] = [10, 100, 1_000];
+
+fn key(blknum: u32) -> Key {
+    Key::from_hex(&format!("0100000000333333334444444455{blknum:08X}")).unwrap()
+}
+
+fn conf(workdir: &Path) -> &'static PageServerConf {
+    // parse_and_validate() insists on a postgres binary, even though nothing
+    // here runs one.
+    let pg_distrib_dir = workdir.join("pg_distrib");
+    fs::create_dir_all(pg_distrib_dir.join("bin")).unwrap();
+    fs::write(pg_distrib_dir.join("bin/postgres"), b"").unwrap();
+
+    let toml = format!("id = 1\npg_distrib_dir = '{}'\n", pg_distrib_dir.display());
+    let conf = PageServerConf::parse_and_validate(&toml.parse().unwrap(), workdir).unwrap();
+    Box::leak(Box::new(conf))
+}
+
+fn repository(conf: &'static PageServerConf) -> LayeredRepository {
+    let tenant_id = ZTenantId::generate();
+    fs::create_dir_all(conf.timelines_path(&tenant_id)).unwrap();
+    LayeredRepository::new(
+        conf,
+        TenantConfOpt::default(),
+        Arc::new(DummyRedoManager {}),
+        tenant_id,
+        RemoteIndex::default(),
+        false,
+    )
+}
+
+///
+/// Write 'iters' batches of 'batch_size' values to a new timeline, one record
+/// LSN per batch, and return the time spent in the writes. Each value is at
+/// its own LSN, like the blocks touched by a stream of WAL records.
+///
+fn write_batches(repo: &LayeredRepository, batch_size: u32, iters: u64, batched: bool) -> Duration {
+    let tline = repo
+        .create_empty_timeline(ZTimelineId::generate(), Lsn(0))
+        .unwrap();
+    let img = Value::Image(Bytes::from(vec![0u8; 128]));
+
+    let mut lsn = Lsn(0x10);
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iters {
+        let values: Vec<_> = (0..batch_size)
+            .map(|blknum| {
+                lsn += 8;
+                (key(blknum), lsn, img.clone())
+            })
+            .collect();
+
+        let start = Instant::now();
+        let writer = tline.writer();
+        if batched {
+            writer.put_batch(&values).unwrap();
+        } else {
+            for (key, lsn, value) in &values {
+                writer.put(*key, *lsn, value).unwrap();
+            }
+        }
+        writer.finish_write(lsn).unwrap();
+        drop(writer);
+        elapsed += start.elapsed();
+    }
+    // Don't leave the open layer behind for the next round
+    tline.checkpoint(CheckpointConfig::Flush).unwrap();
+    elapsed
+}
+
+fn bench_put(c: &mut Criterion) {
+    page_cache::init(10_000);
+    virtual_file::init(100);
+
+    let workdir = tempfile::tempdir().unwrap();
+    let repo = repository(conf(workdir.path()));
+
+    let mut group = c.benchmark_group("timeline_writer_put");
+    group.sample_size(10);
+    for batch_size in BATCH_SIZES {
+        for (name, batched) in [("put", false), ("put_batch", true)] {
+            group.bench_function(BenchmarkId::new(name, batch_size), |b| {
+                b.iter_custom(|iters| write_batches(&repo, batch_size, iters, batched))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_put);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+//!
+//! Compares reconstructing pages with a request_redo() call per page, and
+//! with request_redo_batch(), which does one round trip to the WAL redo
+//! process for the whole batch.
+//!
+//! This launches a real WAL redo process, so it needs a Postgres build with
+//! the wal-redo mode. By default that is looked for in 'tmp_install' at the
+//! top of the repository; set POSTGRES_DISTRIB_DIR to use another one.
+//!
+//! Run with `cargo bench -p pageserver --bench walredo_batch`.
+//!
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pageserver::config::PageServerConf;
+use pageserver::repository::Key;
+use pageserver::walrecord::ZenithWalRecord;
+use pageserver::walredo::{PostgresRedoManager, RedoRequest, WalRedoManager};
+use postgres_ffi::pg_constants;
+use utils::lsn::Lsn;
+use utils::zid::ZTenantId;
+
+const BATCH_SIZES: [u32; 3] = [1, 8, 32];
+
+fn key(blknum: u32) -> Key {
+    Key {
+        field1: 0x00,
+        field2: pg_constants::DEFAULTTABLESPACE_OID,
+        field3: 13010,
+        field4: 16384,
+        field5: pg_constants::MAIN_FORKNUM,
+        field6: blknum,
+    }
+}
+
+fn conf(workdir: &Path) -> &'static PageServerConf {
+    let pg_distrib_dir = env::var_os("POSTGRES_DISTRIB_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("../tmp_install"));
+
+    let toml = format!("id = 1\npg_distrib_dir = '{}'\n", pg_distrib_dir.display());
+    let conf = PageServerConf::parse_and_validate(&toml.parse().unwrap(), workdir)
+        .expect("a Postgres distribution is needed to run the WAL redo process");
+    Box::leak(Box::new(conf))
+}
+
+/// An XLOG_NOOP record. Replaying it doesn't change the page, so the
+/// measurement is dominated by the round trips to the WAL redo process.
+fn noop_record() -> ZenithWalRecord {
+    const XLOG_NOOP: u8 = 0x20;
+    const XLOG_RECORD_HEADER_SIZE: u32 = 24;
+
+    let mut rec = BytesMut::new();
+    rec.put_u32_le(XLOG_RECORD_HEADER_SIZE); // xl_tot_len
+    rec.put_u32_le(0); // xl_xid
+    rec.put_u64_le(0); // xl_prev
+    rec.put_u8(XLOG_NOOP); // xl_info
+    rec.put_u8(pg_constants::RM_XLOG_ID); // xl_rmid
+    rec.put_u16_le(0); // padding
+    rec.put_u32_le(0); // xl_crc, not checked by the WAL redo process
+    ZenithWalRecord::Postgres {
+        will_init: false,
+        rec: rec.freeze(),
+    }
+}
+
+fn requests(n: u32) -> Vec<RedoRequest> {
+    let img = Bytes::from(vec![0u8; pg_constants::BLCKSZ as usize]);
+    (0..n)
+        .map(|blknum| RedoRequest {
+            key: key(blknum),
+            lsn: Lsn(0x20),
+            base_img: Some(img.clone()),
+            records: vec![(Lsn(0x20), noop_record())],
+        })
+        .collect()
+}
+
+fn bench_redo(c: &mut Criterion) {
+    let workdir = tempfile::tempdir().unwrap();
+    let conf = conf(workdir.path());
+    let tenantid = ZTenantId::generate();
+    fs::create_dir_all(conf.tenant_path(&tenantid)).unwrap();
+    let manager = PostgresRedoManager::new(conf, tenantid);
+
+    // Launch the process before measuring anything.
+    for result in manager.request_redo_batch(requests(1)) {
+        result.unwrap();
+    }
+
+    let mut group = c.benchmark_group("walredo");
+    for n in BATCH_SIZES {
+        group.bench_function(BenchmarkId::new("request_redo", n), |b| {
+            b.iter(|| {
+                for req in requests(n) {
+                    manager
+                        .request_redo(req.key, req.lsn, req.base_img, req.records)
+                        .unwrap();
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("request_redo_batch", n), |b| {
+            b.iter(|| {
+                for result in manager.request_redo_batch(requests(n)) {
+                    result.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_redo);
+criterion_main!(benches);
@@ -19,7 +19,7 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTimelineId},
 };
 
-use crate::layered_repository::TIMELINES_SEGMENT_NAME;
+use crate::layered_repository::{DeltaCompression, TIMELINES_SEGMENT_NAME};
 use crate::tenant_config::{TenantConf, TenantConfOpt};
 
 pub mod defaults {
@@ -38,6 +38,19 @@ pub mod defaults {
 
     pub const DEFAULT_PAGE_CACHE_SIZE: usize = 8192;
     pub const DEFAULT_MAX_FILE_DESCRIPTORS: usize = 100;
+    pub const DEFAULT_MAX_FSYNC_PARALLELISM: usize = 16;
+    pub const DEFAULT_MAX_ANCESTOR_DEPTH: usize = 100;
+    pub const DEFAULT_MAX_TRAVERSAL_PATH_LEN: usize = 50;
+    pub const DEFAULT_WAL_REDO_BATCH_SIZE: usize = 32;
+    pub const DEFAULT_MAX_QUARANTINED_FILES: usize = 10;
+    pub const DEFAULT_MAINTENANCE_STALL_THRESHOLD: &str = "10 min";
+    pub const DEFAULT_MAINTENANCE_JITTER_PERCENT: u64 = 10;
+    pub const DEFAULT_MIN_FREE_DISK_SPACE: u64 = 0;
+    pub const DEFAULT_DELTA_KEY_INDEX: bool = false;
+    pub const DEFAULT_DELTA_COMPRESSION: &str = "none";
+    pub const DEFAULT_VALIDATE_LAYERS_ON_STARTUP: bool = false;
+    pub const DEFAULT_WALREDO_FAILURE_THRESHOLD: usize = 3;
+    pub const DEFAULT_WALREDO_FAILURE_COOLDOWN: &str = "5 min";
 
     ///
     /// Default built-in configuration file.
@@ -53,6 +66,21 @@ pub mod defaults {
 #wal_redo_timeout = '{DEFAULT_WAL_REDO_TIMEOUT}'
 
 #max_file_descriptors = {DEFAULT_MAX_FILE_DESCRIPTORS}
+#max_fsync_parallelism = {DEFAULT_MAX_FSYNC_PARALLELISM}
+#max_ancestor_depth = {DEFAULT_MAX_ANCESTOR_DEPTH}
+#max_traversal_path_len = {DEFAULT_MAX_TRAVERSAL_PATH_LEN}
+#wal_redo_batch_size = {DEFAULT_WAL_REDO_BATCH_SIZE}
+#max_quarantined_files = {DEFAULT_MAX_QUARANTINED_FILES}
+#quarantined_file_retention = '30 days' # not set by default
+#allow_metadata_recovery = false
+#maintenance_stall_threshold = '{DEFAULT_MAINTENANCE_STALL_THRESHOLD}'
+#maintenance_jitter_percent = {DEFAULT_MAINTENANCE_JITTER_PERCENT}
+#min_free_disk_space = {DEFAULT_MIN_FREE_DISK_SPACE} # in bytes
+#delta_key_index = {DEFAULT_DELTA_KEY_INDEX}
+#delta_compression = '{DEFAULT_DELTA_COMPRESSION}'
+#validate_layers_on_startup = {DEFAULT_VALIDATE_LAYERS_ON_STARTUP}
+#walredo_failure_threshold = {DEFAULT_WALREDO_FAILURE_THRESHOLD}
+#walredo_failure_cooldown = '{DEFAULT_WALREDO_FAILURE_COOLDOWN}'
 
 # initial superuser role name to use when creating a new tenant
 #initial_superuser_name = '{DEFAULT_SUPERUSER}'
@@ -60,7 +88,12 @@ pub mod defaults {
 # [tenant_config]
 #checkpoint_distance = {DEFAULT_CHECKPOINT_DISTANCE} # in bytes
 #checkpoint_timeout = {DEFAULT_CHECKPOINT_TIMEOUT}
+#checkpoint_max_entries = {DEFAULT_CHECKPOINT_MAX_ENTRIES}
 #compaction_target_size = {DEFAULT_COMPACTION_TARGET_SIZE} # in bytes
+#compaction_target_file_size = # in bytes, defaults to checkpoint_distance
+#compaction_target_file_size_relation = # in bytes, defaults to compaction_target_file_size
+#compaction_target_file_size_slru = # in bytes, defaults to compaction_target_file_size
+#compaction_target_file_size_metadata = # in bytes, defaults to compaction_target_file_size
 #compaction_period = '{DEFAULT_COMPACTION_PERIOD}'
 #compaction_threshold = '{DEFAULT_COMPACTION_THRESHOLD}'
 
@@ -68,6 +101,15 @@ pub mod defaults {
 #gc_horizon = {DEFAULT_GC_HORIZON}
 #image_creation_threshold = {DEFAULT_IMAGE_CREATION_THRESHOLD}
 #pitr_interval = '{DEFAULT_PITR_INTERVAL}'
+#materialized_cache_enabled = {DEFAULT_MATERIALIZED_CACHE_ENABLED}
+#maintenance_window = '22:00-06:00' # in UTC, not set by default
+#walredo_max_records_size = {DEFAULT_WALREDO_MAX_RECORDS_SIZE} # in bytes
+#compaction_concurrency = {DEFAULT_COMPACTION_CONCURRENCY}
+#compaction_max_input_layers = {DEFAULT_COMPACTION_MAX_INPUT_LAYERS}
+#compaction_strategy = '{DEFAULT_COMPACTION_STRATEGY}'
+#compaction_min_benefit = {DEFAULT_COMPACTION_MIN_BENEFIT}
+#get_requests_per_second = {DEFAULT_GET_REQUESTS_PER_SECOND} # 0 means no limit
+#get_bytes_per_second = {DEFAULT_GET_BYTES_PER_SECOND} # 0 means no limit
 
 # [remote_storage]
 
@@ -95,6 +137,56 @@ pub struct PageServerConf {
 
     pub page_cache_size: usize,
     pub max_file_descriptors: usize,
+    // Max number of threads used to fsync new layer files in parallel.
+    pub max_fsync_parallelism: usize,
+    // Max number of ancestor timelines a read may traverse to reconstruct a value.
+    pub max_ancestor_depth: usize,
+    // Max number of layers of a read's traversal path that are remembered for
+    // error messages. Only the most recent ones are kept.
+    pub max_traversal_path_len: usize,
+    // Max number of WAL redo requests sent to the WAL redo process at once,
+    // when reading many keys.
+    pub wal_redo_batch_size: usize,
+    // Flush frozen in-memory layers on the thread that froze them, instead
+    // of a separate layer flush thread. For deterministic tests only.
+    pub synchronous_flush: bool,
+    // Max number of quarantined .old copies kept per file name. The oldest
+    // copies are deleted when a file is quarantined once more.
+    pub max_quarantined_files: usize,
+    // If set, quarantined .old files older than this are deleted when the
+    // timeline is loaded.
+    pub quarantined_file_retention: Option<Duration>,
+    // Reconstruct the metadata of a timeline from its layer files, if the
+    // metadata file is missing or corrupt. Set by the operator to recover a
+    // timeline that fails to load.
+    pub allow_metadata_recovery: bool,
+    // Report a flush, compaction or GC thread that holds one of the
+    // timeline's maintenance locks for longer than this.
+    pub maintenance_stall_threshold: Duration,
+    // Max random deviation, in percent, of the checkpoint timeout of each
+    // timeline and the compaction period of each tenant from the configured
+    // values. Keeps their background work from running in lockstep.
+    pub maintenance_jitter_percent: u64,
+    // Don't start writing a new layer file if less than this many bytes are
+    // free on the disk, and stop ingesting WAL until there's room again.
+    // 0 disables the check.
+    pub min_free_disk_space: u64,
+    // Write a second index in new delta layers, with the newest version of
+    // each key, to speed up range scans at a single LSN. Layers written with
+    // it can't be read by older pageserver versions.
+    pub delta_key_index: bool,
+    // Compress the values in new delta layers with this codec. Layers
+    // written with compression can't be read by older pageserver versions.
+    pub delta_compression: DeltaCompression,
+    // Check the index of every layer file when a timeline's layer map is
+    // loaded, and fail to load the timeline if one is inconsistent. This
+    // reads all the index blocks, so it slows down startup.
+    pub validate_layers_on_startup: bool,
+    // After this many consecutive WAL redo failures for the same key and
+    // the same WAL, reads of the key fail without attempting WAL redo, until
+    // 'walredo_failure_cooldown' has passed. 0 disables this.
+    pub walredo_failure_threshold: usize,
+    pub walredo_failure_cooldown: Duration,
 
     // Repository directory, relative to current working directory.
     // Normally, the page server changes the current working directory
@@ -170,6 +262,22 @@ struct PageServerConfigBuilder {
 
     page_cache_size: BuilderValue<usize>,
     max_file_descriptors: BuilderValue<usize>,
+    max_fsync_parallelism: BuilderValue<usize>,
+    max_ancestor_depth: BuilderValue<usize>,
+    max_traversal_path_len: BuilderValue<usize>,
+    wal_redo_batch_size: BuilderValue<usize>,
+    synchronous_flush: BuilderValue<bool>,
+    max_quarantined_files: BuilderValue<usize>,
+    quarantined_file_retention: BuilderValue<Option<Duration>>,
+    allow_metadata_recovery: BuilderValue<bool>,
+    maintenance_stall_threshold: BuilderValue<Duration>,
+    maintenance_jitter_percent: BuilderValue<u64>,
+    min_free_disk_space: BuilderValue<u64>,
+    delta_key_index: BuilderValue<bool>,
+    delta_compression: BuilderValue<DeltaCompression>,
+    validate_layers_on_startup: BuilderValue<bool>,
+    walredo_failure_threshold: BuilderValue<usize>,
+    walredo_failure_cooldown: BuilderValue<Duration>,
 
     workdir: BuilderValue<PathBuf>,
 
@@ -202,6 +310,30 @@ impl Default for PageServerConfigBuilder {
             superuser: Set(DEFAULT_SUPERUSER.to_string()),
             page_cache_size: Set(DEFAULT_PAGE_CACHE_SIZE),
             max_file_descriptors: Set(DEFAULT_MAX_FILE_DESCRIPTORS),
+            max_fsync_parallelism: Set(DEFAULT_MAX_FSYNC_PARALLELISM),
+            max_ancestor_depth: Set(DEFAULT_MAX_ANCESTOR_DEPTH),
+            max_traversal_path_len: Set(DEFAULT_MAX_TRAVERSAL_PATH_LEN),
+            wal_redo_batch_size: Set(DEFAULT_WAL_REDO_BATCH_SIZE),
+            synchronous_flush: Set(false),
+            max_quarantined_files: Set(DEFAULT_MAX_QUARANTINED_FILES),
+            quarantined_file_retention: Set(None),
+            allow_metadata_recovery: Set(false),
+            maintenance_stall_threshold: Set(humantime::parse_duration(
+                DEFAULT_MAINTENANCE_STALL_THRESHOLD,
+            )
+            .expect("cannot parse default maintenance stall threshold")),
+            maintenance_jitter_percent: Set(DEFAULT_MAINTENANCE_JITTER_PERCENT),
+            min_free_disk_space: Set(DEFAULT_MIN_FREE_DISK_SPACE),
+            delta_key_index: Set(DEFAULT_DELTA_KEY_INDEX),
+            delta_compression: Set(DEFAULT_DELTA_COMPRESSION
+                .parse()
+                .expect("cannot parse default delta compression")),
+            validate_layers_on_startup: Set(DEFAULT_VALIDATE_LAYERS_ON_STARTUP),
+            walredo_failure_threshold: Set(DEFAULT_WALREDO_FAILURE_THRESHOLD),
+            walredo_failure_cooldown: Set(humantime::parse_duration(
+                DEFAULT_WALREDO_FAILURE_COOLDOWN,
+            )
+            .expect("cannot parse default walredo failure cooldown")),
             workdir: Set(PathBuf::new()),
             pg_distrib_dir: Set(env::current_dir()
                 .expect("cannot access current directory")
@@ -246,6 +378,70 @@ impl PageServerConfigBuilder {
         self.max_file_descriptors = BuilderValue::Set(max_file_descriptors)
     }
 
+    pub fn max_fsync_parallelism(&mut self, max_fsync_parallelism: usize) {
+        self.max_fsync_parallelism = BuilderValue::Set(max_fsync_parallelism)
+    }
+
+    pub fn max_ancestor_depth(&mut self, max_ancestor_depth: usize) {
+        self.max_ancestor_depth = BuilderValue::Set(max_ancestor_depth)
+    }
+
+    pub fn max_traversal_path_len(&mut self, max_traversal_path_len: usize) {
+        self.max_traversal_path_len = BuilderValue::Set(max_traversal_path_len)
+    }
+
+    pub fn wal_redo_batch_size(&mut self, wal_redo_batch_size: usize) {
+        self.wal_redo_batch_size = BuilderValue::Set(wal_redo_batch_size)
+    }
+
+    pub fn synchronous_flush(&mut self, synchronous_flush: bool) {
+        self.synchronous_flush = BuilderValue::Set(synchronous_flush)
+    }
+
+    pub fn max_quarantined_files(&mut self, max_quarantined_files: usize) {
+        self.max_quarantined_files = BuilderValue::Set(max_quarantined_files)
+    }
+
+    pub fn quarantined_file_retention(&mut self, quarantined_file_retention: Option<Duration>) {
+        self.quarantined_file_retention = BuilderValue::Set(quarantined_file_retention)
+    }
+
+    pub fn allow_metadata_recovery(&mut self, allow_metadata_recovery: bool) {
+        self.allow_metadata_recovery = BuilderValue::Set(allow_metadata_recovery)
+    }
+
+    pub fn maintenance_stall_threshold(&mut self, maintenance_stall_threshold: Duration) {
+        self.maintenance_stall_threshold = BuilderValue::Set(maintenance_stall_threshold)
+    }
+
+    pub fn maintenance_jitter_percent(&mut self, maintenance_jitter_percent: u64) {
+        self.maintenance_jitter_percent = BuilderValue::Set(maintenance_jitter_percent)
+    }
+
+    pub fn min_free_disk_space(&mut self, min_free_disk_space: u64) {
+        self.min_free_disk_space = BuilderValue::Set(min_free_disk_space)
+    }
+
+    pub fn delta_key_index(&mut self, delta_key_index: bool) {
+        self.delta_key_index = BuilderValue::Set(delta_key_index)
+    }
+
+    pub fn delta_compression(&mut self, delta_compression: DeltaCompression) {
+        self.delta_compression = BuilderValue::Set(delta_compression)
+    }
+
+    pub fn validate_layers_on_startup(&mut self, validate_layers_on_startup: bool) {
+        self.validate_layers_on_startup = BuilderValue::Set(validate_layers_on_startup)
+    }
+
+    pub fn walredo_failure_threshold(&mut self, walredo_failure_threshold: usize) {
+        self.walredo_failure_threshold = BuilderValue::Set(walredo_failure_threshold)
+    }
+
+    pub fn walredo_failure_cooldown(&mut self, walredo_failure_cooldown: Duration) {
+        self.walredo_failure_cooldown = BuilderValue::Set(walredo_failure_cooldown)
+    }
+
     pub fn workdir(&mut self, workdir: PathBuf) {
         self.workdir = BuilderValue::Set(workdir)
     }
@@ -310,6 +506,54 @@ impl PageServerConfigBuilder {
             max_file_descriptors: self
                 .max_file_descriptors
                 .ok_or(anyhow!("missing max_file_descriptors"))?,
+            max_fsync_parallelism: self
+                .max_fsync_parallelism
+                .ok_or(anyhow!("missing max_fsync_parallelism"))?,
+            max_ancestor_depth: self
+                .max_ancestor_depth
+                .ok_or(anyhow!("missing max_ancestor_depth"))?,
+            max_traversal_path_len: self
+                .max_traversal_path_len
+                .ok_or(anyhow!("missing max_traversal_path_len"))?,
+            wal_redo_batch_size: self
+                .wal_redo_batch_size
+                .ok_or(anyhow!("missing wal_redo_batch_size"))?,
+            synchronous_flush: self
+                .synchronous_flush
+                .ok_or(anyhow!("missing synchronous_flush"))?,
+            max_quarantined_files: self
+                .max_quarantined_files
+                .ok_or(anyhow!("missing max_quarantined_files"))?,
+            quarantined_file_retention: self
+                .quarantined_file_retention
+                .ok_or(anyhow!("missing quarantined_file_retention"))?,
+            allow_metadata_recovery: self
+                .allow_metadata_recovery
+                .ok_or(anyhow!("missing allow_metadata_recovery"))?,
+            maintenance_stall_threshold: self
+                .maintenance_stall_threshold
+                .ok_or(anyhow!("missing maintenance_stall_threshold"))?,
+            maintenance_jitter_percent: self
+                .maintenance_jitter_percent
+                .ok_or(anyhow!("missing maintenance_jitter_percent"))?,
+            min_free_disk_space: self
+                .min_free_disk_space
+                .ok_or(anyhow!("missing min_free_disk_space"))?,
+            delta_key_index: self
+                .delta_key_index
+                .ok_or(anyhow!("missing delta_key_index"))?,
+            delta_compression: self
+                .delta_compression
+                .ok_or(anyhow!("missing delta_compression"))?,
+            validate_layers_on_startup: self
+                .validate_layers_on_startup
+                .ok_or(anyhow!("missing validate_layers_on_startup"))?,
+            walredo_failure_threshold: self
+                .walredo_failure_threshold
+                .ok_or(anyhow!("missing walredo_failure_threshold"))?,
+            walredo_failure_cooldown: self
+                .walredo_failure_cooldown
+                .ok_or(anyhow!("missing walredo_failure_cooldown"))?,
             workdir: self.workdir.ok_or(anyhow!("missing workdir"))?,
             pg_distrib_dir: self
                 .pg_distrib_dir
@@ -387,6 +631,55 @@ impl PageServerConf {
                 "max_file_descriptors" => {
                     builder.max_file_descriptors(parse_toml_u64(key, item)? as usize)
                 }
+                "max_fsync_parallelism" => {
+                    builder.max_fsync_parallelism(parse_toml_u64(key, item)? as usize)
+                }
+                "max_ancestor_depth" => {
+                    builder.max_ancestor_depth(parse_toml_u64(key, item)? as usize)
+                }
+                "max_traversal_path_len" => {
+                    builder.max_traversal_path_len(parse_toml_u64(key, item)? as usize)
+                }
+                "wal_redo_batch_size" => {
+                    builder.wal_redo_batch_size(parse_toml_u64(key, item)? as usize)
+                }
+                "synchronous_flush" => builder.synchronous_flush(parse_toml_bool(key, item)?),
+                "max_quarantined_files" => {
+                    let max_files = parse_toml_u64(key, item)?;
+                    ensure!(max_files > 0, "max_quarantined_files must be at least 1");
+                    builder.max_quarantined_files(max_files as usize)
+                }
+                "quarantined_file_retention" => {
+                    builder.quarantined_file_retention(Some(parse_toml_duration(key, item)?))
+                }
+                "allow_metadata_recovery" => {
+                    builder.allow_metadata_recovery(parse_toml_bool(key, item)?)
+                }
+                "maintenance_stall_threshold" => {
+                    builder.maintenance_stall_threshold(parse_toml_duration(key, item)?)
+                }
+                "maintenance_jitter_percent" => {
+                    let percent = parse_toml_u64(key, item)?;
+                    ensure!(
+                        percent <= 100,
+                        "maintenance_jitter_percent cannot be more than 100"
+                    );
+                    builder.maintenance_jitter_percent(percent)
+                }
+                "min_free_disk_space" => builder.min_free_disk_space(parse_toml_u64(key, item)?),
+                "delta_key_index" => builder.delta_key_index(parse_toml_bool(key, item)?),
+                "delta_compression" => {
+                    builder.delta_compression(parse_toml_from_str(key, item)?)
+                }
+                "validate_layers_on_startup" => {
+                    builder.validate_layers_on_startup(parse_toml_bool(key, item)?)
+                }
+                "walredo_failure_threshold" => {
+                    builder.walredo_failure_threshold(parse_toml_u64(key, item)? as usize)
+                }
+                "walredo_failure_cooldown" => {
+                    builder.walredo_failure_cooldown(parse_toml_duration(key, item)?)
+                }
                 "pg_distrib_dir" => {
                     builder.pg_distrib_dir(PathBuf::from(parse_toml_string(key, item)?))
                 }
@@ -460,6 +753,11 @@ impl PageServerConf {
             )?);
         }
 
+        if let Some(checkpoint_max_entries) = item.get("checkpoint_max_entries") {
+            t_conf.checkpoint_max_entries =
+                Some(parse_toml_u64("checkpoint_max_entries", checkpoint_max_entries)?.try_into()?);
+        }
+
         if let Some(compaction_target_size) = item.get("compaction_target_size") {
             t_conf.compaction_target_size = Some(parse_toml_u64(
                 "compaction_target_size",
@@ -467,6 +765,39 @@ impl PageServerConf {
             )?);
         }
 
+        if let Some(compaction_target_file_size) = item.get("compaction_target_file_size") {
+            t_conf.compaction_target_file_size = Some(parse_toml_u64(
+                "compaction_target_file_size",
+                compaction_target_file_size,
+            )?);
+        }
+
+        if let Some(compaction_target_file_size_relation) =
+            item.get("compaction_target_file_size_relation")
+        {
+            t_conf.compaction_target_file_size_relation = Some(parse_toml_u64(
+                "compaction_target_file_size_relation",
+                compaction_target_file_size_relation,
+            )?);
+        }
+
+        if let Some(compaction_target_file_size_slru) = item.get("compaction_target_file_size_slru")
+        {
+            t_conf.compaction_target_file_size_slru = Some(parse_toml_u64(
+                "compaction_target_file_size_slru",
+                compaction_target_file_size_slru,
+            )?);
+        }
+
+        if let Some(compaction_target_file_size_metadata) =
+            item.get("compaction_target_file_size_metadata")
+        {
+            t_conf.compaction_target_file_size_metadata = Some(parse_toml_u64(
+                "compaction_target_file_size_metadata",
+                compaction_target_file_size_metadata,
+            )?);
+        }
+
         if let Some(compaction_period) = item.get("compaction_period") {
             t_conf.compaction_period =
                 Some(parse_toml_duration("compaction_period", compaction_period)?);
@@ -503,6 +834,59 @@ impl PageServerConf {
         if let Some(max_lsn_wal_lag) = item.get("max_lsn_wal_lag") {
             t_conf.max_lsn_wal_lag = Some(parse_toml_from_str("max_lsn_wal_lag", max_lsn_wal_lag)?);
         }
+        if let Some(materialized_cache_enabled) = item.get("materialized_cache_enabled") {
+            t_conf.materialized_cache_enabled = Some(parse_toml_bool(
+                "materialized_cache_enabled",
+                materialized_cache_enabled,
+            )?);
+        }
+        if let Some(maintenance_window) = item.get("maintenance_window") {
+            t_conf.maintenance_window = Some(parse_toml_from_str(
+                "maintenance_window",
+                maintenance_window,
+            )?);
+        }
+        if let Some(walredo_max_records_size) = item.get("walredo_max_records_size") {
+            t_conf.walredo_max_records_size = Some(parse_toml_u64(
+                "walredo_max_records_size",
+                walredo_max_records_size,
+            )?);
+        }
+        if let Some(compaction_concurrency) = item.get("compaction_concurrency") {
+            let concurrency = parse_toml_u64("compaction_concurrency", compaction_concurrency)?;
+            ensure!(concurrency > 0, "compaction_concurrency must be at least 1");
+            t_conf.compaction_concurrency = Some(concurrency.try_into()?);
+        }
+        if let Some(compaction_max_input_layers) = item.get("compaction_max_input_layers") {
+            t_conf.compaction_max_input_layers = Some(
+                parse_toml_u64("compaction_max_input_layers", compaction_max_input_layers)?
+                    .try_into()?,
+            );
+        }
+        if let Some(compaction_strategy) = item.get("compaction_strategy") {
+            t_conf.compaction_strategy = Some(parse_toml_from_str(
+                "compaction_strategy",
+                compaction_strategy,
+            )?);
+        }
+        if let Some(compaction_min_benefit) = item.get("compaction_min_benefit") {
+            t_conf.compaction_min_benefit = Some(parse_toml_u64(
+                "compaction_min_benefit",
+                compaction_min_benefit,
+            )?);
+        }
+        if let Some(get_requests_per_second) = item.get("get_requests_per_second") {
+            t_conf.get_requests_per_second = Some(parse_toml_u64(
+                "get_requests_per_second",
+                get_requests_per_second,
+            )?);
+        }
+        if let Some(get_bytes_per_second) = item.get("get_bytes_per_second") {
+            t_conf.get_bytes_per_second = Some(parse_toml_u64(
+                "get_bytes_per_second",
+                get_bytes_per_second,
+            )?);
+        }
 
         Ok(t_conf)
     }
@@ -520,6 +904,22 @@ impl PageServerConf {
             wal_redo_timeout: Duration::from_secs(60),
             page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
             max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
+            max_fsync_parallelism: defaults::DEFAULT_MAX_FSYNC_PARALLELISM,
+            max_ancestor_depth: defaults::DEFAULT_MAX_ANCESTOR_DEPTH,
+            max_traversal_path_len: defaults::DEFAULT_MAX_TRAVERSAL_PATH_LEN,
+            wal_redo_batch_size: defaults::DEFAULT_WAL_REDO_BATCH_SIZE,
+            synchronous_flush: false,
+            max_quarantined_files: defaults::DEFAULT_MAX_QUARANTINED_FILES,
+            quarantined_file_retention: None,
+            allow_metadata_recovery: false,
+            maintenance_stall_threshold: Duration::from_secs(600),
+            maintenance_jitter_percent: 0,
+            min_free_disk_space: 0,
+            delta_key_index: false,
+            delta_compression: DeltaCompression::None,
+            validate_layers_on_startup: false,
+            walredo_failure_threshold: defaults::DEFAULT_WALREDO_FAILURE_THRESHOLD,
+            walredo_failure_cooldown: Duration::from_secs(300),
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
             listen_http_addr: defaults::DEFAULT_HTTP_LISTEN_ADDR.to_string(),
             superuser: "cloud_admin".to_string(),
@@ -557,6 +957,11 @@ fn parse_toml_u64(name: &str, item: &Item) -> Result<u64> {
     Ok(i as u64)
 }
 
+fn parse_toml_bool(name: &str, item: &Item) -> Result<bool> {
+    item.as_bool()
+        .with_context(|| format!("configure option {name} is not a boolean"))
+}
+
 fn parse_toml_duration(name: &str, item: &Item) -> Result<Duration> {
     let s = item
         .as_str()
@@ -620,6 +1025,22 @@ wal_redo_timeout = '111 s'
 
 page_cache_size = 444
 max_file_descriptors = 333
+max_fsync_parallelism = 7
+max_ancestor_depth = 55
+max_traversal_path_len = 44
+wal_redo_batch_size = 66
+synchronous_flush = true
+max_quarantined_files = 77
+quarantined_file_retention = '88 s'
+allow_metadata_recovery = true
+maintenance_stall_threshold = '99 s'
+maintenance_jitter_percent = 5
+min_free_disk_space = 12345
+delta_key_index = true
+delta_compression = 'zstd'
+validate_layers_on_startup = true
+walredo_failure_threshold = 13
+walredo_failure_cooldown = '111 s'
 
 # initial superuser role name to use when creating a new tenant
 initial_superuser_name = 'zzzz'
@@ -653,6 +1074,26 @@ id = 10
                 superuser: defaults::DEFAULT_SUPERUSER.to_string(),
                 page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
                 max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
+                max_fsync_parallelism: defaults::DEFAULT_MAX_FSYNC_PARALLELISM,
+                max_ancestor_depth: defaults::DEFAULT_MAX_ANCESTOR_DEPTH,
+                max_traversal_path_len: defaults::DEFAULT_MAX_TRAVERSAL_PATH_LEN,
+                wal_redo_batch_size: defaults::DEFAULT_WAL_REDO_BATCH_SIZE,
+                synchronous_flush: false,
+                max_quarantined_files: defaults::DEFAULT_MAX_QUARANTINED_FILES,
+                quarantined_file_retention: None,
+                allow_metadata_recovery: false,
+                maintenance_stall_threshold: humantime::parse_duration(
+                    defaults::DEFAULT_MAINTENANCE_STALL_THRESHOLD
+                )?,
+                maintenance_jitter_percent: defaults::DEFAULT_MAINTENANCE_JITTER_PERCENT,
+                min_free_disk_space: defaults::DEFAULT_MIN_FREE_DISK_SPACE,
+                delta_key_index: defaults::DEFAULT_DELTA_KEY_INDEX,
+                delta_compression: defaults::DEFAULT_DELTA_COMPRESSION.parse()?,
+                validate_layers_on_startup: defaults::DEFAULT_VALIDATE_LAYERS_ON_STARTUP,
+                walredo_failure_threshold: defaults::DEFAULT_WALREDO_FAILURE_THRESHOLD,
+                walredo_failure_cooldown: humantime::parse_duration(
+                    defaults::DEFAULT_WALREDO_FAILURE_COOLDOWN
+                )?,
                 workdir,
                 pg_distrib_dir,
                 auth_type: AuthType::Trust,
@@ -697,6 +1138,22 @@ id = 10
                 superuser: "zzzz".to_string(),
                 page_cache_size: 444,
                 max_file_descriptors: 333,
+                max_fsync_parallelism: 7,
+                max_ancestor_depth: 55,
+                max_traversal_path_len: 44,
+                wal_redo_batch_size: 66,
+                synchronous_flush: true,
+                max_quarantined_files: 77,
+                quarantined_file_retention: Some(Duration::from_secs(88)),
+                allow_metadata_recovery: true,
+                maintenance_stall_threshold: Duration::from_secs(99),
+                maintenance_jitter_percent: 5,
+                min_free_disk_space: 12345,
+                delta_key_index: true,
+                delta_compression: DeltaCompression::Zstd,
+                validate_layers_on_startup: true,
+                walredo_failure_threshold: 13,
+                walredo_failure_cooldown: Duration::from_secs(111),
                 workdir,
                 pg_distrib_dir,
                 auth_type: AuthType::Trust,
@@ -840,6 +1297,38 @@ broker_endpoints = ['{broker_endpoint}']
         Ok(())
     }
 
+    #[test]
+    fn parse_rejects_invalid_values() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        for (option, expected_error) in [
+            (
+                "max_quarantined_files = 0",
+                "max_quarantined_files must be at least 1",
+            ),
+            (
+                "[tenant_config]\ncompaction_concurrency = 0",
+                "compaction_concurrency must be at least 1",
+            ),
+        ] {
+            let config_string = format!(
+                "pg_distrib_dir='{}'\nid=10\nbroker_endpoints = ['{broker_endpoint}']\n{option}",
+                pg_distrib_dir.display()
+            );
+            let toml = config_string.parse()?;
+
+            let err = PageServerConf::parse_and_validate(&toml, &workdir)
+                .expect_err("config with an invalid value should be rejected");
+            assert!(
+                format!("{err:#}").contains(expected_error),
+                "unexpected error for '{option}': {err:#}"
+            );
+        }
+        Ok(())
+    }
+
     fn prepare_fs(tempdir: &TempDir) -> anyhow::Result<(PathBuf, PathBuf)> {
         let tempdir_path = tempdir.path();
 
@@ -33,7 +33,12 @@ pub struct TenantCreateRequest {
     pub new_tenant_id: Option<ZTenantId>,
     pub checkpoint_distance: Option<u64>,
     pub checkpoint_timeout: Option<String>,
+    pub checkpoint_max_entries: Option<usize>,
     pub compaction_target_size: Option<u64>,
+    pub compaction_target_file_size: Option<u64>,
+    pub compaction_target_file_size_relation: Option<u64>,
+    pub compaction_target_file_size_slru: Option<u64>,
+    pub compaction_target_file_size_metadata: Option<u64>,
     pub compaction_period: Option<String>,
     pub compaction_threshold: Option<usize>,
     pub gc_horizon: Option<u64>,
@@ -43,6 +48,15 @@ pub struct TenantCreateRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub materialized_cache_enabled: Option<bool>,
+    pub maintenance_window: Option<String>,
+    pub walredo_max_records_size: Option<u64>,
+    pub compaction_concurrency: Option<usize>,
+    pub compaction_max_input_layers: Option<usize>,
+    pub compaction_strategy: Option<String>,
+    pub compaction_min_benefit: Option<u64>,
+    pub get_requests_per_second: Option<u64>,
+    pub get_bytes_per_second: Option<u64>,
 }
 
 #[serde_as]
@@ -72,7 +86,12 @@ pub struct TenantConfigRequest {
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub checkpoint_distance: Option<u64>,
     pub checkpoint_timeout: Option<String>,
+    pub checkpoint_max_entries: Option<usize>,
     pub compaction_target_size: Option<u64>,
+    pub compaction_target_file_size: Option<u64>,
+    pub compaction_target_file_size_relation: Option<u64>,
+    pub compaction_target_file_size_slru: Option<u64>,
+    pub compaction_target_file_size_metadata: Option<u64>,
     pub compaction_period: Option<String>,
     pub compaction_threshold: Option<usize>,
     pub gc_horizon: Option<u64>,
@@ -82,6 +101,15 @@ pub struct TenantConfigRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub materialized_cache_enabled: Option<bool>,
+    pub maintenance_window: Option<String>,
+    pub walredo_max_records_size: Option<u64>,
+    pub compaction_concurrency: Option<usize>,
+    pub compaction_max_input_layers: Option<usize>,
+    pub compaction_strategy: Option<String>,
+    pub compaction_min_benefit: Option<u64>,
+    pub get_requests_per_second: Option<u64>,
+    pub get_bytes_per_second: Option<u64>,
 }
 
 impl TenantConfigRequest {
@@ -90,7 +118,12 @@ impl TenantConfigRequest {
             tenant_id,
             checkpoint_distance: None,
             checkpoint_timeout: None,
+            checkpoint_max_entries: None,
             compaction_target_size: None,
+            compaction_target_file_size: None,
+            compaction_target_file_size_relation: None,
+            compaction_target_file_size_slru: None,
+            compaction_target_file_size_metadata: None,
             compaction_period: None,
             compaction_threshold: None,
             gc_horizon: None,
@@ -100,6 +133,15 @@ impl TenantConfigRequest {
             walreceiver_connect_timeout: None,
             lagging_wal_timeout: None,
             max_lsn_wal_lag: None,
+            materialized_cache_enabled: None,
+            maintenance_window: None,
+            walredo_max_records_size: None,
+            compaction_concurrency: None,
+            compaction_max_input_layers: None,
+            compaction_strategy: None,
+            compaction_min_benefit: None,
+            get_requests_per_second: None,
+            get_bytes_per_second: None,
         }
     }
 }
@@ -127,6 +169,9 @@ pub struct LocalTimelineInfo {
     pub prev_record_lsn: Option<Lsn>,
     #[serde_as(as = "DisplayFromStr")]
     pub latest_gc_cutoff_lsn: Lsn,
+    /// The earliest LSN that can be read or branched from
+    #[serde_as(as = "DisplayFromStr")]
+    pub earliest_available_lsn: Lsn,
     #[serde_as(as = "DisplayFromStr")]
     pub disk_consistent_lsn: Lsn,
     pub current_logical_size: Option<usize>, // is None when timeline is Unloaded
@@ -1,3 +1,4 @@
+use std::cmp::max;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -116,6 +117,7 @@ fn local_timeline_info_from_loaded_timeline(
         last_record_lsn,
         prev_record_lsn: Some(timeline.get_prev_record_lsn()),
         latest_gc_cutoff_lsn: *timeline.get_latest_gc_cutoff_lsn(),
+        earliest_available_lsn: timeline.effective_gc_floor(),
         timeline_state: LocalTimelineState::Loaded,
         current_logical_size: Some(timeline.get_current_logical_size()),
         current_physical_size: Some(timeline.get_physical_size()),
@@ -149,6 +151,7 @@ fn local_timeline_info_from_unloaded_timeline(metadata: &TimelineMetadata) -> Lo
         last_record_lsn: metadata.disk_consistent_lsn(),
         prev_record_lsn: metadata.prev_record_lsn(),
         latest_gc_cutoff_lsn: metadata.latest_gc_cutoff_lsn(),
+        earliest_available_lsn: max(metadata.initdb_lsn(), metadata.latest_gc_cutoff_lsn()),
         timeline_state: LocalTimelineState::Unloaded,
         current_logical_size: None,
         current_physical_size: None,
@@ -368,6 +371,29 @@ async fn timeline_detail_handler(request: Request<Body>) -> Result<Response<Body
 }
 
 // TODO makes sense to provide tenant config right away the same way as it handled in tenant_create
+// Reports the tenant config that the timeline actually uses, with the
+// pageserver defaults filled in for the settings the tenant doesn't override.
+async fn timeline_effective_config_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let effective_conf = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("timeline_effective_config_handler", tenant = %tenant_id, timeline = %timeline_id).entered();
+        let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+        let timeline = repo.get_timeline_load(timeline_id)?;
+        Ok::<_, anyhow::Error>(timeline.effective_tenant_conf())
+    })
+    .await
+    .map_err(ApiError::from_err)?
+    .map_err(|e| ApiError::NotFound(format!("{e:#}")))?;
+
+    json_response(StatusCode::OK, effective_conf)
+}
+
 async fn tenant_attach_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -539,6 +565,20 @@ async fn tenant_list_handler(request: Request<Body>) -> Result<Response<Body>, A
     json_response(StatusCode::OK, response_data)
 }
 
+async fn inmemory_layers_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let response_data = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("inmemory_layers").entered();
+        tenant_mgr::inmemory_layer_report()
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, response_data)
+}
+
 async fn tenant_status(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -621,14 +661,41 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    tenant_conf.materialized_cache_enabled = request_data.materialized_cache_enabled;
+    if let Some(maintenance_window) = request_data.maintenance_window {
+        tenant_conf.maintenance_window =
+            Some(maintenance_window.parse().map_err(ApiError::from_err)?);
+    }
+    tenant_conf.walredo_max_records_size = request_data.walredo_max_records_size;
+    if request_data.compaction_concurrency == Some(0) {
+        return Err(ApiError::BadRequest(
+            "compaction_concurrency must be at least 1".to_string(),
+        ));
+    }
+    tenant_conf.compaction_concurrency = request_data.compaction_concurrency;
+    tenant_conf.compaction_max_input_layers = request_data.compaction_max_input_layers;
+    if let Some(compaction_strategy) = request_data.compaction_strategy {
+        tenant_conf.compaction_strategy =
+            Some(compaction_strategy.parse().map_err(ApiError::from_err)?);
+    }
+    tenant_conf.compaction_min_benefit = request_data.compaction_min_benefit;
+    tenant_conf.get_requests_per_second = request_data.get_requests_per_second;
+    tenant_conf.get_bytes_per_second = request_data.get_bytes_per_second;
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     if let Some(checkpoint_timeout) = request_data.checkpoint_timeout {
         tenant_conf.checkpoint_timeout =
             Some(humantime::parse_duration(&checkpoint_timeout).map_err(ApiError::from_err)?);
     }
+    tenant_conf.checkpoint_max_entries = request_data.checkpoint_max_entries;
 
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
+    tenant_conf.compaction_target_file_size = request_data.compaction_target_file_size;
+    tenant_conf.compaction_target_file_size_relation =
+        request_data.compaction_target_file_size_relation;
+    tenant_conf.compaction_target_file_size_slru = request_data.compaction_target_file_size_slru;
+    tenant_conf.compaction_target_file_size_metadata =
+        request_data.compaction_target_file_size_metadata;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
 
     if let Some(compaction_period) = request_data.compaction_period {
@@ -686,13 +753,40 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    tenant_conf.materialized_cache_enabled = request_data.materialized_cache_enabled;
+    if let Some(maintenance_window) = request_data.maintenance_window {
+        tenant_conf.maintenance_window =
+            Some(maintenance_window.parse().map_err(ApiError::from_err)?);
+    }
+    tenant_conf.walredo_max_records_size = request_data.walredo_max_records_size;
+    if request_data.compaction_concurrency == Some(0) {
+        return Err(ApiError::BadRequest(
+            "compaction_concurrency must be at least 1".to_string(),
+        ));
+    }
+    tenant_conf.compaction_concurrency = request_data.compaction_concurrency;
+    tenant_conf.compaction_max_input_layers = request_data.compaction_max_input_layers;
+    if let Some(compaction_strategy) = request_data.compaction_strategy {
+        tenant_conf.compaction_strategy =
+            Some(compaction_strategy.parse().map_err(ApiError::from_err)?);
+    }
+    tenant_conf.compaction_min_benefit = request_data.compaction_min_benefit;
+    tenant_conf.get_requests_per_second = request_data.get_requests_per_second;
+    tenant_conf.get_bytes_per_second = request_data.get_bytes_per_second;
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     if let Some(checkpoint_timeout) = request_data.checkpoint_timeout {
         tenant_conf.checkpoint_timeout =
             Some(humantime::parse_duration(&checkpoint_timeout).map_err(ApiError::from_err)?);
     }
+    tenant_conf.checkpoint_max_entries = request_data.checkpoint_max_entries;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
+    tenant_conf.compaction_target_file_size = request_data.compaction_target_file_size;
+    tenant_conf.compaction_target_file_size_relation =
+        request_data.compaction_target_file_size_relation;
+    tenant_conf.compaction_target_file_size_slru = request_data.compaction_target_file_size_slru;
+    tenant_conf.compaction_target_file_size_metadata =
+        request_data.compaction_target_file_size_metadata;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
 
     if let Some(compaction_period) = request_data.compaction_period {
@@ -742,6 +836,7 @@ pub fn make_router(
         ))
         .get("/v1/status", status_handler)
         .get("/v1/tenant", tenant_list_handler)
+        .get("/v1/inmemory_layers", inmemory_layers_handler)
         .post("/v1/tenant", tenant_create_handler)
         .get("/v1/tenant/:tenant_id", tenant_status)
         .put("/v1/tenant/config", tenant_config_handler)
@@ -753,6 +848,10 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_detail_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/effective_config",
+            timeline_effective_config_handler,
+        )
         .delete(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_handler,
@@ -538,8 +538,13 @@ pub fn import_file<T: DatadirTimeline, Reader: Read>(
         // zenith.signal is not necessarily the last file, that we handle
         // but it is ok to call `finish_write()`, because final `modification.commit()`
         // will update lsn once more to the final one.
-        let writer = modification.tline.writer();
-        writer.finish_write(prev_lsn);
+        //
+        // Without a prev LSN there's nothing to record, and finish_write()
+        // would refuse Lsn(0) on a fresh timeline.
+        if prev_lsn.is_valid() {
+            let writer = modification.tline.writer();
+            writer.finish_write(prev_lsn)?;
+        }
 
         debug!("imported zenith signal {}", prev_lsn);
     } else if file_path.starts_with("pg_tblspc") {
@@ -0,0 +1,67 @@
+//!
+//! Random jitter for the periods of background maintenance.
+//!
+//! Timelines and tenants created with the same configuration would otherwise
+//! freeze, flush and compact in lockstep, and their I/O would pile up in
+//! periodic spikes. Each of them draws its own jitter factor once, so their
+//! periods stay stable but differ from each other by up to
+//! 'maintenance_jitter_percent'.
+//!
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jitter {
+    factor: f64,
+}
+
+impl Jitter {
+    /// A random jitter of at most 'percent' percent, in either direction.
+    pub fn new<R: Rng + ?Sized>(percent: u64, rng: &mut R) -> Self {
+        let percent = percent.min(100) as f64;
+        let factor = if percent > 0.0 {
+            1.0 + rng.gen_range(-percent..=percent) / 100.0
+        } else {
+            1.0
+        };
+        Jitter { factor }
+    }
+
+    /// Jitter with the thread-local RNG.
+    pub fn random(percent: u64) -> Self {
+        Self::new(percent, &mut rand::thread_rng())
+    }
+
+    pub fn apply(&self, period: Duration) -> Duration {
+        period.mul_f64(self.factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn jitter_desynchronizes_periods() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let period = Duration::from_secs(600);
+
+        // Two timelines with the same configuration
+        let first = Jitter::new(10, &mut rng).apply(period);
+        let second = Jitter::new(10, &mut rng).apply(period);
+        assert_ne!(first, second);
+        for jittered in [first, second] {
+            assert!(jittered >= Duration::from_secs(540), "{jittered:?}");
+            assert!(jittered <= Duration::from_secs(660), "{jittered:?}");
+        }
+
+        // The period of one timeline stays the same
+        let jitter = Jitter::new(10, &mut rng);
+        assert_eq!(jitter.apply(period), jitter.apply(period));
+
+        assert_eq!(Jitter::new(0, &mut rng).apply(period), period);
+    }
+}
@@ -5,7 +5,7 @@ use std::ops::Range;
 ///
 /// Represents a set of Keys, in a compact form.
 ///
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct KeySpace {
     /// Contiguous ranges of keys that belong to the key space. In key order,
     /// and with no overlap.
@@ -13,6 +13,36 @@ pub struct KeySpace {
 }
 
 impl KeySpace {
+    ///
+    /// Return the keys in this key space that are not in 'other'.
+    ///
+    pub fn subtract(&self, other: &KeySpace) -> KeySpace {
+        let mut result = KeySpaceAccum::new();
+        let mut cut_idx = 0;
+        for range in &self.ranges {
+            // Ranges in 'other' that end before this range can't overlap with
+            // the following ranges either.
+            while cut_idx < other.ranges.len() && other.ranges[cut_idx].end <= range.start {
+                cut_idx += 1;
+            }
+
+            let mut start = range.start;
+            for cut in other.ranges[cut_idx..]
+                .iter()
+                .take_while(|cut| cut.start < range.end)
+            {
+                if cut.start > start {
+                    result.add_range(start..cut.start);
+                }
+                start = start.max(cut.end);
+            }
+            if start < range.end {
+                result.add_range(start..range.end);
+            }
+        }
+        result.to_keyspace()
+    }
+
     ///
     /// Partition a key space into roughly chunks of roughly 'target_size' bytes
     /// in each partition.
@@ -70,7 +100,7 @@ impl KeySpace {
 /// partitions that are roughly equal in physical size (see KeySpace::partition).
 /// But this data structure could represent any partitioning.
 ///
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct KeyPartitioning {
     pub parts: Vec<KeySpace>,
 }
@@ -79,6 +109,15 @@ impl KeyPartitioning {
     pub fn new() -> Self {
         KeyPartitioning { parts: Vec::new() }
     }
+
+    /// Return the key space that was partitioned.
+    pub fn keyspace(&self) -> KeySpace {
+        let mut result = KeySpaceAccum::new();
+        for range in self.parts.iter().flat_map(|part| part.ranges.iter()) {
+            result.add_range(range.clone());
+        }
+        result.to_keyspace()
+    }
 }
 
 ///
@@ -106,6 +145,11 @@ impl KeySpaceAccum {
     }
 
     pub fn add_range(&mut self, range: Range<Key>) {
+        // An empty range doesn't add any keys. Skip it, so that the same set of
+        // keys always results in the same ranges.
+        if range.start == range.end {
+            return;
+        }
         match self.accum.as_mut() {
             Some(accum) => {
                 if range.start == accum.end {
@@ -129,3 +173,57 @@ impl KeySpaceAccum {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(field6: u32) -> Key {
+        Key {
+            field1: 0,
+            field2: 0,
+            field3: 0,
+            field4: 1,
+            field5: 0,
+            field6,
+        }
+    }
+
+    fn keyspace(ranges: &[(u32, u32)]) -> KeySpace {
+        KeySpace {
+            ranges: ranges
+                .iter()
+                .map(|&(start, end)| key(start)..key(end))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn subtract_keyspace() {
+        let ks = keyspace(&[(0, 10), (20, 30), (40, 50)]);
+
+        assert_eq!(ks.subtract(&keyspace(&[])), ks);
+        assert_eq!(ks.subtract(&ks), keyspace(&[]));
+        assert_eq!(
+            ks.subtract(&keyspace(&[(5, 25), (45, 46)])),
+            keyspace(&[(0, 5), (25, 30), (40, 45), (46, 50)])
+        );
+        assert_eq!(ks.subtract(&keyspace(&[(10, 20), (30, 40), (50, 60)])), ks);
+        assert_eq!(ks.subtract(&keyspace(&[(0, 100)])), keyspace(&[]));
+    }
+
+    #[test]
+    fn partitioning_keyspace_roundtrip() {
+        let mut accum = KeySpaceAccum::new();
+        accum.add_range(key(0)..key(1000));
+        accum.add_range(key(1000)..key(1000));
+        accum.add_range(key(2000)..key(2500));
+        let ks = accum.to_keyspace();
+        assert_eq!(ks, keyspace(&[(0, 1000), (2000, 2500)]));
+
+        // Split into several partitions, and merge back
+        let partitioning = ks.partition(100 * pg_constants::BLCKSZ as u64);
+        assert!(partitioning.parts.len() > 1);
+        assert_eq!(partitioning.keyspace(), ks);
+    }
+}
@@ -20,19 +20,22 @@ use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
+use std::io::Read;
 use std::num::NonZeroU64;
 use std::ops::Bound::Included;
 use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use self::metadata::{metadata_path, TimelineMetadata};
 use crate::config::PageServerConf;
+use crate::jitter::Jitter;
 use crate::storage_sync::index::RemoteIndex;
-use crate::tenant_config::{TenantConf, TenantConfOpt};
+use crate::tenant_config::{CompactionStrategy, MaintenanceWindow, TenantConf, TenantConfOpt};
 
 use crate::repository::{GcResult, Repository, RepositoryTimeline, Timeline};
 use crate::thread_mgr;
+use crate::thread_mgr::CancellationToken;
 use crate::walredo::WalRedoManager;
 use crate::CheckpointConfig;
 
@@ -43,21 +46,32 @@ use utils::{
     zid::{ZTenantId, ZTimelineId},
 };
 
+mod access_tracker;
 mod blob_io;
 pub mod block_io;
+mod compaction_limiter;
 mod delta_layer;
 mod disk_btree;
+mod disk_space;
 pub(crate) mod ephemeral_file;
 mod filename;
+mod get_rate_limiter;
 mod image_layer;
 mod inmemory_layer;
 mod layer_map;
+mod layer_transfer;
+pub mod maintenance_observer;
 pub mod metadata;
 mod par_fsync;
+mod prev_record_lsns;
+mod stall_watchdog;
 mod storage_layer;
 
 mod timeline;
 
+use compaction_limiter::CompactionLimiter;
+use get_rate_limiter::{GetRateLimiter, GetRateLimits};
+use maintenance_observer::{MaintenanceObserver, ObserverSlot};
 use storage_layer::Layer;
 use timeline::{LayeredTimeline, LayeredTimelineEntry};
 
@@ -70,6 +84,45 @@ pub use crate::layered_repository::timeline::save_metadata;
 // re-export for use in walreceiver
 pub use crate::layered_repository::timeline::WalReceiverInfo;
 
+// re-export for tenant_mgr and the HTTP API
+pub use crate::layered_repository::timeline::InMemoryLayerStat;
+
+// re-export so that callers of get_with_deadline() can recognize timeouts
+pub use crate::layered_repository::timeline::GetTimeoutError;
+
+// re-export so that readers can recognize requests for garbage collected LSNs
+pub use crate::layered_repository::timeline::LsnGarbageCollected;
+
+// re-export so that the WAL receiver can recognize paused timelines
+pub use crate::layered_repository::timeline::IngestPaused;
+
+// re-export so that readers can recognize pages that have never been written
+pub use crate::layered_repository::timeline::KeyNotFoundError;
+
+// re-export for the HTTP API
+pub use crate::layered_repository::timeline::{GcInfoSnapshot, RetainLsn, RetainLsnSource};
+
+// re-export so that the page service can hold back rate limited requests
+pub use crate::layered_repository::get_rate_limiter::RateLimited;
+
+// re-export so that readers can recognize keys whose WAL redo keeps failing
+pub use crate::layered_repository::timeline::WalRedoCircuitOpen;
+
+// re-export so that callers can recognize writes refused for lack of disk space
+pub use crate::layered_repository::disk_space::DiskSpaceLow;
+
+// re-export so that damaged files can be moved aside from outside of the timeline
+pub use crate::layered_repository::timeline::quarantine_file;
+
+// re-export so that storage_sync.rs doesn't upload the file as a layer
+pub use crate::layered_repository::prev_record_lsns::PREV_RECORD_LSNS_FILE_NAME;
+
+// re-export for the delta layer benchmarks
+pub use crate::layered_repository::delta_layer::{DeltaLayer, DeltaLayerWriter};
+
+// re-export for the 'delta_compression' option in config.rs
+pub use crate::layered_repository::delta_layer::DeltaCompression;
+
 /// Parts of the `.neon/tenants/<tenantid>/timelines/<timelineid>` directory prefix.
 pub const TIMELINES_SEGMENT_NAME: &str = "timelines";
 
@@ -109,6 +162,24 @@ pub struct LayeredRepository {
     gc_cs: Mutex<()>,
     walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
 
+    // Shared by all the timelines of the tenant, to limit the number of
+    // compactions running at the same time.
+    compaction_limiter: Arc<CompactionLimiter>,
+
+    // Set when the tenant is detached or the pageserver shuts down, to stop
+    // compactions in progress on all timelines of the tenant.
+    compaction_cancel: Arc<CancellationToken>,
+
+    // Told about the layers created and removed by compaction and GC on all
+    // the timelines of the tenant
+    maintenance_observer: Arc<ObserverSlot>,
+
+    // Limits the rate of GetPage requests to all the timelines of the tenant
+    get_rate_limiter: Arc<GetRateLimiter>,
+
+    // Spreads the compaction rounds of tenants with the same compaction_period
+    compaction_jitter: Jitter,
+
     // provides access to timeline data sitting in the remote storage
     // supposed to be used for retrieval of remote consistent lsn in walreceiver
     remote_index: RemoteIndex,
@@ -182,6 +253,11 @@ impl Repository for LayeredRepository {
             timeline_id,
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
+            Arc::clone(&self.compaction_limiter),
+            Arc::clone(&self.compaction_cancel),
+            Arc::clone(&self.maintenance_observer),
+            Arc::clone(&self.get_rate_limiter),
+            self.remote_index.clone(),
             self.upload_layers,
         );
         timeline.layers.write().unwrap().next_open_layer_at = Some(initdb_lsn);
@@ -231,13 +307,12 @@ impl Repository for LayeredRepository {
         });
 
         // Check if the starting LSN is out of scope because it is less than
-        // 1. the latest GC cutoff LSN or
-        // 2. the planned GC cutoff LSN, which is from an in-queue GC iteration.
+        // 1. the initdb LSN,
+        // 2. the latest GC cutoff LSN or
+        // 3. the planned GC cutoff LSN, which is from an in-queue GC iteration.
         src_timeline
             .check_lsn_is_in_scope(start_lsn, &latest_gc_cutoff_lsn)
-            .context(format!(
-                "invalid branch start lsn: less than latest GC cutoff {latest_gc_cutoff_lsn}"
-            ))?;
+            .context("invalid branch start lsn")?;
         {
             let gc_info = src_timeline.gc_info.read().unwrap();
             let cutoff = min(gc_info.pitr_cutoff, gc_info.horizon_cutoff);
@@ -323,6 +398,10 @@ impl Repository for LayeredRepository {
             match timeline {
                 LayeredTimelineEntry::Loaded(timeline) => {
                     timeline.compact()?;
+                    // Throttled, this only does something every few minutes
+                    if let Err(e) = timeline.verify_logical_size(false) {
+                        warn!("failed to verify the logical size: {:#}", e);
+                    }
                 }
                 LayeredTimelineEntry::Unloaded { .. } => {
                     debug!("Cannot compact remote timeline {}", timelineid)
@@ -440,6 +519,13 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.checkpoint_timeout)
     }
 
+    pub fn get_checkpoint_max_entries(&self) -> usize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .checkpoint_max_entries
+            .unwrap_or(self.conf.default_tenant_conf.checkpoint_max_entries)
+    }
+
     pub fn get_compaction_target_size(&self) -> u64 {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -447,6 +533,63 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.compaction_target_size)
     }
 
+    pub fn get_compaction_target_file_size(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .compaction_target_file_size
+            .or(self.conf.default_tenant_conf.compaction_target_file_size)
+            .unwrap_or_else(|| {
+                tenant_conf
+                    .checkpoint_distance
+                    .unwrap_or(self.conf.default_tenant_conf.checkpoint_distance)
+            })
+    }
+
+    pub fn get_compaction_target_file_size_relation(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        let defaults = &self.conf.default_tenant_conf;
+        tenant_conf
+            .compaction_target_file_size_relation
+            .or(defaults.compaction_target_file_size_relation)
+            .or(tenant_conf.compaction_target_file_size)
+            .or(defaults.compaction_target_file_size)
+            .unwrap_or_else(|| {
+                tenant_conf
+                    .checkpoint_distance
+                    .unwrap_or(defaults.checkpoint_distance)
+            })
+    }
+
+    pub fn get_compaction_target_file_size_slru(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        let defaults = &self.conf.default_tenant_conf;
+        tenant_conf
+            .compaction_target_file_size_slru
+            .or(defaults.compaction_target_file_size_slru)
+            .or(tenant_conf.compaction_target_file_size)
+            .or(defaults.compaction_target_file_size)
+            .unwrap_or_else(|| {
+                tenant_conf
+                    .checkpoint_distance
+                    .unwrap_or(defaults.checkpoint_distance)
+            })
+    }
+
+    pub fn get_compaction_target_file_size_metadata(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        let defaults = &self.conf.default_tenant_conf;
+        tenant_conf
+            .compaction_target_file_size_metadata
+            .or(defaults.compaction_target_file_size_metadata)
+            .or(tenant_conf.compaction_target_file_size)
+            .or(defaults.compaction_target_file_size)
+            .unwrap_or_else(|| {
+                tenant_conf
+                    .checkpoint_distance
+                    .unwrap_or(defaults.checkpoint_distance)
+            })
+    }
+
     pub fn get_compaction_period(&self) -> Duration {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -454,6 +597,13 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.compaction_period)
     }
 
+    /// The compaction period of this tenant with its random jitter applied,
+    /// see 'maintenance_jitter_percent'. The background compaction task sleeps
+    /// this long between rounds.
+    pub fn get_jittered_compaction_period(&self) -> Duration {
+        self.compaction_jitter.apply(self.get_compaction_period())
+    }
+
     pub fn get_compaction_threshold(&self) -> usize {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -510,6 +660,252 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.max_lsn_wal_lag)
     }
 
+    pub fn get_maintenance_window(&self) -> Option<MaintenanceWindow> {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .maintenance_window
+            .or(self.conf.default_tenant_conf.maintenance_window)
+    }
+
+    /// Should background compaction and GC run at 'now'? The background
+    /// tasks check this before each iteration.
+    pub fn should_run_background_maintenance(&self, now: SystemTime) -> bool {
+        match self.get_maintenance_window() {
+            Some(window) => window.contains(now),
+            None => true,
+        }
+    }
+
+    pub fn get_materialized_cache_enabled(&self) -> bool {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .materialized_cache_enabled
+            .unwrap_or(self.conf.default_tenant_conf.materialized_cache_enabled)
+    }
+
+    pub fn get_walredo_max_records_size(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .walredo_max_records_size
+            .unwrap_or(self.conf.default_tenant_conf.walredo_max_records_size)
+    }
+
+    pub fn get_compaction_concurrency(&self) -> usize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .compaction_concurrency
+            .unwrap_or(self.conf.default_tenant_conf.compaction_concurrency)
+    }
+
+    pub fn get_compaction_max_input_layers(&self) -> usize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .compaction_max_input_layers
+            .unwrap_or(self.conf.default_tenant_conf.compaction_max_input_layers)
+    }
+
+    pub fn get_compaction_strategy(&self) -> CompactionStrategy {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .compaction_strategy
+            .unwrap_or(self.conf.default_tenant_conf.compaction_strategy)
+    }
+
+    pub fn get_compaction_min_benefit(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .compaction_min_benefit
+            .unwrap_or(self.conf.default_tenant_conf.compaction_min_benefit)
+    }
+
+    pub fn get_rate_limits(&self) -> GetRateLimits {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        let defaults = &self.conf.default_tenant_conf;
+        GetRateLimits {
+            requests_per_second: tenant_conf
+                .get_requests_per_second
+                .unwrap_or(defaults.get_requests_per_second),
+            bytes_per_second: tenant_conf
+                .get_bytes_per_second
+                .unwrap_or(defaults.get_bytes_per_second),
+        }
+    }
+
+    /// Stop the compactions running on the timelines of this tenant, and
+    /// any started later. Used when the tenant is detached or the pageserver
+    /// shuts down, so that they don't have to wait for a long compaction.
+    /// The compactions leave the timelines in a consistent state.
+    pub fn cancel_compactions(&self) {
+        self.compaction_cancel.cancel();
+    }
+
+    /// Replace the observer of the layer changes made by compaction and GC on
+    /// the timelines of this tenant, see [`MaintenanceObserver`].
+    pub fn set_maintenance_observer(&self, observer: Arc<dyn MaintenanceObserver>) {
+        self.maintenance_observer.set(observer);
+    }
+
+    ///
+    /// Discard all data of timeline 'timeline_id' after 'lsn', see
+    /// [`LayeredTimeline::truncate_to`]. The data cannot be recovered, so the
+    /// caller must pass 'confirm' to show that it means it. WAL ingestion on
+    /// the timeline must be paused first.
+    ///
+    /// Fails if a child timeline was branched after 'lsn', because the
+    /// child would lose the data it was branched from.
+    ///
+    pub fn truncate_timeline(
+        &self,
+        timeline_id: ZTimelineId,
+        lsn: Lsn,
+        confirm: bool,
+    ) -> Result<()> {
+        ensure!(
+            confirm,
+            "truncating timeline {} to {} discards data, it must be confirmed",
+            timeline_id,
+            lsn
+        );
+
+        // Hold 'gc_cs' so that no new branches are created while we truncate.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        let mut timelines = self.timelines.lock().unwrap();
+        if let Some((child_id, child)) = timelines.iter().find(|(_, entry)| {
+            entry.ancestor_timeline_id() == Some(timeline_id) && entry.ancestor_lsn() > lsn
+        }) {
+            bail!(
+                "cannot truncate timeline {} to {}, child timeline {} was branched at {}",
+                timeline_id,
+                lsn,
+                child_id,
+                child.ancestor_lsn()
+            );
+        }
+        let timeline = self
+            .get_timeline_load_internal(timeline_id, &mut timelines)?
+            .ok_or_else(|| anyhow::anyhow!("unknown timeline id: {}", timeline_id))?;
+        drop(timelines);
+
+        timeline.truncate_to(lsn)
+    }
+
+    ///
+    /// Memory used by the in-memory layers of the loaded timelines, largest
+    /// first. Timelines without in-memory layers are left out.
+    ///
+    pub fn inmemory_layer_report(&self) -> Vec<InMemoryLayerStat> {
+        let timelines = self
+            .timelines
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|entry| match entry {
+                LayeredTimelineEntry::Loaded(timeline) => Some(Arc::clone(timeline)),
+                LayeredTimelineEntry::Unloaded { .. } => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut report = Vec::new();
+        for timeline in timelines {
+            match timeline.inmemory_layer_stat() {
+                Ok(Some(stat)) => report.push(stat),
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "could not get the in-memory layer sizes of timeline {}: {:#}",
+                    timeline.timeline_id, e
+                ),
+            }
+        }
+        report.sort_by_key(|stat| std::cmp::Reverse(stat.total_bytes()));
+        report
+    }
+
+    /// Report stuck flush, compaction and GC threads on all loaded timelines,
+    /// see [`LayeredTimeline::check_maintenance_stalls`]. Returns the number
+    /// of new stalls found.
+    pub fn check_maintenance_stalls(&self) -> usize {
+        let timelines = self.timelines.lock().unwrap();
+        timelines
+            .values()
+            .filter_map(|entry| match entry {
+                LayeredTimelineEntry::Loaded(timeline) => Some(timeline.check_maintenance_stalls()),
+                LayeredTimelineEntry::Unloaded { .. } => None,
+            })
+            .sum()
+    }
+
+    ///
+    /// Create timeline 'timeline_id' from a stream written by
+    /// [`LayeredTimeline::export_layers`] on another pageserver.
+    ///
+    /// The layer files are fsync'd before the metadata file is written, so an
+    /// interrupted import cannot leave behind a timeline with missing layers.
+    /// The ancestor of the timeline, if any, must already exist. The layer map
+    /// is built when the timeline is first accessed.
+    ///
+    pub fn import_layers<R: Read>(&self, timeline_id: ZTimelineId, reader: &mut R) -> Result<()> {
+        ensure!(
+            !self.timelines.lock().unwrap().contains_key(&timeline_id),
+            "Timeline {timeline_id} already exists"
+        );
+        let timeline_path = self.conf.timeline_path(&timeline_id, &self.tenant_id);
+        // Fails if the directory exists, so concurrent imports of the same
+        // timeline can't step on each other.
+        crashsafe_dir::create_dir(&timeline_path).with_context(|| {
+            format!(
+                "failed to create timeline directory {}",
+                timeline_path.display()
+            )
+        })?;
+
+        let mut import = || -> Result<TimelineMetadata> {
+            let (layer_paths, metadata_bytes) = layer_transfer::read_files(reader, &timeline_path)?;
+            let metadata = TimelineMetadata::from_bytes(&metadata_bytes)?;
+            if let Some(ancestor_id) = metadata.ancestor_timeline() {
+                ensure!(
+                    self.timelines.lock().unwrap().contains_key(&ancestor_id),
+                    "ancestor timeline {ancestor_id} is not present"
+                );
+            }
+
+            par_fsync::par_fsync(&layer_paths, self.conf.max_fsync_parallelism)?;
+            File::open(&timeline_path)?.sync_all()?;
+            timeline::save_metadata(self.conf, timeline_id, self.tenant_id, &metadata, true)?;
+            info!(
+                "imported {} layers of timeline {}",
+                layer_paths.len(),
+                timeline_id
+            );
+            Ok(metadata)
+        };
+
+        let metadata = match import() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                if let Err(remove_err) = fs::remove_dir_all(&timeline_path) {
+                    warn!(
+                        "failed to remove partially imported timeline directory {}: {:#}",
+                        timeline_path.display(),
+                        remove_err
+                    );
+                }
+                return Err(e.context(format!("failed to import timeline {timeline_id}")));
+            }
+        };
+
+        match self.timelines.lock().unwrap().entry(timeline_id) {
+            Entry::Occupied(_) => bail!("Timeline {timeline_id} was created during the import"),
+            Entry::Vacant(entry) => {
+                entry.insert(LayeredTimelineEntry::Unloaded {
+                    id: timeline_id,
+                    metadata,
+                });
+            }
+        }
+        Ok(())
+    }
+
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) -> Result<()> {
         let mut tenant_conf = self.tenant_conf.write().unwrap();
 
@@ -597,6 +993,11 @@ impl LayeredRepository {
             timeline_id,
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
+            Arc::clone(&self.compaction_limiter),
+            Arc::clone(&self.compaction_cancel),
+            Arc::clone(&self.maintenance_observer),
+            Arc::clone(&self.get_rate_limiter),
+            self.remote_index.clone(),
             self.upload_layers,
         );
         timeline
@@ -622,6 +1023,11 @@ impl LayeredRepository {
             timelines: Mutex::new(HashMap::new()),
             gc_cs: Mutex::new(()),
             walredo_mgr,
+            compaction_limiter: Arc::new(CompactionLimiter::new(tenant_id)),
+            compaction_cancel: Arc::new(CancellationToken::default()),
+            maintenance_observer: Arc::new(ObserverSlot::default()),
+            get_rate_limiter: Arc::new(GetRateLimiter::new(tenant_id)),
+            compaction_jitter: Jitter::random(conf.maintenance_jitter_percent),
             remote_index,
             upload_layers,
         }
@@ -830,6 +1236,15 @@ impl LayeredRepository {
 
             let result = timeline.gc()?;
             totals += result;
+
+            // Correct any drift in the incrementally maintained physical size,
+            // while we're at it. This is best effort; it doesn't affect GC.
+            if let Err(e) = timeline.reconcile_physical_size() {
+                warn!(
+                    "failed to reconcile physical size of timeline {}: {:?}",
+                    timeline.timeline_id, e
+                );
+            }
         }
 
         totals.elapsed = now.elapsed();
@@ -864,24 +1279,59 @@ pub fn dump_layerfile_from_path(path: &Path, verbose: bool) -> Result<()> {
     Ok(())
 }
 
+///
+/// Load the metadata of a timeline from its metadata file.
+///
+/// If the file can't be read or parsed and `allow_metadata_recovery` is set
+/// in the config, the metadata is reconstructed from the layer files instead,
+/// see [`timeline::recover_metadata_from_layers`], and saved. The bad metadata
+/// file is quarantined.
+///
 pub fn load_metadata(
     conf: &'static PageServerConf,
     timeline_id: ZTimelineId,
     tenant_id: ZTenantId,
 ) -> anyhow::Result<TimelineMetadata> {
     let metadata_path = metadata_path(conf, timeline_id, tenant_id);
-    let metadata_bytes = std::fs::read(&metadata_path).with_context(|| {
-        format!(
-            "Failed to read metadata bytes from path {}",
-            metadata_path.display()
-        )
-    })?;
-    TimelineMetadata::from_bytes(&metadata_bytes).with_context(|| {
-        format!(
-            "Failed to parse metadata bytes from path {}",
-            metadata_path.display()
-        )
-    })
+    let result = std::fs::read(&metadata_path)
+        .with_context(|| {
+            format!(
+                "Failed to read metadata bytes from path {}",
+                metadata_path.display()
+            )
+        })
+        .and_then(|metadata_bytes| {
+            TimelineMetadata::from_bytes(&metadata_bytes).with_context(|| {
+                format!(
+                    "Failed to parse metadata bytes from path {}",
+                    metadata_path.display()
+                )
+            })
+        });
+
+    match result {
+        Err(e) if conf.allow_metadata_recovery => {
+            warn!("{e:#}, recovering metadata of timeline {timeline_id} from its layer files");
+            let metadata = timeline::recover_metadata_from_layers(conf, timeline_id, tenant_id)
+                .context("Failed to recover metadata from layer files")?;
+
+            if metadata_path.exists() {
+                quarantine_file(
+                    &metadata_path,
+                    "corrupt metadata",
+                    conf.max_quarantined_files,
+                )?;
+            }
+            save_metadata(conf, timeline_id, tenant_id, &metadata, true)?;
+            info!(
+                "recovered metadata of timeline {timeline_id}, disk_consistent_lsn is {}",
+                metadata.disk_consistent_lsn()
+            );
+
+            Ok(metadata)
+        }
+        result => result,
+    }
 }
 
 ///
@@ -893,12 +1343,14 @@ pub fn load_metadata(
 ///
 #[cfg(test)]
 pub mod tests {
+    use super::filename::DeltaFileName;
     use super::metadata::METADATA_FILE_NAME;
     use super::*;
     use crate::keyspace::KeySpaceAccum;
     use crate::repository::repo_harness::*;
     use crate::repository::{Key, Value};
     use rand::{thread_rng, Rng};
+    use std::time::UNIX_EPOCH;
 
     #[test]
     fn corrupt_metadata() -> Result<()> {
@@ -938,6 +1390,134 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn inmemory_layer_report() -> Result<()> {
+        let repo = RepoHarness::create("inmemory_layer_report")?.load();
+        let small = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let large = repo.create_empty_timeline(NEW_TIMELINE_ID, Lsn(0))?;
+        // Has no in-memory layers, and is not listed
+        repo.create_empty_timeline(ZTimelineId::generate(), Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = small.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        let writer = large.writer();
+        for i in 1..=100 {
+            let lsn = Lsn(i * 0x10);
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+        }
+        drop(writer);
+
+        let report = repo.inmemory_layer_report();
+        let timelines = report
+            .iter()
+            .map(|stat| stat.timeline_id)
+            .collect::<Vec<_>>();
+        assert_eq!(timelines, vec![NEW_TIMELINE_ID, TIMELINE_ID]);
+        assert!(report[0].open_layer_bytes > report[1].open_layer_bytes);
+        assert!(report[1].open_layer_bytes > 0);
+        for stat in &report {
+            assert_eq!(stat.tenant_id, repo.tenant_id());
+            assert_eq!(stat.frozen_layers, 0);
+            assert_eq!(stat.frozen_bytes, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_metadata_from_layers() -> Result<()> {
+        let mut harness = RepoHarness::create("recover_metadata_from_layers")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("012222222233333333444444445500000001").unwrap();
+        for lsn in [Lsn(0x10), Lsn(0x20)] {
+            let writer = tline.writer();
+            writer.put(
+                TEST_KEY,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        drop(tline);
+        drop(repo);
+
+        let metadata_path = harness.timeline_path(&TIMELINE_ID).join(METADATA_FILE_NAME);
+        std::fs::remove_file(&metadata_path)?;
+
+        // Without the operator's consent, the timeline fails to load
+        assert!(harness.try_load().is_err());
+        assert!(!metadata_path.exists());
+
+        harness.update_conf(|conf| conf.allow_metadata_recovery = true);
+        let repo = harness.load();
+        assert!(metadata_path.is_file());
+
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x20));
+        assert!(tline.get_ancestor_timeline_id().is_none());
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_metadata_refuses_incomplete_layers() -> Result<()> {
+        let harness = RepoHarness::create("recover_metadata_refuses_incomplete_layers")?;
+        let repo = harness.load();
+        repo.create_empty_timeline(TIMELINE_ID, Lsn(0x10))?;
+
+        let create_delta = |timeline_id, key_range, lsn_range| -> Result<()> {
+            let timeline_path = harness.timeline_path(&timeline_id);
+            std::fs::create_dir_all(&timeline_path)?;
+            let fname = DeltaFileName {
+                key_range,
+                lsn_range,
+            };
+            std::fs::write(timeline_path.join(fname.to_string()), b"")?;
+            Ok(())
+        };
+        let recover = |timeline_id| {
+            timeline::recover_metadata_from_layers(harness.conf, timeline_id, harness.tenant_id)
+        };
+
+        // The layers of a branch start after the initdb LSN
+        let branch_id = ZTimelineId::generate();
+        create_delta(branch_id, Key::MIN..Key::MAX, Lsn(0x21)..Lsn(0x31))?;
+        assert!(recover(branch_id).is_err());
+
+        // Compacted layers, with the versions of 'hot_key' split off into
+        // layers of their own, and one of those lost
+        let timeline_id = ZTimelineId::generate();
+        let hot_key = Key::from_hex("012222222233333333444444445500000010")?;
+        let lsn_range = Lsn(0x1)..Lsn(0x41);
+        create_delta(timeline_id, Key::MIN..hot_key, lsn_range.clone())?;
+        create_delta(timeline_id, hot_key.next()..Key::MAX, lsn_range.clone())?;
+        create_delta(timeline_id, hot_key..hot_key.next(), Lsn(0x8)..Lsn(0x20))?;
+        create_delta(timeline_id, hot_key..hot_key.next(), Lsn(0x30)..Lsn(0x41))?;
+        assert!(recover(timeline_id).is_err());
+
+        create_delta(timeline_id, hot_key..hot_key.next(), Lsn(0x20)..Lsn(0x30))?;
+        let metadata = recover(timeline_id)?;
+        assert_eq!(metadata.disk_consistent_lsn(), Lsn(0x40));
+        assert_eq!(metadata.initdb_lsn(), Lsn(0x10));
+
+        Ok(())
+    }
+
     // Target file size in the unit tests. In production, the target
     // file size is much larger, maybe 1 GB. But a small size makes it
     // much faster to exercise all the logic for creating the files,
@@ -950,11 +1530,11 @@ pub mod tests {
         let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
 
         #[allow(non_snake_case)]
-        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        let TEST_KEY: Key = Key::from_hex("012222222233333333444444445500000001").unwrap();
 
         let writer = tline.writer();
         writer.put(TEST_KEY, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
-        writer.finish_write(Lsn(0x10));
+        writer.finish_write(Lsn(0x10))?;
         drop(writer);
 
         tline.checkpoint(CheckpointConfig::Forced)?;
@@ -962,7 +1542,7 @@ pub mod tests {
 
         let writer = tline.writer();
         writer.put(TEST_KEY, Lsn(0x20), &Value::Image(TEST_IMG("foo at 0x20")))?;
-        writer.finish_write(Lsn(0x20));
+        writer.finish_write(Lsn(0x20))?;
         drop(writer);
 
         tline.checkpoint(CheckpointConfig::Forced)?;
@@ -970,7 +1550,7 @@ pub mod tests {
 
         let writer = tline.writer();
         writer.put(TEST_KEY, Lsn(0x30), &Value::Image(TEST_IMG("foo at 0x30")))?;
-        writer.finish_write(Lsn(0x30));
+        writer.finish_write(Lsn(0x30))?;
         drop(writer);
 
         tline.checkpoint(CheckpointConfig::Forced)?;
@@ -978,7 +1558,7 @@ pub mod tests {
 
         let writer = tline.writer();
         writer.put(TEST_KEY, Lsn(0x40), &Value::Image(TEST_IMG("foo at 0x40")))?;
-        writer.finish_write(Lsn(0x40));
+        writer.finish_write(Lsn(0x40))?;
         drop(writer);
 
         tline.checkpoint(CheckpointConfig::Forced)?;
@@ -1017,7 +1597,7 @@ pub mod tests {
                     lsn,
                     &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
                 )?;
-                writer.finish_write(lsn);
+                writer.finish_write(lsn)?;
                 drop(writer);
 
                 keyspace.add_key(test_key);
@@ -1063,7 +1643,7 @@ pub mod tests {
                 lsn,
                 &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
             updated[blknum] = lsn;
             drop(writer);
 
@@ -1081,7 +1661,7 @@ pub mod tests {
                     lsn,
                     &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
                 )?;
-                writer.finish_write(lsn);
+                writer.finish_write(lsn)?;
                 drop(writer);
                 updated[blknum] = lsn;
             }
@@ -1107,6 +1687,76 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compaction_concurrency() -> Result<()> {
+        let mut harness = RepoHarness::create("test_compaction_concurrency")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        harness.tenant_conf.compaction_concurrency = 2;
+        let repo = harness.load();
+
+        // Three timelines, each with enough level 0 delta layers to compact
+        let mut timelines = Vec::new();
+        for _ in 0..3 {
+            let tline = repo.create_empty_timeline(ZTimelineId::generate(), Lsn(0))?;
+            let mut lsn = Lsn(0x10);
+            for _ in 0..2 {
+                let writer = tline.writer();
+                writer.put(
+                    Key::from_hex("012222222233333333444444445500000001")?,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn)?;
+                drop(writer);
+                tline.checkpoint(CheckpointConfig::Flush)?;
+                lsn = Lsn(lsn.0 + 0x10);
+            }
+            timelines.push(tline);
+        }
+        let num_level0_deltas = |tline: &LayeredTimeline| {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()
+                .unwrap()
+                .len()
+        };
+
+        // While two compactions are running, the other timelines skip theirs
+        let first = repo.compaction_limiter.try_acquire(2).unwrap();
+        let second = repo.compaction_limiter.try_acquire(2).unwrap();
+        for tline in &timelines {
+            tline.compact()?;
+            assert_eq!(num_level0_deltas(tline), 2);
+        }
+        assert_eq!(repo.compaction_limiter.active(), 2);
+
+        // A forced checkpoint waits for a slot instead
+        let (tx, rx) = std::sync::mpsc::channel();
+        let forced = {
+            let tline = Arc::clone(&timelines[0]);
+            std::thread::spawn(move || tx.send(tline.checkpoint(CheckpointConfig::Forced)))
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // Once one of them is done, the timelines get to compact, one at a time
+        drop(first);
+        rx.recv_timeout(Duration::from_secs(10))??;
+        forced.join().unwrap().unwrap();
+        assert_eq!(num_level0_deltas(&timelines[0]), 0);
+        for tline in &timelines {
+            tline.compact()?;
+            assert_eq!(num_level0_deltas(tline), 0);
+            assert_eq!(repo.compaction_limiter.active(), 1);
+        }
+
+        drop(second);
+        assert_eq!(repo.compaction_limiter.active(), 0);
+
+        Ok(())
+    }
+
     #[test]
     fn test_traverse_branches() -> Result<()> {
         let repo = RepoHarness::create("test_traverse_branches")?.load();
@@ -1133,7 +1783,7 @@ pub mod tests {
                 lsn,
                 &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
             updated[blknum] = lsn;
             drop(writer);
 
@@ -1158,7 +1808,7 @@ pub mod tests {
                     &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
                 )?;
                 println!("updating {} at {}", blknum, lsn);
-                writer.finish_write(lsn);
+                writer.finish_write(lsn)?;
                 drop(writer);
                 updated[blknum] = lsn;
             }
@@ -1217,7 +1867,7 @@ pub mod tests {
                     &Value::Image(TEST_IMG(&format!("{} {} at {}", idx, blknum, lsn))),
                 )?;
                 println!("updating [{}][{}] at {}", idx, blknum, lsn);
-                writer.finish_write(lsn);
+                writer.finish_write(lsn)?;
                 drop(writer);
                 updated[idx][blknum] = lsn;
             }
@@ -1240,4 +1890,161 @@ pub mod tests {
         }
         Ok(())
     }
+
+    /// Create a timeline with a few layers, and export it.
+    /// Returns the stream, and the id of the tenant that the timeline belongs
+    /// to. The layer files can only be loaded by the same tenant.
+    fn export_test_timeline(test_name: &'static str) -> Result<(Vec<u8>, ZTenantId)> {
+        let harness = RepoHarness::create(test_name)?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0x10);
+        for _ in 0..3 {
+            let writer = tline.writer();
+            for blknum in 0..100 {
+                test_key.field6 = blknum;
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn += 0x10;
+        }
+
+        let mut stream = Vec::new();
+        tline.export_layers(&mut stream)?;
+        Ok((stream, harness.tenant_id))
+    }
+
+    #[test]
+    fn export_import_layers() -> Result<()> {
+        let (stream, tenant_id) = export_test_timeline("export_import_layers_src")?;
+
+        let harness = RepoHarness::create_with_tenant_id("export_import_layers_dst", tenant_id)?;
+        let repo = harness.load();
+        repo.import_layers(TIMELINE_ID, &mut &stream[..])?;
+        let tline = repo
+            .get_timeline_load(TIMELINE_ID)
+            .expect("should load imported timeline");
+
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x30));
+        assert_eq!(
+            tline.layers.read().unwrap().iter_historic_layers().count(),
+            3
+        );
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for blknum in [0, 42, 99] {
+            test_key.field6 = blknum;
+            assert_eq!(
+                tline.get(test_key, Lsn(0x20))?,
+                TEST_IMG(&format!("{} at {}", blknum, Lsn(0x20)))
+            );
+            assert_eq!(
+                tline.get(test_key, Lsn(0x30))?,
+                TEST_IMG(&format!("{} at {}", blknum, Lsn(0x30)))
+            );
+        }
+
+        // Importing the same timeline again is refused
+        assert!(repo.import_layers(TIMELINE_ID, &mut &stream[..]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_corrupt_layers() -> Result<()> {
+        let (mut stream, tenant_id) = export_test_timeline("import_corrupt_layers_src")?;
+
+        // Flip a bit in the contents of the first file. It starts after the
+        // header (12 bytes), the frame type, name length, name, file length
+        // and checksum.
+        let name_len = u16::from_be_bytes([stream[13], stream[14]]) as usize;
+        let contents_offset = 12 + 1 + 2 + name_len + 8 + 4;
+        stream[contents_offset + 10] ^= 1;
+
+        let harness = RepoHarness::create_with_tenant_id("import_corrupt_layers_dst", tenant_id)?;
+        let repo = harness.load();
+        let err = repo
+            .import_layers(TIMELINE_ID, &mut &stream[..])
+            .expect_err("corrupt stream should not be imported");
+        assert!(
+            format!("{err:#}").contains("checksum mismatch"),
+            "unexpected error: {err:#}"
+        );
+        // Nothing is left behind
+        assert!(!harness.timeline_path(&TIMELINE_ID).exists());
+        assert!(repo.get_timeline_load(TIMELINE_ID).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_layers_rejects_untrusted_frames() -> Result<()> {
+        let harness = RepoHarness::create("import_layers_rejects_untrusted_frames")?;
+        let repo = harness.load();
+
+        let frame = |name: &str, file_len: u64| {
+            let mut stream = Vec::new();
+            layer_transfer::write_header(&mut stream).unwrap();
+            stream.push(1); // FRAME_FILE
+            stream.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            stream.extend_from_slice(name.as_bytes());
+            stream.extend_from_slice(&file_len.to_be_bytes());
+            stream.extend_from_slice(&0u32.to_be_bytes());
+            stream
+        };
+
+        for (name, file_len, expected_err) in [
+            ("../escape", 0, "invalid file name"),
+            ("not_a_layer", 0, "invalid file name"),
+            (METADATA_FILE_NAME, u64::MAX, "metadata file is too large"),
+        ] {
+            let stream = frame(name, file_len);
+            let err = repo
+                .import_layers(TIMELINE_ID, &mut &stream[..])
+                .expect_err("bad frame should not be imported");
+            assert!(
+                format!("{err:#}").contains(expected_err),
+                "unexpected error: {err:#}"
+            );
+            assert!(!harness.timeline_path(&TIMELINE_ID).exists());
+        }
+        assert!(!harness
+            .conf
+            .timelines_path(&harness.tenant_id)
+            .join("escape")
+            .exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn maintenance_window() -> Result<()> {
+        let mut harness = RepoHarness::create("maintenance_window")?;
+        harness.tenant_conf.maintenance_window = Some("22:00-06:00".parse()?);
+        let repo = harness.load();
+
+        let at = |hours: u64, minutes: u64| {
+            // A day later than the epoch, to check that the date doesn't matter
+            UNIX_EPOCH + Duration::from_secs(((24 + hours) * 60 + minutes) * 60)
+        };
+        assert!(repo.should_run_background_maintenance(at(23, 0)));
+        assert!(repo.should_run_background_maintenance(at(0, 0)));
+        assert!(repo.should_run_background_maintenance(at(5, 59)));
+        assert!(!repo.should_run_background_maintenance(at(6, 0)));
+        assert!(!repo.should_run_background_maintenance(at(12, 30)));
+        assert!(!repo.should_run_background_maintenance(at(21, 59)));
+
+        // Without a window, maintenance can run at any time
+        let repo = RepoHarness::create("maintenance_window_unset")?.load();
+        assert!(repo.should_run_background_maintenance(at(12, 30)));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,205 @@
+//!
+//! Lightweight tracking of which parts of the key space are read the most.
+//!
+//! Reads are sampled, and attributed to coarse key ranges: all the keys that
+//! differ only by the last key field, i.e. the blocks of a relation fork.
+//! The number of tracked ranges is bounded. When a new range comes in while
+//! the table is full, it replaces the least-read range and inherits its count
+//! (the "space-saving" algorithm), so ranges that are read frequently stay in
+//! the table, while rarely read ones keep replacing each other.
+//!
+//! To make the counts reflect recent activity, all of them are halved every
+//! DECAY_INTERVAL samples.
+//!
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::repository::Key;
+
+/// Only one in this many reads is counted.
+const SAMPLE_INTERVAL: u64 = 16;
+
+/// Max number of key ranges with a counter.
+const MAX_TRACKED_RANGES: usize = 1024;
+
+/// Halve all counts after this many samples.
+const DECAY_INTERVAL: u64 = 64 * 1024;
+
+/// Number of the most read key ranges that are considered hot
+const NUM_HOT_RANGES: usize = 16;
+
+/// Min number of sampled reads for a key range to be considered hot
+const MIN_HOT_SAMPLES: u64 = 8;
+
+pub struct KeyAccessTracker {
+    reads: AtomicU64,
+    inner: Mutex<TrackerInner>,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    /// Sampled reads, keyed by the first key of the range
+    counts: HashMap<Key, u64>,
+    samples_since_decay: u64,
+}
+
+impl Default for KeyAccessTracker {
+    fn default() -> Self {
+        KeyAccessTracker {
+            reads: AtomicU64::new(0),
+            inner: Mutex::new(TrackerInner::default()),
+        }
+    }
+}
+
+/// The coarse key range that 'key' belongs to, as first and last key.
+fn range_bounds(key: Key) -> (Key, Key) {
+    (
+        Key { field6: 0, ..key },
+        Key {
+            field6: u32::MAX,
+            ..key
+        },
+    )
+}
+
+impl KeyAccessTracker {
+    /// Record a read of 'key'. Cheap enough to call on every read.
+    pub fn record(&self, key: Key) {
+        if self.reads.fetch_add(1, Ordering::Relaxed) % SAMPLE_INTERVAL != 0 {
+            return;
+        }
+
+        let (range_start, _) = range_bounds(key);
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(count) = inner.counts.get_mut(&range_start) {
+            *count += 1;
+        } else if inner.counts.len() < MAX_TRACKED_RANGES {
+            inner.counts.insert(range_start, 1);
+        } else {
+            // Replace the least-read range
+            let (&coldest, &min_count) = inner
+                .counts
+                .iter()
+                .min_by_key(|(_, &count)| count)
+                .expect("table is full");
+            inner.counts.remove(&coldest);
+            inner.counts.insert(range_start, min_count + 1);
+        }
+
+        inner.samples_since_decay += 1;
+        if inner.samples_since_decay >= DECAY_INTERVAL {
+            inner.samples_since_decay = 0;
+            inner.counts.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+    }
+
+    /// Return up to 'n' most read key ranges, with their sampled read counts,
+    /// hottest first.
+    pub fn hottest(&self, n: usize) -> Vec<(Range<Key>, u64)> {
+        let inner = self.inner.lock().unwrap();
+        let mut ranges: Vec<(Key, u64)> = inner
+            .counts
+            .iter()
+            .map(|(&start, &count)| (start, count))
+            .collect();
+        drop(inner);
+
+        ranges.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranges.truncate(n);
+        ranges
+            .into_iter()
+            .map(|(start, count)| {
+                let (_, last) = range_bounds(start);
+                // Not using last.next() as the end, because that would overflow
+                // for the very last range of the key space.
+                (start..last, count)
+            })
+            .collect()
+    }
+
+    /// Return the key ranges that are read frequently enough to be worth
+    /// materializing sooner than the others.
+    pub fn hot_ranges(&self) -> Vec<Range<Key>> {
+        self.hottest(NUM_HOT_RANGES)
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_HOT_SAMPLES)
+            .map(|(range, _)| range)
+            .collect()
+    }
+}
+
+/// Does 'range' overlap with a range returned by [`KeyAccessTracker::hottest`]?
+pub fn overlaps_hot_range(range: &Range<Key>, hot_range: &Range<Key>) -> bool {
+    // 'hot_range.end' is the last key of the hot range, not one past it
+    range.start <= hot_range.end && hot_range.start < range.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel_key(relnode: u32, blknum: u32) -> Key {
+        Key {
+            field1: 0,
+            field2: 1663,
+            field3: 1,
+            field4: relnode,
+            field5: 0,
+            field6: blknum,
+        }
+    }
+
+    #[test]
+    fn hot_ranges_are_tracked() {
+        let tracker = KeyAccessTracker::default();
+
+        for i in 0..100 * SAMPLE_INTERVAL {
+            tracker.record(rel_key(1, i as u32));
+        }
+        for i in 0..10 * SAMPLE_INTERVAL {
+            tracker.record(rel_key(2, i as u32));
+        }
+
+        let hottest = tracker.hottest(10);
+        assert_eq!(hottest.len(), 2);
+        assert!(hottest[0].0.contains(&rel_key(1, 12345)));
+        assert!(hottest[1].0.contains(&rel_key(2, 0)));
+        assert!(hottest[0].1 > hottest[1].1);
+
+        assert!(overlaps_hot_range(
+            &(rel_key(1, 10)..rel_key(1, 20)),
+            &hottest[0].0
+        ));
+        assert!(!overlaps_hot_range(
+            &(rel_key(3, 0)..rel_key(4, 0)),
+            &hottest[0].0
+        ));
+    }
+
+    #[test]
+    fn memory_is_bounded() {
+        let tracker = KeyAccessTracker::default();
+
+        // A hot range, followed by lots of ranges that are read only once
+        for _ in 0..100 * SAMPLE_INTERVAL {
+            tracker.record(rel_key(0, 0));
+        }
+        for relnode in 1..=(2 * MAX_TRACKED_RANGES as u64 * SAMPLE_INTERVAL) {
+            tracker.record(rel_key(relnode as u32, 0));
+        }
+
+        assert_eq!(
+            tracker.inner.lock().unwrap().counts.len(),
+            MAX_TRACKED_RANGES
+        );
+        let hottest = tracker.hottest(1);
+        assert!(hottest[0].0.contains(&rel_key(0, 0)));
+    }
+}
@@ -0,0 +1,143 @@
+//!
+//! Limits the number of timelines of a tenant that run compaction at the
+//! same time.
+//!
+//! Every timeline flushes and compacts on its own thread, so a tenant with
+//! many timelines could otherwise run many compactions in parallel, all of
+//! them competing for the same disk. A timeline that finds the limit reached
+//! in its background compaction doesn't wait for a permit: it skips the
+//! compaction round, and tries again at the next one. Compactions that were
+//! explicitly asked for, like in a forced checkpoint, wait for a permit.
+//!
+use std::sync::{Condvar, Mutex};
+
+use metrics::{register_int_gauge_vec, IntGauge, IntGaugeVec};
+use once_cell::sync::Lazy;
+use utils::zid::ZTenantId;
+
+static ACTIVE_COMPACTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_active_compactions",
+        "Number of timelines of the tenant that are running compaction",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+pub struct CompactionLimiter {
+    active: Mutex<usize>,
+    // Signaled when a permit is dropped
+    released: Condvar,
+    active_gauge: IntGauge,
+}
+
+/// Permit to run one compaction, returned by CompactionLimiter::try_acquire
+/// and CompactionLimiter::acquire.
+/// The compaction slot is released when this is dropped.
+pub struct CompactionPermit<'a> {
+    limiter: &'a CompactionLimiter,
+}
+
+impl CompactionLimiter {
+    pub fn new(tenant_id: ZTenantId) -> Self {
+        CompactionLimiter {
+            active: Mutex::new(0),
+            released: Condvar::new(),
+            active_gauge: ACTIVE_COMPACTIONS.with_label_values(&[&tenant_id.to_string()]),
+        }
+    }
+
+    ///
+    /// Start a compaction, if fewer than 'limit' compactions are running.
+    ///
+    /// Returns None if the limit has been reached.
+    ///
+    pub fn try_acquire(&self, limit: usize) -> Option<CompactionPermit<'_>> {
+        let mut active = self.active.lock().unwrap();
+        if *active >= limit {
+            return None;
+        }
+        *active += 1;
+        self.active_gauge.inc();
+        Some(CompactionPermit { limiter: self })
+    }
+
+    ///
+    /// Start a compaction, waiting for one of the running ones to finish if
+    /// 'limit' compactions are running already. 'limit' must not be zero.
+    ///
+    pub fn acquire(&self, limit: usize) -> CompactionPermit<'_> {
+        let mut active = self.active.lock().unwrap();
+        while *active >= limit {
+            active = self.released.wait(active).unwrap();
+        }
+        *active += 1;
+        self.active_gauge.inc();
+        CompactionPermit { limiter: self }
+    }
+
+    /// Number of compactions currently running
+    pub fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+}
+
+impl Drop for CompactionPermit<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        *active -= 1;
+        self.limiter.active_gauge.dec();
+        self.limiter.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn permits_are_limited() {
+        let limiter = CompactionLimiter::new(ZTenantId::generate());
+
+        let first = limiter.try_acquire(2).unwrap();
+        let second = limiter.try_acquire(2).unwrap();
+        assert!(limiter.try_acquire(2).is_none());
+        assert_eq!(limiter.active(), 2);
+
+        // A slot is freed when a permit is dropped
+        drop(first);
+        assert_eq!(limiter.active(), 1);
+        let third = limiter.try_acquire(2).unwrap();
+        assert!(limiter.try_acquire(2).is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(limiter.active(), 0);
+        assert_eq!(limiter.active_gauge.get(), 0);
+    }
+
+    #[test]
+    fn acquire_waits_for_a_slot() {
+        let limiter = Arc::new(CompactionLimiter::new(ZTenantId::generate()));
+        let first = limiter.try_acquire(1).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            thread::spawn(move || {
+                let _permit = limiter.acquire(1);
+                tx.send(limiter.active()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // Dropping the permit lets the waiting compaction start
+        drop(first);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)).unwrap(), 1);
+        waiter.join().unwrap();
+        assert_eq!(limiter.active(), 0);
+    }
+}
@@ -23,6 +23,17 @@
 //! "values" part.  The actual page images and WAL records are stored in the
 //! "values" part.
 //!
+//! Optionally, a second B-tree, the "key index", follows the index. It maps
+//! each key to its newest version in the layer, so that a scan over a range of
+//! keys at a single LSN can find the visible version of most keys without
+//! visiting all their versions. Files with a key index are marked with
+//! KEY_INDEX_FORMAT_VERSION instead of STORAGE_FORMAT_VERSION, so that older
+//! pageserver versions don't read them.
+//!
+//! The values can be compressed, see [`DeltaCompression`]. The codec is stored
+//! in the summary, and applies to all the values in the file. Compressed files
+//! are marked with COMPRESSED_FORMAT_VERSION, with or without a key index.
+//!
 use crate::config::PageServerConf;
 use crate::layered_repository::blob_io::{BlobCursor, BlobWriter, WriteBlobWriter};
 use crate::layered_repository::block_io::{BlockBuf, BlockCursor, BlockReader, FileBlockReader};
@@ -39,12 +50,15 @@ use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION};
 use anyhow::{bail, ensure, Context, Result};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
 use std::fs;
 use std::io::{BufWriter, Write};
 use std::io::{Seek, SeekFrom};
 use std::ops::Range;
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tracing::*;
 
@@ -58,7 +72,8 @@ use utils::{
 /// Header stored in the beginning of the file
 ///
 /// After this comes the 'values' part, starting on block 1. After that,
-/// the 'index' starts at the block indicated by 'index_start_blk'
+/// the 'index' starts at the block indicated by 'index_start_blk', followed
+/// by the 'key index' if there is one.
 ///
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 struct Summary {
@@ -75,6 +90,16 @@ struct Summary {
     index_start_blk: u32,
     /// Block within the 'index', where the B-tree root page is stored
     index_root_blk: u32,
+
+    /// Block number where the 'key index' begins, or 0 if there is none.
+    /// Older files have zeros here.
+    key_index_start_blk: u32,
+    /// Block within the 'key index', where the B-tree root page is stored
+    key_index_root_blk: u32,
+
+    /// Compression of the values. Older files have zeros here, which is
+    /// DeltaCompression::None.
+    value_compression: DeltaCompression,
 }
 
 impl From<&DeltaLayer> for Summary {
@@ -90,10 +115,81 @@ impl From<&DeltaLayer> for Summary {
 
             index_start_blk: 0,
             index_root_blk: 0,
+            key_index_start_blk: 0,
+            key_index_root_blk: 0,
+            value_compression: DeltaCompression::None,
+        }
+    }
+}
+
+/// Format version of delta files that have a key index.
+const KEY_INDEX_FORMAT_VERSION: u16 = STORAGE_FORMAT_VERSION + 1;
+
+/// Format version of delta files with compressed values. They may or may not
+/// have a key index.
+const COMPRESSED_FORMAT_VERSION: u16 = STORAGE_FORMAT_VERSION + 2;
+
+///
+/// Compression of the values in a delta layer file, set with the
+/// 'delta_compression' option for new files.
+///
+/// Each value is compressed on its own, so that a single page version can be
+/// read without decompressing its neighbours. The order of the variants must
+/// not change, the variant index is stored in the summary.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl DeltaCompression {
+    fn compress(self, buf: &[u8]) -> Result<Cow<'_, [u8]>> {
+        Ok(match self {
+            DeltaCompression::None => Cow::Borrowed(buf),
+            DeltaCompression::Lz4 => Cow::Owned(lz4_flex::compress_prepend_size(buf)),
+            DeltaCompression::Zstd => Cow::Owned(zstd::encode_all(buf, 0)?),
+        })
+    }
+
+    fn decompress(self, buf: &[u8]) -> Result<Cow<'_, [u8]>> {
+        Ok(match self {
+            DeltaCompression::None => Cow::Borrowed(buf),
+            DeltaCompression::Lz4 => Cow::Owned(lz4_flex::decompress_size_prepended(buf)?),
+            DeltaCompression::Zstd => Cow::Owned(zstd::decode_all(buf)?),
+        })
+    }
+
+    /// Decompress and deserialize a value read from the file.
+    fn des_value(self, buf: &[u8]) -> Result<Value> {
+        Ok(Value::des(&self.decompress(buf)?)?)
+    }
+}
+
+impl FromStr for DeltaCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(DeltaCompression::None),
+            "lz4" => Ok(DeltaCompression::Lz4),
+            "zstd" => Ok(DeltaCompression::Zstd),
+            _ => bail!("unknown delta compression '{s}', expected 'none', 'lz4' or 'zstd'"),
         }
     }
 }
 
+impl fmt::Display for DeltaCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeltaCompression::None => "none",
+            DeltaCompression::Lz4 => "lz4",
+            DeltaCompression::Zstd => "zstd",
+        })
+    }
+}
+
 // Flag indicating that this version initialize the page
 const WILL_INIT: u64 = 1;
 
@@ -190,6 +286,9 @@ pub struct DeltaLayerInner {
     // values copied from summary
     index_start_blk: u32,
     index_root_blk: u32,
+    key_index_start_blk: u32,
+    key_index_root_blk: u32,
+    value_compression: DeltaCompression,
 
     /// Reader object for reading blocks from the file. (None if not loaded yet)
     file: Option<FileBlockReader<VirtualFile>>,
@@ -269,7 +368,7 @@ impl Layer for DeltaLayer {
                         file.file.path.display()
                     )
                 })?;
-                let val = Value::des(&buf).with_context(|| {
+                let val = inner.value_compression.des_value(&buf).with_context(|| {
                     format!(
                         "Failed to deserialize file blob from virtual file {}",
                         file.file.path.display()
@@ -316,6 +415,37 @@ impl Layer for DeltaLayer {
         }
     }
 
+    fn prefetch(&self, key_range: &Range<Key>) -> Result<()> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        // The values are stored in the same order as the index, before it.
+        // Find the offsets of the first value in the range, and of the first
+        // one after it.
+        let first_offset_from = |key: &Key| -> Result<Option<u64>> {
+            let search_key = DeltaKey::from_key_lsn(key, Lsn(0));
+            let mut offset = None;
+            tree_reader.visit(&search_key.0, VisitDirection::Forwards, |_key, value| {
+                offset = Some(BlobRef(value).pos());
+                false
+            })?;
+            Ok(offset)
+        };
+        let values_end = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let start = first_offset_from(&key_range.start)?.unwrap_or(values_end);
+        let end = first_offset_from(&key_range.end)?.unwrap_or(values_end);
+
+        if start < end {
+            file.file.prefetch(start, end - start)?;
+        }
+        Ok(())
+    }
+
     fn key_iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Lsn, u64)> + 'a> {
         let inner = match self.load() {
             Ok(inner) => inner,
@@ -328,6 +458,122 @@ impl Layer for DeltaLayer {
         }
     }
 
+    fn estimate_reclaimable_bytes(&self, cutoff: Lsn) -> Result<u64> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        let mut entries: Vec<(DeltaKey, BlobRef)> = Vec::new();
+        tree_reader.visit(
+            &[0u8; DELTA_KEY_SIZE],
+            VisitDirection::Forwards,
+            |key, value| {
+                entries.push((DeltaKey::from_slice(key), BlobRef(value)));
+                true
+            },
+        )?;
+
+        // The values are stored in the same order as the index, so the size of
+        // each value is the distance to the next one. The last one extends to
+        // the start of the index, give or take some padding.
+        let values_end = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let value_size = |i: usize| {
+            let next_pos = entries.get(i + 1).map_or(values_end, |e| e.1.pos());
+            next_pos - entries[i].1.pos()
+        };
+
+        // For each key, the versions older than the last version at or below
+        // the cutoff that initializes the page are not needed anymore.
+        let mut reclaimable = 0;
+        let mut key_start = 0;
+        while key_start < entries.len() {
+            let key = entries[key_start].0.key();
+            let key_end = entries[key_start..]
+                .iter()
+                .position(|e| e.0.key() != key)
+                .map_or(entries.len(), |n| key_start + n);
+
+            let last_init = entries[key_start..key_end]
+                .iter()
+                .rposition(|e| e.0.lsn() <= cutoff && e.1.will_init());
+            if let Some(last_init) = last_init {
+                reclaimable += (key_start..key_start + last_init)
+                    .map(value_size)
+                    .sum::<u64>();
+            }
+            key_start = key_end;
+        }
+        Ok(reclaimable)
+    }
+
+    fn validate_structure(&self) -> Result<()> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+
+        // The values are stored between the summary block and the index, in
+        // the same order as the index. The key index points to the newest
+        // version of each key, so its values are in order, too.
+        let values_end = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut indexes = vec![("index", inner.index_start_blk, inner.index_root_blk)];
+        if inner.key_index_start_blk != 0 {
+            indexes.push((
+                "key index",
+                inner.key_index_start_blk,
+                inner.key_index_root_blk,
+            ));
+        }
+        for (index_name, start_blk, root_blk) in indexes {
+            let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(start_blk, root_blk, file);
+            let mut prev_pos = None;
+            tree_reader
+                .validate(|delta_key, value| {
+                    let key = DeltaKey::extract_key_from_buf(delta_key);
+                    let lsn = DeltaKey::extract_lsn_from_buf(delta_key);
+                    let pos = BlobRef(value).pos();
+                    if !self.key_range.contains(&key) {
+                        return Err(format!(
+                            "key {} is outside the key range {}-{}",
+                            key, self.key_range.start, self.key_range.end
+                        ));
+                    }
+                    if !self.lsn_range.contains(&lsn) {
+                        return Err(format!(
+                            "LSN {} of key {} is outside the LSN range {}-{}",
+                            lsn, key, self.lsn_range.start, self.lsn_range.end
+                        ));
+                    }
+                    if pos < PAGE_SZ as u64 || pos >= values_end {
+                        return Err(format!(
+                            "value of key {} at {} is at offset {}, outside the values at {}-{}",
+                            key, lsn, pos, PAGE_SZ, values_end
+                        ));
+                    }
+                    if let Some(prev_pos) = prev_pos {
+                        if pos <= prev_pos {
+                            return Err(format!(
+                                "value of key {} at {} is at offset {}, not after the previous value at {}",
+                                key, lsn, pos, prev_pos
+                            ));
+                        }
+                    }
+                    prev_pos = Some(pos);
+                    Ok(())
+                })
+                .with_context(|| {
+                    format!(
+                        "invalid {} in delta layer {}",
+                        index_name,
+                        self.filename().display()
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
     fn delete(&self) -> Result<()> {
         // delete underlying file
         fs::remove_file(self.path())?;
@@ -364,6 +610,15 @@ impl Layer for DeltaLayer {
             "index_start_blk: {}, root {}",
             inner.index_start_blk, inner.index_root_blk
         );
+        if inner.key_index_start_blk != 0 {
+            println!(
+                "key_index_start_blk: {}, root {}",
+                inner.key_index_start_blk, inner.key_index_root_blk
+            );
+        }
+        if inner.value_compression != DeltaCompression::None {
+            println!("value_compression: {}", inner.value_compression);
+        }
 
         let file = inner.file.as_ref().unwrap();
         let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
@@ -379,7 +634,7 @@ impl Layer for DeltaLayer {
         // A subroutine to dump a single blob
         let mut dump_blob = |blob_ref: BlobRef| -> anyhow::Result<String> {
             let buf = cursor.read_blob(blob_ref.pos())?;
-            let val = Value::des(&buf)?;
+            let val = inner.value_compression.des_value(&buf)?;
             let desc = match val {
                 Value::Image(img) => {
                     format!(" img {} bytes", img.len())
@@ -506,6 +761,16 @@ impl DeltaLayer {
                 let mut expected_summary = Summary::from(self);
                 expected_summary.index_start_blk = actual_summary.index_start_blk;
                 expected_summary.index_root_blk = actual_summary.index_root_blk;
+                if actual_summary.format_version == KEY_INDEX_FORMAT_VERSION
+                    || actual_summary.format_version == COMPRESSED_FORMAT_VERSION
+                {
+                    expected_summary.format_version = actual_summary.format_version;
+                    expected_summary.key_index_start_blk = actual_summary.key_index_start_blk;
+                    expected_summary.key_index_root_blk = actual_summary.key_index_root_blk;
+                }
+                if actual_summary.format_version == COMPRESSED_FORMAT_VERSION {
+                    expected_summary.value_compression = actual_summary.value_compression;
+                }
                 if actual_summary != expected_summary {
                     bail!("in-file summary does not match expected summary. actual = {:?} expected = {:?}", actual_summary, expected_summary);
                 }
@@ -526,6 +791,9 @@ impl DeltaLayer {
 
         inner.index_start_blk = actual_summary.index_start_blk;
         inner.index_root_blk = actual_summary.index_root_blk;
+        inner.key_index_start_blk = actual_summary.key_index_start_blk;
+        inner.key_index_root_blk = actual_summary.key_index_root_blk;
+        inner.value_compression = actual_summary.value_compression;
 
         debug!("loaded from {}", &path.display());
 
@@ -551,13 +819,17 @@ impl DeltaLayer {
                 file: None,
                 index_start_blk: 0,
                 index_root_blk: 0,
+                key_index_start_blk: 0,
+                key_index_root_blk: 0,
+                value_compression: DeltaCompression::None,
             }),
         }
     }
 
     /// Create a DeltaLayer struct representing an existing file on disk.
     ///
-    /// This variant is only used for debugging purposes, by the 'dump_layerfile' binary.
+    /// This variant is used for debugging purposes, by the 'dump_layerfile' binary,
+    /// and for layer files outside the timeline directory.
     pub fn new_for_path<F>(path: &Path, file: F) -> Result<Self>
     where
         F: FileExt,
@@ -578,6 +850,9 @@ impl DeltaLayer {
                 file: None,
                 index_start_blk: 0,
                 index_root_blk: 0,
+                key_index_start_blk: 0,
+                key_index_root_blk: 0,
+                value_compression: DeltaCompression::None,
             }),
         })
     }
@@ -598,6 +873,97 @@ impl DeltaLayer {
             &self.layer_name(),
         )
     }
+
+    ///
+    /// Return the newest version at or below 'lsn' of each key in 'key_range'
+    /// that has one in this layer, in key order.
+    ///
+    /// If the layer has a key index, the newest version of each key is looked
+    /// up in it, and the main index is only searched for the keys that have
+    /// versions newer than 'lsn'. Otherwise, all versions of the keys in the
+    /// range are visited.
+    ///
+    pub fn scan_at_lsn(&self, key_range: &Range<Key>, lsn: Lsn) -> Result<Vec<(Key, Lsn, Value)>> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+        let search_key = DeltaKey::from_key_lsn(&key_range.start, Lsn(0));
+
+        let mut found: Vec<(Key, Lsn, BlobRef)> = Vec::new();
+        if inner.key_index_start_blk != 0 {
+            let key_index_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+                inner.key_index_start_blk,
+                inner.key_index_root_blk,
+                file,
+            );
+            let mut too_new: Vec<Key> = Vec::new();
+            key_index_reader.visit(&search_key.0, VisitDirection::Forwards, |key, value| {
+                let entry_key = DeltaKey::extract_key_from_buf(key);
+                if entry_key >= key_range.end {
+                    return false;
+                }
+                let entry_lsn = DeltaKey::extract_lsn_from_buf(key);
+                if entry_lsn <= lsn {
+                    found.push((entry_key, entry_lsn, BlobRef(value)));
+                } else {
+                    too_new.push(entry_key);
+                }
+                true
+            })?;
+
+            // For the keys whose newest version is too new, search backwards
+            // from 'lsn' in the main index.
+            for key in too_new {
+                let search_key = DeltaKey::from_key_lsn(&key, lsn);
+                tree_reader.visit(
+                    &search_key.0,
+                    VisitDirection::Backwards,
+                    |delta_key, value| {
+                        if delta_key[..KEY_SIZE] == search_key.0[..KEY_SIZE] {
+                            let entry_lsn = DeltaKey::extract_lsn_from_buf(delta_key);
+                            found.push((key, entry_lsn, BlobRef(value)));
+                        }
+                        false
+                    },
+                )?;
+            }
+            found.sort_by_key(|(key, _, _)| *key);
+        } else {
+            tree_reader.visit(&search_key.0, VisitDirection::Forwards, |key, value| {
+                let entry_key = DeltaKey::extract_key_from_buf(key);
+                if entry_key >= key_range.end {
+                    return false;
+                }
+                let entry_lsn = DeltaKey::extract_lsn_from_buf(key);
+                if entry_lsn <= lsn {
+                    let entry = (entry_key, entry_lsn, BlobRef(value));
+                    match found.last_mut() {
+                        Some(last) if last.0 == entry_key => *last = entry,
+                        _ => found.push(entry),
+                    }
+                }
+                true
+            })?;
+        }
+
+        let mut cursor = file.block_cursor();
+        found
+            .into_iter()
+            .map(|(key, entry_lsn, blob_ref)| {
+                let buf = cursor.read_blob(blob_ref.pos()).with_context(|| {
+                    format!(
+                        "Failed to read blob from virtual file {}",
+                        file.file.path.display()
+                    )
+                })?;
+                Ok((key, entry_lsn, inner.value_compression.des_value(&buf)?))
+            })
+            .collect()
+    }
 }
 
 /// A builder object for constructing a new delta layer.
@@ -622,6 +988,14 @@ pub struct DeltaLayerWriter {
 
     tree: DiskBtreeBuilder<BlockBuf, DELTA_KEY_SIZE>,
 
+    /// The key index, if 'delta_key_index' is enabled, and the newest version
+    /// of the current key, which goes into it when the next key begins.
+    key_index: Option<DiskBtreeBuilder<BlockBuf, DELTA_KEY_SIZE>>,
+    newest_version: Option<(Key, Lsn, BlobRef)>,
+
+    /// Compression of the values, from 'delta_compression'
+    value_compression: DeltaCompression,
+
     blob_writer: WriteBlobWriter<BufWriter<VirtualFile>>,
 }
 
@@ -653,6 +1027,9 @@ impl DeltaLayerWriter {
         // Initialize the b-tree index builder
         let block_buf = BlockBuf::new();
         let tree_builder = DiskBtreeBuilder::new(block_buf);
+        let key_index = conf
+            .delta_key_index
+            .then(|| DiskBtreeBuilder::new(BlockBuf::new()));
 
         Ok(DeltaLayerWriter {
             conf,
@@ -662,6 +1039,9 @@ impl DeltaLayerWriter {
             key_start,
             lsn_range,
             tree: tree_builder,
+            key_index,
+            newest_version: None,
+            value_compression: conf.delta_compression,
             blob_writer,
         })
     }
@@ -675,6 +1055,8 @@ impl DeltaLayerWriter {
         self.put_value_bytes(key, lsn, &Value::ser(&val)?, val.will_init())
     }
 
+    /// Like put_value(), with the value already serialized. It is compressed
+    /// here, if compression is enabled.
     pub fn put_value_bytes(
         &mut self,
         key: Key,
@@ -684,18 +1066,34 @@ impl DeltaLayerWriter {
     ) -> Result<()> {
         assert!(self.lsn_range.start <= lsn);
 
-        let off = self.blob_writer.write_blob(val)?;
+        let off = self
+            .blob_writer
+            .write_blob(&self.value_compression.compress(val)?)?;
 
         let blob_ref = BlobRef::new(off, will_init);
 
         let delta_key = DeltaKey::from_key_lsn(&key, lsn);
         self.tree.append(&delta_key.0, blob_ref.0)?;
 
+        if let Some(key_index) = &mut self.key_index {
+            if let Some((newest_key, newest_lsn, newest_ref)) = self.newest_version {
+                if newest_key != key {
+                    let delta_key = DeltaKey::from_key_lsn(&newest_key, newest_lsn);
+                    key_index.append(&delta_key.0, newest_ref.0)?;
+                }
+            }
+            self.newest_version = Some((key, lsn, blob_ref));
+        }
+
         Ok(())
     }
 
     pub fn size(&self) -> u64 {
-        self.blob_writer.size() + self.tree.borrow_writer().size()
+        let key_index_size = self
+            .key_index
+            .as_ref()
+            .map_or(0, |key_index| key_index.borrow_writer().size());
+        self.blob_writer.size() + self.tree.borrow_writer().size() + key_index_size
     }
 
     ///
@@ -710,21 +1108,46 @@ impl DeltaLayerWriter {
 
         // Write out the index
         let (index_root_blk, block_buf) = self.tree.finish()?;
+        let index_blocks = block_buf.blocks.len() as u32;
         file.seek(SeekFrom::Start(index_start_blk as u64 * PAGE_SZ as u64))?;
         for buf in block_buf.blocks {
             file.write_all(buf.as_ref())?;
         }
 
+        // Write out the key index right after it, if we built one
+        let mut format_version = STORAGE_FORMAT_VERSION;
+        let mut key_index_start_blk = 0;
+        let mut key_index_root_blk = 0;
+        if let Some(mut key_index) = self.key_index {
+            if let Some((newest_key, newest_lsn, newest_ref)) = self.newest_version {
+                let delta_key = DeltaKey::from_key_lsn(&newest_key, newest_lsn);
+                key_index.append(&delta_key.0, newest_ref.0)?;
+            }
+            let (root_blk, block_buf) = key_index.finish()?;
+            for buf in block_buf.blocks {
+                file.write_all(buf.as_ref())?;
+            }
+            format_version = KEY_INDEX_FORMAT_VERSION;
+            key_index_start_blk = index_start_blk + index_blocks;
+            key_index_root_blk = root_blk;
+        }
+        if self.value_compression != DeltaCompression::None {
+            format_version = COMPRESSED_FORMAT_VERSION;
+        }
+
         // Fill in the summary on blk 0
         let summary = Summary {
             magic: DELTA_FILE_MAGIC,
-            format_version: STORAGE_FORMAT_VERSION,
+            format_version,
             tenantid: self.tenantid,
             timelineid: self.timelineid,
             key_range: self.key_start..key_end,
             lsn_range: self.lsn_range.clone(),
             index_start_blk,
             index_root_blk,
+            key_index_start_blk,
+            key_index_root_blk,
+            value_compression: self.value_compression,
         };
         file.seek(SeekFrom::Start(0))?;
         Summary::ser_into(&summary, &mut file)?;
@@ -743,6 +1166,9 @@ impl DeltaLayerWriter {
                 file: None,
                 index_start_blk,
                 index_root_blk,
+                key_index_start_blk,
+                key_index_root_blk,
+                value_compression: self.value_compression,
             }),
         };
 
@@ -779,6 +1205,7 @@ impl DeltaLayerWriter {
 struct DeltaValueIter<'a> {
     all_offsets: Vec<(DeltaKey, BlobRef)>,
     next_idx: usize,
+    value_compression: DeltaCompression,
     reader: BlockCursor<Adapter<'a>>,
 }
 
@@ -822,6 +1249,7 @@ impl<'a> DeltaValueIter<'a> {
         let iter = DeltaValueIter {
             all_offsets,
             next_idx: 0,
+            value_compression: inner.value_compression,
             reader: BlockCursor::new(Adapter(inner)),
         };
 
@@ -836,7 +1264,7 @@ impl<'a> DeltaValueIter<'a> {
             let lsn = delta_key.lsn();
 
             let buf = self.reader.read_blob(blob_ref.pos())?;
-            let val = Value::des(&buf)?;
+            let val = self.value_compression.des_value(&buf)?;
             self.next_idx += 1;
             Ok(Some((key, lsn, val)))
         } else {
@@ -916,3 +1344,399 @@ impl<'a> DeltaKeyIter {
         Ok(iter)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::repo_harness::*;
+    use crate::walrecord::ZenithWalRecord;
+    use bytes::Bytes;
+
+    #[test]
+    fn estimate_reclaimable_bytes() -> Result<()> {
+        let harness = RepoHarness::create("delta_estimate_reclaimable_bytes")?;
+        fs::create_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+
+        let key_a = Key::from_hex("010000000033333333444444445500000001")?;
+        let key_b = Key::from_hex("010000000033333333444444445500000002")?;
+        let wal_record = |data: &'static [u8]| {
+            Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(data),
+            })
+        };
+        let values = [
+            (key_a, Lsn(0x10), wal_record(b"record 1")),
+            (key_a, Lsn(0x20), Value::Image(TEST_IMG("image 2"))),
+            (key_a, Lsn(0x30), wal_record(b"record 3")),
+            (key_b, Lsn(0x10), Value::Image(TEST_IMG("image 1"))),
+            (key_b, Lsn(0x40), Value::Image(TEST_IMG("image 4"))),
+        ];
+        // All the values are short, so they have a 1-byte length header
+        let stored_size =
+            |i: usize| -> Result<u64> { Ok(1 + Value::ser(&values[i].2)?.len() as u64) };
+
+        let mut writer = DeltaLayerWriter::new(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            key_a,
+            Lsn(0x10)..Lsn(0x50),
+        )?;
+        for (key, lsn, value) in values.iter() {
+            writer.put_value(*key, *lsn, value.clone())?;
+        }
+        let layer = writer.finish(key_b.next())?;
+
+        // Nothing is shadowed by an image at or before 0x10
+        assert_eq!(layer.estimate_reclaimable_bytes(Lsn(0x10))?, 0);
+        // The first record of key A is shadowed by the image at 0x20
+        assert_eq!(
+            layer.estimate_reclaimable_bytes(Lsn(0x20))?,
+            stored_size(0)?
+        );
+        assert_eq!(
+            layer.estimate_reclaimable_bytes(Lsn(0x3f))?,
+            stored_size(0)?
+        );
+        // The first image of key B is shadowed by the image at 0x40
+        assert_eq!(
+            layer.estimate_reclaimable_bytes(Lsn(0x40))?,
+            stored_size(0)? + stored_size(3)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_structure_detects_corrupt_index() -> Result<()> {
+        let harness = RepoHarness::create("delta_validate_structure")?;
+        fs::create_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+
+        let key_a = Key::from_hex("010000000033333333444444445500000001")?;
+        let key_b = Key::from_hex("010000000033333333444444445500000002")?;
+        let mut writer = DeltaLayerWriter::new(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            key_a,
+            Lsn(0x10)..Lsn(0x20),
+        )?;
+        writer.put_value(key_a, Lsn(0x10), Value::Image(TEST_IMG("a")))?;
+        writer.put_value(key_b, Lsn(0x10), Value::Image(TEST_IMG("b")))?;
+        let layer = writer.finish(key_b.next())?;
+        layer.validate_structure()?;
+
+        // The index fits in a single leaf node. Locate its keys and values.
+        let path = layer.path();
+        let original = fs::read(&path)?;
+        let (index_start_blk, index_root_blk) = {
+            let inner = layer.inner.read().unwrap();
+            (inner.index_start_blk, inner.index_root_blk)
+        };
+        let node_off = (index_start_blk + index_root_blk) as usize * PAGE_SZ;
+        let num_children = u16::from_be_bytes([original[node_off], original[node_off + 1]]);
+        assert_eq!(num_children, 2);
+        let prefix_len = original[node_off + 3] as usize;
+        let suffix_len = original[node_off + 4] as usize;
+        let keys_off = node_off + 5 + prefix_len;
+        let values_off = keys_off + 2 * suffix_len;
+
+        // Validate a corrupted copy with a fresh layer, so that nothing is
+        // served from the page cache
+        let validate_corrupted = |corrupt: &dyn Fn(&mut Vec<u8>)| -> Result<String> {
+            let mut contents = original.clone();
+            corrupt(&mut contents);
+            fs::write(&path, &contents)?;
+            let layer = DeltaLayer::new(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+                &layer.layer_name(),
+            );
+            let err = layer.validate_structure().unwrap_err();
+            Ok(format!("{err:#}"))
+        };
+
+        // Out-of-order entries
+        let err = validate_corrupted(&|contents| {
+            let (first, second) = contents[keys_off..values_off].split_at_mut(suffix_len);
+            first.swap_with_slice(second);
+        })?;
+        assert!(err.contains("is not after the previous key"), "{err}");
+
+        // An offset past the end of the values
+        let err = validate_corrupted(&|contents| {
+            let bad_ref = BlobRef::new(1 << 30, false).0;
+            contents[values_off..values_off + 5].copy_from_slice(&bad_ref.to_be_bytes()[3..]);
+        })?;
+        assert!(err.contains("outside the values"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch_is_only_a_hint() -> Result<()> {
+        let harness = RepoHarness::create("delta_prefetch_is_only_a_hint")?;
+        fs::create_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+
+        let key = |blknum| -> Result<Key> {
+            Key::from_hex(&format!("0100000000333333334444444455{blknum:08X}"))
+        };
+        let mut writer = DeltaLayerWriter::new(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            key(0)?,
+            Lsn(0x10)..Lsn(0x30),
+        )?;
+        for blknum in 0..100 {
+            for lsn in [Lsn(0x10), Lsn(0x20)] {
+                let img = TEST_IMG(&format!("{blknum} at {lsn}"));
+                writer.put_value(key(blknum)?, lsn, Value::Image(img))?;
+            }
+        }
+        let layer = writer.finish(key(100)?)?;
+
+        let read_all = || -> Result<()> {
+            for blknum in 0..100 {
+                let mut state = ValueReconstructState {
+                    records: Vec::new(),
+                    img: None,
+                };
+                layer.get_value_reconstruct_data(key(blknum)?, Lsn(0x10)..Lsn(0x30), &mut state)?;
+                assert_eq!(
+                    state.img,
+                    Some((Lsn(0x20), TEST_IMG(&format!("{blknum} at 0/20"))))
+                );
+            }
+            Ok(())
+        };
+
+        read_all()?;
+        // Ranges within the layer, past either end of it, and empty ones
+        for range in [
+            key(10)?..key(20)?,
+            key(0)?..key(100)?,
+            key(90)?..key(200)?,
+            Key::MIN..key(1)?,
+            key(150)?..key(200)?,
+            key(20)?..key(20)?,
+        ] {
+            layer.prefetch(&range)?;
+            read_all()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_at_lsn_with_and_without_key_index() -> Result<()> {
+        let key = |blknum| -> Result<Key> {
+            Key::from_hex(&format!("0100000000333333334444444455{blknum:08X}"))
+        };
+        // Every third key has no version before 0x30, and every fifth one
+        // none after 0x10.
+        let mut values = Vec::new();
+        for blknum in 0..100u32 {
+            for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+                if (blknum % 3 == 0 && lsn < Lsn(0x30)) || (blknum % 5 == 0 && lsn > Lsn(0x10)) {
+                    continue;
+                }
+                values.push((key(blknum)?, lsn, TEST_IMG(&format!("{blknum} at {lsn}"))));
+            }
+        }
+
+        let write_layer = |name: &'static str, delta_key_index: bool| -> Result<DeltaLayer> {
+            let harness =
+                RepoHarness::create_with_conf(name, |conf| conf.delta_key_index = delta_key_index)?;
+            fs::create_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+
+            let mut writer = DeltaLayerWriter::new(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+                key(0)?,
+                Lsn(0x10)..Lsn(0x40),
+            )?;
+            for (key, lsn, img) in values.iter() {
+                writer.put_value(*key, *lsn, Value::Image(img.clone()))?;
+            }
+            writer.finish(key(100)?)
+        };
+        let plain = write_layer("delta_scan_at_lsn_plain", false)?;
+        let indexed = write_layer("delta_scan_at_lsn_indexed", true)?;
+        assert_eq!(plain.load()?.key_index_start_blk, 0);
+        assert_ne!(indexed.load()?.key_index_start_blk, 0);
+
+        let scan = |layer: &DeltaLayer, range: &Range<Key>, lsn: Lsn| -> Result<Vec<_>> {
+            let mut result = Vec::new();
+            for (key, lsn, value) in layer.scan_at_lsn(range, lsn)? {
+                match value {
+                    Value::Image(img) => result.push((key, lsn, img)),
+                    Value::WalRecord(_) => bail!("unexpected WAL record"),
+                }
+            }
+            Ok(result)
+        };
+        for range in [key(0)?..key(100)?, key(10)?..key(20)?, key(150)?..key(200)?] {
+            for lsn in [Lsn(0x08), Lsn(0x10), Lsn(0x28), Lsn(0x30), Lsn(0x50)] {
+                let mut expected = Vec::new();
+                for (key, entry_lsn, img) in values.iter() {
+                    if !range.contains(key) || *entry_lsn > lsn {
+                        continue;
+                    }
+                    if matches!(expected.last(), Some((last_key, _, _)) if last_key == key) {
+                        expected.pop();
+                    }
+                    expected.push((*key, *entry_lsn, img.clone()));
+                }
+                assert_eq!(scan(&plain, &range, lsn)?, expected);
+                assert_eq!(scan(&indexed, &range, lsn)?, expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// A sample of WAL like an insert-heavy workload produces: heap insert
+    /// records of rows with a counter and a short text column, and a full page
+    /// image of each page when it's first touched.
+    ///
+    fn wal_sample() -> Result<Vec<(Key, Lsn, Value)>> {
+        let mut values = Vec::new();
+        let mut lsn = Lsn(0x1000);
+        let mut row_id = 0u32;
+        for blknum in 0..50u32 {
+            let key = Key::from_hex(&format!("0100000000333333334444444455{blknum:08X}"))?;
+
+            // The page image: line pointers at the start, tuples at the end,
+            // and a hole of zeros in between.
+            let mut page = vec![0u8; 8192];
+            for i in 0..40 {
+                page[24 + i * 4..28 + i * 4].copy_from_slice(&(8192 - 64 * i as u32).to_le_bytes());
+                let tuple = format!("customer {:>10}", row_id + i as u32);
+                page[8192 - 64 * (i + 1)..][..tuple.len()].copy_from_slice(tuple.as_bytes());
+            }
+            values.push((key, lsn, Value::Image(Bytes::from(page))));
+            lsn += 0x100;
+
+            for _ in 0..20 {
+                // Record header with the xid, length and CRC, the block
+                // reference, and the new tuple.
+                let mut rec = Vec::new();
+                rec.extend_from_slice(&(100 + row_id).to_le_bytes());
+                rec.extend_from_slice(&(lsn.0 as u32).to_le_bytes());
+                rec.extend_from_slice(&crc32c::crc32c(&row_id.to_le_bytes()).to_le_bytes());
+                rec.extend_from_slice(&[0, 0x20, 0, 0, 0x7f, 0x06, 0, 0, 0x40, 0x1f]);
+                rec.extend_from_slice(&blknum.to_le_bytes());
+                rec.extend_from_slice(&row_id.to_le_bytes());
+                rec.extend_from_slice(format!("customer {row_id:>10}, status: active").as_bytes());
+                values.push((
+                    key,
+                    lsn,
+                    Value::WalRecord(ZenithWalRecord::Postgres {
+                        will_init: false,
+                        rec: Bytes::from(rec),
+                    }),
+                ));
+                lsn += 0x80;
+                row_id += 1;
+            }
+        }
+        Ok(values)
+    }
+
+    #[test]
+    fn compressed_values() -> Result<()> {
+        let values = wal_sample()?;
+        let key_range = values[0].0..values.last().unwrap().0.next();
+        let lsn_range = values[0].1..values.last().unwrap().1 + 1;
+
+        let write_layer = |compression: DeltaCompression| -> Result<DeltaLayer> {
+            let test_name = match compression {
+                DeltaCompression::None => "delta_compressed_values_none",
+                DeltaCompression::Lz4 => "delta_compressed_values_lz4",
+                DeltaCompression::Zstd => "delta_compressed_values_zstd",
+            };
+            let harness = RepoHarness::create_with_conf(test_name, |conf| {
+                conf.delta_compression = compression;
+                // Compression works with and without the key index
+                conf.delta_key_index = compression == DeltaCompression::Zstd;
+            })?;
+            fs::create_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+
+            let mut writer = DeltaLayerWriter::new(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+                key_range.start,
+                lsn_range.clone(),
+            )?;
+            for (key, lsn, value) in values.iter() {
+                writer.put_value(*key, *lsn, value.clone())?;
+            }
+            writer.finish(key_range.end)
+        };
+
+        let mut sizes = Vec::new();
+        for compression in [
+            DeltaCompression::None,
+            DeltaCompression::Lz4,
+            DeltaCompression::Zstd,
+        ] {
+            let layer = write_layer(compression)?;
+            assert_eq!(layer.load()?.value_compression, compression);
+            sizes.push(layer.path().metadata()?.len());
+
+            // All the values read back as they were written
+            let read: Vec<_> = layer.iter().collect::<Result<_>>()?;
+            assert_eq!(read.len(), values.len());
+            for ((key, lsn, value), (read_key, read_lsn, read_value)) in values.iter().zip(read) {
+                assert_eq!((*key, *lsn), (read_key, read_lsn));
+                assert_eq!(Value::ser(value)?, Value::ser(&read_value)?);
+            }
+
+            // Reconstruct a page from its image and records
+            let (key, lsn, _) = &values[30];
+            let mut state = ValueReconstructState {
+                records: Vec::new(),
+                img: None,
+            };
+            layer.get_value_reconstruct_data(*key, lsn_range.start..*lsn + 1, &mut state)?;
+            assert_eq!(state.records.len(), 9);
+            match &values[21] {
+                (_, img_lsn, Value::Image(img)) => {
+                    assert_eq!(state.img, Some((*img_lsn, img.clone())))
+                }
+                _ => panic!("expected a page image"),
+            }
+
+            let scanned = layer.scan_at_lsn(&key_range, lsn_range.end)?;
+            assert_eq!(scanned.len(), 50);
+        }
+
+        // The values are very compressible, so compression should at least
+        // halve the size of the file.
+        assert!(sizes[1] * 2 < sizes[0], "{sizes:?}");
+        assert!(sizes[2] * 2 < sizes[0], "{sizes:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn delta_compression_from_str() {
+        for compression in [
+            DeltaCompression::None,
+            DeltaCompression::Lz4,
+            DeltaCompression::Zstd,
+        ] {
+            assert_eq!(
+                compression.to_string().parse::<DeltaCompression>().unwrap(),
+                compression
+            );
+        }
+        assert!("gzip".parse::<DeltaCompression>().is_err());
+    }
+}
@@ -97,6 +97,9 @@ pub enum DiskBtreeError {
     #[error("Could not push to new leaf node")]
     FailedToPushToNewLeafNode,
 
+    #[error("Corrupt node at block {blknum}: {reason}")]
+    Corrupt { blknum: u32, reason: String },
+
     #[error("IoError: {0}")]
     Io(#[from] io::Error),
 }
@@ -140,7 +143,15 @@ impl<'a, const L: usize> OnDiskNode<'a, L> {
 
         let values_off = off as usize;
         let values_len = num_children as usize * VALUE_SZ as usize;
-        //off += values_len as u64;
+        off += values_len as u64;
+
+        if off as usize > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("node of {} bytes does not fit in the page", off),
+            )
+            .into());
+        }
 
         let prefix = &buf[prefix_off..prefix_off + prefix_len as usize];
         let keys = &buf[keys_off..keys_off + keys_len];
@@ -389,6 +400,112 @@ where
         Ok(true)
     }
 
+    ///
+    /// Check that the tree is well-formed, without trusting its structure:
+    /// every node fits in its page, the children of each internal node are
+    /// one level down and were written before it, and the keys are in
+    /// strictly ascending order. 'visitor' is called for every key-value pair
+    /// in the leaves, in order, to check the values. It returns a description
+    /// of the problem if a value is bad. Stops at the first problem.
+    ///
+    pub fn validate<V>(&self, mut visitor: V) -> Result<()>
+    where
+        V: FnMut(&[u8], u64) -> result::Result<(), String>,
+    {
+        let mut last_key = None;
+        self.validate_recurse(self.root_blk, None, &mut last_key, &mut visitor)
+    }
+
+    fn validate_recurse<V>(
+        &self,
+        blknum: u32,
+        expected_level: Option<u8>,
+        last_key: &mut Option<Vec<u8>>,
+        visitor: &mut V,
+    ) -> Result<()>
+    where
+        V: FnMut(&[u8], u64) -> result::Result<(), String>,
+    {
+        let corrupt = |reason: String| DiskBtreeError::Corrupt { blknum, reason };
+
+        let blk = self
+            .reader
+            .read_blk(self.start_blk + blknum)
+            .map_err(|e| corrupt(e.to_string()))?;
+        let node = OnDiskNode::<L>::deparse(blk.as_ref()).map_err(|e| corrupt(e.to_string()))?;
+        let prefix_len = node.prefix_len as usize;
+        let suffix_len = node.suffix_len as usize;
+
+        // Only the root of an empty tree has no entries
+        if node.num_children == 0 && expected_level.is_some() {
+            return Err(corrupt("node has no entries".to_string()));
+        }
+        if prefix_len + suffix_len != L {
+            return Err(corrupt(format!(
+                "prefix length {} and suffix length {} don't add up to the key length {}",
+                prefix_len, suffix_len, L
+            )));
+        }
+        if let Some(expected_level) = expected_level {
+            if node.level != expected_level {
+                return Err(corrupt(format!(
+                    "node is at level {}, expected {}",
+                    node.level, expected_level
+                )));
+            }
+        }
+
+        let mut keybuf = Vec::new();
+        keybuf.extend(node.prefix);
+        keybuf.resize(L, 0);
+        let mut prev_key: Option<Vec<u8>> = None;
+        for idx in 0..node.num_children as usize {
+            let key_off = idx * suffix_len;
+            keybuf[prefix_len..].copy_from_slice(&node.keys[key_off..key_off + suffix_len]);
+            let value = node.value(idx);
+
+            if node.level == 0 {
+                if let Some(last_key) = last_key {
+                    if keybuf <= *last_key {
+                        return Err(corrupt(format!(
+                            "key {} is not after the previous key {}",
+                            hex::encode(&keybuf),
+                            hex::encode(last_key)
+                        )));
+                    }
+                }
+                visitor(&keybuf, value.to_u64()).map_err(corrupt)?;
+                *last_key = Some(keybuf.clone());
+            } else {
+                if let Some(prev_key) = &prev_key {
+                    if keybuf <= *prev_key {
+                        return Err(corrupt(format!(
+                            "key {} is not after the previous key {}",
+                            hex::encode(&keybuf),
+                            hex::encode(prev_key)
+                        )));
+                    }
+                }
+                if value.0[0] != 0x80 {
+                    return Err(corrupt(format!(
+                        "entry {} of an internal node is not a block number",
+                        idx
+                    )));
+                }
+                let child_blknum = value.to_blknum();
+                if child_blknum >= blknum {
+                    return Err(corrupt(format!(
+                        "child block {} is not before its parent",
+                        child_blknum
+                    )));
+                }
+                self.validate_recurse(child_blknum, Some(node.level - 1), last_key, visitor)?;
+                prev_key = Some(keybuf.clone());
+            }
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn dump(&self) -> Result<()> {
         self.dump_recurse(self.root_blk, &[], 0)
@@ -835,6 +952,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn validate() -> Result<()> {
+        let mut disk = TestDisk::new();
+        let mut writer = DiskBtreeBuilder::<_, 8>::new(&mut disk);
+        const NUM_KEYS: u64 = 10000;
+        for idx in 0..NUM_KEYS {
+            writer.append(&u64::to_be_bytes(idx * 2), idx)?;
+        }
+        let (root_offset, _writer) = writer.finish()?;
+        assert!(root_offset > 1, "expected a tree with internal nodes");
+
+        let reader = DiskBtreeReader::<_, 8>::new(0, root_offset, disk);
+        let mut num_visited = 0;
+        reader.validate(|key, value| {
+            assert_eq!(key, u64::to_be_bytes(value * 2));
+            num_visited += 1;
+            Ok(())
+        })?;
+        assert_eq!(num_visited, NUM_KEYS);
+
+        // The first problem reported by the visitor is returned
+        let err = reader
+            .validate(|_key, value| {
+                if value == 5000 {
+                    Err("bad value".to_string())
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap_err();
+        assert!(
+            matches!(&err, DiskBtreeError::Corrupt { reason, .. } if reason == "bad value"),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn lots_of_keys() -> Result<()> {
         let mut disk = TestDisk::new();
@@ -0,0 +1,69 @@
+//!
+//! Checks the free disk space before new layer files are written.
+//!
+//! A write that runs out of space fails with ENOSPC partway through, and
+//! leaves a partial file behind. Instead, flushing and compaction check that
+//! at least 'min_free_disk_space' bytes are free before they start writing a
+//! layer, and fail with [`DiskSpaceLow`] if not. The timeline also stops
+//! ingesting WAL until there's room again, so that in-memory layers don't
+//! pile up while they can't be flushed.
+//!
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+#[cfg(test)]
+use std::cell::Cell;
+
+/// Returned when there's less free disk space than 'min_free_disk_space',
+/// before any new layer file is written.
+#[derive(Debug, thiserror::Error)]
+#[error("only {available} bytes are free on the disk of {}, {reserve} bytes are reserved", path.display())]
+pub struct DiskSpaceLow {
+    pub path: PathBuf,
+    pub available: u64,
+    pub reserve: u64,
+}
+
+#[cfg(test)]
+thread_local! {
+    static MOCKED_AVAILABLE_SPACE: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Number of bytes available to unprivileged users on the filesystem of 'path'.
+pub fn available_space(path: &Path) -> Result<u64> {
+    #[cfg(test)]
+    if let Some(available) = MOCKED_AVAILABLE_SPACE.with(Cell::get) {
+        return Ok(available);
+    }
+    let stat = nix::sys::statvfs::statvfs(path)
+        .with_context(|| format!("failed to query free disk space of {}", path.display()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+///
+/// Check that at least 'reserve' bytes are free on the filesystem of 'path'.
+/// A 'reserve' of 0 disables the check.
+///
+pub fn check_space(path: &Path, reserve: u64) -> Result<()> {
+    if reserve == 0 {
+        return Ok(());
+    }
+    let available = available_space(path)?;
+    if available < reserve {
+        return Err(DiskSpaceLow {
+            path: path.to_path_buf(),
+            available,
+            reserve,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Make available_space() return 'available' on the current thread, or
+/// query the filesystem again with None.
+#[cfg(test)]
+pub fn mock_available_space(available: Option<u64>) {
+    MOCKED_AVAILABLE_SPACE.with(|cell| cell.set(available));
+}
@@ -0,0 +1,234 @@
+//!
+//! Per-tenant rate limiting of the GetPage requests of the page service.
+//!
+//! A tenant flooding the pageserver with reads of cold pages can keep the WAL
+//! redo process and the disk busy for everyone. Each tenant has a token bucket
+//! for the number of GetPage requests, and another one for the bytes returned,
+//! refilled at the 'get_requests_per_second' and 'get_bytes_per_second' rates
+//! of the tenant config. Each bucket holds up to one second's worth of tokens,
+//! so a short burst is allowed after an idle period.
+//!
+//! The size of a page is only known once it has been read, so the bytes are
+//! charged after the request. A large page can leave the bytes bucket in
+//! debt, and the following requests are refused until it has been paid back.
+//!
+//! A refused request fails with [`RateLimited`] before doing any work, and the
+//! page service holds it back until the tenant is under its limits again. Only
+//! GetPage requests are limited. The other smgr requests, basebackup, import,
+//! WAL ingestion and background tasks don't take tokens.
+//!
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use metrics::{register_int_counter_vec, IntCounter, IntCounterVec};
+use once_cell::sync::Lazy;
+use utils::zid::ZTenantId;
+
+static THROTTLED_GET_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_throttled_get_requests_total",
+        "Number of GetPage requests refused because the tenant exceeded its rate limit",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+/// Returned when the tenant has exceeded its GetPage rate limit. The request
+/// can be retried after 'retry_after'.
+#[derive(Debug, thiserror::Error)]
+#[error("tenant {tenant_id} exceeded its GetPage rate limit, retry after {retry_after:?}")]
+pub struct RateLimited {
+    pub tenant_id: ZTenantId,
+    pub retry_after: Duration,
+}
+
+/// The rate limits from the tenant config. 0 means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetRateLimits {
+    pub requests_per_second: u64,
+    pub bytes_per_second: u64,
+}
+
+impl GetRateLimits {
+    pub const UNLIMITED: GetRateLimits = GetRateLimits {
+        requests_per_second: 0,
+        bytes_per_second: 0,
+    };
+
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_second == 0 && self.bytes_per_second == 0
+    }
+}
+
+struct TokenBucket {
+    /// Can be negative, see the module comment
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(now: Instant) -> Self {
+        // Starts full, the first refill caps it at the rate
+        TokenBucket {
+            tokens: f64::INFINITY,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+        self.refilled_at = now;
+    }
+
+    /// How long until the bucket holds 'needed' tokens
+    fn time_until(&self, rate: u64, needed: f64) -> Duration {
+        Duration::from_secs_f64((needed - self.tokens).max(0.0) / rate as f64)
+    }
+}
+
+struct Buckets {
+    requests: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Shared by all the timelines of a tenant.
+pub struct GetRateLimiter {
+    tenant_id: ZTenantId,
+    buckets: Mutex<Buckets>,
+    throttled_counter: IntCounter,
+}
+
+impl GetRateLimiter {
+    pub fn new(tenant_id: ZTenantId) -> Self {
+        let now = Instant::now();
+        GetRateLimiter {
+            tenant_id,
+            buckets: Mutex::new(Buckets {
+                requests: TokenBucket::new(now),
+                bytes: TokenBucket::new(now),
+            }),
+            throttled_counter: THROTTLED_GET_REQUESTS.with_label_values(&[&tenant_id.to_string()]),
+        }
+    }
+
+    ///
+    /// Take a token for a new request, or fail with [`RateLimited`] if the
+    /// tenant is over one of its limits.
+    ///
+    pub fn check(&self, limits: GetRateLimits) -> Result<(), RateLimited> {
+        if limits.is_unlimited() {
+            return Ok(());
+        }
+        self.check_at(limits, Instant::now())
+    }
+
+    /// Charge the size of the page returned by a request that passed check().
+    pub fn charge_bytes(&self, limits: GetRateLimits, bytes: usize) {
+        if limits.bytes_per_second == 0 {
+            return;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .bytes
+            .refill(limits.bytes_per_second, Instant::now());
+        buckets.bytes.tokens -= bytes as f64;
+    }
+
+    /// Number of requests refused so far
+    pub fn num_throttled(&self) -> u64 {
+        self.throttled_counter.get()
+    }
+
+    fn check_at(&self, limits: GetRateLimits, now: Instant) -> Result<(), RateLimited> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut retry_after = Duration::ZERO;
+        if limits.requests_per_second != 0 {
+            buckets.requests.refill(limits.requests_per_second, now);
+            retry_after = buckets.requests.time_until(limits.requests_per_second, 1.0);
+        }
+        if limits.bytes_per_second != 0 {
+            buckets.bytes.refill(limits.bytes_per_second, now);
+            // Any debt has to be paid back first
+            retry_after = retry_after.max(buckets.bytes.time_until(limits.bytes_per_second, 0.0));
+        }
+
+        if retry_after > Duration::ZERO {
+            drop(buckets);
+            self.throttled_counter.inc();
+            return Err(RateLimited {
+                tenant_id: self.tenant_id,
+                retry_after,
+            });
+        }
+        if limits.requests_per_second != 0 {
+            buckets.requests.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_limited() {
+        let limiter = GetRateLimiter::new(ZTenantId::generate());
+        let limits = GetRateLimits {
+            requests_per_second: 10,
+            bytes_per_second: 0,
+        };
+        let start = Instant::now();
+
+        // A burst of one second's worth of requests is allowed
+        for _ in 0..10 {
+            limiter.check_at(limits, start).unwrap();
+        }
+        let err = limiter.check_at(limits, start).unwrap_err();
+        assert_eq!(err.retry_after, Duration::from_millis(100));
+        assert_eq!(limiter.num_throttled(), 1);
+
+        // One token is back after 100 ms
+        let later = start + Duration::from_millis(100);
+        limiter.check_at(limits, later).unwrap();
+        assert!(limiter.check_at(limits, later).is_err());
+
+        // The bucket doesn't fill up beyond one second's worth
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..10 {
+            limiter.check_at(limits, much_later).unwrap();
+        }
+        assert!(limiter.check_at(limits, much_later).is_err());
+        assert_eq!(limiter.num_throttled(), 3);
+
+        // No limit
+        for _ in 0..100 {
+            limiter.check(GetRateLimits::UNLIMITED).unwrap();
+        }
+    }
+
+    #[test]
+    fn bytes_are_limited() {
+        let limiter = GetRateLimiter::new(ZTenantId::generate());
+        let limits = GetRateLimits {
+            requests_per_second: 0,
+            bytes_per_second: 8192,
+        };
+        let start = Instant::now();
+
+        limiter.check_at(limits, start).unwrap();
+        // Go into debt with a page larger than the bucket
+        limiter.buckets.lock().unwrap().bytes.tokens -= 3.0 * 8192.0;
+        let err = limiter.check_at(limits, start).unwrap_err();
+        assert_eq!(err.retry_after, Duration::from_secs(2));
+
+        assert!(limiter
+            .check_at(limits, start + Duration::from_secs(1))
+            .is_err());
+        limiter
+            .check_at(limits, start + Duration::from_secs(2))
+            .unwrap();
+    }
+}
@@ -182,10 +182,79 @@ impl Layer for ImageLayer {
         }
     }
 
+    fn prefetch(&self, key_range: &Range<Key>) -> Result<()> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::new(inner.index_start_blk, inner.index_root_blk, file);
+
+        // The values are stored in key order, before the index. Find the
+        // offsets of the first value in the range, and of the first one after it.
+        let first_offset_from = |key: &Key| -> Result<Option<u64>> {
+            let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
+            key.write_to_byte_slice(&mut keybuf);
+            let mut offset = None;
+            tree_reader.visit(&keybuf, VisitDirection::Forwards, |_key, value| {
+                offset = Some(value);
+                false
+            })?;
+            Ok(offset)
+        };
+        let values_end = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let start = first_offset_from(&key_range.start)?.unwrap_or(values_end);
+        let end = first_offset_from(&key_range.end)?.unwrap_or(values_end);
+
+        if start < end {
+            file.file.prefetch(start, end - start)?;
+        }
+        Ok(())
+    }
+
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>>> {
         todo!();
     }
 
+    fn validate_structure(&self) -> Result<()> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader =
+            DiskBtreeReader::<_, KEY_SIZE>::new(inner.index_start_blk, inner.index_root_blk, file);
+
+        // The values are stored in key order, between the summary block and
+        // the index.
+        let values_end = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut prev_offset = None;
+        tree_reader
+            .validate(|keybuf, offset| {
+                let key = Key::from_slice(keybuf);
+                if !self.key_range.contains(&key) {
+                    return Err(format!(
+                        "key {} is outside the key range {}-{}",
+                        key, self.key_range.start, self.key_range.end
+                    ));
+                }
+                if offset < PAGE_SZ as u64 || offset >= values_end {
+                    return Err(format!(
+                        "value of key {} is at offset {}, outside the values at {}-{}",
+                        key, offset, PAGE_SZ, values_end
+                    ));
+                }
+                if let Some(prev_offset) = prev_offset {
+                    if offset <= prev_offset {
+                        return Err(format!(
+                            "value of key {} is at offset {}, not after the previous value at {}",
+                            key, offset, prev_offset
+                        ));
+                    }
+                }
+                prev_offset = Some(offset);
+                Ok(())
+            })
+            .with_context(|| {
+                format!("invalid index in image layer {}", self.filename().display())
+            })?;
+        Ok(())
+    }
+
     fn delete(&self) -> Result<()> {
         // delete underlying file
         fs::remove_file(self.path())?;
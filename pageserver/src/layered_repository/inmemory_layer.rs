@@ -15,6 +15,7 @@ use crate::layered_repository::storage_layer::{
 use crate::repository::{Key, Value};
 use crate::walrecord;
 use anyhow::{bail, ensure, Result};
+use serde_json::json;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use tracing::*;
@@ -65,6 +66,9 @@ pub struct InMemoryLayerInner {
     ///
     index: HashMap<Key, VecMap<Lsn, u64>>,
 
+    /// Number of page versions in 'index'.
+    num_entries: usize,
+
     /// The values are stored in a serialized format in this file.
     /// Each serialized Value is preceded by a 'u32' length field.
     /// PerSeg::page_versions map stores offsets into this file.
@@ -75,6 +79,29 @@ impl InMemoryLayerInner {
     fn assert_writeable(&self) {
         assert!(self.end_lsn.is_none());
     }
+
+    fn put_value(&mut self, key: Key, lsn: Lsn, val: &Value) -> Result<()> {
+        let off = {
+            SER_BUFFER.with(|x| -> Result<_> {
+                let mut buf = x.borrow_mut();
+                buf.clear();
+                val.ser_into(&mut (*buf))?;
+                let off = self.file.write_blob(&buf)?;
+                Ok(off)
+            })?
+        };
+
+        let vec_map = self.index.entry(key).or_default();
+        let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
+        if old.is_some() {
+            // We already had an entry for this LSN. That's odd..
+            warn!("Key {} at {} already exists", key, lsn);
+        } else {
+            self.num_entries += 1;
+        }
+
+        Ok(())
+    }
 }
 
 impl Layer for InMemoryLayer {
@@ -248,9 +275,73 @@ impl InMemoryLayer {
         Ok(inner.file.size)
     }
 
+    ///
+    /// Get the number of page versions in the layer
+    ///
+    pub fn num_entries(&self) -> usize {
+        self.inner.read().unwrap().num_entries
+    }
+
+    ///
+    /// Range from the smallest to the largest key in the layer, or None if
+    /// the layer is empty
+    ///
+    pub fn key_bounds(&self) -> Option<Range<Key>> {
+        let inner = self.inner.read().unwrap();
+        let min_key = inner.index.keys().min()?;
+        let max_key = inner.index.keys().max()?;
+        Some(*min_key..max_key.next())
+    }
+
     ///
     /// Create a new, empty, in-memory layer
     ///
+    /// Like [`Layer::dump`] with 'verbose', but returns the contents as JSON,
+    /// for tools that compare layers. The keys are sorted, so that layers with
+    /// the same contents produce the same output.
+    pub fn dump_json(&self) -> Result<serde_json::Value> {
+        let inner = self.inner.read().unwrap();
+
+        let mut keys: Vec<_> = inner.index.iter().collect();
+        keys.sort_by_key(|(key, _)| **key);
+
+        let mut cursor = inner.file.block_cursor();
+        let mut buf = Vec::new();
+        let mut keys_json = Vec::new();
+        for (key, vec_map) in keys {
+            let mut versions = Vec::new();
+            for (lsn, pos) in vec_map.as_slice() {
+                cursor.read_blob_into_buf(*pos, &mut buf)?;
+                let version = match Value::des(&buf) {
+                    Ok(value) => json!({
+                        "lsn": lsn.to_string(),
+                        "has_image": matches!(value, Value::Image(_)),
+                        "has_record": matches!(value, Value::WalRecord(_)),
+                        "will_init": value.will_init(),
+                        "size": buf.len(),
+                    }),
+                    Err(err) => json!({
+                        "lsn": lsn.to_string(),
+                        "error": err.to_string(),
+                    }),
+                };
+                versions.push(version);
+            }
+            keys_json.push(json!({
+                "key": key.to_string(),
+                "versions": versions,
+            }));
+        }
+
+        Ok(json!({
+            "tenant_id": self.tenantid.to_string(),
+            "timeline_id": self.timelineid.to_string(),
+            "start_lsn": self.start_lsn.to_string(),
+            "end_lsn": inner.end_lsn.map(|lsn| lsn.to_string()),
+            "keys": keys_json,
+        }))
+    }
+
     pub fn create(
         conf: &'static PageServerConf,
         timelineid: ZTimelineId,
@@ -273,6 +364,7 @@ impl InMemoryLayer {
             inner: RwLock::new(InMemoryLayerInner {
                 end_lsn: None,
                 index: HashMap::new(),
+                num_entries: 0,
                 file,
             }),
         })
@@ -286,24 +378,18 @@ impl InMemoryLayer {
         trace!("put_value key {} at {}/{}", key, self.timelineid, lsn);
         let mut inner = self.inner.write().unwrap();
         inner.assert_writeable();
+        inner.put_value(key, lsn, val)
+    }
 
-        let off = {
-            SER_BUFFER.with(|x| -> Result<_> {
-                let mut buf = x.borrow_mut();
-                buf.clear();
-                val.ser_into(&mut (*buf))?;
-                let off = inner.file.write_blob(&buf)?;
-                Ok(off)
-            })?
-        };
-
-        let vec_map = inner.index.entry(key).or_default();
-        let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
-        if old.is_some() {
-            // We already had an entry for this LSN. That's odd..
-            warn!("Key {} at {} already exists", key, lsn);
+    /// Like put_value(), for several page versions, with a single lock of the
+    /// layer. Readers of the layer see either none or all of them.
+    pub fn put_values(&self, values: &[(Key, Lsn, Value)]) -> Result<()> {
+        trace!("put_values {} values at {}", values.len(), self.timelineid);
+        let mut inner = self.inner.write().unwrap();
+        inner.assert_writeable();
+        for (key, lsn, val) in values {
+            inner.put_value(*key, *lsn, val)?;
         }
-
         Ok(())
     }
 
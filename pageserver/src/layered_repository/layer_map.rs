@@ -9,17 +9,23 @@
 //! are frozen, and it is split up into new image and delta layers and the
 //! corresponding files are written to disk.
 //!
+//! The layer map is versioned, see [`VersionedLayerMap`]. Readers can take a
+//! snapshot of it, which stays unchanged while flushing, compaction and GC
+//! install new versions of the map.
+//!
 
 use crate::layered_repository::inmemory_layer::InMemoryLayer;
 use crate::layered_repository::storage_layer::Layer;
 use crate::layered_repository::storage_layer::{range_eq, range_overlaps};
 use crate::repository::Key;
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use metrics::{register_int_gauge, IntGauge};
 use once_cell::sync::Lazy;
-use std::collections::VecDeque;
-use std::ops::Range;
-use std::sync::Arc;
+use serde_json::json;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::{Arc, LockResult, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tracing::*;
 use utils::lsn::Lsn;
 
@@ -31,7 +37,7 @@ static NUM_ONDISK_LAYERS: Lazy<IntGauge> = Lazy::new(|| {
 ///
 /// LayerMap tracks what layers exist on a timeline.
 ///
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct LayerMap {
     //
     // 'open_layer' holds the current InMemoryLayer that is accepting new
@@ -61,6 +67,79 @@ pub struct LayerMap {
     /// number of layers grows. I'm imagining that an R-tree or some
     /// other 2D data structure would be the long-term solution here.
     historic_layers: Vec<Arc<dyn Layer>>,
+
+    /// Bumped whenever a historic layer is inserted or removed, so that
+    /// results computed from the historic layers can be cached.
+    generation: u64,
+
+    /// Upper bound of the end LSNs of all the historic layers. Doesn't go
+    /// down when layers are removed.
+    historic_lsn_end: Lsn,
+
+    /// Key ranges of the historic layers, with the number of layers that
+    /// have each range. See [`LayerMap::covered_ranges`].
+    key_coverage: BTreeMap<(Key, Key), usize>,
+}
+
+/// An immutable version of the layer map. The layers in it are kept alive
+/// as long as the snapshot is held, even if they have been removed from the
+/// current version of the map.
+pub type LayerMapSnapshot = Arc<LayerMap>;
+
+///
+/// The current version of a timeline's [`LayerMap`].
+///
+/// This is used like an RwLock<LayerMap>, but modifications are copy-on-write:
+/// if someone holds a snapshot of the current version, a write clones the map
+/// and modifies the copy, which becomes the current version. Cloning the map
+/// only clones the Arcs of the layers in it.
+///
+#[derive(Default)]
+pub struct VersionedLayerMap {
+    current: RwLock<Arc<LayerMap>>,
+}
+
+impl VersionedLayerMap {
+    /// Take a snapshot of the current version of the map.
+    pub fn snapshot(&self) -> LayerMapSnapshot {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, Arc<LayerMap>>> {
+        self.current.read()
+    }
+
+    pub fn write(&self) -> LockResult<LayerMapWriteGuard<'_>> {
+        match self.current.write() {
+            Ok(guard) => Ok(LayerMapWriteGuard(guard)),
+            Err(err) => Err(PoisonError::new(LayerMapWriteGuard(err.into_inner()))),
+        }
+    }
+}
+
+/// Write access to the current version of the layer map. The map is cloned
+/// on the first modification, if there are snapshots of it.
+pub struct LayerMapWriteGuard<'a>(RwLockWriteGuard<'a, Arc<LayerMap>>);
+
+impl Deref for LayerMapWriteGuard<'_> {
+    type Target = LayerMap;
+
+    fn deref(&self) -> &LayerMap {
+        &self.0
+    }
+}
+
+impl DerefMut for LayerMapWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut LayerMap {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+/// Should layer 'a' be chosen over layer 'b' of the same kind, when both
+/// cover the key being searched? See [`LayerMap::search`].
+fn is_preferred(a: &Arc<dyn Layer>, b: &Arc<dyn Layer>) -> bool {
+    let (a_end, b_end) = (a.get_lsn_range().end, b.get_lsn_range().end);
+    a_end > b_end || (a_end == b_end && a.filename() < b.filename())
 }
 
 /// Return value of LayerMap::search
@@ -81,11 +160,21 @@ impl LayerMap {
     /// contain the version, even if it's missing from the returned
     /// layer.
     ///
+    /// Normally, there is only one layer that fits, but there can be
+    /// overlapping candidates, e.g. if a compaction was interrupted. The
+    /// choice between them doesn't depend on the order the layers were
+    /// inserted in:
+    ///
+    /// 1. An image layer is preferred over a delta layer that doesn't
+    ///    contain anything newer than the image.
+    /// 2. Otherwise the layer with the higher end LSN wins.
+    /// 3. Between layers with the same end LSN, the one whose filename
+    ///    sorts first wins.
+    ///
     pub fn search(&self, key: Key, end_lsn: Lsn) -> Result<Option<SearchResult>> {
         // linear search
         // Find the latest image layer that covers the given key
-        let mut latest_img: Option<Arc<dyn Layer>> = None;
-        let mut latest_img_lsn: Option<Lsn> = None;
+        let mut latest_img: Option<&Arc<dyn Layer>> = None;
         for l in self.historic_layers.iter() {
             if l.is_incremental() {
                 continue;
@@ -93,27 +182,17 @@ impl LayerMap {
             if !l.get_key_range().contains(&key) {
                 continue;
             }
-            let img_lsn = l.get_lsn_range().start;
-
-            if img_lsn >= end_lsn {
+            if l.get_lsn_range().start >= end_lsn {
                 // too new
                 continue;
             }
-            if Lsn(img_lsn.0 + 1) == end_lsn {
-                // found exact match
-                return Ok(Some(SearchResult {
-                    layer: Arc::clone(l),
-                    lsn_floor: img_lsn,
-                }));
-            }
-            if img_lsn > latest_img_lsn.unwrap_or(Lsn(0)) {
-                latest_img = Some(Arc::clone(l));
-                latest_img_lsn = Some(img_lsn);
+            if latest_img.map_or(true, |best| is_preferred(l, best)) {
+                latest_img = Some(l);
             }
         }
 
         // Search the delta layers
-        let mut latest_delta: Option<Arc<dyn Layer>> = None;
+        let mut latest_delta: Option<&Arc<dyn Layer>> = None;
         for l in self.historic_layers.iter() {
             if !l.is_incremental() {
                 continue;
@@ -121,50 +200,41 @@ impl LayerMap {
             if !l.get_key_range().contains(&key) {
                 continue;
             }
-
             if l.get_lsn_range().start >= end_lsn {
                 // too new
                 continue;
             }
-
-            if l.get_lsn_range().end >= end_lsn {
-                // this layer contains the requested point in the key/lsn space.
-                // No need to search any further
-                trace!(
-                    "found layer {} for request on {key} at {end_lsn}",
-                    l.filename().display(),
-                );
-                latest_delta.replace(Arc::clone(l));
-                break;
+            if latest_delta.map_or(true, |best| is_preferred(l, best)) {
+                latest_delta = Some(l);
             }
-            // this layer's end LSN is smaller than the requested point. If there's
-            // nothing newer, this is what we need to return. Remember this.
-            if let Some(old_candidate) = &latest_delta {
-                if l.get_lsn_range().end > old_candidate.get_lsn_range().end {
-                    latest_delta.replace(Arc::clone(l));
-                }
-            } else {
-                latest_delta.replace(Arc::clone(l));
+        }
+
+        // If the image layer has everything that the delta layer has, below
+        // 'end_lsn', use the image.
+        if let (Some(img), Some(delta)) = (latest_img, latest_delta) {
+            if img.get_lsn_range().end >= min(delta.get_lsn_range().end, end_lsn) {
+                latest_delta = None;
             }
         }
+
         if let Some(l) = latest_delta {
             trace!(
-                "found (old) layer {} for request on {key} at {end_lsn}",
+                "found layer {} for request on {key} at {end_lsn}",
                 l.filename().display(),
             );
-            let lsn_floor = std::cmp::max(
-                Lsn(latest_img_lsn.unwrap_or(Lsn(0)).0 + 1),
-                l.get_lsn_range().start,
-            );
+            let lsn_floor = match latest_img {
+                Some(img) => max(img.get_lsn_range().end, l.get_lsn_range().start),
+                None => l.get_lsn_range().start,
+            };
             Ok(Some(SearchResult {
                 lsn_floor,
-                layer: l,
+                layer: Arc::clone(l),
             }))
         } else if let Some(l) = latest_img {
             trace!("found img layer and no deltas for request on {key} at {end_lsn}");
             Ok(Some(SearchResult {
-                lsn_floor: latest_img_lsn.unwrap(),
-                layer: l,
+                lsn_floor: l.get_lsn_range().start,
+                layer: Arc::clone(l),
             }))
         } else {
             trace!("no layer found for request on {key} at {end_lsn}");
@@ -176,7 +246,14 @@ impl LayerMap {
     /// Insert an on-disk layer
     ///
     pub fn insert_historic(&mut self, layer: Arc<dyn Layer>) {
+        self.historic_lsn_end = max(self.historic_lsn_end, layer.get_lsn_range().end);
+        let key_range = layer.get_key_range();
+        *self
+            .key_coverage
+            .entry((key_range.start, key_range.end))
+            .or_default() += 1;
         self.historic_layers.push(layer);
+        self.generation += 1;
         NUM_ONDISK_LAYERS.inc();
     }
 
@@ -197,9 +274,103 @@ impl LayerMap {
             .retain(|other| !Arc::ptr_eq(other, &layer));
 
         assert_eq!(self.historic_layers.len(), len_before - 1);
+        let key_range = layer.get_key_range();
+        let coverage_key = (key_range.start, key_range.end);
+        let count = self.key_coverage.get_mut(&coverage_key).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.key_coverage.remove(&coverage_key);
+        }
+        self.generation += 1;
         NUM_ONDISK_LAYERS.dec();
     }
 
+    ///
+    /// Replace an on-disk layer with a rebuilt version of it, e.g. one that
+    /// was rewritten with a different compression.
+    ///
+    /// The new layer must be of the same kind and cover exactly the same key
+    /// and LSN range as the old one, so the search results don't change
+    /// other than pointing to the new layer. The swap happens in one step,
+    /// so readers of the map see either the old or the new layer, and
+    /// never a gap. Snapshots taken before the swap keep using the old
+    /// layer. Like with remove_historic(), its file must not be deleted
+    /// while they might still read it, the caller should hand it over to
+    /// the timeline's doomed layers.
+    ///
+    /// Layer file names are derived from the key and LSN ranges, so the
+    /// rebuilt layer has the same file name as the old one. It cannot be
+    /// stored in the old file, which is still read through the snapshots and
+    /// is deleted with the old layer, so a layer with the same local path is
+    /// refused. The new layer has to be kept in a file elsewhere, e.g. opened
+    /// with DeltaLayer::new_for_path(). Such a file is not found when the
+    /// layer map is loaded again, so until the file names can tell rebuilt
+    /// layers apart, the replacement only lasts until the tenant is reloaded.
+    ///
+    pub fn replace_historic(&mut self, old: &Arc<dyn Layer>, new: Arc<dyn Layer>) -> Result<()> {
+        ensure!(
+            !new.is_in_memory(),
+            "cannot replace layer {} with an in-memory layer",
+            old.filename().display()
+        );
+        ensure!(
+            new.is_incremental() == old.is_incremental()
+                && range_eq(&new.get_key_range(), &old.get_key_range())
+                && range_eq(&new.get_lsn_range(), &old.get_lsn_range()),
+            "layer {} doesn't cover the same range as layer {}",
+            new.filename().display(),
+            old.filename().display()
+        );
+        ensure!(
+            new.local_path().is_none() || new.local_path() != old.local_path(),
+            "cannot replace layer {} with a layer in the same file",
+            old.filename().display()
+        );
+
+        // See remove_historic() about comparing 'dyn' pointers
+        #[allow(clippy::vtable_address_comparisons)]
+        let slot = self
+            .historic_layers
+            .iter_mut()
+            .find(|other| Arc::ptr_eq(other, old));
+        match slot {
+            Some(slot) => *slot = new,
+            None => bail!("layer {} is not in the layer map", old.filename().display()),
+        }
+        // The key coverage and LSN bounds stay the same
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Changes whenever the set of historic layers changes.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// No historic layer has any data at or after the returned LSN.
+    pub fn historic_lsn_end(&self) -> Lsn {
+        self.historic_lsn_end
+    }
+
+    ///
+    /// The key ranges that are covered by at least one historic layer, in
+    /// key order and merged where they overlap or touch.
+    ///
+    /// This is a coarse summary: a layer covers its whole key range, even if
+    /// it only has a few keys in it. A key outside the returned ranges has
+    /// no data in any historic layer, at any LSN.
+    ///
+    pub fn covered_ranges(&self) -> Vec<Range<Key>> {
+        let mut result: Vec<Range<Key>> = Vec::new();
+        for (start, end) in self.key_coverage.keys() {
+            match result.last_mut() {
+                Some(last) if *start <= last.end => last.end = max(last.end, *end),
+                _ => result.push(*start..*end),
+            }
+        }
+        result
+    }
+
     /// Is there a newer image layer for given key- and LSN-range?
     ///
     /// This is used for garbage collection, to determine if an old layer can
@@ -244,7 +415,7 @@ impl LayerMap {
 
     /// Find the last image layer that covers 'key', ignoring any image layers
     /// newer than 'lsn'.
-    fn find_latest_image(&self, key: Key, lsn: Lsn) -> Option<Arc<dyn Layer>> {
+    pub fn find_latest_image(&self, key: Key, lsn: Lsn) -> Option<Arc<dyn Layer>> {
         let mut candidate_lsn = Lsn(0);
         let mut candidate = None;
         for l in self.historic_layers.iter() {
@@ -382,4 +553,417 @@ impl LayerMap {
         println!("End dump LayerMap");
         Ok(())
     }
+
+    /// Like [`LayerMap::dump`], but returns the state of the map as JSON.
+    /// The contents of the in-memory layers are included, the historic layers
+    /// are only listed.
+    pub fn dump_json(&self) -> Result<serde_json::Value> {
+        let open_layer = match &self.open_layer {
+            Some(open_layer) => open_layer.dump_json()?,
+            None => serde_json::Value::Null,
+        };
+        let frozen_layers = self
+            .frozen_layers
+            .iter()
+            .map(|frozen_layer| frozen_layer.dump_json())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut historic_layers: Vec<_> = self.historic_layers.iter().collect();
+        historic_layers.sort_by_key(|layer| layer.filename());
+        let historic_layers: Vec<_> = historic_layers
+            .into_iter()
+            .map(|layer| {
+                let key_range = layer.get_key_range();
+                let lsn_range = layer.get_lsn_range();
+                json!({
+                    "filename": layer.filename().display().to_string(),
+                    "key_start": key_range.start.to_string(),
+                    "key_end": key_range.end.to_string(),
+                    "lsn_start": lsn_range.start.to_string(),
+                    "lsn_end": lsn_range.end.to_string(),
+                    "is_incremental": layer.is_incremental(),
+                })
+            })
+            .collect();
+
+        Ok(json!({
+            "open_layer": open_layer,
+            "next_open_layer_at": self.next_open_layer_at.map(|lsn| lsn.to_string()),
+            "frozen_layers": frozen_layers,
+            "historic_layers": historic_layers,
+        }))
+    }
+
+    #[test]
+    fn generation_changes_on_modification() {
+        let mut layer_map = LayerMap::default();
+        let generation = layer_map.generation();
+
+        let layer = delta("a", 10..20);
+        layer_map.insert_historic(Arc::clone(&layer));
+        let after_insert = layer_map.generation();
+        assert_ne!(after_insert, generation);
+        assert_eq!(layer_map.historic_lsn_end(), Lsn(20));
+
+        layer_map.remove_historic(layer);
+        assert_ne!(layer_map.generation(), after_insert);
+        assert_ne!(layer_map.generation(), generation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layered_repository::delta_layer::{DeltaLayer, DeltaLayerWriter};
+    use crate::layered_repository::storage_layer::{ValueReconstructResult, ValueReconstructState};
+    use crate::repository::repo_harness::*;
+    use crate::repository::Value;
+    use bytes::Bytes;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use utils::zid::{ZTenantId, ZTimelineId};
+
+    /// A layer that only has a key/LSN rectangle and a name, for testing the
+    /// layer selection.
+    struct MockLayer {
+        name: &'static str,
+        key_range: Range<Key>,
+        lsn_range: Range<Lsn>,
+        incremental: bool,
+    }
+
+    impl Layer for MockLayer {
+        fn get_tenant_id(&self) -> ZTenantId {
+            ZTenantId::from([0; 16])
+        }
+        fn get_timeline_id(&self) -> ZTimelineId {
+            ZTimelineId::from([0; 16])
+        }
+        fn get_key_range(&self) -> Range<Key> {
+            self.key_range.clone()
+        }
+        fn get_lsn_range(&self) -> Range<Lsn> {
+            self.lsn_range.clone()
+        }
+        fn filename(&self) -> PathBuf {
+            PathBuf::from(self.name)
+        }
+        fn local_path(&self) -> Option<PathBuf> {
+            None
+        }
+        /// Returns the name of the layer as the page image
+        fn get_value_reconstruct_data(
+            &self,
+            _key: Key,
+            _lsn_range: Range<Lsn>,
+            reconstruct_data: &mut ValueReconstructState,
+        ) -> Result<ValueReconstructResult> {
+            reconstruct_data.img = Some((self.lsn_range.start, Bytes::from(self.name)));
+            Ok(ValueReconstructResult::Complete)
+        }
+        fn is_incremental(&self) -> bool {
+            self.incremental
+        }
+        fn is_in_memory(&self) -> bool {
+            false
+        }
+        /// The layer has no contents besides its name
+        fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + '_> {
+            Box::new(std::iter::empty())
+        }
+        fn delete(&self) -> Result<()> {
+            Ok(())
+        }
+        fn dump(&self, _verbose: bool) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn delta(name: &'static str, lsn_range: Range<u64>) -> Arc<dyn Layer> {
+        Arc::new(MockLayer {
+            name,
+            key_range: Key::MIN..Key::MAX,
+            lsn_range: Lsn(lsn_range.start)..Lsn(lsn_range.end),
+            incremental: true,
+        })
+    }
+
+    fn image(name: &'static str, lsn: u64) -> Arc<dyn Layer> {
+        Arc::new(MockLayer {
+            name,
+            key_range: Key::MIN..Key::MAX,
+            lsn_range: Lsn(lsn)..Lsn(lsn + 1),
+            incremental: false,
+        })
+    }
+
+    /// Search with the layers inserted in different orders, check that the
+    /// result is always the same, and return the filename of the chosen layer.
+    fn search_in_any_order(layers: &[Arc<dyn Layer>], end_lsn: u64) -> Result<String> {
+        let key = Key::from_hex("000000000000000000000000000000000001")?;
+        let mut chosen = None;
+        for rotation in 0..layers.len() {
+            for reverse in [false, true] {
+                let mut order = layers.to_vec();
+                order.rotate_left(rotation);
+                if reverse {
+                    order.reverse();
+                }
+                let mut layer_map = LayerMap::default();
+                for layer in order {
+                    layer_map.insert_historic(layer);
+                }
+                let result = layer_map.search(key, Lsn(end_lsn))?.unwrap();
+                let name = result.layer.filename().display().to_string();
+                match &chosen {
+                    None => chosen = Some(name),
+                    Some(chosen) => assert_eq!(chosen, &name),
+                }
+            }
+        }
+        Ok(chosen.unwrap())
+    }
+
+    #[test]
+    fn search_overlapping_layers() -> Result<()> {
+        // Deltas with the same LSN range: the first filename wins
+        let layers = [delta("b", 10..20), delta("a", 10..20), delta("c", 10..20)];
+        assert_eq!(search_in_any_order(&layers, 15)?, "a");
+        assert_eq!(search_in_any_order(&layers, 25)?, "a");
+
+        // The delta with the higher end LSN wins
+        let layers = [delta("a", 10..20), delta("b", 5..30)];
+        assert_eq!(search_in_any_order(&layers, 40)?, "b");
+        assert_eq!(search_in_any_order(&layers, 15)?, "b");
+
+        // An image is preferred over a delta with nothing newer than the image
+        let layers = [delta("a", 10..20), image("b", 19)];
+        assert_eq!(search_in_any_order(&layers, 40)?, "b");
+        let layers = [delta("a", 10..20), image("b", 15), image("c", 15)];
+        assert_eq!(search_in_any_order(&layers, 16)?, "b");
+        assert_eq!(search_in_any_order(&layers, 17)?, "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn generation_changes_on_modification() {
+        let mut layer_map = LayerMap::default();
+        let generation = layer_map.generation();
+
+        let layer = delta("a", 10..20);
+        layer_map.insert_historic(Arc::clone(&layer));
+        let after_insert = layer_map.generation();
+        assert_ne!(after_insert, generation);
+        assert_eq!(layer_map.historic_lsn_end(), Lsn(20));
+
+        layer_map.remove_historic(layer);
+        assert_ne!(layer_map.generation(), after_insert);
+        assert_ne!(layer_map.generation(), generation);
+    }
+
+    #[test]
+    fn covered_ranges_follow_inserts_and_removes() -> Result<()> {
+        let key = |n: u8| Key::from_hex(&format!("0000000000000000000000000000000000{n:02X}"));
+        let layer = |start: u8, end: u8| -> Result<Arc<dyn Layer>> {
+            Ok(Arc::new(MockLayer {
+                name: "layer",
+                key_range: key(start)?..key(end)?,
+                lsn_range: Lsn(10)..Lsn(20),
+                incremental: true,
+            }))
+        };
+        let mut layer_map = LayerMap::default();
+        assert!(layer_map.covered_ranges().is_empty());
+
+        let a = layer(0x10, 0x20)?;
+        let b = layer(0x18, 0x30)?;
+        let c = layer(0x30, 0x40)?;
+        let d = layer(0x50, 0x60)?;
+        let d_again = layer(0x50, 0x60)?;
+        for l in [&a, &b, &c, &d, &d_again] {
+            layer_map.insert_historic(Arc::clone(l));
+        }
+        // Overlapping and adjacent ranges are merged
+        assert_eq!(
+            layer_map.covered_ranges(),
+            vec![key(0x10)?..key(0x40)?, key(0x50)?..key(0x60)?]
+        );
+
+        layer_map.remove_historic(b);
+        assert_eq!(
+            layer_map.covered_ranges(),
+            vec![
+                key(0x10)?..key(0x20)?,
+                key(0x30)?..key(0x40)?,
+                key(0x50)?..key(0x60)?
+            ]
+        );
+
+        // The range stays covered as long as one of its layers remains
+        layer_map.remove_historic(d);
+        assert_eq!(
+            layer_map.covered_ranges().last(),
+            Some(&(key(0x50)?..key(0x60)?))
+        );
+        layer_map.remove_historic(d_again);
+        assert_eq!(
+            layer_map.covered_ranges().last(),
+            Some(&(key(0x30)?..key(0x40)?))
+        );
+
+        Ok(())
+    }
+
+    /// Read 'key' at 'end_lsn' from the layer that the map returns for it.
+    fn read_page(layer_map: &LayerMap, key: Key, end_lsn: Lsn) -> Result<Option<Bytes>> {
+        let mut state = ValueReconstructState {
+            records: Vec::new(),
+            img: None,
+        };
+        match layer_map.search(key, end_lsn)? {
+            Some(result) => {
+                result.layer.get_value_reconstruct_data(
+                    key,
+                    result.lsn_floor..end_lsn,
+                    &mut state,
+                )?;
+                Ok(state.img.map(|(_, img)| img))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[test]
+    fn replace_historic_layer() -> Result<()> {
+        let key = Key::from_hex("000000000000000000000000000000000001")?;
+        let layers = VersionedLayerMap::default();
+        let old = delta("old", 10..20);
+        layers.write().unwrap().insert_historic(Arc::clone(&old));
+        let generation = layers.read().unwrap().generation();
+
+        // The ranges must match
+        let mut layer_map = layers.write().unwrap();
+        assert!(layer_map
+            .replace_historic(&old, delta("shorter", 10..15))
+            .is_err());
+        assert!(layer_map
+            .replace_historic(&old, image("image", 10))
+            .is_err());
+        assert!(layer_map
+            .replace_historic(&delta("missing", 10..20), delta("new", 10..20))
+            .is_err());
+        drop(layer_map);
+        assert_eq!(layers.read().unwrap().generation(), generation);
+
+        // A reader that took a snapshot before the swap keeps reading the old
+        // layer. Readers of the current map see the old layer or the new one,
+        // never nothing.
+        let snapshot = layers.snapshot();
+        let new = delta("new", 10..20);
+        crossbeam_utils::thread::scope(|s| -> Result<()> {
+            let layers = &layers;
+            let reader = s.spawn(move |_| -> Result<()> {
+                for _ in 0..1000 {
+                    let page = read_page(&layers.read().unwrap(), key, Lsn(20))?;
+                    assert!(matches!(page.as_deref(), Some(b"old" | b"new")));
+                }
+                Ok(())
+            });
+            layers
+                .write()
+                .unwrap()
+                .replace_historic(&old, Arc::clone(&new))?;
+            reader.join().unwrap()
+        })
+        .unwrap()?;
+
+        assert_eq!(
+            read_page(&snapshot, key, Lsn(20))?.as_deref(),
+            Some(&b"old"[..])
+        );
+        let current = layers.snapshot();
+        assert_eq!(
+            read_page(&current, key, Lsn(20))?.as_deref(),
+            Some(&b"new"[..])
+        );
+        assert_ne!(current.generation(), generation);
+        assert_eq!(current.iter_historic_layers().count(), 1);
+        assert_eq!(current.covered_ranges(), snapshot.covered_ranges());
+
+        // The old layer is only referenced by the snapshot and by us now,
+        // so the timeline would delete its file once the snapshot is dropped
+        drop(snapshot);
+        assert_eq!(Arc::strong_count(&old), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replace_historic_layer_on_disk() -> Result<()> {
+        let harness = RepoHarness::create("replace_historic_layer_on_disk")?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        fs::create_dir_all(&timeline_path)?;
+        let key = Key::from_hex("010000000033333333444444445500000001")?;
+        let write_layer = |img: &str| -> Result<DeltaLayer> {
+            let mut writer = DeltaLayerWriter::new(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+                key,
+                Lsn(0x10)..Lsn(0x20),
+            )?;
+            writer.put_value(key, Lsn(0x10), Value::Image(TEST_IMG(img)))?;
+            writer.finish(key.next())
+        };
+
+        // The rebuilt layer is written first, and moved out of the way of
+        // the old layer, which has the same file name
+        let rebuilt = write_layer("new")?;
+        let rebuilt_dir = timeline_path.join("rebuilt");
+        fs::create_dir_all(&rebuilt_dir)?;
+        let rebuilt_path = rebuilt_dir.join(rebuilt.filename());
+        fs::rename(rebuilt.path(), &rebuilt_path)?;
+        let new: Arc<dyn Layer> = Arc::new(DeltaLayer::new_for_path(
+            &rebuilt_path,
+            File::open(&rebuilt_path)?,
+        )?);
+        let old: Arc<dyn Layer> = Arc::new(write_layer("old")?);
+        let old_path = old.local_path().unwrap();
+        assert_eq!(new.filename(), old.filename());
+
+        let layers = VersionedLayerMap::default();
+        layers.write().unwrap().insert_historic(Arc::clone(&old));
+
+        // A layer in the old layer's file is refused
+        let same_file: Arc<dyn Layer> =
+            Arc::new(DeltaLayer::new_for_path(&old_path, File::open(&old_path)?)?);
+        assert!(layers
+            .write()
+            .unwrap()
+            .replace_historic(&old, same_file)
+            .is_err());
+
+        let snapshot = layers.snapshot();
+        layers
+            .write()
+            .unwrap()
+            .replace_historic(&old, Arc::clone(&new))?;
+        assert_eq!(read_page(&snapshot, key, Lsn(0x20))?, Some(TEST_IMG("old")));
+        assert_eq!(
+            read_page(&layers.read().unwrap(), key, Lsn(0x20))?,
+            Some(TEST_IMG("new"))
+        );
+
+        // Deleting the old layer once the snapshot is gone leaves the new one
+        drop(snapshot);
+        old.delete()?;
+        assert!(!old_path.exists());
+        assert_eq!(
+            read_page(&layers.read().unwrap(), key, Lsn(0x20))?,
+            Some(TEST_IMG("new"))
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,199 @@
+//!
+//! Streaming format for copying the files of a timeline directly from one
+//! pageserver to another, without going through remote storage.
+//!
+//! The stream starts with a header, followed by one frame per file, and an
+//! end marker:
+//!
+//! ```text
+//! header:     magic "NEONLAYR", format version (u32)
+//! file frame: FRAME_FILE (u8), name length (u16), name,
+//!             file length (u64), CRC32C of the contents (u32), contents
+//! end:        FRAME_END (u8)
+//! ```
+//!
+//! All integers are big-endian. The metadata file is sent as the last file.
+//! On import, it is written only after all the layer files are durable, so
+//! that the timeline doesn't become loadable with layers missing.
+//!
+use anyhow::{bail, ensure, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::filename::{DeltaFileName, ImageFileName};
+use super::metadata::{METADATA_FILE_NAME, METADATA_MAX_SIZE};
+
+const MAGIC: &[u8; 8] = b"NEONLAYR";
+const FORMAT_VERSION: u32 = 1;
+
+const FRAME_END: u8 = 0;
+const FRAME_FILE: u8 = 1;
+
+/// Size of the chunks that file contents are copied in.
+const COPY_BUF_SIZE: usize = 64 * 1024;
+
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_be_bytes())?;
+    Ok(())
+}
+
+pub fn write_end<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&[FRAME_END])?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the file at 'path' to the stream, under the name 'name'.
+pub fn write_file<W: Write>(writer: &mut W, name: &str, path: &Path) -> Result<()> {
+    // The checksum precedes the contents, so the file has to be read twice.
+    // Layer files are immutable, so it doesn't change in between.
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+    let mut crc = 0;
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc = crc32c::crc32c_append(crc, &buf[..n]);
+    }
+
+    write_file_header(writer, name, file_len, crc)?;
+    let file = File::open(path)?;
+    let copied = io::copy(&mut file.take(file_len), writer)?;
+    ensure!(
+        copied == file_len,
+        "file {} changed while it was being exported",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Write a file with the given contents to the stream, under the name 'name'.
+pub fn write_file_contents<W: Write>(writer: &mut W, name: &str, contents: &[u8]) -> Result<()> {
+    write_file_header(
+        writer,
+        name,
+        contents.len() as u64,
+        crc32c::crc32c(contents),
+    )?;
+    writer.write_all(contents)?;
+    Ok(())
+}
+
+fn write_file_header<W: Write>(writer: &mut W, name: &str, file_len: u64, crc: u32) -> Result<()> {
+    let name_len = u16::try_from(name.len()).context("file name is too long")?;
+    writer.write_all(&[FRAME_FILE])?;
+    writer.write_all(&name_len.to_be_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&file_len.to_be_bytes())?;
+    writer.write_all(&crc.to_be_bytes())?;
+    Ok(())
+}
+
+/// Read a stream written with the functions above, writing the layer files
+/// into directory 'dir'. Returns the paths of the layer files written, and
+/// the contents of the metadata file, which is not written.
+///
+/// The files are not fsync'd, that's left to the caller.
+pub fn read_files<R: Read>(reader: &mut R, dir: &Path) -> Result<(Vec<PathBuf>, Vec<u8>)> {
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("failed to read header")?;
+    ensure!(&magic == MAGIC, "not a layer stream");
+    let version = u32::from_be_bytes(read_array(reader)?);
+    ensure!(
+        version == FORMAT_VERSION,
+        "unsupported layer stream version {version}"
+    );
+
+    let mut paths = Vec::new();
+    let mut metadata = None;
+    loop {
+        let [frame_type] = read_array(reader)?;
+        match frame_type {
+            FRAME_END => break,
+            FRAME_FILE => {}
+            _ => bail!("unexpected frame type {frame_type}"),
+        }
+
+        let name_len = u16::from_be_bytes(read_array(reader)?);
+        let mut name = vec![0; name_len as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).context("file name is not valid UTF-8")?;
+        // Only accept the files of a timeline. This also keeps the stream
+        // from writing outside the directory.
+        ensure!(
+            name == METADATA_FILE_NAME
+                || DeltaFileName::parse_str(&name).is_some()
+                || ImageFileName::parse_str(&name).is_some(),
+            "invalid file name {name:?}"
+        );
+        ensure!(metadata.is_none(), "file {name} after the metadata file");
+
+        let file_len = u64::from_be_bytes(read_array(reader)?);
+        let expected_crc = u32::from_be_bytes(read_array(reader)?);
+
+        if name == METADATA_FILE_NAME {
+            // The length comes from the stream, don't trust it for the allocation
+            ensure!(
+                file_len <= METADATA_MAX_SIZE as u64,
+                "metadata file is too large: {file_len} bytes"
+            );
+            let mut contents = Vec::with_capacity(file_len as usize);
+            copy_checked(reader, &mut contents, file_len, expected_crc, &name)?;
+            metadata = Some(contents);
+        } else {
+            let path = dir.join(&name);
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            copy_checked(reader, &mut file, file_len, expected_crc, &name)?;
+            paths.push(path);
+        }
+    }
+
+    let metadata = metadata.context("no metadata file in the stream")?;
+    Ok((paths, metadata))
+}
+
+/// Copy 'len' bytes of file 'name' from the stream, and check that their
+/// checksum matches.
+fn copy_checked<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    expected_crc: u32,
+    name: &str,
+) -> Result<()> {
+    let mut remaining = len;
+    let mut crc = 0;
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    while remaining > 0 {
+        let n = remaining.min(buf.len() as u64) as usize;
+        reader
+            .read_exact(&mut buf[..n])
+            .with_context(|| format!("stream ended in the middle of file {name}"))?;
+        crc = crc32c::crc32c_append(crc, &buf[..n]);
+        writer.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+    ensure!(
+        crc == expected_crc,
+        "checksum mismatch in file {name}: expected {expected_crc:08X}, got {crc:08X}"
+    );
+    Ok(())
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
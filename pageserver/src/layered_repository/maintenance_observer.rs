@@ -0,0 +1,97 @@
+//!
+//! Hooks for observing the changes that compaction and GC make to the layers
+//! of a timeline.
+//!
+//! Each tenant has one MaintenanceObserver, shared by all its timelines. It
+//! is told about every layer that compaction creates or removes, every image
+//! layer that is created, and every layer that GC removes, so that external
+//! tooling can follow the life cycle of the layers without parsing the logs.
+//! The default observer ignores everything.
+//!
+//! The observer is called on the flush, compaction and GC threads, after the
+//! layer map has been updated. It must not block for long.
+//!
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use utils::lsn::Lsn;
+use utils::zid::ZTenantTimelineId;
+
+use crate::layered_repository::storage_layer::Layer;
+use crate::repository::Key;
+
+/// Identifies a layer in the events passed to a [`MaintenanceObserver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerDescriptor {
+    pub filename: PathBuf,
+    pub key_range: Range<Key>,
+    pub lsn_range: Range<Lsn>,
+    /// True for delta layers, false for image layers
+    pub is_incremental: bool,
+}
+
+impl LayerDescriptor {
+    pub(crate) fn of(layer: &dyn Layer) -> Self {
+        LayerDescriptor {
+            filename: layer.filename(),
+            key_range: layer.get_key_range(),
+            lsn_range: layer.get_lsn_range(),
+            is_incremental: layer.is_incremental(),
+        }
+    }
+}
+
+/// Why layers were removed, see [`MaintenanceObserver::on_layers_removed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerRemovalCause {
+    /// The layers were compacted into the layers of the preceding
+    /// on_layers_created() call.
+    Compaction,
+    /// Garbage collection with the given cutoff LSN.
+    Gc { cutoff: Lsn },
+}
+
+pub trait MaintenanceObserver: Send + Sync {
+    /// Compaction created new delta layers.
+    fn on_layers_created(&self, _timeline: ZTenantTimelineId, _layers: &[LayerDescriptor]) {}
+
+    /// Compaction or GC removed layers from the timeline.
+    fn on_layers_removed(
+        &self,
+        _timeline: ZTenantTimelineId,
+        _layers: &[LayerDescriptor],
+        _cause: LayerRemovalCause,
+    ) {
+    }
+
+    /// An image layer of the timeline at 'lsn' was created.
+    fn on_image_created(&self, _timeline: ZTenantTimelineId, _layer: &LayerDescriptor, _lsn: Lsn) {}
+}
+
+/// The default observer, which ignores all events.
+pub struct NoopMaintenanceObserver;
+
+impl MaintenanceObserver for NoopMaintenanceObserver {}
+
+///
+/// Holds the observer of a tenant. The timelines keep a reference to this,
+/// so that the observer can be replaced after they've been loaded.
+///
+pub struct ObserverSlot(RwLock<Arc<dyn MaintenanceObserver>>);
+
+impl Default for ObserverSlot {
+    fn default() -> Self {
+        ObserverSlot(RwLock::new(Arc::new(NoopMaintenanceObserver)))
+    }
+}
+
+impl ObserverSlot {
+    pub fn get(&self) -> Arc<dyn MaintenanceObserver> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    pub fn set(&self, observer: Arc<dyn MaintenanceObserver>) {
+        *self.0.write().unwrap() = observer;
+    }
+}
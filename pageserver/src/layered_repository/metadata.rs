@@ -23,7 +23,7 @@ use crate::STORAGE_FORMAT_VERSION;
 ///
 /// This is the same assumption that PostgreSQL makes with the control file,
 /// see PG_CONTROL_MAX_SAFE_SIZE
-const METADATA_MAX_SIZE: usize = 512;
+pub const METADATA_MAX_SIZE: usize = 512;
 
 /// The name of the metadata file pageserver creates per timeline.
 pub const METADATA_FILE_NAME: &str = "metadata";
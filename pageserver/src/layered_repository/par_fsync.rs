@@ -11,39 +11,52 @@ fn fsync_path(path: &Path) -> io::Result<()> {
     file.sync_all()
 }
 
-fn parallel_worker(paths: &[PathBuf], next_path_idx: &AtomicUsize) -> io::Result<()> {
+fn parallel_worker<F>(paths: &[PathBuf], next_path_idx: &AtomicUsize, f: &F) -> io::Result<()>
+where
+    F: Fn(&Path) -> io::Result<()>,
+{
     while let Some(path) = paths.get(next_path_idx.fetch_add(1, Ordering::Relaxed)) {
-        fsync_path(path)?;
+        f(path)?;
     }
 
     Ok(())
 }
 
-pub fn par_fsync(paths: &[PathBuf]) -> io::Result<()> {
+/// Fsync all the given paths, using at most `max_parallelism` threads
+/// (including the current one).
+///
+/// Using more threads will
+/// - use more memory
+/// - increase the cost of spawn/join latency
+///
+/// so the bound is configured with `PageServerConf::max_fsync_parallelism`.
+pub fn par_fsync(paths: &[PathBuf], max_parallelism: usize) -> io::Result<()> {
+    par_for_each_path(paths, max_parallelism, fsync_path)
+}
+
+fn par_for_each_path<F>(paths: &[PathBuf], max_parallelism: usize, f: F) -> io::Result<()>
+where
+    F: Fn(&Path) -> io::Result<()> + Sync,
+{
     const PARALLEL_PATH_THRESHOLD: usize = 1;
-    if paths.len() <= PARALLEL_PATH_THRESHOLD {
+    if paths.len() <= PARALLEL_PATH_THRESHOLD || max_parallelism <= 1 {
         for path in paths {
-            fsync_path(path)?;
+            f(path)?;
         }
         return Ok(());
     }
 
-    /// Use at most this number of threads.
-    /// Increasing this limit will
-    /// - use more memory
-    /// - increase the cost of spawn/join latency
-    const MAX_NUM_THREADS: usize = 64;
-    let num_threads = paths.len().min(MAX_NUM_THREADS);
+    let num_threads = paths.len().min(max_parallelism);
     let next_path_idx = AtomicUsize::new(0);
 
     crossbeam_utils::thread::scope(|s| -> io::Result<()> {
         let mut handles = vec![];
         // Spawn `num_threads - 1`, as the current thread is also a worker.
         for _ in 1..num_threads {
-            handles.push(s.spawn(|_| parallel_worker(paths, &next_path_idx)));
+            handles.push(s.spawn(|_| parallel_worker(paths, &next_path_idx, &f)));
         }
 
-        parallel_worker(paths, &next_path_idx)?;
+        parallel_worker(paths, &next_path_idx, &f)?;
 
         for handle in handles {
             handle.join().unwrap()?;
@@ -53,3 +66,60 @@ pub fn par_fsync(paths: &[PathBuf]) -> io::Result<()> {
     })
     .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    #[test]
+    fn par_fsync_many_files() -> io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let paths = (0..200)
+            .map(|i| {
+                let path = tempdir.path().join(format!("file_{i}"));
+                File::create(&path)?;
+                Ok(path)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        par_fsync(&paths, 4)?;
+        par_fsync(&paths, 1)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn parallelism_is_bounded() -> io::Result<()> {
+        const MAX_PARALLELISM: usize = 4;
+        let paths = (0..200)
+            .map(|i| PathBuf::from(format!("file_{i}")))
+            .collect::<Vec<_>>();
+
+        let active = AtomicUsize::new(0);
+        let peak_active = AtomicUsize::new(0);
+        let workers = Mutex::new(Vec::<ThreadId>::new());
+
+        par_for_each_path(&paths, MAX_PARALLELISM, |_| {
+            let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak_active.fetch_max(now_active, Ordering::SeqCst);
+            {
+                let mut workers = workers.lock().unwrap();
+                if !workers.contains(&thread::current().id()) {
+                    workers.push(thread::current().id());
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+            active.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })?;
+
+        assert!(peak_active.load(Ordering::SeqCst) <= MAX_PARALLELISM);
+        assert!(workers.into_inner().unwrap().len() <= MAX_PARALLELISM);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//!
+//! Remembers the prev-record LSN at each point where a timeline was flushed
+//! to disk.
+//!
+//! The metadata file only stores the prev-record LSN of the latest
+//! 'disk_consistent_lsn', and only if all the WAL up to it was flushed. A read
+//! replica that starts from an older flushed LSN also needs the LSN of the
+//! record preceding it, to fill in 'xl_prev'. The open in-memory layer is
+//! frozen at a record boundary, so the prev-record LSN is known at that point.
+//! It's kept in memory until the frozen layer has been flushed, and then
+//! appended to a small file next to the layer files.
+//!
+//! The file is a sequence of 16-byte entries, each holding a flushed LSN and
+//! its prev-record LSN, big-endian. An entry is appended before the metadata
+//! file is updated, so after a crash, the file can contain entries above
+//! 'disk_consistent_lsn', or a torn entry at the end. Those are dropped when
+//! the file is loaded. The file is not uploaded to remote storage.
+//!
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::*;
+use utils::lsn::Lsn;
+
+pub const PREV_RECORD_LSNS_FILE_NAME: &str = "prev_record_lsns";
+
+/// Size of an entry in the file: the flushed LSN and its prev-record LSN.
+const ENTRY_SIZE: usize = 16;
+
+pub struct PrevRecordLsns {
+    path: PathBuf,
+    /// Prev-record LSNs of frozen layers that haven't been flushed yet, by the
+    /// LSN of the last record in the layer.
+    pending: BTreeMap<Lsn, Lsn>,
+    /// The entries in the file.
+    flushed: BTreeMap<Lsn, Lsn>,
+}
+
+impl PrevRecordLsns {
+    pub fn new(timeline_path: &Path) -> Self {
+        PrevRecordLsns {
+            path: timeline_path.join(PREV_RECORD_LSNS_FILE_NAME),
+            pending: BTreeMap::new(),
+            flushed: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// Read the entries from the file. Entries above 'disk_consistent_lsn',
+    /// and a torn entry at the end, are removed from the file.
+    ///
+    pub fn load(&mut self, disk_consistent_lsn: Lsn) -> Result<()> {
+        self.pending.clear();
+        self.flushed.clear();
+
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", self.path.display()))
+            }
+        };
+        for entry in contents.chunks_exact(ENTRY_SIZE) {
+            let lsn = Lsn(u64::from_be_bytes(entry[..8].try_into().unwrap()));
+            let prev = Lsn(u64::from_be_bytes(entry[8..].try_into().unwrap()));
+            if lsn <= disk_consistent_lsn {
+                self.flushed.insert(lsn, prev);
+            }
+        }
+        if self.flushed.len() * ENTRY_SIZE != contents.len() {
+            info!(
+                "removing entries above {} from {}",
+                disk_consistent_lsn,
+                self.path.display()
+            );
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Remember the prev-record LSN of the last record in a layer that was
+    /// just frozen.
+    pub fn freeze_at(&mut self, lsn: Lsn, prev: Lsn) {
+        self.pending.insert(lsn, prev);
+    }
+
+    ///
+    /// Append the entries up to 'disk_consistent_lsn' to the file. Must be
+    /// called after the layers up to it are durable, and before the metadata
+    /// file is updated.
+    ///
+    pub fn flush_upto(&mut self, disk_consistent_lsn: Lsn) -> Result<()> {
+        let not_flushed = self.pending.split_off(&(disk_consistent_lsn + 1));
+        let to_flush = std::mem::replace(&mut self.pending, not_flushed);
+        if to_flush.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        let res = file
+            .write_all(&serialize(&to_flush))
+            .and_then(|()| file.sync_all());
+        if let Err(e) = res {
+            // Don't leave a partial entry behind, the next ones would be
+            // misaligned.
+            let _ = file.set_len((self.flushed.len() * ENTRY_SIZE) as u64);
+            return Err(e).with_context(|| format!("failed to write {}", self.path.display()));
+        }
+        self.flushed.extend(to_flush);
+        Ok(())
+    }
+
+    /// The prev-record LSN at flushed LSN 'lsn', if it's known.
+    pub fn get(&self, lsn: Lsn) -> Option<Lsn> {
+        self.flushed.get(&lsn).copied()
+    }
+
+    /// Forget the entries above 'lsn', when the timeline is truncated.
+    pub fn truncate(&mut self, lsn: Lsn) -> Result<()> {
+        self.pending.clear();
+        let removed = self.flushed.split_off(&(lsn + 1));
+        if !removed.is_empty() {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Forget the entries below the GC cutoff. The timeline can't be read
+    /// there anymore, so a replica can't start there either.
+    pub fn remove_older_than(&mut self, cutoff: Lsn) -> Result<()> {
+        let kept = self.flushed.split_off(&cutoff);
+        let removed = std::mem::replace(&mut self.flushed, kept);
+        if !removed.is_empty() {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the file with the entries in 'flushed'.
+    fn rewrite(&self) -> Result<()> {
+        let temp_path = self.path.with_extension("temp");
+        let mut file = File::create(&temp_path)
+            .with_context(|| format!("failed to create {}", temp_path.display()))?;
+        file.write_all(&serialize(&self.flushed))?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("failed to rename {}", temp_path.display()))?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+fn serialize(entries: &BTreeMap<Lsn, Lsn>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entries.len() * ENTRY_SIZE);
+    for (lsn, prev) in entries {
+        buf.extend_from_slice(&lsn.0.to_be_bytes());
+        buf.extend_from_slice(&prev.0.to_be_bytes());
+    }
+    buf
+}
@@ -0,0 +1,161 @@
+//!
+//! Detects flush, compaction and GC threads that are stuck while holding
+//! one of the timeline's maintenance locks.
+//!
+//! A thread that hangs while holding 'layer_flush_lock' or 'layer_removal_cs',
+//! e.g. on an fsync that never returns, silently stops all flushing,
+//! compaction and GC on the timeline. These locks are WatchedMutexes, which
+//! remember which thread holds them and since when, so that a periodic check
+//! can report the locks that have been held for too long. The check only
+//! looks at the holder information, so it never blocks on the lock itself.
+//!
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::Lazy;
+
+static MAINTENANCE_STALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_maintenance_stall_total",
+        "Number of times a timeline maintenance lock was held longer than the stall threshold",
+        &["lock"]
+    )
+    .expect("failed to define a metric")
+});
+
+struct Holder {
+    thread: String,
+    since: Instant,
+    reported: bool,
+}
+
+/// A stall found by [`WatchedMutex::check_stall`].
+#[derive(Debug)]
+pub struct Stall {
+    /// Name and id of the thread holding the lock
+    pub thread: String,
+    pub held_for: Duration,
+}
+
+/// A `Mutex<()>` that keeps track of the thread holding it.
+pub struct WatchedMutex {
+    name: &'static str,
+    lock: Mutex<()>,
+    holder: Mutex<Option<Holder>>,
+}
+
+pub struct WatchedGuard<'a> {
+    mutex: &'a WatchedMutex,
+    // Released after the holder has been cleared in drop()
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl WatchedMutex {
+    pub fn new(name: &'static str) -> Self {
+        WatchedMutex {
+            name,
+            lock: Mutex::new(()),
+            holder: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn lock(&self) -> WatchedGuard<'_> {
+        let guard = self
+            .lock
+            .lock()
+            .unwrap_or_else(|e| panic!("{} is poisoned: {e}", self.name));
+        self.enter(guard)
+    }
+
+    pub fn try_lock(&self) -> Result<WatchedGuard<'_>, TryLockError<()>> {
+        match self.lock.try_lock() {
+            Ok(guard) => Ok(self.enter(guard)),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+            Err(TryLockError::Poisoned(_)) => Err(TryLockError::Poisoned(PoisonError::new(()))),
+        }
+    }
+
+    fn enter<'a>(&'a self, guard: MutexGuard<'a, ()>) -> WatchedGuard<'a> {
+        let current = thread::current();
+        *self.holder.lock().unwrap() = Some(Holder {
+            thread: format!(
+                "{} ({:?})",
+                current.name().unwrap_or("unnamed"),
+                current.id()
+            ),
+            since: Instant::now(),
+            reported: false,
+        });
+        WatchedGuard {
+            mutex: self,
+            _guard: guard,
+        }
+    }
+
+    ///
+    /// Check if the lock has been held for longer than 'threshold'.
+    ///
+    /// Each stall is reported only once, and counted in the
+    /// 'pageserver_maintenance_stall_total' metric. Returns None if the lock
+    /// is free, or the stall has already been reported.
+    ///
+    pub fn check_stall(&self, threshold: Duration) -> Option<Stall> {
+        let mut holder = self.holder.lock().unwrap();
+        let holder = holder.as_mut()?;
+        let held_for = holder.since.elapsed();
+        if holder.reported || held_for < threshold {
+            return None;
+        }
+        holder.reported = true;
+        MAINTENANCE_STALLS.with_label_values(&[self.name]).inc();
+        Some(Stall {
+            thread: holder.thread.clone(),
+            held_for,
+        })
+    }
+}
+
+impl Drop for WatchedGuard<'_> {
+    fn drop(&mut self) {
+        *self.mutex.holder.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_are_reported_once() {
+        let mutex = WatchedMutex::new("test_lock");
+        let stalls = || MAINTENANCE_STALLS.with_label_values(&["test_lock"]).get();
+
+        // A free lock never stalls
+        assert!(mutex.check_stall(Duration::ZERO).is_none());
+
+        let guard = mutex.lock();
+        assert!(mutex.check_stall(Duration::from_secs(3600)).is_none());
+        assert!(mutex.try_lock().is_err());
+
+        let stall = mutex.check_stall(Duration::ZERO).unwrap();
+        assert!(stall
+            .thread
+            .contains(&format!("{:?}", thread::current().id())));
+        assert_eq!(stalls(), 1);
+        assert!(mutex.check_stall(Duration::ZERO).is_none());
+        assert_eq!(stalls(), 1);
+
+        // Holding the lock again is a new stall
+        drop(guard);
+        assert!(mutex.check_stall(Duration::ZERO).is_none());
+        let _guard = mutex.try_lock().unwrap();
+        assert!(mutex.check_stall(Duration::ZERO).is_some());
+        assert_eq!(stalls(), 2);
+    }
+}
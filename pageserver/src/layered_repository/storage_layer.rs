@@ -8,6 +8,7 @@ use anyhow::Result;
 use bytes::Bytes;
 use std::ops::Range;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 use utils::{
     lsn::Lsn,
@@ -108,6 +109,14 @@ pub trait Layer: Send + Sync {
     /// If a layer has a corresponding file on a local filesystem, return its absolute path.
     fn local_path(&self) -> Option<PathBuf>;
 
+    /// When this layer was created, for policies that depend on the age of the
+    /// layer. For layers on local disk, this is the modification time of the
+    /// file. Returns None for in-memory layers, or if the time is not available.
+    fn created_at(&self) -> Option<SystemTime> {
+        let metadata = self.local_path()?.metadata().ok()?;
+        metadata.modified().ok()
+    }
+
     ///
     /// Return data needed to reconstruct given page at LSN.
     ///
@@ -136,6 +145,13 @@ pub trait Layer: Send + Sync {
     /// Returns true for layers that are represented in memory.
     fn is_in_memory(&self) -> bool;
 
+    /// Hint that the values in 'key_range' will be read soon, e.g. by a scan
+    /// over the range, so that the layer can start reading them ahead. This
+    /// doesn't change the result of any reads. Does nothing by default.
+    fn prefetch(&self, _key_range: &Range<Key>) -> Result<()> {
+        Ok(())
+    }
+
     /// Iterate through all keys and values stored in the layer
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + '_>;
 
@@ -145,6 +161,27 @@ pub trait Layer: Send + Sync {
         panic!("Not implemented")
     }
 
+    /// Estimate how many bytes in this layer are not needed to reconstruct
+    /// any page version at or after 'cutoff', i.e. how much garbage collection
+    /// could reclaim by rewriting the layer. This is meant to be cheap, so it
+    /// doesn't read the values themselves.
+    ///
+    /// Layers that can't make the estimate cheaply return 0. That includes
+    /// image layers, which GC can only remove as a whole.
+    fn estimate_reclaimable_bytes(&self, _cutoff: Lsn) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Check that the index of the layer file is consistent: the entries are
+    /// in order, their keys and LSNs are within the range of the layer, and
+    /// they point into the values part of the file. The values themselves are
+    /// not read. Returns an error describing the first problem found.
+    ///
+    /// Layers that aren't stored in a file have nothing to check.
+    fn validate_structure(&self) -> Result<()> {
+        Ok(())
+    }
+
     /// Permanently remove this layer from disk.
     fn delete(&self) -> Result<()>;
 
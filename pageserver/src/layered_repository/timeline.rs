@@ -7,56 +7,75 @@ use itertools::Itertools;
 use once_cell::sync::Lazy;
 use tracing::*;
 
-use std::cmp::{max, min, Ordering};
-use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::cell::RefCell;
+use std::cmp::{max, max_by_key, min, Ordering};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::ops::{Deref, Range};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{self, AtomicBool, AtomicIsize, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, TryLockError};
 use std::time::{Duration, Instant, SystemTime};
 
 use metrics::{
-    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
-    register_uint_gauge_vec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
-    IntGaugeVec, UIntGauge, UIntGaugeVec,
+    exponential_buckets, register_gauge_vec, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, register_uint_gauge_vec, Gauge, GaugeVec,
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, UIntGauge,
+    UIntGaugeVec,
 };
 
 use crate::layered_repository::{
+    access_tracker::{overlaps_hot_range, KeyAccessTracker},
+    compaction_limiter::{CompactionLimiter, CompactionPermit},
     delta_layer::{DeltaLayer, DeltaLayerWriter},
+    disk_space::{self, DiskSpaceLow},
     ephemeral_file::is_ephemeral_file,
     filename::{DeltaFileName, ImageFileName},
+    get_rate_limiter::{GetRateLimiter, GetRateLimits, RateLimited},
     image_layer::{ImageLayer, ImageLayerWriter},
     inmemory_layer::InMemoryLayer,
-    layer_map::{LayerMap, SearchResult},
+    layer_map::{LayerMap, LayerMapSnapshot, SearchResult, VersionedLayerMap},
+    layer_transfer,
+    maintenance_observer::{LayerDescriptor, LayerRemovalCause, ObserverSlot},
     metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME},
     par_fsync,
-    storage_layer::{Layer, ValueReconstructResult, ValueReconstructState},
+    prev_record_lsns::{PrevRecordLsns, PREV_RECORD_LSNS_FILE_NAME},
+    stall_watchdog::{WatchedGuard, WatchedMutex},
+    storage_layer::{range_overlaps, Layer, ValueReconstructResult, ValueReconstructState},
 };
 
 use crate::config::PageServerConf;
-use crate::keyspace::{KeyPartitioning, KeySpace};
+use crate::jitter::Jitter;
+use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum};
+use crate::pgdatadir_mapping::rel_key_range;
 use crate::pgdatadir_mapping::BlockNumber;
+use crate::pgdatadir_mapping::KeyCategory;
 use crate::pgdatadir_mapping::LsnForTimestamp;
 use crate::reltag::RelTag;
-use crate::tenant_config::TenantConfOpt;
+use crate::tenant_config::{CompactionStrategy, TenantConf, TenantConfOpt};
 use crate::DatadirTimeline;
 
 use postgres_ffi::xlog_utils::to_pg_timestamp;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
 use utils::{
+    bin_ser::BeSer,
     lsn::{AtomicLsn, Lsn, RecordLsn},
-    seqwait::SeqWait,
-    zid::{ZTenantId, ZTimelineId},
+    seqwait::{SeqWait, SeqWaitError},
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
+use crate::repository::{key_range_size, Key, Value};
 use crate::repository::{GcResult, RepositoryTimeline, Timeline, TimelineWriter};
-use crate::repository::{Key, Value};
+use crate::storage_sync::index::RemoteIndex;
 use crate::thread_mgr;
+use crate::thread_mgr::CancellationToken;
 use crate::virtual_file::VirtualFile;
 use crate::walreceiver::IS_WAL_RECEIVER;
-use crate::walredo::WalRedoManager;
+use crate::walrecord::ZenithWalRecord;
+use crate::walredo::{RedoRequest, WalRedoError, WalRedoManager};
 use crate::CheckpointConfig;
 use crate::{page_cache, storage_sync};
 
@@ -99,6 +118,32 @@ static RECONSTRUCT_TIME: Lazy<HistogramVec> = Lazy::new(|| {
     .expect("failed to define a metric")
 });
 
+// Number of ancestor timelines a read had to traverse. Tells whether slow reads
+// are caused by deep branch chains rather than long delta chains.
+static ANCESTOR_HOPS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_getpage_ancestor_hops",
+        "Number of ancestor timelines traversed to reconstruct a value",
+        &["tenant_id", "timeline_id"],
+        vec![0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0],
+    )
+    .expect("failed to define a metric")
+});
+
+// Time to read a value from one layer, to tell whether slow reads are spent in
+// delta or image layers, and in layers that have to be fetched from the remote
+// storage. Labeled by kind and residency only, not by file, to keep the number
+// of series small.
+static LAYER_READ_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_layer_read_seconds",
+        "Time spent reading a value from one layer",
+        &["kind", "residency"],
+        get_buckets_for_critical_operations(),
+    )
+    .expect("failed to define a metric")
+});
+
 static MATERIALIZED_PAGE_CACHE_HIT: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "pageserver_materialized_cache_hits_total",
@@ -139,6 +184,143 @@ static CURRENT_PHYSICAL_SIZE: Lazy<UIntGaugeVec> = Lazy::new(|| {
     .expect("failed to define a metric")
 });
 
+// The difference between the actual physical size and the incrementally maintained
+// gauge, as of the last reconcile_physical_size() call. Nonzero means that some code
+// path failed to update the gauge.
+static PHYSICAL_SIZE_DRIFT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_physical_size_drift_bytes",
+        "Discrepancy between actual and tracked physical size found by the last reconciliation",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+// The same for the logical size, as of the last verify_logical_size() call.
+// Nonzero means that the size changes of some WAL records were not accounted
+// for correctly.
+static LOGICAL_SIZE_DRIFT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_logical_size_drift_bytes",
+        "Discrepancy between recomputed and tracked logical size found by the last check",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+/// Minimum time between two [`LayeredTimeline::verify_logical_size`] checks.
+/// Recomputing the logical size reads the size of every relation.
+const LOGICAL_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Metrics for the open in-memory layer, i.e. how close the timeline is to a
+// time- or size-based checkpoint. Updated by check_checkpoint_distance().
+static OPEN_LAYER_AGE: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "pageserver_open_layer_age_seconds",
+        "Time since the open in-memory layer was last frozen",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static OPEN_LAYER_WAL_BYTES: Lazy<UIntGaugeVec> = Lazy::new(|| {
+    register_uint_gauge_vec!(
+        "pageserver_open_layer_wal_bytes",
+        "Amount of WAL in the open in-memory layer since it was last frozen",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static INMEMORY_LAYERS_CREATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_inmemory_layers_created_total",
+        "Number of in-memory layers created to accept new WAL",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static OPEN_LAYER_START_LSN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_open_layer_start_lsn",
+        "Start LSN of the most recently created in-memory layer",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+// Metrics for garbage collection, to chart its effectiveness over time.
+// Updated by every gc() run.
+static GC_LAYERS_REMOVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_gc_layers_removed_total",
+        "Number of layer files removed by garbage collection",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static GC_BYTES_REMOVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_gc_bytes_removed_total",
+        "Size of the local layer files removed by garbage collection",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+static GC_RUNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_gc_runs_total",
+        "Number of garbage collection runs, by whether they removed any layers",
+        &["tenant_id", "timeline_id", "outcome"]
+    )
+    .expect("failed to define a metric")
+});
+
+// Size distribution of the layer files of a timeline, by kind ("delta" or
+// "image"), to tell whether flushing and compaction produce files of a
+// reasonable size. Every layer file is observed once: when it's loaded at
+// timeline startup, or when it's created. Histograms can't forget
+// observations, so files removed by compaction or GC are not subtracted, and
+// the histogram describes the files written since startup rather than the
+// current layer map; use rate() over it to see the sizes of the files being
+// produced now.
+static LAYER_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_layer_size_bytes",
+        "Size of the layer files loaded or created, by layer kind",
+        &["tenant_id", "timeline_id", "kind"],
+        // 64 kB .. 1 GB
+        exponential_buckets(64.0 * 1024.0, 4.0, 8).expect("valid buckets"),
+    )
+    .expect("failed to define a metric")
+});
+
+// Time from receiving WAL to having it durable on local disk, i.e. until
+// disk_consistent_lsn moves past it. Measured for a sample of the received
+// LSNs, see DURABILITY_SAMPLE_INTERVAL.
+static DURABILITY_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_durability_latency_seconds",
+        "Time from receiving WAL until it is flushed to disk, for sampled LSNs",
+        &["tenant_id", "timeline_id"],
+        // 100 ms .. ~55 min
+        exponential_buckets(0.1, 2.0, 16).expect("valid buckets"),
+    )
+    .expect("failed to define a metric")
+});
+
+static WALREDO_CIRCUIT_OPENED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_walredo_circuit_opened_total",
+        "Number of times reads of a key started to fail without WAL redo, after repeated WAL redo failures",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric")
+});
+
 // Metrics for cloud upload. These metrics reflect data uploaded to cloud storage,
 // or in testing they estimate how much we would upload if we did.
 static NUM_PERSISTENT_FILES_CREATED: Lazy<IntCounter> = Lazy::new(|| {
@@ -199,7 +381,7 @@ impl LayeredTimelineEntry {
         }
     }
 
-    pub fn layer_removal_guard(&self) -> Result<Option<MutexGuard<()>>, anyhow::Error> {
+    pub fn layer_removal_guard(&self) -> Result<Option<WatchedGuard<'_>>, anyhow::Error> {
         match self {
             LayeredTimelineEntry::Loaded(timeline) => timeline
                 .layer_removal_cs
@@ -230,7 +412,12 @@ pub struct LayeredTimeline {
     tenant_id: ZTenantId,
     pub timeline_id: ZTimelineId,
 
-    pub layers: RwLock<LayerMap>,
+    pub layers: VersionedLayerMap,
+
+    /// Layers that have been removed from the layer map by compaction or GC, but
+    /// might still be in use by readers holding an older snapshot of the map.
+    /// Their files are deleted once the readers are done with them.
+    doomed_layers: Mutex<Vec<Arc<dyn Layer>>>,
 
     last_freeze_at: AtomicLsn,
     // Atomic would be more appropriate here.
@@ -239,12 +426,24 @@ pub struct LayeredTimeline {
     // WAL redo manager
     walredo_mgr: Arc<dyn WalRedoManager + Sync + Send>,
 
+    // Limits the number of timelines of the tenant compacting at the same time
+    compaction_limiter: Arc<CompactionLimiter>,
+
+    // The tenant's observer of the layers created and removed by compaction and GC
+    maintenance_observer: Arc<ObserverSlot>,
+
+    // Limits the rate of GetPage requests to the timelines of the tenant
+    get_rate_limiter: Arc<GetRateLimiter>,
+
+    // Shared by all the timelines of the tenant, see LayeredRepository::cancel_compactions
+    compaction_cancel: Arc<CancellationToken>,
+
     // What page versions do we hold in the repository? If we get a
     // request > last_record_lsn, we need to wait until we receive all
     // the WAL up to the request. The SeqWait provides functions for
-    // that. TODO: If we get a request for an old LSN, such that the
-    // versions have already been garbage collected away, we should
-    // throw an error, but we don't track that currently.
+    // that. Requests for an LSN older than 'latest_gc_cutoff_lsn' fail
+    // with LsnGarbageCollected, as the versions might have already been
+    // garbage collected away.
     //
     // last_record_lsn.load().last points to the end of last processed WAL record.
     //
@@ -261,7 +460,9 @@ pub struct LayeredTimeline {
     // Some later WAL records might have been processed and also flushed to disk
     // already, so don't be surprised to see some, but there's no guarantee on
     // them yet.
-    disk_consistent_lsn: AtomicLsn,
+    //
+    // This is a SeqWait, so that wait_flush_lsn() can wait for it to advance.
+    disk_consistent_lsn: SeqWait<Lsn, Lsn>,
 
     // Parent timeline that this timeline was branched from, and the LSN
     // of the branch point.
@@ -270,13 +471,33 @@ pub struct LayeredTimeline {
 
     // Metrics
     reconstruct_time_histo: Histogram,
+    ancestor_hops_histo: Histogram,
     materialized_page_cache_hit_counter: IntCounter,
     flush_time_histo: Histogram,
     compact_time_histo: Histogram,
     create_images_time_histo: Histogram,
+    save_metadata_time_histo: Histogram,
     last_record_gauge: IntGauge,
     wait_lsn_time_histo: Histogram,
     current_physical_size_gauge: UIntGauge,
+    physical_size_drift_gauge: IntGauge,
+    logical_size_drift_gauge: IntGauge,
+    open_layer_age_gauge: Gauge,
+    open_layer_wal_bytes_gauge: UIntGauge,
+    inmemory_layers_created_counter: IntCounter,
+    open_layer_start_lsn_gauge: IntGauge,
+    gc_layers_removed_counter: IntCounter,
+    gc_bytes_removed_counter: IntCounter,
+    gc_runs_reclaimed_counter: IntCounter,
+    gc_runs_nothing_to_do_counter: IntCounter,
+    delta_layer_size_histo: Histogram,
+    image_layer_size_histo: Histogram,
+    durability_latency_histo: Histogram,
+    walredo_circuit_opened_counter: IntCounter,
+
+    /// Index of the files present in the remote storage, used to check that
+    /// a local layer can be safely dropped.
+    remote_index: RemoteIndex,
 
     /// If `true`, will backup its files that appear after each checkpointing to the remote storage.
     upload_layers: AtomicBool,
@@ -288,18 +509,38 @@ pub struct LayeredTimeline {
     /// to avoid deadlock.
     write_lock: Mutex<()>,
 
+    /// If `true`, writes fail with [`IngestPaused`]. See [`LayeredTimeline::pause_ingest`].
+    ingest_paused: AtomicBool,
+
+    /// Set when a layer file wasn't written because the disk was almost full.
+    /// Until there's enough free space again, writes fail with [`DiskSpaceLow`].
+    /// See [`LayeredTimeline::check_disk_space`].
+    disk_space_low: AtomicBool,
+
+    /// Set once the timeline has any layers of its own, in memory or on disk.
+    /// Until then, all reads of a branch are served by its ancestor, and
+    /// get_reconstruct_data() skips the layer map search on this timeline.
+    /// Never goes back to `false`, so a reader holding an older layer map
+    /// snapshot can rely on it too.
+    has_local_layers: AtomicBool,
+
     /// Used to ensure that there is only one thread
-    layer_flush_lock: Mutex<()>,
+    layer_flush_lock: WatchedMutex,
 
     /// Layer removal lock.
     /// A lock to ensure that no layer of the timeline is removed concurrently by other threads.
     /// This lock is acquired in [`LayeredTimeline::gc`], [`LayeredTimeline::compact`],
     /// and [`LayeredRepository::delete_timeline`].
-    layer_removal_cs: Mutex<()>,
+    layer_removal_cs: WatchedMutex,
 
     // Needed to ensure that we can't create a branch at a point that was already garbage collected
     pub latest_gc_cutoff_lsn: RwLock<Lsn>,
 
+    /// The read point of followers, see [`LayeredTimeline::get_at_follower`].
+    /// GC doesn't move 'latest_gc_cutoff_lsn' past it. Lock this before
+    /// 'latest_gc_cutoff_lsn'.
+    follower_lsn: Mutex<Option<Lsn>>,
+
     // List of child timelines and their branch points. This is needed to avoid
     // garbage collecting data that is still needed by the child timelines.
     pub gc_info: RwLock<GcInfo>,
@@ -321,13 +562,117 @@ pub struct LayeredTimeline {
     /// Current logical size of the "datadir", at the last LSN.
     current_logical_size: AtomicIsize,
 
+    /// When verify_logical_size() last recomputed the logical size
+    last_logical_size_check: Mutex<Option<Instant>>,
+
     /// Information about the last processed message by the WAL receiver,
     /// or None if WAL receiver has not received anything for this timeline
     /// yet.
     pub last_received_wal: Mutex<Option<WalReceiverInfo>>,
 
+    /// Sampled LSNs received by the WAL receiver, with the time they were
+    /// received in microseconds since the epoch, that aren't durable yet.
+    /// See [`LayeredTimeline::record_received_wal`].
+    durability_samples: Mutex<VecDeque<(Lsn, u128)>>,
+
+    /// Prev-record LSNs at the points where the timeline was flushed to disk.
+    /// See [`LayeredTimeline::prev_record_lsn_at`].
+    prev_record_lsns: Mutex<PrevRecordLsns>,
+
+    /// Recent WAL redo failures, by key. See [`WalRedoCircuitOpen`].
+    redo_failures: Mutex<HashMap<Key, RedoFailure>>,
+
     /// Relation size cache
     rel_size_cache: RwLock<HashMap<RelTag, (Lsn, BlockNumber)>>,
+
+    /// Keys that define the shape of the key space, modified since the last
+    /// partitioning. Allows recalculating the partitioning incrementally.
+    changed_keys: Mutex<ChangedKeys>,
+
+    /// Sampled read counts of key ranges, to create image layers sooner
+    /// for the ranges that are read the most.
+    access_tracker: KeyAccessTracker,
+
+    /// Applied to checkpoint_timeout, so that timelines with the same config
+    /// don't all freeze their open layers at the same time.
+    maintenance_jitter: Jitter,
+
+    /// Image coverage of the partitions, from the last compaction.
+    image_coverage_cache: Mutex<ImageCoverageCache>,
+}
+
+/// Ephemeral files of in-memory layers, left behind by a crash and removed by
+/// [`LayeredTimeline::load_layer_map`]. Their data had not been flushed to
+/// layer files, so it is ingested again from the WAL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrphanedEphemeralFiles {
+    pub num_files: usize,
+    pub total_bytes: u64,
+    /// Modification time of the oldest file
+    pub oldest: Option<SystemTime>,
+    /// Modification time of the newest file
+    pub newest: Option<SystemTime>,
+}
+
+impl OrphanedEphemeralFiles {
+    fn add(&mut self, size: u64, modified: Option<SystemTime>) {
+        self.num_files += 1;
+        self.total_bytes += size;
+        if let Some(modified) = modified {
+            self.oldest = Some(self.oldest.map_or(modified, |t| t.min(modified)));
+            self.newest = Some(self.newest.map_or(modified, |t| t.max(modified)));
+        }
+    }
+}
+
+impl std::fmt::Display for OrphanedEphemeralFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} files, {} bytes", self.num_files, self.total_bytes)?;
+        if let (Some(oldest), Some(newest)) = (self.oldest, self.newest) {
+            let age = |t: SystemTime| {
+                let age = t.elapsed().unwrap_or_default();
+                humantime::format_duration(Duration::from_secs(age.as_secs()))
+            };
+            write!(
+                f,
+                ", last modified between {} and {} ago",
+                age(newest),
+                age(oldest)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Memory used by the in-memory layers of a timeline, see
+/// [`LayeredTimeline::inmemory_layer_stat`].
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct InMemoryLayerStat {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: ZTenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    pub open_layer_bytes: u64,
+    pub frozen_layers: usize,
+    pub frozen_bytes: u64,
+}
+
+impl InMemoryLayerStat {
+    pub fn total_bytes(&self) -> u64 {
+        self.open_layer_bytes + self.frozen_bytes
+    }
+}
+
+/// Pending compaction work of a timeline, see [`LayeredTimeline::compaction_debt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionDebt {
+    /// Number of level 0 delta layers waiting to be compacted
+    pub l0_count: usize,
+    /// Total size of those layer files
+    pub l0_bytes: u64,
+    /// Number of partitions that would get new image layers
+    pub partitions_needing_images: usize,
 }
 
 pub struct WalReceiverInfo {
@@ -336,6 +681,301 @@ pub struct WalReceiverInfo {
     pub last_received_msg_ts: u128,
 }
 
+/// How often [`LayeredTimeline::wait_flush_lsn`] checks for shutdown requests
+const WAIT_FLUSH_LSN_SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum time between samples of received LSNs for the durability latency
+/// metric, see [`LayeredTimeline::record_received_wal`]
+const DURABILITY_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max number of received LSNs waiting to become durable. No more samples are
+/// taken while there are this many.
+const MAX_DURABILITY_SAMPLES: usize = 1000;
+
+/// How long removing layers from the layer map waits for the readers that are
+/// still using them, before leaving the deletion of their files to the next
+/// compaction or GC. See [`LayeredTimeline::delete_unused_layers`].
+const DOOMED_LAYER_WAIT: Duration = Duration::from_millis(100);
+
+/// Max number of keys with WAL redo failures to remember. When there are
+/// more, the key that failed longest ago is forgotten.
+const MAX_TRACKED_REDO_FAILURES: usize = 1000;
+
+/// Consecutive WAL redo failures of a key, see [`WalRedoCircuitOpen`].
+struct RedoFailure {
+    inputs: RedoInputs,
+    count: usize,
+    last_failure: Instant,
+}
+
+/// Identifies the WAL replayed for a key. If it changes, e.g. because new
+/// WAL arrived or a new image layer was created, the WAL redo is attempted
+/// again even if it failed before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RedoInputs {
+    has_base_img: bool,
+    first_rec_lsn: Lsn,
+    last_rec_lsn: Lsn,
+    num_records: usize,
+}
+
+impl RedoInputs {
+    fn of(req: &RedoRequest) -> Self {
+        RedoInputs {
+            has_base_img: req.base_img.is_some(),
+            first_rec_lsn: req.records.first().unwrap().0,
+            last_rec_lsn: req.records.last().unwrap().0,
+            num_records: req.records.len(),
+        }
+    }
+}
+
+/// Max number of keys to track in [`ChangedKeys`]
+const MAX_TRACKED_CHANGED_KEYS: usize = 10_000;
+
+#[derive(Default)]
+struct ChangedKeys {
+    /// Last modification LSN of each key
+    keys: HashMap<Key, Lsn>,
+    /// All the modifications after this LSN are in 'keys'. Earlier ones are
+    /// forgotten when too many keys are modified.
+    complete_since: Lsn,
+}
+
+/// The sub-ranges of a partition range between image layer boundaries that
+/// have data newer than their latest image, with the number of delta layers
+/// on top of the image.
+type ImageCoverage = Arc<[(Range<Key>, usize)]>;
+
+/// Cache of [`LayeredTimeline::image_coverage_deltas`] results.
+#[derive(Default)]
+struct ImageCoverageCache {
+    /// Layer map generation that the entries were computed with
+    generation: u64,
+    /// The coverage of each partition range, and the LSN it was computed at
+    entries: HashMap<Range<Key>, (Lsn, ImageCoverage)>,
+}
+
+/// Target sizes of the L1 delta layers of each [`KeyCategory`].
+#[derive(Debug, Clone, Copy)]
+struct CompactionTargetSizes {
+    relation: u64,
+    slru: u64,
+    metadata: u64,
+}
+
+impl CompactionTargetSizes {
+    #[cfg(test)]
+    fn uniform(size: u64) -> Self {
+        CompactionTargetSizes {
+            relation: size,
+            slru: size,
+            metadata: size,
+        }
+    }
+
+    fn for_key(&self, key: Key) -> u64 {
+        self.for_category(KeyCategory::of(key))
+    }
+
+    fn for_category(&self, category: KeyCategory) -> u64 {
+        match category {
+            KeyCategory::Relation => self.relation,
+            KeyCategory::Slru => self.slru,
+            KeyCategory::Metadata => self.metadata,
+        }
+    }
+}
+
+///
+/// Kind of a stored version of a key, as reported by [`LayeredTimeline::key_history`].
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Image,
+    WalRecord { will_init: bool },
+}
+
+///
+/// The versions of a key that reconstructing it at an LSN starts from: the
+/// base image, if any, and the WAL records on top of it in LSN order, with
+/// their 'will_init' flags. See [`LayeredTimeline::record_chain`].
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordChain {
+    pub base_image_lsn: Option<Lsn>,
+    pub records: Vec<(Lsn, bool)>,
+}
+
+/// How many records of a [`RecordChain`] are listed in error messages.
+const RECORD_CHAIN_MAX_DISPLAYED: usize = 16;
+
+impl RecordChain {
+    /// 'records' must be in LSN order.
+    fn of(img: Option<&(Lsn, Bytes)>, records: &[(Lsn, ZenithWalRecord)]) -> Self {
+        RecordChain {
+            base_image_lsn: img.map(|(lsn, _)| *lsn),
+            records: records
+                .iter()
+                .map(|(lsn, rec)| (*lsn, rec.will_init()))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for RecordChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.base_image_lsn {
+            Some(lsn) => write!(f, "base image at {lsn}")?,
+            None => write!(f, "no base image")?,
+        }
+        write!(f, ", records:")?;
+        // The oldest records are the interesting ones, that's where the
+        // chain should have been anchored
+        for (lsn, will_init) in self.records.iter().take(RECORD_CHAIN_MAX_DISPLAYED) {
+            write!(f, " {lsn} (will_init: {will_init})")?;
+        }
+        if self.records.len() > RECORD_CHAIN_MAX_DISPLAYED {
+            write!(
+                f,
+                " ... and {} more",
+                self.records.len() - RECORD_CHAIN_MAX_DISPLAYED
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How many failures [`LayeredTimeline::self_check`] reports in detail.
+const SELF_CHECK_MAX_REPORTED_FAILURES: usize = 5;
+
+///
+/// Result of [`LayeredTimeline::self_check`].
+///
+#[derive(Debug, Default)]
+pub struct SelfCheckReport {
+    /// LSN at which the keys were read
+    pub lsn: Lsn,
+    pub keys_checked: usize,
+    pub successes: usize,
+    pub failures: usize,
+    /// Details of the first few failures
+    pub first_failures: Vec<SelfCheckFailure>,
+}
+
+///
+/// Result of [`LayeredTimeline::reconcile_layers`]. Contains the local paths of
+/// the layer files, sorted.
+///
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Layer files in the timeline directory that are not in the layer map
+    pub local_files_not_in_map: Vec<PathBuf>,
+    /// Layers in the layer map whose local file is gone
+    pub map_layers_missing_locally: Vec<PathBuf>,
+    /// Layers in the layer map that are not in the remote storage. Always
+    /// empty if the timeline is not in the remote index.
+    pub map_layers_missing_remotely: Vec<PathBuf>,
+    /// Layers in the remote storage that are not in the layer map
+    pub remote_layers_not_in_map: Vec<PathBuf>,
+    /// Local files that were added back to the layer map
+    pub repaired: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct SelfCheckFailure {
+    /// The key that could not be reconstructed, or None if the keyspace
+    /// itself could not be collected.
+    pub key: Option<Key>,
+    /// The error with all its context, including the layer traversal path.
+    pub error: String,
+}
+
+impl SelfCheckReport {
+    fn add_failure(&mut self, key: Option<Key>, err: anyhow::Error) {
+        self.failures += 1;
+        if self.first_failures.len() < SELF_CHECK_MAX_REPORTED_FAILURES {
+            self.first_failures.push(SelfCheckFailure {
+                key,
+                error: format!("{err:#}"),
+            });
+        }
+    }
+}
+
+/// Returned by [`LayeredTimeline::get_with_deadline`] when the value could not
+/// be reconstructed before the deadline. The request can be retried.
+#[derive(Debug, thiserror::Error)]
+#[error("timed out reconstructing key {key} at LSN {lsn}")]
+pub struct GetTimeoutError {
+    pub key: Key,
+    pub lsn: Lsn,
+}
+
+/// Returned by reads at an LSN that is below the GC cutoff of the timeline,
+/// where the data needed might have been garbage collected already.
+#[derive(Debug, thiserror::Error)]
+#[error("LSN {requested} is earlier than latest GC horizon {gc_cutoff} (we might've already garbage collected needed data)")]
+pub struct LsnGarbageCollected {
+    pub requested: Lsn,
+    pub gc_cutoff: Lsn,
+}
+
+/// Returned for operations at an LSN before the timeline was initialized,
+/// where there is no data at all.
+#[derive(Debug, thiserror::Error)]
+#[error("LSN {requested} is earlier than the initdb LSN {initdb_lsn} of the timeline")]
+pub struct LsnBeforeInitdb {
+    pub requested: Lsn,
+    pub initdb_lsn: Lsn,
+}
+
+/// Returned by compaction when it was cancelled, see
+/// [`super::LayeredRepository::cancel_compactions`].
+#[derive(Debug, thiserror::Error)]
+#[error("compaction of timeline {timeline_id} was cancelled")]
+pub struct CompactionCancelled {
+    pub timeline_id: ZTimelineId,
+}
+
+/// Returned by writes to a timeline while WAL ingestion is paused with
+/// [`LayeredTimeline::pause_ingest`]. The write can be retried after
+/// ingestion is resumed.
+#[derive(Debug, thiserror::Error)]
+#[error("WAL ingestion is paused on timeline {timeline_id}")]
+pub struct IngestPaused {
+    pub timeline_id: ZTimelineId,
+}
+
+/// Returned by reads of a key whose WAL redo has failed
+/// 'walredo_failure_threshold' times in a row, with the same WAL. Usually
+/// that means a corrupt WAL record, which would fail the same way again. The
+/// WAL redo is attempted again after 'walredo_failure_cooldown', or when the
+/// WAL for the key changes.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "WAL redo of key {key} failed {failures} times in a row, circuit open for another {retry_in:?}"
+)]
+pub struct WalRedoCircuitOpen {
+    pub key: Key,
+    pub failures: usize,
+    pub retry_in: Duration,
+}
+
+/// The reconstruction of a key ran out of layers without finding a page image
+/// or a WAL record that initializes the page. If no WAL records were found
+/// either, the key has never been written.
+#[derive(Debug, thiserror::Error)]
+#[error("could not find data for key {key} at LSN {cont_lsn}, for request at LSN {request_lsn}")]
+pub struct KeyNotFoundError {
+    pub key: Key,
+    pub cont_lsn: Lsn,
+    pub request_lsn: Lsn,
+    /// Number of WAL records found above 'cont_lsn', with no base image to
+    /// apply them to
+    pub num_records: usize,
+}
+
 /// Inherit all the functions from DatadirTimeline, to provide the
 /// functionality to store PostgreSQL relations, SLRUs, etc. in a
 /// LayeredTimeline.
@@ -374,6 +1014,73 @@ impl DatadirTimeline for LayeredTimeline {
         let mut rel_size_cache = self.rel_size_cache.write().unwrap();
         rel_size_cache.remove(tag);
     }
+
+    fn record_keyspace_change(&self, key: Key, lsn: Lsn) {
+        let mut changed_keys = self.changed_keys.lock().unwrap();
+        if changed_keys.keys.len() >= MAX_TRACKED_CHANGED_KEYS
+            && !changed_keys.keys.contains_key(&key)
+        {
+            // Too many to keep track of. Forget them, which forces the next
+            // partitioning to be calculated from scratch.
+            changed_keys.keys.clear();
+            changed_keys.complete_since = lsn;
+            return;
+        }
+        changed_keys.keys.insert(key, lsn);
+    }
+
+    fn changed_keys(&self, lsn: Lsn) -> Option<Vec<Key>> {
+        let changed_keys = self.changed_keys.lock().unwrap();
+        if lsn < changed_keys.complete_since {
+            return None;
+        }
+        Some(
+            changed_keys
+                .keys
+                .iter()
+                .filter(|(_, modified_lsn)| **modified_lsn > lsn)
+                .map(|(key, _)| *key)
+                .collect(),
+        )
+    }
+
+    fn covered_key_ranges(&self) -> Option<Vec<Range<Key>>> {
+        let mut ranges = Vec::new();
+        let mut timeline_owned;
+        let mut timeline = self;
+        loop {
+            // Flushing inserts the new delta layer before it removes the
+            // frozen layer, so every key is in one or the other.
+            let layers = timeline.layers.read().unwrap();
+            ranges.extend(layers.covered_ranges());
+            for l in layers.open_layer.iter().chain(layers.frozen_layers.iter()) {
+                ranges.extend(l.key_bounds());
+            }
+            drop(layers);
+
+            if timeline.ancestor_timeline.is_none() {
+                break;
+            }
+            timeline_owned = match timeline.get_ancestor_timeline() {
+                Ok(ancestor) => ancestor,
+                Err(e) => {
+                    warn!("could not get the key coverage of the ancestor timeline: {e:#}");
+                    return None;
+                }
+            };
+            timeline = &*timeline_owned;
+        }
+
+        ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<Key>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = max(last.end, range.end),
+                _ => merged.push(range),
+            }
+        }
+        Some(merged)
+    }
 }
 
 ///
@@ -405,6 +1112,40 @@ pub struct GcInfo {
     pub pitr_cutoff: Lsn,
 }
 
+/// Why GC retains the versions at a 'retain_lsns' point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetainLsnSource {
+    /// A child branch was forked off at the LSN.
+    ChildBranch,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetainLsn {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    pub source: RetainLsnSource,
+}
+
+///
+/// Serializable view of the GC state of a timeline, as returned by
+/// gc_info_snapshot(). The cutoffs are the ones computed by the last GC
+/// iteration, they are all zero until GC has run on the timeline.
+///
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GcInfoSnapshot {
+    pub retain_lsns: Vec<RetainLsn>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub horizon_cutoff: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub pitr_cutoff: Lsn,
+    /// Nothing older than this can be read from the timeline anymore.
+    #[serde_as(as = "DisplayFromStr")]
+    pub latest_gc_cutoff_lsn: Lsn,
+}
+
 /// Public interface functions
 impl Timeline for LayeredTimeline {
     fn get_ancestor_lsn(&self) -> Lsn {
@@ -439,37 +1180,47 @@ impl Timeline for LayeredTimeline {
         Ok(())
     }
 
+    fn wait_flush_lsn(&self, lsn: Lsn, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Wake up every now and then, to check for shutdown requests
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self
+                .disk_consistent_lsn
+                .wait_for_timeout(lsn, remaining.min(WAIT_FLUSH_LSN_SHUTDOWN_CHECK_INTERVAL))
+            {
+                Ok(()) => return Ok(()),
+                Err(SeqWaitError::Timeout) => {}
+                Err(SeqWaitError::Shutdown) => {
+                    bail!(
+                        "Timeline shut down while waiting for LSN {} to be flushed",
+                        lsn
+                    )
+                }
+            }
+            ensure!(
+                Instant::now() < deadline,
+                "Timed out while waiting for LSN {} to be flushed, last_record_lsn {} disk consistent LSN={}",
+                lsn,
+                self.get_last_record_lsn(),
+                self.get_disk_consistent_lsn()
+            );
+            ensure!(
+                !thread_mgr::is_shutdown_requested(),
+                "Shutdown requested while waiting for LSN {} to be flushed",
+                lsn
+            );
+        }
+    }
+
     fn get_latest_gc_cutoff_lsn(&self) -> RwLockReadGuard<Lsn> {
         self.latest_gc_cutoff_lsn.read().unwrap()
     }
 
     /// Look up the value with the given a key
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes> {
-        // Check the page cache. We will get back the most recent page with lsn <= `lsn`.
-        // The cached image can be returned directly if there is no WAL between the cached image
-        // and requested LSN. The cached image can also be used to reduce the amount of WAL needed
-        // for redo.
-        let cached_page_img = match self.lookup_cached_page(&key, lsn) {
-            Some((cached_lsn, cached_img)) => {
-                match cached_lsn.cmp(&lsn) {
-                    Ordering::Less => {} // there might be WAL between cached_lsn and lsn, we need to check
-                    Ordering::Equal => return Ok(cached_img), // exact LSN match, return the image
-                    Ordering::Greater => panic!(), // the returned lsn should never be after the requested lsn
-                }
-                Some((cached_lsn, cached_img))
-            }
-            None => None,
-        };
-
-        let mut reconstruct_state = ValueReconstructState {
-            records: Vec::new(),
-            img: cached_page_img,
-        };
-
-        self.get_reconstruct_data(key, lsn, &mut reconstruct_state)?;
-
-        self.reconstruct_time_histo
-            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
+        self.access_tracker.record(key);
+        self.get_internal(key, lsn, None)
     }
 
     /// Public entry point for checkpoint(). All the logic is in the private
@@ -484,7 +1235,7 @@ impl Timeline for LayeredTimeline {
             CheckpointConfig::Forced => {
                 self.freeze_inmem_layer(false);
                 self.flush_frozen_layers(true)?;
-                self.compact()
+                self.compact_forced()
             }
         }
     }
@@ -497,6 +1248,13 @@ impl Timeline for LayeredTimeline {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()> {
+        if lsn < self.initdb_lsn {
+            return Err(LsnBeforeInitdb {
+                requested: lsn,
+                initdb_lsn: self.initdb_lsn,
+            }
+            .into());
+        }
         ensure!(
             lsn >= **latest_gc_cutoff_lsn,
             "LSN {} is earlier than latest GC horizon {} (we might've already garbage collected needed data)",
@@ -526,6 +1284,7 @@ impl Timeline for LayeredTimeline {
         Box::new(LayeredTimelineWriter {
             tl: self,
             _write_guard: self.write_lock.lock().unwrap(),
+            staged: RefCell::new(None),
         })
     }
 
@@ -539,55 +1298,145 @@ impl Timeline for LayeredTimeline {
         let mut total_physical_size = 0;
 
         for direntry in fs::read_dir(timeline_path)? {
-            let direntry = direntry?;
-            let fname = direntry.file_name();
-            let fname = fname.to_string_lossy();
-
-            if ImageFileName::parse_str(&fname).is_some()
-                || DeltaFileName::parse_str(&fname).is_some()
-            {
-                total_physical_size += direntry.metadata()?.len();
-            }
+            total_physical_size += layer_file_size(&direntry?)?;
         }
 
         Ok(total_physical_size)
     }
 }
 
+/// Size of the layer file at 'direntry', or 0 if it's not a layer file.
+fn layer_file_size(direntry: &fs::DirEntry) -> Result<u64> {
+    let fname = direntry.file_name();
+    let fname = fname.to_string_lossy();
+
+    if ImageFileName::parse_str(&fname).is_some() || DeltaFileName::parse_str(&fname).is_some() {
+        Ok(direntry.metadata()?.len())
+    } else {
+        Ok(0)
+    }
+}
+
+/// Number of directory entries that get_physical_size_non_incremental_async()
+/// examines in one blocking task.
+const PHYSICAL_SIZE_CHUNK_SIZE: usize = 1000;
+
 impl LayeredTimeline {
-    fn get_checkpoint_distance(&self) -> u64 {
+    ///
+    /// The tenant config in effect for this timeline: the tenant's overrides,
+    /// with the pageserver's defaults for everything that isn't overridden.
+    ///
+    pub fn effective_tenant_conf(&self) -> TenantConf {
         let tenant_conf = self.tenant_conf.read().unwrap();
-        tenant_conf
-            .checkpoint_distance
-            .unwrap_or(self.conf.default_tenant_conf.checkpoint_distance)
+        tenant_conf.merge(self.conf.default_tenant_conf)
+    }
+
+    fn get_checkpoint_distance(&self) -> u64 {
+        self.effective_tenant_conf().checkpoint_distance
     }
 
     fn get_checkpoint_timeout(&self) -> Duration {
-        let tenant_conf = self.tenant_conf.read().unwrap();
-        tenant_conf
-            .checkpoint_timeout
-            .unwrap_or(self.conf.default_tenant_conf.checkpoint_timeout)
+        self.effective_tenant_conf().checkpoint_timeout
+    }
+
+    fn get_checkpoint_max_entries(&self) -> usize {
+        self.effective_tenant_conf().checkpoint_max_entries
     }
 
     fn get_compaction_target_size(&self) -> u64 {
-        let tenant_conf = self.tenant_conf.read().unwrap();
+        self.effective_tenant_conf().compaction_target_size
+    }
+
+    fn get_compaction_target_file_size(&self) -> u64 {
+        let tenant_conf = self.effective_tenant_conf();
         tenant_conf
-            .compaction_target_size
-            .unwrap_or(self.conf.default_tenant_conf.compaction_target_size)
+            .compaction_target_file_size
+            .unwrap_or(tenant_conf.checkpoint_distance)
+    }
+
+    fn get_compaction_target_file_sizes(&self) -> CompactionTargetSizes {
+        let tenant_conf = self.effective_tenant_conf();
+        let default_size = tenant_conf
+            .compaction_target_file_size
+            .unwrap_or(tenant_conf.checkpoint_distance);
+        CompactionTargetSizes {
+            relation: tenant_conf
+                .compaction_target_file_size_relation
+                .unwrap_or(default_size),
+            slru: tenant_conf
+                .compaction_target_file_size_slru
+                .unwrap_or(default_size),
+            metadata: tenant_conf
+                .compaction_target_file_size_metadata
+                .unwrap_or(default_size),
+        }
     }
 
     fn get_compaction_threshold(&self) -> usize {
-        let tenant_conf = self.tenant_conf.read().unwrap();
-        tenant_conf
-            .compaction_threshold
-            .unwrap_or(self.conf.default_tenant_conf.compaction_threshold)
+        self.effective_tenant_conf().compaction_threshold
     }
 
     fn get_image_creation_threshold(&self) -> usize {
+        self.effective_tenant_conf().image_creation_threshold
+    }
+
+    fn get_materialized_cache_enabled(&self) -> bool {
+        self.effective_tenant_conf().materialized_cache_enabled
+    }
+
+    fn get_walredo_max_records_size(&self) -> u64 {
+        self.effective_tenant_conf().walredo_max_records_size
+    }
+
+    fn get_compaction_concurrency(&self) -> usize {
+        self.effective_tenant_conf().compaction_concurrency
+    }
+
+    fn get_compaction_max_input_layers(&self) -> usize {
+        self.effective_tenant_conf().compaction_max_input_layers
+    }
+
+    fn get_compaction_strategy(&self) -> CompactionStrategy {
+        self.effective_tenant_conf().compaction_strategy
+    }
+
+    fn get_compaction_min_benefit(&self) -> u64 {
+        self.effective_tenant_conf().compaction_min_benefit
+    }
+
+    ///
+    /// Take a token for a GetPage request of the page service, or fail with
+    /// [`RateLimited`] if the tenant is over its rate limits. Returns the
+    /// limits to pass to charge_get_page_bytes() once the page has been read.
+    ///
+    pub fn check_get_page_rate_limit(&self) -> Result<GetRateLimits, RateLimited> {
+        let rate_limits = self.get_rate_limits();
+        self.get_rate_limiter.check(rate_limits)?;
+        Ok(rate_limits)
+    }
+
+    /// Charge the size of a page returned to a GetPage request.
+    pub fn charge_get_page_bytes(&self, rate_limits: GetRateLimits, bytes: usize) {
+        self.get_rate_limiter.charge_bytes(rate_limits, bytes);
+    }
+
+    ///
+    /// The GetPage rate limits of the tenant.
+    ///
+    /// This is called on every GetPage request, so it doesn't merge the whole
+    /// tenant config like effective_tenant_conf().
+    ///
+    fn get_rate_limits(&self) -> GetRateLimits {
         let tenant_conf = self.tenant_conf.read().unwrap();
-        tenant_conf
-            .image_creation_threshold
-            .unwrap_or(self.conf.default_tenant_conf.image_creation_threshold)
+        let defaults = &self.conf.default_tenant_conf;
+        GetRateLimits {
+            requests_per_second: tenant_conf
+                .get_requests_per_second
+                .unwrap_or(defaults.get_requests_per_second),
+            bytes_per_second: tenant_conf
+                .get_bytes_per_second
+                .unwrap_or(defaults.get_bytes_per_second),
+        }
     }
 
     /// Open a Timeline handle.
@@ -602,11 +1451,19 @@ impl LayeredTimeline {
         timeline_id: ZTimelineId,
         tenant_id: ZTenantId,
         walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
+        compaction_limiter: Arc<CompactionLimiter>,
+        compaction_cancel: Arc<CancellationToken>,
+        maintenance_observer: Arc<ObserverSlot>,
+        get_rate_limiter: Arc<GetRateLimiter>,
+        remote_index: RemoteIndex,
         upload_layers: bool,
     ) -> LayeredTimeline {
         let reconstruct_time_histo = RECONSTRUCT_TIME
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
+        let ancestor_hops_histo = ANCESTOR_HOPS
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
         let materialized_page_cache_hit_counter = MATERIALIZED_PAGE_CACHE_HIT
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
@@ -631,6 +1488,13 @@ impl LayeredTimeline {
                 &timeline_id.to_string(),
             ])
             .unwrap();
+        let save_metadata_time_histo = STORAGE_TIME
+            .get_metric_with_label_values(&[
+                "save metadata",
+                &tenant_id.to_string(),
+                &timeline_id.to_string(),
+            ])
+            .unwrap();
         let last_record_gauge = LAST_RECORD_LSN
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
@@ -640,22 +1504,85 @@ impl LayeredTimeline {
         let current_physical_size_gauge = CURRENT_PHYSICAL_SIZE
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
+        let physical_size_drift_gauge = PHYSICAL_SIZE_DRIFT
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let logical_size_drift_gauge = LOGICAL_SIZE_DRIFT
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let open_layer_age_gauge = OPEN_LAYER_AGE
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let open_layer_wal_bytes_gauge = OPEN_LAYER_WAL_BYTES
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let inmemory_layers_created_counter = INMEMORY_LAYERS_CREATED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let open_layer_start_lsn_gauge = OPEN_LAYER_START_LSN
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let walredo_circuit_opened_counter = WALREDO_CIRCUIT_OPENED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let gc_layers_removed_counter = GC_LAYERS_REMOVED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let gc_bytes_removed_counter = GC_BYTES_REMOVED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let gc_runs_reclaimed_counter = GC_RUNS
+            .get_metric_with_label_values(&[
+                &tenant_id.to_string(),
+                &timeline_id.to_string(),
+                "reclaimed",
+            ])
+            .unwrap();
+        let gc_runs_nothing_to_do_counter = GC_RUNS
+            .get_metric_with_label_values(&[
+                &tenant_id.to_string(),
+                &timeline_id.to_string(),
+                "nothing_to_do",
+            ])
+            .unwrap();
+        let delta_layer_size_histo = LAYER_SIZE
+            .get_metric_with_label_values(&[
+                &tenant_id.to_string(),
+                &timeline_id.to_string(),
+                "delta",
+            ])
+            .unwrap();
+        let image_layer_size_histo = LAYER_SIZE
+            .get_metric_with_label_values(&[
+                &tenant_id.to_string(),
+                &timeline_id.to_string(),
+                "image",
+            ])
+            .unwrap();
+        let durability_latency_histo = DURABILITY_LATENCY
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
 
         let mut result = LayeredTimeline {
             conf,
             tenant_conf,
             timeline_id,
             tenant_id,
-            layers: RwLock::new(LayerMap::default()),
+            layers: VersionedLayerMap::default(),
+            doomed_layers: Mutex::new(Vec::new()),
 
             walredo_mgr,
+            compaction_limiter,
+            compaction_cancel,
+            maintenance_observer,
+            get_rate_limiter,
 
             // initialize in-memory 'last_record_lsn' from 'disk_consistent_lsn'.
             last_record_lsn: SeqWait::new(RecordLsn {
                 last: metadata.disk_consistent_lsn(),
                 prev: metadata.prev_record_lsn().unwrap_or(Lsn(0)),
             }),
-            disk_consistent_lsn: AtomicLsn::new(metadata.disk_consistent_lsn().0),
+            disk_consistent_lsn: SeqWait::new(metadata.disk_consistent_lsn()),
 
             last_freeze_at: AtomicLsn::new(metadata.disk_consistent_lsn().0),
             last_freeze_ts: RwLock::new(Instant::now()),
@@ -664,19 +1591,39 @@ impl LayeredTimeline {
             ancestor_lsn: metadata.ancestor_lsn(),
 
             reconstruct_time_histo,
+            ancestor_hops_histo,
             materialized_page_cache_hit_counter,
             flush_time_histo,
             compact_time_histo,
             create_images_time_histo,
+            save_metadata_time_histo,
             last_record_gauge,
             wait_lsn_time_histo,
             current_physical_size_gauge,
-
+            physical_size_drift_gauge,
+            logical_size_drift_gauge,
+            open_layer_age_gauge,
+            open_layer_wal_bytes_gauge,
+            inmemory_layers_created_counter,
+            open_layer_start_lsn_gauge,
+            gc_layers_removed_counter,
+            gc_bytes_removed_counter,
+            walredo_circuit_opened_counter,
+            gc_runs_reclaimed_counter,
+            gc_runs_nothing_to_do_counter,
+            delta_layer_size_histo,
+            image_layer_size_histo,
+            durability_latency_histo,
+
+            remote_index,
             upload_layers: AtomicBool::new(upload_layers),
 
             write_lock: Mutex::new(()),
-            layer_flush_lock: Mutex::new(()),
-            layer_removal_cs: Mutex::new(()),
+            ingest_paused: AtomicBool::new(false),
+            disk_space_low: AtomicBool::new(false),
+            has_local_layers: AtomicBool::new(false),
+            layer_flush_lock: WatchedMutex::new("layer_flush_lock"),
+            layer_removal_cs: WatchedMutex::new("layer_removal_cs"),
 
             gc_info: RwLock::new(GcInfo {
                 retain_lsns: Vec::new(),
@@ -685,14 +1632,25 @@ impl LayeredTimeline {
             }),
 
             latest_gc_cutoff_lsn: RwLock::new(metadata.latest_gc_cutoff_lsn()),
+            follower_lsn: Mutex::new(None),
             initdb_lsn: metadata.initdb_lsn(),
 
             current_logical_size: AtomicIsize::new(0),
+            last_logical_size_check: Mutex::new(None),
             partitioning: Mutex::new((KeyPartitioning::new(), Lsn(0))),
             repartition_threshold: 0,
 
             last_received_wal: Mutex::new(None),
+            durability_samples: Mutex::new(VecDeque::new()),
+            prev_record_lsns: Mutex::new(PrevRecordLsns::new(
+                &conf.timeline_path(&timeline_id, &tenant_id),
+            )),
+            redo_failures: Mutex::new(HashMap::new()),
             rel_size_cache: RwLock::new(HashMap::new()),
+            changed_keys: Mutex::new(ChangedKeys::default()),
+            access_tracker: KeyAccessTracker::default(),
+            maintenance_jitter: Jitter::random(conf.maintenance_jitter_percent),
+            image_coverage_cache: Mutex::new(ImageCoverageCache::default()),
         };
         result.repartition_threshold = result.get_checkpoint_distance() / 10;
         result
@@ -700,9 +1658,15 @@ impl LayeredTimeline {
 
     ///
     /// Scan the timeline directory to populate the layer map.
-    /// Returns all timeline-related files that were found and loaded.
     ///
-    pub fn load_layer_map(&self, disk_consistent_lsn: Lsn) -> anyhow::Result<()> {
+    /// Ephemeral files left behind by a crash are removed. They are counted
+    /// and logged first, to show how much unflushed data the crash lost, and
+    /// the counts are returned.
+    ///
+    pub fn load_layer_map(
+        &self,
+        disk_consistent_lsn: Lsn,
+    ) -> anyhow::Result<OrphanedEphemeralFiles> {
         let mut layers = self.layers.write().unwrap();
         let mut num_layers = 0;
 
@@ -711,6 +1675,8 @@ impl LayeredTimeline {
         let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
         // total size of layer files in the current timeline directory
         let mut total_physical_size = 0;
+        let mut orphaned_ephemeral_files = OrphanedEphemeralFiles::default();
+        let mut orphaned_ephemeral_paths = Vec::new();
 
         for direntry in fs::read_dir(timeline_path)? {
             let direntry = direntry?;
@@ -719,59 +1685,146 @@ impl LayeredTimeline {
 
             if let Some(imgfilename) = ImageFileName::parse_str(&fname) {
                 // create an ImageLayer struct for each image file.
-                if imgfilename.lsn > disk_consistent_lsn {
+                if is_future_layer(imgfilename.lsn + 1, disk_consistent_lsn) {
                     warn!(
                         "found future image layer {} on timeline {} disk_consistent_lsn is {}",
                         imgfilename, self.timeline_id, disk_consistent_lsn
                     );
 
-                    rename_to_backup(direntry.path())?;
+                    quarantine_file(
+                        &direntry.path(),
+                        "future layer",
+                        self.conf.max_quarantined_files,
+                    )?;
                     continue;
                 }
 
                 let layer =
                     ImageLayer::new(self.conf, self.timeline_id, self.tenant_id, &imgfilename);
+                if self.conf.validate_layers_on_startup {
+                    layer.validate_structure()?;
+                }
 
                 trace!("found layer {}", layer.filename().display());
-                total_physical_size += layer.path().metadata()?.len();
+                let size = layer.path().metadata()?.len();
+                total_physical_size += size;
+                self.image_layer_size_histo.observe(size as f64);
                 layers.insert_historic(Arc::new(layer));
                 num_layers += 1;
             } else if let Some(deltafilename) = DeltaFileName::parse_str(&fname) {
                 // Create a DeltaLayer struct for each delta file.
-                // The end-LSN is exclusive, while disk_consistent_lsn is
-                // inclusive. For example, if disk_consistent_lsn is 100, it is
-                // OK for a delta layer to have end LSN 101, but if the end LSN
-                // is 102, then it might not have been fully flushed to disk
-                // before crash.
-                if deltafilename.lsn_range.end > disk_consistent_lsn + 1 {
+                if is_future_layer(deltafilename.lsn_range.end, disk_consistent_lsn) {
                     warn!(
                         "found future delta layer {} on timeline {} disk_consistent_lsn is {}",
                         deltafilename, self.timeline_id, disk_consistent_lsn
                     );
 
-                    rename_to_backup(direntry.path())?;
+                    quarantine_file(
+                        &direntry.path(),
+                        "future layer",
+                        self.conf.max_quarantined_files,
+                    )?;
                     continue;
                 }
 
                 let layer =
                     DeltaLayer::new(self.conf, self.timeline_id, self.tenant_id, &deltafilename);
+                if self.conf.validate_layers_on_startup {
+                    layer.validate_structure()?;
+                }
 
                 trace!("found layer {}", layer.filename().display());
-                total_physical_size += layer.path().metadata()?.len();
+                let size = layer.path().metadata()?.len();
+                total_physical_size += size;
+                self.delta_layer_size_histo.observe(size as f64);
                 layers.insert_historic(Arc::new(layer));
                 num_layers += 1;
-            } else if fname == METADATA_FILE_NAME || fname.ends_with(".old") {
-                // ignore these
+            } else if fname == METADATA_FILE_NAME || fname == PREV_RECORD_LSNS_FILE_NAME {
+                // ignore
+            } else if fname.ends_with(".old") {
+                // Quarantined files are kept for debugging, until they expire
+                // They are not needed to load the timeline, so failing to
+                // expire one is not an error.
+                if let Some(retention) = self.conf.quarantined_file_retention {
+                    match direntry.metadata().and_then(|m| m.modified()) {
+                        Ok(modified) if modified.elapsed().unwrap_or_default() > retention => {
+                            info!(
+                                "removing expired quarantined file in timeline dir: {}",
+                                fname
+                            );
+                            if let Err(e) = fs::remove_file(direntry.path()) {
+                                warn!("could not remove expired quarantined file {}: {}", fname, e);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("could not get the age of quarantined file {}: {}", fname, e);
+                        }
+                    }
+                }
             } else if is_ephemeral_file(&fname) {
-                // Delete any old ephemeral files
-                trace!("deleting old ephemeral file in timeline dir: {}", fname);
+                // Old ephemeral files are removed after the scan
+                let metadata = direntry.metadata()?;
+                orphaned_ephemeral_files.add(metadata.len(), metadata.modified().ok());
+                orphaned_ephemeral_paths.push(direntry.path());
+            } else if fname.ends_with(".temp") {
+                // DeltaLayerWriter and ImageLayerWriter write under a temporary
+                // name and rename the file when complete, so this is a partially
+                // written layer file left behind by a crash. Usually these are
+                // removed at startup already, but not if the timeline was
+                // attached later.
+                info!("removing temp layer file in timeline dir: {}", fname);
                 fs::remove_file(direntry.path())?;
             } else {
                 warn!("unrecognized filename in timeline dir: {}", fname);
             }
         }
 
+        // Compaction deletes the layers it replaced after the new layers are
+        // in place. If we crashed in between, set the old ones aside, so that
+        // they are not read, compacted or uploaded again.
+        for layer in superseded_delta_layers(&layers) {
+            let path = layer.local_path().unwrap();
+            warn!(
+                "found delta layer {} superseded by compacted layers on timeline {}",
+                layer.filename().display(),
+                self.timeline_id
+            );
+            let size = path.metadata()?.len();
+            layers.remove_historic(layer);
+            quarantine_file(&path, "superseded layer", self.conf.max_quarantined_files)?;
+            total_physical_size -= size;
+            num_layers -= 1;
+        }
+
+        if orphaned_ephemeral_files.num_files > 0 {
+            // More than a checkpoint distance means that several in-memory
+            // layers were waiting to be flushed when the pageserver crashed.
+            if orphaned_ephemeral_files.total_bytes > self.get_checkpoint_distance() {
+                warn!(
+                    "removing orphaned ephemeral files in timeline dir: {}",
+                    orphaned_ephemeral_files
+                );
+            } else {
+                info!(
+                    "removing orphaned ephemeral files in timeline dir: {}",
+                    orphaned_ephemeral_files
+                );
+            }
+            for path in orphaned_ephemeral_paths {
+                trace!("deleting old ephemeral file {}", path.display());
+                fs::remove_file(path)?;
+            }
+        }
+
         layers.next_open_layer_at = Some(Lsn(disk_consistent_lsn.0) + 1);
+        self.prev_record_lsns
+            .lock()
+            .unwrap()
+            .load(disk_consistent_lsn)?;
+        if num_layers > 0 {
+            self.has_local_layers.store(true, AtomicOrdering::Release);
+        }
 
         info!(
             "loaded layer map with {} layers at {}, total physical size: {}",
@@ -779,6 +1832,50 @@ impl LayeredTimeline {
         );
         self.current_physical_size_gauge.set(total_physical_size);
 
+        Ok(orphaned_ephemeral_files)
+    }
+
+    ///
+    /// Write all the layer files of the timeline, and its metadata, to 'writer'
+    /// in the format described in [`layer_transfer`], for importing them on
+    /// another pageserver with [`LayeredRepository::import_layers`].
+    ///
+    /// Only the data on disk is exported, call checkpoint() first to include
+    /// the recent changes. Compaction and GC are blocked while the export
+    /// runs, so that the exported layer files are not removed. Flushing can
+    /// go on, the files it creates are just not included.
+    ///
+    /// [`LayeredRepository::import_layers`]: super::LayeredRepository::import_layers
+    ///
+    pub fn export_layers<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+
+        // The metadata on disk matches the layer map, as long as we hold
+        // 'layer_flush_lock'. Hold it only to pick the files to export.
+        let (layers, metadata) = {
+            let _layer_flush_lock = self.layer_flush_lock.lock();
+            let metadata_path = metadata_path(self.conf, self.timeline_id, self.tenant_id);
+            let metadata = fs::read(&metadata_path)
+                .with_context(|| format!("failed to read {}", metadata_path.display()))?;
+            (self.layers.snapshot(), metadata)
+        };
+
+        layer_transfer::write_header(writer)?;
+        let mut num_layers = 0;
+        for layer in layers.iter_historic_layers() {
+            let path = layer.local_path().with_context(|| {
+                format!("layer {} is not on local disk", layer.filename().display())
+            })?;
+            layer_transfer::write_file(writer, &layer.filename().to_string_lossy(), &path)?;
+            num_layers += 1;
+        }
+        layer_transfer::write_file_contents(writer, METADATA_FILE_NAME, &metadata)?;
+        layer_transfer::write_end(writer)?;
+
+        info!(
+            "exported {} layers of timeline {}",
+            num_layers, self.timeline_id
+        );
         Ok(())
     }
 
@@ -835,102 +1932,691 @@ impl LayeredTimeline {
     }
 
     ///
-    /// Get a handle to a Layer for reading.
+    /// Like [`Timeline::get`], but gives up with a [`GetTimeoutError`] if the
+    /// value cannot be reconstructed before 'deadline'. The deadline is checked
+    /// at each step of the layer traversal and before WAL redo, so the cost for
+    /// reads that are served quickly is negligible.
     ///
-    /// The returned Layer might be from an ancestor timeline, if the
-    /// segment hasn't been updated on this timeline yet.
+    pub fn get_with_deadline(&self, key: Key, lsn: Lsn, deadline: Instant) -> Result<Bytes> {
+        self.access_tracker.record(key);
+        self.get_internal(key, lsn, Some(deadline))
+    }
+
     ///
-    /// This function takes the current timeline's locked LayerMap as an argument,
-    /// so callers can avoid potential race conditions.
-    fn get_reconstruct_data(
+    /// Like [`Timeline::get`], but for clients that can tolerate slightly
+    /// stale data. If WAL up to 'lsn' hasn't been processed yet, but the last
+    /// record LSN is at most 'max_staleness' behind it, the value is read at
+    /// the last record LSN instead, without waiting. Otherwise, waits until
+    /// the last record LSN is within 'max_staleness' of 'lsn'.
+    ///
+    /// Returns the value and the LSN it was read at. Never reads at an LSN
+    /// older than the GC cutoff.
+    ///
+    pub fn get_bounded_staleness(
         &self,
         key: Key,
-        request_lsn: Lsn,
-        reconstruct_state: &mut ValueReconstructState,
-    ) -> anyhow::Result<()> {
-        // Start from the current timeline.
-        let mut timeline_owned;
-        let mut timeline = self;
-
-        // For debugging purposes, collect the path of layers that we traversed
-        // through. It's included in the error message if we fail to find the key.
-        let mut traversal_path: Vec<(ValueReconstructResult, Lsn, Arc<dyn Layer>)> = Vec::new();
-
-        let cached_lsn = if let Some((cached_lsn, _)) = &reconstruct_state.img {
-            *cached_lsn
-        } else {
-            Lsn(0)
-        };
+        lsn: Lsn,
+        max_staleness: Lsn,
+    ) -> Result<(Bytes, Lsn)> {
+        let oldest_acceptable_lsn = Lsn(lsn.0.saturating_sub(max_staleness.0));
+        if self.get_last_record_lsn() < oldest_acceptable_lsn {
+            self.wait_lsn(oldest_acceptable_lsn)?;
+        }
+        let read_lsn = min(lsn, self.get_last_record_lsn());
 
-        // 'prev_lsn' tracks the last LSN that we were at in our search. It's used
-        // to check that each iteration make some progress, to break infinite
-        // looping if something goes wrong.
-        let mut prev_lsn = Lsn(u64::MAX);
+        let latest_gc_cutoff_lsn = *self.get_latest_gc_cutoff_lsn();
+        if read_lsn < latest_gc_cutoff_lsn {
+            return Err(LsnGarbageCollected {
+                requested: read_lsn,
+                gc_cutoff: latest_gc_cutoff_lsn,
+            }
+            .into());
+        }
 
-        let mut result = ValueReconstructResult::Continue;
-        let mut cont_lsn = Lsn(request_lsn.0 + 1);
+        self.access_tracker.record(key);
+        let value = self.get_internal(key, read_lsn, None)?;
+        Ok((value, read_lsn))
+    }
 
-        'outer: loop {
-            // The function should have updated 'state'
-            //info!("CALLED for {} at {}: {:?} with {} records, cached {}", key, cont_lsn, result, reconstruct_state.records.len(), cached_lsn);
-            match result {
-                ValueReconstructResult::Complete => return Ok(()),
-                ValueReconstructResult::Continue => {
-                    // If we reached an earlier cached page image, we're done.
-                    if cont_lsn == cached_lsn + 1 {
-                        self.materialized_page_cache_hit_counter.inc_by(1);
-                        return Ok(());
-                    }
-                    if prev_lsn <= cont_lsn {
-                        // Didn't make any progress in last iteration. Error out to avoid
-                        // getting stuck in the loop.
-                        return layer_traversal_error(format!(
-                            "could not find layer with more data for key {} at LSN {}, request LSN {}, ancestor {}",
-                            key,
-                            Lsn(cont_lsn.0 - 1),
-                            request_lsn,
-                            timeline.ancestor_lsn
-                        ), traversal_path);
-                    }
-                    prev_lsn = cont_lsn;
+    ///
+    /// Return the newest stored image of 'key', with the LSN it was taken at,
+    /// without applying any deltas on top of it. For clients that prefer a
+    /// stale value to paying for WAL redo, like previews.
+    ///
+    /// The image comes from an image layer or the materialized page cache, of
+    /// this timeline or of its ancestors. Returns None if there's no image of
+    /// the key. Never reads delta layers or calls the WAL redo manager.
+    ///
+    pub fn get_latest_image(&self, key: Key) -> Result<Option<(Lsn, Bytes)>> {
+        let mut timeline_owned;
+        let mut timeline = self;
+        let mut lsn = Lsn(u64::MAX);
+        loop {
+            let layers = timeline.layers.snapshot();
+            let layer_img = match layers.find_latest_image(key, lsn) {
+                Some(layer) => {
+                    let mut state = ValueReconstructState {
+                        records: Vec::new(),
+                        img: None,
+                    };
+                    layer.get_value_reconstruct_data(key, layer.get_lsn_range(), &mut state)?;
+                    state.img
                 }
-                ValueReconstructResult::Missing => {
-                    return layer_traversal_error(
-                        format!(
-                            "could not find data for key {} at LSN {}, for request at LSN {}",
-                            key, cont_lsn, request_lsn
-                        ),
-                        traversal_path,
-                    );
+                None => None,
+            };
+            let cached_img = timeline.lookup_cached_page(&key, lsn);
+
+            let img = match (layer_img, cached_img) {
+                (Some(layer_img), Some(cached_img)) => {
+                    Some(max_by_key(layer_img, cached_img, |(img_lsn, _)| *img_lsn))
                 }
+                (layer_img, cached_img) => layer_img.or(cached_img),
+            };
+            // Anything on this timeline is newer than what the ancestor has
+            if img.is_some() || timeline.ancestor_timeline.is_none() {
+                return Ok(img);
             }
+            lsn = timeline.ancestor_lsn;
+            timeline_owned = timeline.get_ancestor_timeline()?;
+            timeline = &*timeline_owned;
+        }
+    }
 
-            // Recurse into ancestor if needed
-            if Lsn(cont_lsn.0 - 1) <= timeline.ancestor_lsn {
-                trace!(
-                    "going into ancestor {}, cont_lsn is {}",
-                    timeline.ancestor_lsn,
-                    cont_lsn
-                );
-                let ancestor = timeline.get_ancestor_timeline()?;
-                timeline_owned = ancestor;
-                timeline = &*timeline_owned;
-                prev_lsn = Lsn(u64::MAX);
-                continue;
-            }
+    ///
+    /// The earliest LSN that can currently be read, or branched from, on this
+    /// timeline: the later of initdb_lsn and latest_gc_cutoff_lsn. Reads and
+    /// branches at older LSNs are refused by check_lsn_is_in_scope().
+    ///
+    /// The floor never moves backwards. It is raised only by GC, which moves
+    /// latest_gc_cutoff_lsn up to the older of the horizon and PITR cutoffs in
+    /// 'gc_info'; until the next GC run, those cutoffs don't affect the floor.
+    /// Branch points in 'retain_lsns' don't lower it either: GC keeps the layers
+    /// that the child branches need, but they are only readable through the
+    /// child branches, not at those LSNs on this timeline.
+    ///
+    pub fn effective_gc_floor(&self) -> Lsn {
+        max(self.initdb_lsn, *self.get_latest_gc_cutoff_lsn())
+    }
 
-            let layers = timeline.layers.read().unwrap();
+    ///
+    /// Read the value of 'key' at the follower LSN.
+    ///
+    /// Read replicas that want a consistent snapshot, rather than the latest
+    /// data, read at the follower LSN. It only changes when
+    /// [`Self::advance_follower`] is called, so the replicas see the same data
+    /// between refreshes, and never have to wait for WAL to arrive.
+    ///
+    pub fn get_at_follower(&self, key: Key) -> Result<Bytes> {
+        let lsn = self
+            .get_follower_lsn()
+            .with_context(|| format!("timeline {} has no follower LSN", self.timeline_id))?;
+        self.get(key, lsn)
+    }
 
-            // Check the open and frozen in-memory layers first, in order from newest
-            // to oldest.
-            if let Some(open_layer) = &layers.open_layer {
-                let start_lsn = open_layer.get_lsn_range().start;
+    pub fn get_follower_lsn(&self) -> Option<Lsn> {
+        *self.follower_lsn.lock().unwrap()
+    }
+
+    ///
+    /// Move the follower LSN to 'lsn'. It never moves backwards, and must be
+    /// within the data the timeline has: not beyond the last record LSN, and
+    /// not below the GC cutoff. GC doesn't remove the data at the follower
+    /// LSN afterwards, until it's advanced again.
+    ///
+    pub fn advance_follower(&self, lsn: Lsn) -> Result<()> {
+        let mut follower_lsn = self.follower_lsn.lock().unwrap();
+        if let Some(current) = *follower_lsn {
+            ensure!(
+                lsn >= current,
+                "cannot move the follower LSN backwards from {current} to {lsn}"
+            );
+        }
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "follower LSN {lsn} is beyond the last record LSN {last_record_lsn}"
+        );
+        self.check_lsn_is_in_scope(lsn, &self.get_latest_gc_cutoff_lsn())?;
+        *follower_lsn = Some(lsn);
+        Ok(())
+    }
+
+    ///
+    /// Read the value of 'key' as of the point in time 'timestamp', for
+    /// time-travel queries. The timestamp is mapped to an LSN with
+    /// [`DatadirTimeline::find_lsn_for_timestamp`], based on the commit
+    /// timestamps in the CLOG, so it is just as approximate.
+    ///
+    /// Returns the value and the LSN it was read at.
+    ///
+    pub fn get_at_timestamp(&self, key: Key, timestamp: SystemTime) -> Result<(Bytes, Lsn)> {
+        let lsn = match self.find_lsn_for_timestamp(to_pg_timestamp(timestamp))? {
+            LsnForTimestamp::Present(lsn) => lsn,
+            LsnForTimestamp::Future(_) => bail!(
+                "timestamp {} is in the future of available WAL",
+                humantime::format_rfc3339(timestamp)
+            ),
+            LsnForTimestamp::Past(_) => bail!(
+                "timestamp {} is before the oldest available commit",
+                humantime::format_rfc3339(timestamp)
+            ),
+            LsnForTimestamp::NoData(_) => bail!(
+                "cannot find LSN for timestamp {}, no commit timestamps available",
+                humantime::format_rfc3339(timestamp)
+            ),
+        };
+        self.check_lsn_is_in_scope(lsn, &self.get_latest_gc_cutoff_lsn())?;
+
+        let value = self.get(key, lsn)?;
+        Ok((value, lsn))
+    }
+
+    ///
+    /// Return up to 'n' key ranges that have been read the most recently, with
+    /// their sampled read counts. For debugging.
+    ///
+    pub fn hot_key_ranges(&self, n: usize) -> Vec<(Range<Key>, u64)> {
+        self.access_tracker.hottest(n)
+    }
+
+    fn get_internal(&self, key: Key, lsn: Lsn, deadline: Option<Instant>) -> Result<Bytes> {
+        let reconstruct_state = self.collect_reconstruct_data(key, lsn, deadline, None, true)?;
+
+        self.reconstruct_time_histo
+            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
+    }
+
+    ///
+    /// Like get_internal(), but looks up the layers of this timeline in the
+    /// given snapshot of the layer map, instead of taking a new snapshot.
+    ///
+    /// For callers that read many keys, like image layer creation: the keys are
+    /// all read from the same set of layers, and the reads don't need to take
+    /// the layer map lock one by one.
+    ///
+    fn get_in_snapshot(&self, key: Key, lsn: Lsn, layers: &LayerMapSnapshot) -> Result<Bytes> {
+        let reconstruct_state =
+            self.collect_reconstruct_data(key, lsn, None, Some(layers), true)?;
+
+        self.reconstruct_time_histo
+            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
+    }
+
+    ///
+    /// Like [`Timeline::get`], for many keys at once. The values that need WAL
+    /// redo are reconstructed in batches of 'wal_redo_batch_size', with one
+    /// round trip to the WAL redo process per batch. Values that need more WAL
+    /// than 'walredo_max_records_size' are reconstructed on their own.
+    ///
+    /// Returns the results in the same order as 'keys'. A failure to read one
+    /// key doesn't fail the others.
+    ///
+    /// The materialized page cache is not consulted: a scan over many keys
+    /// mostly misses it. The values reconstructed with WAL redo are
+    /// remembered in the cache only if 'memorize' is true, so that a one-off
+    /// scan doesn't evict the pages that single-key reads keep using.
+    ///
+    pub fn get_multi(&self, keys: &[Key], lsn: Lsn, memorize: bool) -> Vec<Result<Bytes>> {
+        let batch_size = self.conf.wal_redo_batch_size.max(1);
+
+        let mut results: Vec<Option<Result<Bytes>>> = keys.iter().map(|_| None).collect();
+        let mut batch = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            self.access_tracker.record(*key);
+            let redo_request = self
+                .collect_reconstruct_data(*key, lsn, None, None, false)
+                .and_then(|data| self.prepare_reconstruct(*key, lsn, data));
+            match redo_request {
+                Ok(Reconstruct::Done(img)) => results[i] = Some(Ok(img)),
+                Ok(Reconstruct::NeedsRedo(req)) => {
+                    if self.exceeds_walredo_max_records_size(&req) {
+                        let last_rec_lsn = req.records.last().unwrap().0;
+                        results[i] = Some(self.request_redo_checked(req).map(|img| {
+                            if memorize {
+                                self.memorize_reconstructed_page(*key, last_rec_lsn, &img);
+                            }
+                            img
+                        }));
+                    } else if let Err(err) = self.check_redo_circuit(&req) {
+                        results[i] = Some(Err(err));
+                    } else {
+                        batch.push((i, req));
+                    }
+                }
+                Err(err) => results[i] = Some(Err(err)),
+            }
+
+            if !batch.is_empty() && (batch.len() >= batch_size || i == keys.len() - 1) {
+                let (indexes, requests): (Vec<_>, Vec<_>) = batch.drain(..).unzip();
+                let inputs: Vec<_> = requests.iter().map(RedoInputs::of).collect();
+                let images = self
+                    .reconstruct_time_histo
+                    .observe_closure_duration(|| self.walredo_mgr.request_redo_batch(requests));
+                for ((i, inputs), img) in indexes.into_iter().zip(inputs).zip(images) {
+                    let img = img.map_err(anyhow::Error::from);
+                    self.record_redo_outcome(keys[i], inputs, img.as_ref().map(|_| ()));
+                    let last_rec_lsn = inputs.last_rec_lsn;
+                    results[i] = Some(img.map(|img| {
+                        if memorize {
+                            self.memorize_reconstructed_page(keys[i], last_rec_lsn, &img);
+                        }
+                        img
+                    }));
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every key has a result"))
+            .collect()
+    }
+
+    ///
+    /// Collect the page image and WAL records needed to reconstruct the value
+    /// of 'key' at 'lsn'. The materialized page cache is checked first, if
+    /// 'use_cache' is true.
+    ///
+    fn collect_reconstruct_data(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        deadline: Option<Instant>,
+        layers: Option<&LayerMapSnapshot>,
+        use_cache: bool,
+    ) -> Result<ValueReconstructState> {
+        self.check_lsn_not_garbage_collected(lsn)?;
+
+        // Check the page cache. We will get back the most recent page with lsn <= `lsn`.
+        // The cached image can be returned directly if there is no WAL between the cached image
+        // and requested LSN. The cached image can also be used to reduce the amount of WAL needed
+        // for redo.
+        let cached_page_img = match use_cache
+            .then(|| self.lookup_cached_page(&key, lsn))
+            .flatten()
+        {
+            Some((cached_lsn, cached_img)) => {
+                match cached_lsn.cmp(&lsn) {
+                    Ordering::Less => {} // there might be WAL between cached_lsn and lsn, we need to check
+                    Ordering::Equal => {
+                        // exact LSN match, the image is all we need
+                        return Ok(ValueReconstructState {
+                            records: Vec::new(),
+                            img: Some((cached_lsn, cached_img)),
+                        });
+                    }
+                    Ordering::Greater => panic!(), // the returned lsn should never be after the requested lsn
+                }
+                Some((cached_lsn, cached_img))
+            }
+            None => None,
+        };
+
+        let mut reconstruct_state = ValueReconstructState {
+            records: Vec::new(),
+            img: cached_page_img,
+        };
+
+        if let Err(err) =
+            self.get_reconstruct_data(key, lsn, &mut reconstruct_state, deadline, layers)
+        {
+            // GC might have advanced past 'lsn' and removed the layers we needed
+            // while we were traversing. Report that, rather than the traversal error.
+            self.check_lsn_not_garbage_collected(lsn)?;
+            return Err(err);
+        }
+
+        if !reconstruct_state.records.is_empty() {
+            check_deadline(deadline, key, lsn)?;
+        }
+
+        Ok(reconstruct_state)
+    }
+
+    ///
+    /// Fail with [`LsnGarbageCollected`] if 'lsn' is below the GC cutoff.
+    ///
+    /// GC can advance the cutoff while a read is in progress. A read that then
+    /// fails because GC removed the data it needed is caught by checking again
+    /// after the failure.
+    ///
+    /// Callers must not hold a read guard on 'latest_gc_cutoff_lsn' while
+    /// reading: if GC is waiting for the write lock, taking the lock again
+    /// here would deadlock.
+    ///
+    fn check_lsn_not_garbage_collected(&self, lsn: Lsn) -> Result<()> {
+        let latest_gc_cutoff_lsn = *self.latest_gc_cutoff_lsn.read().unwrap();
+        if lsn < latest_gc_cutoff_lsn {
+            return Err(LsnGarbageCollected {
+                requested: lsn,
+                gc_cutoff: latest_gc_cutoff_lsn,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    ///
+    /// Find the keys in 'key_range' that have no value at 'lsn'.
+    ///
+    /// Every key in the range is looked up, so the range must not span multiple
+    /// relations. Note that deletions are currently not recorded in the layers
+    /// (see InMemoryLayer::put_tombstone), so a deleted key still has its last
+    /// value, and is not reported. All reported keys have never been written.
+    ///
+    pub fn missing_keys(&self, key_range: Range<Key>, lsn: Lsn) -> Result<KeySpace> {
+        ensure!(
+            key_range_size(&key_range) != u32::MAX,
+            "key range {}..{} is too large to check for missing keys",
+            key_range.start,
+            key_range.end
+        );
+
+        let mut missing = KeySpaceAccum::new();
+        let mut key = key_range.start;
+        while key < key_range.end {
+            let mut reconstruct_state = ValueReconstructState {
+                records: Vec::new(),
+                img: None,
+            };
+            match self.get_reconstruct_data(key, lsn, &mut reconstruct_state, None, None) {
+                Ok(()) => {}
+                Err(err) if err.downcast_ref::<KeyNotFoundError>().is_some() => {
+                    missing.add_key(key)
+                }
+                Err(err) => return Err(err),
+            }
+            key = key.next();
+        }
+
+        Ok(missing.to_keyspace())
+    }
+
+    ///
+    /// List the versions of 'key' with LSNs in 'lsn_range', in LSN order,
+    /// with the ID of the timeline that stores each one. For debugging.
+    ///
+    /// Versions inherited from ancestors are included up to the branch
+    /// point. Every layer that covers the key is read in full, so this is
+    /// slow. A key that's been materialized into an image layer is reported
+    /// at the image layer's LSN as well as at the versions it was built from,
+    /// if those haven't been garbage collected yet.
+    ///
+    pub fn key_history(
+        &self,
+        key: Key,
+        lsn_range: Range<Lsn>,
+    ) -> Result<Vec<(Lsn, ValueKind, ZTimelineId)>> {
+        let mut history = Vec::new();
+        let mut lsn_range = lsn_range;
+        let mut timeline_owned;
+        let mut timeline = self;
+        loop {
+            let layers = timeline.layers.read().unwrap();
+            let in_memory_layers = layers
+                .open_layer
+                .iter()
+                .chain(layers.frozen_layers.iter())
+                .map(|l| Arc::clone(l) as Arc<dyn Layer>);
+            let candidates: Vec<Arc<dyn Layer>> = layers
+                .iter_historic_layers()
+                .cloned()
+                .chain(in_memory_layers)
+                .filter(|l| {
+                    l.get_key_range().contains(&key)
+                        && range_overlaps(&l.get_lsn_range(), &lsn_range)
+                })
+                .collect();
+            drop(layers);
+
+            for layer in candidates {
+                for item in layer.iter() {
+                    let (entry_key, lsn, value) = item.with_context(|| {
+                        format!("failed to read layer {}", layer.filename().display())
+                    })?;
+                    if entry_key != key || !lsn_range.contains(&lsn) {
+                        continue;
+                    }
+                    let kind = match value {
+                        Value::Image(_) => ValueKind::Image,
+                        Value::WalRecord(rec) => ValueKind::WalRecord {
+                            will_init: rec.will_init(),
+                        },
+                    };
+                    history.push((lsn, kind, timeline.timeline_id));
+                }
+            }
+
+            // Continue in the ancestor, which is visible up to the branch point
+            let branch_end = Lsn(timeline.ancestor_lsn.0 + 1);
+            if timeline.ancestor_timeline.is_none() || lsn_range.start >= branch_end {
+                break;
+            }
+            lsn_range = lsn_range.start..min(lsn_range.end, branch_end);
+            timeline_owned = timeline.get_ancestor_timeline()?;
+            timeline = &*timeline_owned;
+        }
+
+        history.sort_by_key(|(lsn, _, _)| *lsn);
+        Ok(history)
+    }
+
+    ///
+    /// Collect the versions of 'key' that reading it at 'lsn' would start
+    /// from, without reconstructing it. For debugging reads that fail because
+    /// no base image or initializing WAL record was found.
+    ///
+    /// The materialized page cache is not consulted.
+    ///
+    pub fn record_chain(&self, key: Key, lsn: Lsn) -> Result<RecordChain> {
+        let mut reconstruct_state = ValueReconstructState {
+            records: Vec::new(),
+            img: None,
+        };
+        self.get_reconstruct_data(key, lsn, &mut reconstruct_state, None, None)?;
+        reconstruct_state.records.reverse();
+        Ok(RecordChain::of(
+            reconstruct_state.img.as_ref(),
+            &reconstruct_state.records,
+        ))
+    }
+
+    ///
+    /// Check that the timeline can actually serve data, by reconstructing
+    /// up to 'sample_size' keys, spread evenly over the keyspace at the
+    /// last record LSN.
+    ///
+    /// This doesn't modify the timeline; the cost is bounded by 'sample_size'
+    /// plus the cost of collecting the keyspace.
+    ///
+    pub fn self_check(&self, sample_size: usize) -> SelfCheckReport {
+        let lsn = self.get_last_record_lsn();
+        let mut report = SelfCheckReport {
+            lsn,
+            ..Default::default()
+        };
+
+        let keyspace = match self.collect_keyspace(lsn) {
+            Ok(keyspace) => keyspace,
+            Err(err) => {
+                report.add_failure(None, err.context("failed to collect keyspace"));
+                return report;
+            }
+        };
+
+        for key in sample_keys(&keyspace, sample_size) {
+            report.keys_checked += 1;
+            match self.get(key, lsn) {
+                Ok(_) => report.successes += 1,
+                Err(err) => report.add_failure(Some(key), err),
+            }
+        }
+
+        if report.failures > 0 {
+            warn!(
+                "self-check of timeline {} at {} failed for {} of {} keys",
+                self.timeline_id, lsn, report.failures, report.keys_checked
+            );
+        }
+        report
+    }
+
+    ///
+    /// Get a handle to a Layer for reading.
+    ///
+    /// The returned Layer might be from an ancestor timeline, if the
+    /// segment hasn't been updated on this timeline yet.
+    ///
+    /// The layers are looked up in a snapshot of each timeline's layer map, taken
+    /// when the search enters the timeline, so the set of layers stays consistent
+    /// even if compaction replaces some of them meanwhile. If 'snapshot' is given,
+    /// it is used for this timeline instead of taking a new one.
+    ///
+    /// The search stops at the newest page image or WAL record that initializes
+    /// the page ('will_init'), whichever comes first. Older layers, including
+    /// image layers, are only read if no such version is found. This is not
+    /// configurable: reading the older image as well would never be needed to
+    /// reconstruct the page, so there is no tenant option to turn it off.
+    fn get_reconstruct_data(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        reconstruct_state: &mut ValueReconstructState,
+        deadline: Option<Instant>,
+        snapshot: Option<&LayerMapSnapshot>,
+    ) -> anyhow::Result<()> {
+        // Start from the current timeline.
+        let mut timeline_owned;
+        let mut timeline = self;
+        let mut layers: LayerMapSnapshot = match snapshot {
+            Some(snapshot) => Arc::clone(snapshot),
+            None => self.layers.snapshot(),
+        };
+
+        // For debugging purposes, collect the path of layers that we traversed
+        // through. It's included in the error message if we fail to find the key.
+        let mut traversal_path = TraversalPath::new(self.conf.max_traversal_path_len);
+
+        // The timelines we have recursed into, starting from this one. Bounded by
+        // 'max_ancestor_depth' to protect against pathologically deep branch chains.
+        let mut ancestor_chain = vec![self.timeline_id];
+
+        let cached_lsn = if let Some((cached_lsn, _)) = &reconstruct_state.img {
+            *cached_lsn
+        } else {
+            Lsn(0)
+        };
+
+        // 'prev_lsn' tracks the last LSN that we were at in our search. It's used
+        // to check that each iteration make some progress, to break infinite
+        // looping if something goes wrong.
+        let mut prev_lsn = Lsn(u64::MAX);
+
+        let mut result = ValueReconstructResult::Continue;
+        let mut cont_lsn = Lsn(request_lsn.0 + 1);
+
+        'outer: loop {
+            // The function should have updated 'state'
+            //info!("CALLED for {} at {}: {:?} with {} records, cached {}", key, cont_lsn, result, reconstruct_state.records.len(), cached_lsn);
+            match result {
+                ValueReconstructResult::Complete => {
+                    // The layer found a page image, or a WAL record that
+                    // initializes the page. Everything below it, in older
+                    // layers of this timeline or in the ancestors, is
+                    // superseded, so stop here without reading any of it.
+                    debug_assert!(
+                        reconstruct_state.img.is_some()
+                            || matches!(reconstruct_state.records.last(), Some((_, rec)) if rec.will_init()),
+                        "layer reported a complete value for key {} without an image or a will_init record",
+                        key
+                    );
+                    self.ancestor_hops_histo
+                        .observe((ancestor_chain.len() - 1) as f64);
+                    return Ok(());
+                }
+                ValueReconstructResult::Continue => {
+                    // If we reached an earlier cached page image, we're done.
+                    if cont_lsn == cached_lsn + 1 {
+                        self.materialized_page_cache_hit_counter.inc_by(1);
+                        self.ancestor_hops_histo
+                            .observe((ancestor_chain.len() - 1) as f64);
+                        return Ok(());
+                    }
+                    if prev_lsn <= cont_lsn {
+                        // Didn't make any progress in last iteration. Error out to avoid
+                        // getting stuck in the loop.
+                        return layer_traversal_error(format!(
+                            "could not find layer with more data for key {} at LSN {}, request LSN {}, ancestor {}",
+                            key,
+                            Lsn(cont_lsn.0 - 1),
+                            request_lsn,
+                            timeline.ancestor_lsn
+                        ), traversal_path);
+                    }
+                    prev_lsn = cont_lsn;
+                }
+                ValueReconstructResult::Missing => {
+                    return layer_traversal_error(
+                        KeyNotFoundError {
+                            key,
+                            cont_lsn,
+                            request_lsn,
+                            num_records: reconstruct_state.records.len(),
+                        },
+                        traversal_path,
+                    );
+                }
+            }
+
+            check_deadline(deadline, key, request_lsn)?;
+
+            // Recurse into ancestor if needed
+            if Lsn(cont_lsn.0 - 1) <= timeline.ancestor_lsn {
+                trace!(
+                    "going into ancestor {}, cont_lsn is {}",
+                    timeline.ancestor_lsn,
+                    cont_lsn
+                );
+                let ancestor = timeline.get_ancestor_timeline()?;
+                ancestor_chain.push(ancestor.timeline_id);
+                if ancestor_chain.len() - 1 > self.conf.max_ancestor_depth {
+                    bail!(
+                        "reading key {} at LSN {} exceeds the max ancestor depth of {}, timeline chain: {}",
+                        key,
+                        request_lsn,
+                        self.conf.max_ancestor_depth,
+                        ancestor_chain.iter().join(" -> ")
+                    );
+                }
+                timeline_owned = ancestor;
+                timeline = &*timeline_owned;
+                layers = timeline.layers.snapshot();
+                prev_lsn = Lsn(u64::MAX);
+                continue;
+            }
+
+            // A branch that hasn't had any writes of its own yet has nothing
+            // between the ancestor LSN and the request LSN. Go straight to the
+            // ancestor, without searching the (empty) layer map.
+            if !timeline.has_local_layers.load(AtomicOrdering::Acquire)
+                && timeline.ancestor_timeline.is_some()
+            {
+                result = ValueReconstructResult::Continue;
+                cont_lsn = Lsn(timeline.ancestor_lsn.0 + 1);
+                continue;
+            }
+
+            // Check the open and frozen in-memory layers first, in order from newest
+            // to oldest.
+            if let Some(open_layer) = &layers.open_layer {
+                let start_lsn = open_layer.get_lsn_range().start;
                 if cont_lsn > start_lsn {
                     //info!("CHECKING for {} at {} on open layer {}", key, cont_lsn, open_layer.filename().display());
                     // Get all the data needed to reconstruct the page version from this layer.
                     // But if we have an older cached page image, no need to go past that.
                     let lsn_floor = max(cached_lsn + 1, start_lsn);
-                    result = open_layer.get_value_reconstruct_data(
+                    result = read_layer(
+                        open_layer.as_ref(),
                         key,
                         lsn_floor..cont_lsn,
                         reconstruct_state,
@@ -945,7 +2631,8 @@ impl LayeredTimeline {
                 if cont_lsn > start_lsn {
                     //info!("CHECKING for {} at {} on frozen layer {}", key, cont_lsn, frozen_layer.filename().display());
                     let lsn_floor = max(cached_lsn + 1, start_lsn);
-                    result = frozen_layer.get_value_reconstruct_data(
+                    result = read_layer(
+                        frozen_layer.as_ref(),
                         key,
                         lsn_floor..cont_lsn,
                         reconstruct_state,
@@ -960,11 +2647,7 @@ impl LayeredTimeline {
                 //info!("CHECKING for {} at {} on historic layer {}", key, cont_lsn, layer.filename().display());
 
                 let lsn_floor = max(cached_lsn + 1, lsn_floor);
-                result = layer.get_value_reconstruct_data(
-                    key,
-                    lsn_floor..cont_lsn,
-                    reconstruct_state,
-                )?;
+                result = read_layer(layer.as_ref(), key, lsn_floor..cont_lsn, reconstruct_state)?;
                 cont_lsn = lsn_floor;
                 traversal_path.push((result, cont_lsn, layer));
             } else if timeline.ancestor_timeline.is_some() {
@@ -979,6 +2662,9 @@ impl LayeredTimeline {
     }
 
     fn lookup_cached_page(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Bytes)> {
+        if !self.get_materialized_cache_enabled() {
+            return None;
+        }
         let cache = page_cache::get();
 
         // FIXME: It's pointless to check the cache for things that are not 8kB pages.
@@ -1012,13 +2698,383 @@ impl LayeredTimeline {
     }
 
     ///
-    /// Get a handle to the latest layer for appending.
+    /// Update [`LayeredTimeline::last_received_wal`] with the last message
+    /// received by the WAL receiver.
     ///
-    fn get_layer_for_write(&self, lsn: Lsn) -> anyhow::Result<Arc<InMemoryLayer>> {
+    /// The received LSN and time are also sampled, at most once per
+    /// DURABILITY_SAMPLE_INTERVAL, and the time until disk_consistent_lsn
+    /// passes the LSN is recorded in 'pageserver_durability_latency_seconds'.
+    ///
+    pub fn record_received_wal(&self, info: WalReceiverInfo) {
+        let lsn = info.last_received_msg_lsn;
+        let received_at = info.last_received_msg_ts;
+        *self.last_received_wal.lock().unwrap() = Some(info);
+
+        if lsn <= self.get_disk_consistent_lsn() {
+            return;
+        }
+        let mut samples = self.durability_samples.lock().unwrap();
+        let take_sample = match samples.back() {
+            None => true,
+            Some(&(last_lsn, last_received_at)) => {
+                lsn > last_lsn
+                    && received_at >= last_received_at + DURABILITY_SAMPLE_INTERVAL.as_micros()
+                    && samples.len() < MAX_DURABILITY_SAMPLES
+            }
+        };
+        if take_sample {
+            samples.push_back((lsn, received_at));
+        }
+    }
+
+    ///
+    /// The LSN of the record before 'lsn', for starting a replica at 'lsn'.
+    ///
+    /// Known for the latest record, and for the points where the timeline was
+    /// flushed to disk. None for other LSNs, and for flushes that happened
+    /// before the first record after a restart.
+    ///
+    pub fn prev_record_lsn_at(&self, lsn: Lsn) -> Option<Lsn> {
+        let RecordLsn { last, prev } = self.last_record_lsn.load();
+        if lsn == last && prev != Lsn(0) {
+            return Some(prev);
+        }
+        self.prev_record_lsns.lock().unwrap().get(lsn)
+    }
+
+    /// Record the durability latency of the samples that 'disk_consistent_lsn'
+    /// has passed, see [`LayeredTimeline::record_received_wal`].
+    fn observe_durability_latency(&self, disk_consistent_lsn: Lsn) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let mut samples = self.durability_samples.lock().unwrap();
+        while let Some(&(lsn, received_at)) = samples.front() {
+            if lsn > disk_consistent_lsn {
+                break;
+            }
+            let latency = Duration::from_micros(now.saturating_sub(received_at) as u64);
+            self.durability_latency_histo.observe(latency.as_secs_f64());
+            samples.pop_front();
+        }
+    }
+
+    ///
+    /// Stop accepting new WAL, e.g. to check the timeline at a fixed LSN, or to
+    /// drain it before a migration. Until [`LayeredTimeline::resume_ingest`] is
+    /// called, writes fail with [`IngestPaused`], and the WAL receiver
+    /// disconnects and retries periodically. Reads and background tasks are not
+    /// affected.
+    ///
+    /// Waits for a record that is being written to finish, so that no record
+    /// is left half-written. Must not be called while holding a writer.
+    ///
+    pub fn pause_ingest(&self) {
+        let _write_guard = self.write_lock.lock().unwrap();
+        self.ingest_paused.store(true, AtomicOrdering::Relaxed);
+        info!("paused WAL ingestion at {}", self.get_last_record_lsn());
+    }
+
+    ///
+    /// Accept new WAL again after [`LayeredTimeline::pause_ingest`]. Ingestion
+    /// continues from the last record LSN.
+    ///
+    pub fn resume_ingest(&self) {
+        let _write_guard = self.write_lock.lock().unwrap();
+        self.ingest_paused.store(false, AtomicOrdering::Relaxed);
+        info!("resumed WAL ingestion at {}", self.get_last_record_lsn());
+    }
+
+    pub fn is_ingest_paused(&self) -> bool {
+        self.ingest_paused.load(AtomicOrdering::Relaxed)
+    }
+
+    ///
+    /// Discard everything after 'lsn', to roll back the ingestion of bad WAL.
+    ///
+    /// Layers that start after 'lsn' are removed, delta layers that straddle
+    /// it are rewritten without the newer versions, and the in-memory layers
+    /// are dropped. Then the last record LSN and disk_consistent_lsn are moved
+    /// back to 'lsn'. The previous record LSN at 'lsn' is not known, so it's
+    /// reset, like on a branch created in the middle of a timeline. When
+    /// ingestion is resumed, the WAL receiver streams WAL from 'lsn' again.
+    ///
+    /// WAL ingestion must be paused with [`LayeredTimeline::pause_ingest`]
+    /// first. Fails if 'lsn' is before the GC cutoff or the branch point, or
+    /// after the last record LSN. This doesn't check for child timelines that
+    /// branched after 'lsn', use [`LayeredRepository::truncate_timeline`].
+    ///
+    /// [`LayeredRepository::truncate_timeline`]: super::LayeredRepository::truncate_timeline
+    ///
+    pub fn truncate_to(&self, lsn: Lsn) -> Result<()> {
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+        let _layer_flush_lock = self.layer_flush_lock.lock();
+
+        ensure!(
+            self.is_ingest_paused(),
+            "WAL ingestion must be paused before truncating timeline {}",
+            self.timeline_id
+        );
+        ensure!(lsn.is_aligned(), "truncation LSN {} is not aligned", lsn);
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "cannot truncate timeline {} to {}, after its last record LSN {}",
+            self.timeline_id,
+            lsn,
+            last_record_lsn
+        );
+        ensure!(
+            lsn >= self.ancestor_lsn,
+            "cannot truncate timeline {} to {}, before its branch point {}",
+            self.timeline_id,
+            lsn,
+            self.ancestor_lsn
+        );
+        let latest_gc_cutoff_lsn = self.latest_gc_cutoff_lsn.read().unwrap();
+        ensure!(
+            lsn >= *latest_gc_cutoff_lsn,
+            "cannot truncate timeline {} to {}, before its GC cutoff {}",
+            self.timeline_id,
+            lsn,
+            *latest_gc_cutoff_lsn
+        );
+        info!(
+            "truncating timeline {} from {} to {}",
+            self.timeline_id, last_record_lsn, lsn
+        );
+
+        // Rewrite the delta layers that have versions on both sides of 'lsn'.
+        // Image layers cover a single LSN, so they're either kept or removed.
+        let end_lsn = Lsn(lsn.0 + 1);
+        let mut new_layers = Vec::new();
+        let mut old_layers = Vec::new();
+        let layers = self.layers.snapshot();
+        for l in layers.iter_historic_layers() {
+            let lsn_range = l.get_lsn_range();
+            if lsn_range.end <= end_lsn {
+                continue;
+            }
+            if lsn_range.start < end_lsn {
+                let key_range = l.get_key_range();
+                let mut writer = None;
+                for x in l.iter() {
+                    let (key, value_lsn, value) = x?;
+                    if value_lsn > lsn {
+                        continue;
+                    }
+                    let writer = match &mut writer {
+                        Some(writer) => writer,
+                        None => writer.insert(DeltaLayerWriter::new(
+                            self.conf,
+                            self.timeline_id,
+                            self.tenant_id,
+                            key_range.start,
+                            lsn_range.start..end_lsn,
+                        )?),
+                    };
+                    writer.put_value(key, value_lsn, value)?;
+                }
+                if let Some(writer) = writer {
+                    new_layers.push(writer.finish(key_range.end)?);
+                }
+            }
+            old_layers.push(Arc::clone(l));
+        }
+        drop(layers);
+
+        let mut layer_paths: Vec<PathBuf> = new_layers.iter().map(|l| l.path()).collect();
+        layer_paths.push(self.conf.timeline_path(&self.timeline_id, &self.tenant_id));
+        par_fsync::par_fsync(&layer_paths, self.conf.max_fsync_parallelism)?;
+        layer_paths.pop().unwrap();
+
+        // Save the metadata before the old layers are removed. If we crash in
+        // between, the layers after the new disk_consistent_lsn are set aside
+        // at startup, and the rewritten layers are in place already.
+        let metadata = TimelineMetadata::new(
+            lsn,
+            None,
+            self.ancestor_timeline
+                .as_ref()
+                .map(LayeredTimelineEntry::timeline_id),
+            self.ancestor_lsn,
+            *latest_gc_cutoff_lsn,
+            self.initdb_lsn,
+        );
+        drop(latest_gc_cutoff_lsn);
+        save_metadata(
+            self.conf,
+            self.timeline_id,
+            self.tenant_id,
+            &metadata,
+            false,
+        )?;
+
         let mut layers = self.layers.write().unwrap();
+        layers.open_layer = None;
+        layers.frozen_layers.clear();
+        layers.next_open_layer_at = Some(end_lsn);
+        for l in new_layers {
+            self.current_physical_size_gauge
+                .add(l.path().metadata()?.len());
+            layers.insert_historic(Arc::new(l));
+        }
+        let mut layer_paths_to_delete = HashSet::with_capacity(old_layers.len());
+        let mut doomed_layers = self.doomed_layers.lock().unwrap();
+        for l in old_layers {
+            if let Some(path) = l.local_path() {
+                layer_paths_to_delete.insert(path);
+            }
+            layers.remove_historic(Arc::clone(&l));
+            doomed_layers.push(l);
+        }
+        drop(doomed_layers);
+
+        self.disk_consistent_lsn.rewind(lsn);
+        self.last_record_lsn.rewind(RecordLsn {
+            last: lsn,
+            prev: Lsn(0),
+        });
+        self.last_record_gauge.set(lsn.0 as i64);
+        self.last_freeze_at.store(lsn);
+        drop(layers);
+        self.delete_unused_layers(DOOMED_LAYER_WAIT)?;
 
-        ensure!(lsn.is_aligned());
+        // Forget everything derived from the discarded WAL
+        *self.partitioning.lock().unwrap() = (KeyPartitioning::new(), Lsn(0));
+        {
+            let mut changed_keys = self.changed_keys.lock().unwrap();
+            changed_keys.keys.clear();
+            changed_keys.complete_since = lsn;
+        }
+        self.rel_size_cache
+            .write()
+            .unwrap()
+            .retain(|_, (cached_lsn, _)| *cached_lsn <= lsn);
+        page_cache::get().drop_materialized_pages_after(self.tenant_id, self.timeline_id, lsn);
+        self.durability_samples
+            .lock()
+            .unwrap()
+            .retain(|(sample_lsn, _)| *sample_lsn <= lsn);
+        self.prev_record_lsns.lock().unwrap().truncate(lsn)?;
+
+        if self.upload_layers.load(atomic::Ordering::Relaxed) {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                layer_paths.into_iter().collect(),
+                Some(metadata),
+            );
+            storage_sync::schedule_layer_delete(
+                self.tenant_id,
+                self.timeline_id,
+                layer_paths_to_delete,
+            );
+        }
+
+        if let Err(e) = self.init_logical_size() {
+            warn!(
+                "failed to recalculate logical size of timeline {} after truncation: {:?}",
+                self.timeline_id, e
+            );
+        }
+        Ok(())
+    }
+
+    ///
+    /// Check that there's at least 'min_free_disk_space' free on the disk,
+    /// before writing a new layer file. Fails with [`DiskSpaceLow`] if not.
+    ///
+    /// While the disk space is low, WAL ingestion is held back: writes fail,
+    /// and the WAL receiver disconnects and retries periodically. The free
+    /// space is checked again on each attempt.
+    ///
+    fn check_disk_space(&self) -> Result<()> {
+        let path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
+        match disk_space::check_space(&path, self.conf.min_free_disk_space) {
+            Ok(()) => {
+                if self.disk_space_low.swap(false, AtomicOrdering::Relaxed) {
+                    info!("disk space is available again, resuming WAL ingestion");
+                }
+                Ok(())
+            }
+            Err(e) => {
+                if e.is::<DiskSpaceLow>()
+                    && !self.disk_space_low.swap(true, AtomicOrdering::Relaxed)
+                {
+                    warn!("{e}, holding back WAL ingestion");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    ///
+    /// Fail with [`DiskSpaceLow`] if WAL ingestion is held back because the
+    /// disk is almost full. Called once before a write, or a batch of writes,
+    /// is applied, so that the disk space running low doesn't fail a batch
+    /// halfway through.
+    ///
+    fn check_ingest_disk_space(&self) -> Result<()> {
+        if self.disk_space_low.load(AtomicOrdering::Relaxed) {
+            self.check_disk_space()?;
+        }
+        Ok(())
+    }
+
+    /// Is WAL ingestion held back because the disk is almost full?
+    pub fn is_disk_space_low(&self) -> bool {
+        if self.disk_space_low.load(AtomicOrdering::Relaxed) {
+            let _ = self.check_disk_space();
+        }
+        self.disk_space_low.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Does this timeline have any layers of its own yet? See `has_local_layers`.
+    pub fn has_local_layers(&self) -> bool {
+        self.has_local_layers.load(AtomicOrdering::Acquire)
+    }
+
+    ///
+    /// Size of the open in-memory layer and of the frozen layers waiting to
+    /// be flushed. Only takes the read locks of the layer map and the layers,
+    /// the sizes are kept up to date by the layers. Returns None if there are
+    /// no in-memory layers.
+    ///
+    pub fn inmemory_layer_stat(&self) -> Result<Option<InMemoryLayerStat>> {
+        let layers = self.layers.read().unwrap();
+        if layers.open_layer.is_none() && layers.frozen_layers.is_empty() {
+            return Ok(None);
+        }
+        let open_layer_bytes = match &layers.open_layer {
+            Some(open_layer) => open_layer.size()?,
+            None => 0,
+        };
+        let mut frozen_bytes = 0;
+        for frozen_layer in &layers.frozen_layers {
+            frozen_bytes += frozen_layer.size()?;
+        }
+        Ok(Some(InMemoryLayerStat {
+            tenant_id: self.tenant_id,
+            timeline_id: self.timeline_id,
+            open_layer_bytes,
+            frozen_layers: layers.frozen_layers.len(),
+            frozen_bytes,
+        }))
+    }
 
+    ///
+    /// Get a handle to the latest layer for appending.
+    ///
+    fn get_layer_for_write(&self, lsn: Lsn) -> anyhow::Result<Arc<InMemoryLayer>> {
+        ensure!(lsn.is_aligned());
+        if self.is_ingest_paused() {
+            return Err(IngestPaused {
+                timeline_id: self.timeline_id,
+            }
+            .into());
+        }
         let last_record_lsn = self.get_last_record_lsn();
         ensure!(
             lsn > last_record_lsn,
@@ -1027,7 +3083,17 @@ impl LayeredTimeline {
             last_record_lsn,
         );
 
-        // Do we have a layer open for writing already?
+        // Do we have a layer open for writing already? Check that with just the
+        // read lock. Modifying the layer map would force a copy of it, if a reader
+        // holds a snapshot.
+        if let Some(open_layer) = &self.layers.read().unwrap().open_layer {
+            if open_layer.get_lsn_range().start > lsn {
+                bail!("unexpected open layer in the future");
+            }
+            return Ok(Arc::clone(open_layer));
+        }
+
+        let mut layers = self.layers.write().unwrap();
         let layer;
         if let Some(open_layer) = &layers.open_layer {
             if open_layer.get_lsn_range().start > lsn {
@@ -1048,7 +3114,10 @@ impl LayeredTimeline {
             let new_layer =
                 InMemoryLayer::create(self.conf, self.timeline_id, self.tenant_id, start_lsn)?;
             let layer_rc = Arc::new(new_layer);
+            self.inmemory_layers_created_counter.inc();
+            self.open_layer_start_lsn_gauge.set(start_lsn.0 as i64);
 
+            self.has_local_layers.store(true, AtomicOrdering::Release);
             layers.open_layer = Some(Arc::clone(&layer_rc));
             layers.next_open_layer_at = None;
 
@@ -1064,6 +3133,25 @@ impl LayeredTimeline {
         Ok(())
     }
 
+    ///
+    /// Insert several values into the open layer, looking it up only once.
+    /// The checks of get_layer_for_write() are done with the lowest LSN of
+    /// the batch, so that none of the values is written if any of them is
+    /// at or below the last record LSN.
+    ///
+    fn put_values(&self, values: &[(Key, Lsn, Value)]) -> Result<()> {
+        let min_lsn = match values.iter().map(|(_, lsn, _)| *lsn).min() {
+            Some(lsn) => lsn,
+            None => return Ok(()),
+        };
+        ensure!(
+            values.iter().all(|(_, lsn, _)| lsn.is_aligned()),
+            "unaligned LSN in batch of values"
+        );
+        let layer = self.get_layer_for_write(min_lsn)?;
+        layer.put_values(values)
+    }
+
     fn put_tombstone(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
         let layer = self.get_layer_for_write(lsn)?;
         layer.put_tombstone(key_range, lsn)?;
@@ -1071,11 +3159,76 @@ impl LayeredTimeline {
         Ok(())
     }
 
-    fn finish_write(&self, new_lsn: Lsn) {
-        assert!(new_lsn.is_aligned());
+    fn finish_write(&self, new_lsn: Lsn) -> anyhow::Result<()> {
+        // The caller holds 'write_lock', so the last record LSN cannot change
+        // between the check and the advance.
+        self.check_finish_write_lsn(new_lsn)?;
 
         self.last_record_gauge.set(new_lsn.0 as i64);
         self.last_record_lsn.advance(new_lsn);
+        Ok(())
+    }
+
+    fn check_finish_write_lsn(&self, new_lsn: Lsn) -> anyhow::Result<()> {
+        assert!(new_lsn.is_aligned());
+
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            new_lsn > last_record_lsn,
+            "cannot move last_record_lsn backwards or keep it in place (new_lsn={}, last_record_lsn={})",
+            new_lsn,
+            last_record_lsn,
+        );
+        Ok(())
+    }
+
+    ///
+    /// Make everything up to 'lsn' durable, e.g. before taking a backup at
+    /// 'lsn'. Returns once disk_consistent_lsn has reached 'lsn'.
+    ///
+    /// The open layer is frozen if it has data at or below 'lsn', and all the
+    /// frozen layers are flushed. Writes can continue meanwhile, they go to a
+    /// new open layer. 'lsn' must not be past the last record LSN. Calling
+    /// this again for the same or an older LSN does nothing.
+    ///
+    pub fn flush_to_lsn(&self, lsn: Lsn) -> Result<()> {
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "cannot flush timeline {} to {}, after its last record LSN {}",
+            self.timeline_id,
+            lsn,
+            last_record_lsn
+        );
+        if self.get_disk_consistent_lsn() >= lsn {
+            return Ok(());
+        }
+
+        {
+            let _write_guard = self.write_lock.lock().unwrap();
+            let open_layer_start = self
+                .layers
+                .read()
+                .unwrap()
+                .open_layer
+                .as_ref()
+                .map(|l| l.get_lsn_range().start);
+            if matches!(open_layer_start, Some(start) if start <= lsn) {
+                self.freeze_inmem_layer(true);
+            }
+        }
+        self.flush_frozen_layers(true)?;
+
+        // If no page was modified between the last flushed layer and 'lsn',
+        // there was nothing to freeze. All the data up to 'lsn' is on disk
+        // then, so just move disk_consistent_lsn forward.
+        if self.get_disk_consistent_lsn() < lsn {
+            let _layer_flush_lock = self.layer_flush_lock.lock();
+            if self.get_disk_consistent_lsn() < lsn {
+                self.update_disk_consistent_lsn(lsn, HashSet::new())?;
+            }
+        }
+        Ok(())
     }
 
     fn freeze_inmem_layer(&self, write_lock_held: bool) {
@@ -1090,8 +3243,20 @@ impl LayeredTimeline {
         if let Some(open_layer) = &layers.open_layer {
             let open_layer_rc = Arc::clone(open_layer);
             // Does this layer need freezing?
-            let end_lsn = Lsn(self.get_last_record_lsn().0 + 1);
+            let RecordLsn {
+                last: last_record_lsn,
+                prev: prev_record_lsn,
+            } = self.last_record_lsn.load();
+            let end_lsn = Lsn(last_record_lsn.0 + 1);
             open_layer.freeze(end_lsn);
+            // The prev-record LSN is not known after a restart, until the
+            // first new record
+            if prev_record_lsn != Lsn(0) {
+                self.prev_record_lsns
+                    .lock()
+                    .unwrap()
+                    .freeze_at(last_record_lsn, prev_record_lsn);
+            }
 
             // The layer is no longer open, update the layer map to reflect this.
             // We will replace it with on-disk historics below.
@@ -1099,6 +3264,9 @@ impl LayeredTimeline {
             layers.open_layer = None;
             layers.next_open_layer_at = Some(end_lsn);
             self.last_freeze_at.store(end_lsn);
+
+            self.open_layer_age_gauge.set(0.0);
+            self.open_layer_wal_bytes_gauge.set(0);
         }
         drop(layers);
     }
@@ -1115,22 +3283,32 @@ impl LayeredTimeline {
         let layers = self.layers.read().unwrap();
         if let Some(open_layer) = &layers.open_layer {
             let open_layer_size = open_layer.size()?;
+            let open_layer_entries = open_layer.num_entries();
             drop(layers);
             let last_freeze_at = self.last_freeze_at.load();
             let last_freeze_ts = *(self.last_freeze_ts.read().unwrap());
             let distance = last_lsn.widening_sub(last_freeze_at);
+            self.open_layer_age_gauge
+                .set(last_freeze_ts.elapsed().as_secs_f64());
+            self.open_layer_wal_bytes_gauge
+                .set(u64::try_from(distance).unwrap_or(0));
             // Checkpointing the open layer can be triggered by layer size or LSN range.
             // S3 has a 5 GB limit on the size of one upload (without multi-part upload), and
             // we want to stay below that with a big margin.  The LSN distance determines how
-            // much WAL the safekeepers need to store.
+            // much WAL the safekeepers need to store. The number of entries bounds the
+            // memory used by the layer's index, when there are many small values.
             if distance >= self.get_checkpoint_distance().into()
                 || open_layer_size > self.get_checkpoint_distance()
-                || (distance > 0 && last_freeze_ts.elapsed() >= self.get_checkpoint_timeout())
+                || open_layer_entries > self.get_checkpoint_max_entries()
+                || (distance > 0
+                    && last_freeze_ts.elapsed()
+                        >= self.maintenance_jitter.apply(self.get_checkpoint_timeout()))
             {
                 info!(
-                    "check_checkpoint_distance {}, layer size {}, elapsed since last flush {:?}",
+                    "check_checkpoint_distance {}, layer size {}, entries {}, elapsed since last flush {:?}",
                     distance,
                     open_layer_size,
+                    open_layer_entries,
                     last_freeze_ts.elapsed()
                 );
 
@@ -1138,6 +3316,12 @@ impl LayeredTimeline {
                 self.last_freeze_at.store(last_lsn);
                 *(self.last_freeze_ts.write().unwrap()) = Instant::now();
 
+                // In tests that need a deterministic order of events, flush
+                // the frozen layer right here.
+                if self.conf.synchronous_flush {
+                    return self.flush_frozen_layers(true);
+                }
+
                 // Launch a thread to flush the frozen layer to disk, unless
                 // a thread was already running. (If the thread was running
                 // at the time that we froze the layer, it must've seen the
@@ -1169,7 +3353,7 @@ impl LayeredTimeline {
     /// immediately instead.
     fn flush_frozen_layers(&self, wait: bool) -> Result<()> {
         let flush_lock_guard = if wait {
-            self.layer_flush_lock.lock().unwrap()
+            self.layer_flush_lock.lock()
         } else {
             match self.layer_flush_lock.try_lock() {
                 Ok(guard) => guard,
@@ -1180,12 +3364,41 @@ impl LayeredTimeline {
 
         let timer = self.flush_time_histo.start_timer();
 
+        // The metadata file is updated once for all the layers flushed in a batch,
+        // rather than after each layer. 'disk_consistent_lsn' only covers the layers
+        // that have been written and fsynced so far.
+        let mut batch_disk_consistent_lsn = None;
+        let mut batch_layer_paths = HashSet::new();
+
         loop {
             let layers = self.layers.read().unwrap();
             if let Some(frozen_layer) = layers.frozen_layers.front() {
                 let frozen_layer = Arc::clone(frozen_layer);
                 drop(layers); // to allow concurrent reads and writes
-                self.flush_frozen_layer(frozen_layer)?;
+                match self.flush_frozen_layer(frozen_layer) {
+                    Ok((disk_consistent_lsn, layer_paths)) => {
+                        batch_disk_consistent_lsn = Some(disk_consistent_lsn);
+                        batch_layer_paths.extend(layer_paths);
+                    }
+                    Err(err) => {
+                        // Still record the layers that made it to disk
+                        if let Some(disk_consistent_lsn) = batch_disk_consistent_lsn {
+                            self.update_disk_consistent_lsn(
+                                disk_consistent_lsn,
+                                batch_layer_paths,
+                            )?;
+                        }
+                        return Err(err);
+                    }
+                }
+            } else if let Some(disk_consistent_lsn) = batch_disk_consistent_lsn.take() {
+                drop(layers);
+                self.update_disk_consistent_lsn(
+                    disk_consistent_lsn,
+                    std::mem::take(&mut batch_layer_paths),
+                )?;
+                // Check again for layers frozen while we were writing the metadata
+                // file, before releasing 'layer_flush_lock'.
             } else {
                 // Drop the 'layer_flush_lock' *before* 'layers'. That
                 // way, if you freeze a layer, and then call
@@ -1206,7 +3419,13 @@ impl LayeredTimeline {
     }
 
     /// Flush one frozen in-memory layer to disk, as a new delta layer.
-    fn flush_frozen_layer(&self, frozen_layer: Arc<InMemoryLayer>) -> Result<()> {
+    ///
+    /// Returns the new 'disk_consistent_lsn' and the paths of the new layer
+    /// files. It's up to the caller to update the metadata file.
+    fn flush_frozen_layer(
+        &self,
+        frozen_layer: Arc<InMemoryLayer>,
+    ) -> Result<(Lsn, HashSet<PathBuf>)> {
         // As a special case, when we have just imported an image into the repository,
         // instead of writing out a L0 delta layer, we directly write out image layer
         // files instead. This is possible as long as *all* the data imported into the
@@ -1216,7 +3435,12 @@ impl LayeredTimeline {
             if lsn_range.start == self.initdb_lsn && lsn_range.end == Lsn(self.initdb_lsn.0 + 1) {
                 let (partitioning, _lsn) =
                     self.repartition(self.initdb_lsn, self.get_compaction_target_size())?;
-                self.create_image_layers(&partitioning, self.initdb_lsn, true)?
+                self.create_image_layers(
+                    &partitioning,
+                    self.initdb_lsn,
+                    true,
+                    &CancellationToken::default(),
+                )?
             } else {
                 // normal case, write out a L0 delta layer file.
                 let delta_path = self.create_delta_layer(&frozen_layer)?;
@@ -1241,14 +3465,8 @@ impl LayeredTimeline {
 
         fail_point!("checkpoint-after-sync");
 
-        // Update the metadata file, with new 'disk_consistent_lsn'
-        //
-        // TODO: This perhaps should be done in 'flush_frozen_layers', after flushing
-        // *all* the layers, to avoid fsyncing the file multiple times.
         let disk_consistent_lsn = Lsn(lsn_range.end.0 - 1);
-        self.update_disk_consistent_lsn(disk_consistent_lsn, layer_paths_to_upload)?;
-
-        Ok(())
+        Ok((disk_consistent_lsn, layer_paths_to_upload))
     }
 
     /// Update metadata file
@@ -1279,6 +3497,11 @@ impl LayeredTimeline {
                 None
             };
 
+            self.prev_record_lsns
+                .lock()
+                .unwrap()
+                .flush_upto(disk_consistent_lsn)?;
+
             let ancestor_timelineid = self
                 .ancestor_timeline
                 .as_ref()
@@ -1298,13 +3521,15 @@ impl LayeredTimeline {
                 x.unwrap()
             ));
 
-            save_metadata(
-                self.conf,
-                self.timeline_id,
-                self.tenant_id,
-                &metadata,
-                false,
-            )?;
+            self.save_metadata_time_histo.observe_closure_duration(|| {
+                save_metadata(
+                    self.conf,
+                    self.timeline_id,
+                    self.tenant_id,
+                    &metadata,
+                    false,
+                )
+            })?;
 
             if self.upload_layers.load(atomic::Ordering::Relaxed) {
                 storage_sync::schedule_layer_upload(
@@ -1315,8 +3540,9 @@ impl LayeredTimeline {
                 );
             }
 
-            // Also update the in-memory copy
-            self.disk_consistent_lsn.store(disk_consistent_lsn);
+            // Also update the in-memory copy, waking up anyone waiting for it
+            self.disk_consistent_lsn.advance(disk_consistent_lsn);
+            self.observe_durability_latency(disk_consistent_lsn);
         }
 
         Ok(())
@@ -1324,6 +3550,8 @@ impl LayeredTimeline {
 
     // Write out the given frozen in-memory layer as a new L0 delta file
     fn create_delta_layer(&self, frozen_layer: &InMemoryLayer) -> Result<PathBuf> {
+        self.check_disk_space()?;
+
         // Write it out
         let new_delta = frozen_layer.write_to_disk()?;
         let new_delta_path = new_delta.path();
@@ -1336,10 +3564,13 @@ impl LayeredTimeline {
         // TODO: If we're running inside 'flush_frozen_layers' and there are multiple
         // files to flush, it might be better to first write them all, and then fsync
         // them all in parallel.
-        par_fsync::par_fsync(&[
-            new_delta_path.clone(),
-            self.conf.timeline_path(&self.timeline_id, &self.tenant_id),
-        ])?;
+        par_fsync::par_fsync(
+            &[
+                new_delta_path.clone(),
+                self.conf.timeline_path(&self.timeline_id, &self.tenant_id),
+            ],
+            self.conf.max_fsync_parallelism,
+        )?;
 
         // Add it to the layer map
         {
@@ -1350,6 +3581,7 @@ impl LayeredTimeline {
         // update the timeline's physical size
         let sz = new_delta_path.metadata()?.len();
         self.current_physical_size_gauge.add(sz);
+        self.delta_layer_size_histo.observe(sz as f64);
         // update metrics
         NUM_PERSISTENT_FILES_CREATED.inc_by(1);
         PERSISTENT_BYTES_WRITTEN.inc_by(sz);
@@ -1357,7 +3589,40 @@ impl LayeredTimeline {
         Ok(new_delta_path)
     }
 
+    ///
+    /// Compact the timeline, unless too many timelines of the tenant are
+    /// compacting already. In that case this does nothing, and the background
+    /// compaction loop tries again at its next iteration.
+    ///
     pub fn compact(&self) -> Result<()> {
+        let permit = match self
+            .compaction_limiter
+            .try_acquire(self.get_compaction_concurrency())
+        {
+            Some(permit) => permit,
+            None => {
+                info!(
+                    "compaction throttled, {} timelines of the tenant are already compacting",
+                    self.compaction_limiter.active()
+                );
+                return Ok(());
+            }
+        };
+        self.compact_with_permit(permit)
+    }
+
+    ///
+    /// Like compact(), but if too many timelines of the tenant are compacting
+    /// already, wait for one of them to finish instead of skipping.
+    ///
+    pub fn compact_forced(&self) -> Result<()> {
+        let permit = self
+            .compaction_limiter
+            .acquire(self.get_compaction_concurrency());
+        self.compact_with_permit(permit)
+    }
+
+    fn compact_with_permit(&self, _permit: CompactionPermit) -> Result<()> {
         //
         // High level strategy for compaction / image creation:
         //
@@ -1392,9 +3657,13 @@ impl LayeredTimeline {
         // Below are functions compact_level0() and create_image_layers()
         // but they are a bit ad hoc and don't quite work like it's explained
         // above. Rewrite it.
-        let _layer_removal_cs = self.layer_removal_cs.lock().unwrap();
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+
+        // Delete files of layers removed by earlier compactions or GC, which
+        // readers were still using back then.
+        self.delete_unused_layers(Duration::ZERO)?;
 
-        let target_file_size = self.get_checkpoint_distance();
+        let target_file_sizes = self.get_compaction_target_file_sizes();
 
         // Define partitioning schema if needed
 
@@ -1405,7 +3674,8 @@ impl LayeredTimeline {
             Ok((partitioning, lsn)) => {
                 // 2. Create new image layers for partitions that have been modified
                 // "enough".
-                let layer_paths_to_upload = self.create_image_layers(&partitioning, lsn, false)?;
+                let layer_paths_to_upload =
+                    self.create_image_layers(&partitioning, lsn, false, &self.compaction_cancel)?;
                 if !layer_paths_to_upload.is_empty()
                     && self.upload_layers.load(atomic::Ordering::Relaxed)
                 {
@@ -1419,7 +3689,14 @@ impl LayeredTimeline {
 
                 // 3. Compact
                 let timer = self.compact_time_histo.start_timer();
-                self.compact_level0(target_file_size)?;
+                match self.compact_level0(&target_file_sizes, &self.compaction_cancel) {
+                    Err(e) if e.is::<CompactionCancelled>() => {
+                        info!("{e}");
+                        return Ok(());
+                    }
+                    res => res?,
+                }
+                self.coalesce_small_layers(&target_file_sizes)?;
                 timer.stop_and_record();
             }
             Err(err) => {
@@ -1434,53 +3711,256 @@ impl LayeredTimeline {
         Ok(())
     }
 
-    fn repartition(&self, lsn: Lsn, partition_size: u64) -> Result<(KeyPartitioning, Lsn)> {
-        let mut partitioning_guard = self.partitioning.lock().unwrap();
-        if partitioning_guard.1 == Lsn(0)
-            || lsn.0 - partitioning_guard.1 .0 > self.repartition_threshold
-        {
-            let keyspace = self.collect_keyspace(lsn)?;
-            let partitioning = keyspace.partition(partition_size);
-            *partitioning_guard = (partitioning, lsn);
-            return Ok((partitioning_guard.0.clone(), lsn));
+    ///
+    /// Like [`LayeredTimeline::compact`], but first estimates whether compaction
+    /// would accomplish anything, and skips it if not. Compaction is considered
+    /// beneficial if there are at least 'compaction_threshold' level 0 delta
+    /// layers to merge and merging them saves at least 'compaction_min_benefit'
+    /// layer visits, see estimate_level0_compaction_benefit(), or if some
+    /// partition has accumulated at least 'image_creation_threshold' deltas
+    /// since its last image layer.
+    ///
+    /// To keep the estimate cheap, the keyspace is not collected again: only the
+    /// partitioning from the previous compaction is considered for image layers.
+    ///
+    /// Returns true if compaction was performed.
+    ///
+    pub fn compact_if_beneficial(&self) -> Result<bool> {
+        let level0_deltas = self.layers.read().unwrap().get_level0_deltas()?;
+        let compaction_threshold = self.get_compaction_threshold();
+        let level0_skip_reason = if level0_deltas.len() < compaction_threshold {
+            format!(
+                "{} level 0 deltas is below compaction_threshold {}",
+                level0_deltas.len(),
+                compaction_threshold
+            )
+        } else {
+            let benefit = self.estimate_level0_compaction_benefit(&level0_deltas);
+            let min_benefit = self.get_compaction_min_benefit();
+            if benefit >= min_benefit {
+                // Don't keep the layers alive, compaction deletes them
+                drop(level0_deltas);
+                self.compact()?;
+                return Ok(true);
+            }
+            format!(
+                "merging {} level 0 deltas saves {} layer visits, below compaction_min_benefit {}",
+                level0_deltas.len(),
+                benefit,
+                min_benefit
+            )
+        };
+
+        let (partitioning, partitioning_lsn) = self.partitioning.lock().unwrap().clone();
+        if partitioning_lsn != Lsn(0) {
+            let lsn = self.get_last_record_lsn();
+            for partition in partitioning.parts.iter() {
+                if self.time_for_new_image_layer(partition, lsn)? {
+                    self.compact()?;
+                    return Ok(true);
+                }
+            }
         }
-        Ok((partitioning_guard.0.clone(), partitioning_guard.1))
-    }
 
-    // Is it time to create a new image layer for the given partition?
+        info!(
+            "skipping compaction: {}, and no partition needs new image layers",
+            level0_skip_reason
+        );
+        Ok(false)
+    }
+
+    ///
+    /// Estimate how many layer visits merging 'level0_deltas' would save. The
+    /// versions of a key that are spread over N level 0 layers take N layer
+    /// visits to collect, and one after the merge. The savings are summed over
+    /// all keys. Only the indexes of the layers are read, not the values.
+    ///
+    fn estimate_level0_compaction_benefit(&self, level0_deltas: &[Arc<dyn Layer>]) -> u64 {
+        let mut layers_per_key: HashMap<Key, u64> = HashMap::new();
+        for l in level0_deltas {
+            let mut prev_key = None;
+            for (key, _, _) in l.key_iter() {
+                if prev_key != Some(key) {
+                    *layers_per_key.entry(key).or_default() += 1;
+                    prev_key = Some(key);
+                }
+            }
+        }
+        layers_per_key.values().map(|n| n - 1).sum()
+    }
+
+    ///
+    /// Estimate how much work compaction has to do on this timeline, without
+    /// doing any of it. A scheduler can use this to pick the timelines that
+    /// need compaction the most.
+    ///
+    /// Like [`Self::compact_if_beneficial`], only the partitioning from the
+    /// previous compaction is considered for image layers, so a timeline that
+    /// hasn't been compacted yet reports no partitions needing images.
+    ///
+    pub fn compaction_debt(&self) -> Result<CompactionDebt> {
+        let level0_deltas = self.layers.read().unwrap().get_level0_deltas()?;
+        let mut l0_bytes = 0;
+        for l in level0_deltas.iter() {
+            if let Some(path) = l.local_path() {
+                l0_bytes += path.metadata()?.len();
+            }
+        }
+
+        let mut partitions_needing_images = 0;
+        let (partitioning, partitioning_lsn) = self.partitioning.lock().unwrap().clone();
+        if partitioning_lsn != Lsn(0) {
+            let lsn = self.get_last_record_lsn();
+            for partition in partitioning.parts.iter() {
+                if self.time_for_new_image_layer(partition, lsn)? {
+                    partitions_needing_images += 1;
+                }
+            }
+        }
+
+        Ok(CompactionDebt {
+            l0_count: level0_deltas.len(),
+            l0_bytes,
+            partitions_needing_images,
+        })
+    }
+
+    /// Dump the state of the timeline and its layer map as JSON, for
+    /// debugging. See [`LayerMap::dump_json`].
+    pub fn dump_json(&self) -> Result<serde_json::Value> {
+        let layer_map = self.layers.read().unwrap().dump_json()?;
+        Ok(serde_json::json!({
+            "tenant_id": self.tenant_id.to_string(),
+            "timeline_id": self.timeline_id.to_string(),
+            "last_record_lsn": self.get_last_record_lsn().to_string(),
+            "disk_consistent_lsn": self.get_disk_consistent_lsn().to_string(),
+            "layer_map": layer_map,
+        }))
+    }
+
+    ///
+    /// Drop all the data of relation fork 'rel', as of 'lsn'.
+    ///
+    /// Writes a tombstone over the whole key range of the relation at 'lsn',
+    /// which must be newer than the last record LSN, and flushes it to disk.
+    /// Then creates an empty image layer covering the relation at 'lsn'. Reads
+    /// at or after 'lsn' stop at that layer and find nothing, and GC can remove
+    /// the older layers holding the relation's data once the GC horizon has
+    /// passed 'lsn', without waiting for the regular compaction to get to this
+    /// key range.
+    ///
+    /// Reads before 'lsn' are not affected, so the data stays available to
+    /// child branches created before 'lsn'. GC keeps it for them, as usual.
+    ///
+    /// The caller is responsible for removing the relation from the
+    /// relation directory, so that it's no longer part of the keyspace.
+    ///
+    pub fn drop_relation_data(&self, rel: RelTag, lsn: Lsn) -> Result<()> {
+        let key_range = rel_key_range(rel);
+
+        let writer = self.writer();
+        writer.delete(key_range.clone(), lsn)?;
+        writer.finish_write(lsn)?;
+        drop(writer);
+
+        self.freeze_inmem_layer(false);
+        self.flush_frozen_layers(true)?;
+
+        if let Some(retain_lsn) = self
+            .gc_info
+            .read()
+            .unwrap()
+            .retain_lsns
+            .iter()
+            .find(|retain_lsn| **retain_lsn < lsn)
+        {
+            info!("data of dropped relation {rel} is kept for the branch at {retain_lsn}");
+        }
+
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+
+        // No keys are written: the layer just records that the relation
+        // doesn't have any data as of 'lsn'.
+        let image_layer =
+            ImageLayerWriter::new(self.conf, self.timeline_id, self.tenant_id, &key_range, lsn)?
+                .finish()?;
+        let image_layer_path = image_layer.path();
+        par_fsync::par_fsync(
+            &[
+                image_layer_path.clone(),
+                self.conf.timeline_path(&self.timeline_id, &self.tenant_id),
+            ],
+            self.conf.max_fsync_parallelism,
+        )?;
+
+        let sz = image_layer_path.metadata()?.len();
+        self.current_physical_size_gauge.add(sz);
+        self.image_layer_size_histo.observe(sz as f64);
+        self.layers
+            .write()
+            .unwrap()
+            .insert_historic(Arc::new(image_layer));
+
+        if self.upload_layers.load(atomic::Ordering::Relaxed) {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                HashSet::from([image_layer_path]),
+                None,
+            );
+        }
+        info!("dropped data of relation {rel} at {lsn}");
+
+        Ok(())
+    }
+
+    fn repartition(&self, lsn: Lsn, partition_size: u64) -> Result<(KeyPartitioning, Lsn)> {
+        let mut partitioning_guard = self.partitioning.lock().unwrap();
+        if partitioning_guard.1 == Lsn(0)
+            || lsn.0 - partitioning_guard.1 .0 > self.repartition_threshold
+        {
+            let keyspace = self.collect_keyspace_incremental(lsn, &partitioning_guard)?;
+            let partitioning = keyspace.partition(partition_size);
+            *partitioning_guard = (partitioning, lsn);
+
+            // The changes up to 'lsn' are now included in the partitioning
+            self.changed_keys
+                .lock()
+                .unwrap()
+                .keys
+                .retain(|_, modified_lsn| *modified_lsn > lsn);
+
+            return Ok((partitioning_guard.0.clone(), lsn));
+        }
+        Ok((partitioning_guard.0.clone(), partitioning_guard.1))
+    }
+
+    // Is it time to create a new image layer for the given partition?
     fn time_for_new_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> Result<bool> {
+        // Frequently read key ranges get new image layers at a lower threshold,
+        // as reconstructing their pages is what most of the read cost comes from.
+        let hot_ranges = self.access_tracker.hot_ranges();
+        let threshold = self.get_image_creation_threshold();
+        let hot_threshold = (threshold + 1) / 2;
+
         let layers = self.layers.read().unwrap();
 
         for part_range in &partition.ranges {
-            let image_coverage = layers.image_coverage(part_range, lsn)?;
-            for (img_range, last_img) in image_coverage {
-                let img_lsn = if let Some(last_img) = last_img {
-                    last_img.get_lsn_range().end
-                } else {
-                    Lsn(0)
-                };
-                // Let's consider an example:
-                //
-                // delta layer with LSN range 71-81
-                // delta layer with LSN range 81-91
-                // delta layer with LSN range 91-101
-                // image layer at LSN 100
-                //
-                // If 'lsn' is still 100, i.e. no new WAL has been processed since the last image layer,
-                // there's no need to create a new one. We check this case explicitly, to avoid passing
-                // a bogus range to count_deltas below, with start > end. It's even possible that there
-                // are some delta layers *later* than current 'lsn', if more WAL was processed and flushed
-                // after we read last_record_lsn, which is passed here in the 'lsn' argument.
-                if img_lsn < lsn {
-                    let num_deltas = layers.count_deltas(&img_range, &(img_lsn..lsn))?;
-
+            let image_coverage = self.image_coverage_deltas(&layers, part_range, lsn)?;
+            for (img_range, num_deltas) in image_coverage.iter() {
+                if *num_deltas >= threshold {
+                    return Ok(true);
+                }
+                if *num_deltas >= hot_threshold
+                    && hot_ranges
+                        .iter()
+                        .any(|hot_range| overlaps_hot_range(img_range, hot_range))
+                {
                     debug!(
-                        "key range {}-{}, has {} deltas on this timeline in LSN range {}..{}",
-                        img_range.start, img_range.end, num_deltas, img_lsn, lsn
+                        "key range {}-{} is read frequently, creating image layer early",
+                        img_range.start, img_range.end
                     );
-                    if num_deltas >= self.get_image_creation_threshold() {
-                        return Ok(true);
-                    }
+                    return Ok(true);
                 }
             }
         }
@@ -1488,16 +3968,128 @@ impl LayeredTimeline {
         Ok(false)
     }
 
+    ///
+    /// Divide 'part_range' into sub-ranges based on the latest image layer
+    /// that covers each, and count the delta layers on top of the image in
+    /// each sub-range, up to 'lsn'.
+    ///
+    /// This is repeated for every partition on every compaction, while the
+    /// layer map rarely changes in between, so the results are cached until
+    /// the layer map changes. A result computed at a different LSN is still
+    /// valid if no layer has data at or after either LSN.
+    ///
+    fn image_coverage_deltas(
+        &self,
+        layers: &LayerMap,
+        part_range: &Range<Key>,
+        lsn: Lsn,
+    ) -> Result<ImageCoverage> {
+        let mut cache = self.image_coverage_cache.lock().unwrap();
+        if cache.generation != layers.generation() {
+            cache.generation = layers.generation();
+            cache.entries.clear();
+        }
+        if let Some((cached_lsn, coverage)) = cache.entries.get(part_range) {
+            if *cached_lsn == lsn || layers.historic_lsn_end() <= min(*cached_lsn, lsn) {
+                return Ok(Arc::clone(coverage));
+            }
+        }
+
+        let mut coverage = Vec::new();
+        for (img_range, last_img) in layers.image_coverage(part_range, lsn)? {
+            let img_lsn = if let Some(last_img) = last_img {
+                last_img.get_lsn_range().end
+            } else {
+                Lsn(0)
+            };
+            // Let's consider an example:
+            //
+            // delta layer with LSN range 71-81
+            // delta layer with LSN range 81-91
+            // delta layer with LSN range 91-101
+            // image layer at LSN 100
+            //
+            // If 'lsn' is still 100, i.e. no new WAL has been processed since the last image layer,
+            // there's no need to create a new one. We check this case explicitly, to avoid passing
+            // a bogus range to count_deltas below, with start > end. It's even possible that there
+            // are some delta layers *later* than current 'lsn', if more WAL was processed and flushed
+            // after we read last_record_lsn, which is passed here in the 'lsn' argument.
+            if img_lsn < lsn {
+                let num_deltas = layers.count_deltas(&img_range, &(img_lsn..lsn))?;
+
+                debug!(
+                    "key range {}-{}, has {} deltas on this timeline in LSN range {}..{}",
+                    img_range.start, img_range.end, num_deltas, img_lsn, lsn
+                );
+                coverage.push((img_range, num_deltas));
+            }
+        }
+
+        let coverage: ImageCoverage = coverage.into();
+        cache
+            .entries
+            .insert(part_range.clone(), (lsn, Arc::clone(&coverage)));
+        Ok(coverage)
+    }
+
+    ///
+    /// Let the on-disk layers that hold data for 'key_range' at 'lsn' start
+    /// reading it ahead, before a sequential scan over the range. Failures are
+    /// only logged, as this is just an optimization.
+    ///
+    fn prefetch_layers(&self, key_range: &Range<Key>, lsn: Lsn) {
+        let layers = self.layers.read().unwrap();
+        let layers_to_prefetch: Vec<_> = layers
+            .iter_historic_layers()
+            .filter(|l| {
+                !l.is_in_memory()
+                    && l.get_lsn_range().start <= lsn
+                    && range_overlaps(&l.get_key_range(), key_range)
+            })
+            .cloned()
+            .collect();
+        drop(layers);
+
+        for l in layers_to_prefetch {
+            if let Err(e) = l.prefetch(key_range) {
+                warn!(
+                    "could not prefetch from layer {}: {:#}",
+                    l.filename().display(),
+                    e
+                );
+            }
+        }
+    }
+
+    ///
+    /// Create image layers for the partitions that need them.
+    ///
+    /// 'cancel' is checked before each partition. If it is cancelled, the
+    /// image layers that were already finished are still added to the layer
+    /// map, and the rest are left for the next compaction.
+    ///
     fn create_image_layers(
         &self,
         partitioning: &KeyPartitioning,
         lsn: Lsn,
         force: bool,
+        cancel: &CancellationToken,
     ) -> Result<HashSet<PathBuf>> {
+        self.check_disk_space()?;
         let timer = self.create_images_time_histo.start_timer();
         let mut image_layers: Vec<ImageLayer> = Vec::new();
         let mut layer_paths_to_upload = HashSet::new();
+        // Read all the partitions from one snapshot of the layer map. The image
+        // layers created here are only added to the map at the end.
+        let snapshot = self.layers.snapshot();
         for partition in partitioning.parts.iter() {
+            if cancel.is_cancelled() {
+                info!(
+                    "image layer creation cancelled after {} layers",
+                    image_layers.len()
+                );
+                break;
+            }
             if force || self.time_for_new_image_layer(partition, lsn)? {
                 let img_range =
                     partition.ranges.first().unwrap().start..partition.ranges.last().unwrap().end;
@@ -1509,10 +4101,12 @@ impl LayeredTimeline {
                     lsn,
                 )?;
 
+                self.prefetch_layers(&img_range, lsn);
                 for range in &partition.ranges {
                     let mut key = range.start;
                     while key < range.end {
-                        let img = self.get(key, lsn)?;
+                        // Bypass get() so that these reads don't count as accesses
+                        let img = self.get_in_snapshot(key, lsn, &snapshot)?;
                         image_layer_writer.put_image(key, &img)?;
                         key = key.next();
                     }
@@ -1522,6 +4116,7 @@ impl LayeredTimeline {
                 image_layers.push(image_layer);
             }
         }
+        drop(snapshot);
 
         // Sync the new layer to disk before adding it to the layer map, to make sure
         // we don't garbage collect something based on the new layer, before it has
@@ -1534,17 +4129,32 @@ impl LayeredTimeline {
         // and fsync them all in parallel.
         let mut all_paths = Vec::from_iter(layer_paths_to_upload.clone());
         all_paths.push(self.conf.timeline_path(&self.timeline_id, &self.tenant_id));
-        par_fsync::par_fsync(&all_paths)?;
+        par_fsync::par_fsync(&all_paths, self.conf.max_fsync_parallelism)?;
 
+        if !image_layers.is_empty() {
+            self.has_local_layers.store(true, AtomicOrdering::Release);
+        }
         let mut layers = self.layers.write().unwrap();
+        let mut created = Vec::with_capacity(image_layers.len());
         for l in image_layers {
-            self.current_physical_size_gauge
-                .add(l.path().metadata()?.len());
+            let sz = l.path().metadata()?.len();
+            self.current_physical_size_gauge.add(sz);
+            self.image_layer_size_histo.observe(sz as f64);
+            created.push(LayerDescriptor::of(&l));
             layers.insert_historic(Arc::new(l));
         }
         drop(layers);
         timer.stop_and_record();
 
+        let observer = self.maintenance_observer.get();
+        for layer in created.iter() {
+            observer.on_image_created(
+                ZTenantTimelineId::new(self.tenant_id, self.timeline_id),
+                layer,
+                lsn,
+            );
+        }
+
         Ok(layer_paths_to_upload)
     }
 
@@ -1552,7 +4162,16 @@ impl LayeredTimeline {
     /// Collect a bunch of Level 0 layer files, and compact and reshuffle them as
     /// as Level 1 files.
     ///
-    fn compact_level0(&self, target_file_size: u64) -> Result<()> {
+    /// 'cancel' is checked before starting each new output layer. If it is
+    /// cancelled, the output layers written so far are deleted and the input
+    /// layers are left in place, so that the next compaction starts over from
+    /// the same state. Returns a [`CompactionCancelled`] error in that case.
+    ///
+    fn compact_level0(
+        &self,
+        target_file_sizes: &CompactionTargetSizes,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
         let layers = self.layers.read().unwrap();
         let mut level0_deltas = layers.get_level0_deltas()?;
         drop(layers);
@@ -1561,6 +4180,7 @@ impl LayeredTimeline {
         if level0_deltas.is_empty() || level0_deltas.len() < self.get_compaction_threshold() {
             return Ok(());
         }
+        self.check_disk_space()?;
 
         // Gather the files to compact in this iteration.
         //
@@ -1578,13 +4198,21 @@ impl LayeredTimeline {
         level0_deltas.sort_by_key(|l| l.get_lsn_range().start);
         let mut level0_deltas_iter = level0_deltas.iter();
 
+        //
+        // At most 'compaction_max_input_layers' files are compacted at a time,
+        // to bound the time and memory of one pass. The rest are left for the
+        // next passes.
+        let max_input_layers = match self.get_compaction_max_input_layers() {
+            0 => usize::MAX,
+            n => n,
+        };
         let first_level0_delta = level0_deltas_iter.next().unwrap();
         let mut prev_lsn_end = first_level0_delta.get_lsn_range().end;
         let mut deltas_to_compact = vec![Arc::clone(first_level0_delta)];
         for l in level0_deltas_iter {
             let lsn_range = l.get_lsn_range();
 
-            if lsn_range.start != prev_lsn_end {
+            if lsn_range.start != prev_lsn_end || deltas_to_compact.len() >= max_input_layers {
                 break;
             }
             deltas_to_compact.push(Arc::clone(l));
@@ -1609,6 +4237,10 @@ impl LayeredTimeline {
         // we don't accidentally use it later in the function.
         drop(level0_deltas);
 
+        if self.get_compaction_strategy() == CompactionStrategy::LsnWindow {
+            return self.compact_level0_lsn_windows(deltas_to_compact, target_file_sizes, cancel);
+        }
+
         // This iterator walks through all key-value pairs from all the layers
         // we're compacting, in key, LSN order.
         let all_values_iter = deltas_to_compact
@@ -1683,6 +4315,10 @@ impl LayeredTimeline {
         //  | +-----------+            +--+--+--+--+
         //  |
         //  +--------------> key
+        //
+        // Keys of different categories (see KeyCategory) are never put in the
+        // same layer, and each category has its own target file size.
+        //
         // TODO: this actually divides the layers into fixed-size chunks, not
         // based on the partitioning.
         //
@@ -1696,7 +4332,11 @@ impl LayeredTimeline {
         let mut dup_end_lsn: Lsn = Lsn::INVALID; // end LSN of layer containing values of the single key
         for x in all_values_iter {
             let (key, lsn, value) = x?;
+            let target_file_size = target_file_sizes.for_key(key);
             let same_key = prev_key.map_or(false, |prev_key| prev_key == key);
+            let same_category = prev_key.map_or(true, |prev_key| {
+                KeyCategory::of(prev_key) == KeyCategory::of(key)
+            });
             // We need to check key boundaries once we reach next key or end of layer with the same key
             if !same_key || lsn == dup_end_lsn {
                 let mut next_key_size = 0u64;
@@ -1737,15 +4377,30 @@ impl LayeredTimeline {
                     // check if key cause layer overflow
                     if is_dup_layer
                         || dup_end_lsn.is_valid()
+                        || !same_category
                         || written_size + key_values_total_size > target_file_size
                     {
                         new_layers.push(writer.take().unwrap().finish(prev_key.unwrap().next())?);
                         writer = None;
+                        fail_point!("compact-level0-after-output-layer");
                     }
                 }
                 key_values_total_size = next_key_size;
             }
             if writer.is_none() {
+                if cancel.is_cancelled() {
+                    info!(
+                        "Level0 compaction cancelled, removing {} new layers",
+                        new_layers.len()
+                    );
+                    for l in new_layers {
+                        l.delete()?;
+                    }
+                    return Err(CompactionCancelled {
+                        timeline_id: self.timeline_id,
+                    }
+                    .into());
+                }
                 writer = Some(DeltaLayerWriter::new(
                     self.conf,
                     self.timeline_id,
@@ -1768,6 +4423,249 @@ impl LayeredTimeline {
             new_layers.push(writer.finish(prev_key.unwrap().next())?);
         }
 
+        drop(all_keys_iter);
+        self.replace_historic_layers(new_layers, deltas_to_compact)?;
+
+        Ok(())
+    }
+
+    ///
+    /// The LsnWindow compaction strategy: merge runs of consecutive level 0
+    /// delta layers into one layer each, instead of splitting the merged
+    /// values by key range.
+    ///
+    /// Like compact_level0(), this never puts keys of different categories
+    /// in the same layer. For each key category, the input layers, in LSN
+    /// order, are grouped into windows whose total size of the category's
+    /// values stays within the category's target file size. A window always
+    /// holds at least one layer. Each window becomes one delta layer, covering
+    /// the LSN range of the window and the keys of the category that were
+    /// written in it. The output layers of a category don't overlap in LSN,
+    /// so a read at a recent LSN only needs to visit the layers of the recent
+    /// windows.
+    ///
+    fn compact_level0_lsn_windows(
+        &self,
+        deltas_to_compact: Vec<Arc<dyn Layer>>,
+        target_file_sizes: &CompactionTargetSizes,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        // Size of the values of each category, in each input layer
+        let layer_sizes: Vec<[u64; KeyCategory::ALL.len()]> = deltas_to_compact
+            .iter()
+            .map(|l| {
+                let mut sizes = [0; KeyCategory::ALL.len()];
+                for (key, _, size) in l.key_iter() {
+                    sizes[KeyCategory::of(key) as usize] += size;
+                }
+                sizes
+            })
+            .collect();
+
+        let mut windows: Vec<(KeyCategory, &[Arc<dyn Layer>])> = Vec::new();
+        for category in KeyCategory::ALL {
+            let target_file_size = target_file_sizes.for_category(category);
+            let mut window_start = 0;
+            let mut window_size = 0;
+            for (i, sizes) in layer_sizes.iter().enumerate() {
+                let size = sizes[category as usize];
+                if i > window_start && window_size + size > target_file_size {
+                    windows.push((category, &deltas_to_compact[window_start..i]));
+                    window_start = i;
+                    window_size = 0;
+                }
+                window_size += size;
+            }
+            windows.push((category, &deltas_to_compact[window_start..]));
+        }
+
+        let mut new_layers = Vec::new();
+        for (category, window) in windows {
+            if cancel.is_cancelled() {
+                info!(
+                    "Level0 compaction cancelled, removing {} new layers",
+                    new_layers.len()
+                );
+                for l in new_layers {
+                    l.delete()?;
+                }
+                return Err(CompactionCancelled {
+                    timeline_id: self.timeline_id,
+                }
+                .into());
+            }
+            let lsn_range = Range {
+                start: window.first().unwrap().get_lsn_range().start,
+                end: window.last().unwrap().get_lsn_range().end,
+            };
+            debug!(
+                "Create new {:?} layer for {} layers in LSN window {}..{}",
+                category,
+                window.len(),
+                lsn_range.start,
+                lsn_range.end
+            );
+
+            // Walk through the values of the category in the window, in key,
+            // LSN order
+            let window_values_iter = window.iter().map(|l| l.iter()).kmerge_by(|a, b| {
+                if let Ok((a_key, a_lsn, _)) = a {
+                    if let Ok((b_key, b_lsn, _)) = b {
+                        match a_key.cmp(b_key) {
+                            Ordering::Less => true,
+                            Ordering::Equal => a_lsn <= b_lsn,
+                            Ordering::Greater => false,
+                        }
+                    } else {
+                        false
+                    }
+                } else {
+                    true
+                }
+            });
+            let mut writer: Option<DeltaLayerWriter> = None;
+            let mut prev_key: Option<Key> = None;
+            for x in window_values_iter {
+                let (key, lsn, value) = x?;
+                if KeyCategory::of(key) != category {
+                    continue;
+                }
+                if writer.is_none() {
+                    writer = Some(DeltaLayerWriter::new(
+                        self.conf,
+                        self.timeline_id,
+                        self.tenant_id,
+                        key,
+                        lsn_range.clone(),
+                    )?);
+                }
+                writer.as_mut().unwrap().put_value(key, lsn, value)?;
+                prev_key = Some(key);
+            }
+            if let Some(writer) = writer {
+                new_layers.push(writer.finish(prev_key.unwrap().next())?);
+                fail_point!("compact-level0-after-output-layer");
+            }
+        }
+
+        self.replace_historic_layers(new_layers, deltas_to_compact)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Merge runs of delta layers that have the same LSN range and are next to
+    /// each other in the key space into one layer, if their combined size is
+    /// below the target file size. compact_level0() leaves such small layers
+    /// behind when it splits a key with many versions into layers of its own.
+    ///
+    /// Two layers are considered next to each other if no other layer overlaps
+    /// the gap between their key ranges, in the same LSN range. Layers holding
+    /// keys of different categories are not merged.
+    ///
+    /// Returns the number of layers removed.
+    ///
+    fn coalesce_small_layers(&self, target_file_sizes: &CompactionTargetSizes) -> Result<usize> {
+        let layers = self.layers.read().unwrap();
+        let mut candidates = Vec::new();
+        for l in layers.iter_historic_layers() {
+            if !l.is_incremental() || l.get_key_range() == (Key::MIN..Key::MAX) {
+                continue;
+            }
+            // Layers that haven't been downloaded yet are left alone
+            if let Some(path) = l.local_path() {
+                candidates.push((Arc::clone(l), path.metadata()?.len()));
+            }
+        }
+        candidates.sort_by_key(|(l, _)| {
+            let lsn_range = l.get_lsn_range();
+            (lsn_range.start, lsn_range.end, l.get_key_range().start)
+        });
+
+        let gap_is_empty = |gap: Range<Key>, lsn_range: &Range<Lsn>| {
+            gap.is_empty()
+                || !layers.iter_historic_layers().any(|l| {
+                    range_overlaps(&l.get_key_range(), &gap)
+                        && range_overlaps(&l.get_lsn_range(), lsn_range)
+                })
+        };
+
+        let mut runs = Vec::new();
+        let mut run: Vec<Arc<dyn Layer>> = Vec::new();
+        let mut run_size = 0;
+        for (l, size) in candidates {
+            let continues_run = run.last().map_or(false, |prev| {
+                let prev_start = prev.get_key_range().start;
+                let start = l.get_key_range().start;
+                prev.get_lsn_range() == l.get_lsn_range()
+                    && KeyCategory::of(prev_start) == KeyCategory::of(start)
+                    && run_size + size < target_file_sizes.for_key(start)
+                    && gap_is_empty(
+                        prev.get_key_range().end..l.get_key_range().start,
+                        &l.get_lsn_range(),
+                    )
+            });
+            if !continues_run {
+                if run.len() > 1 {
+                    runs.push(std::mem::take(&mut run));
+                }
+                run.clear();
+                run_size = 0;
+            }
+            run.push(l);
+            run_size += size;
+        }
+        if run.len() > 1 {
+            runs.push(run);
+        }
+        drop(layers);
+
+        let mut num_removed = 0;
+        for run in runs {
+            let key_range =
+                run.first().unwrap().get_key_range().start..run.last().unwrap().get_key_range().end;
+            let lsn_range = run[0].get_lsn_range();
+            info!(
+                "coalescing {} small delta layers in key range {}-{}, LSN range {}-{}",
+                run.len(),
+                key_range.start,
+                key_range.end,
+                lsn_range.start,
+                lsn_range.end
+            );
+
+            // The layers don't overlap, so the values come out in key, LSN order
+            let mut writer = DeltaLayerWriter::new(
+                self.conf,
+                self.timeline_id,
+                self.tenant_id,
+                key_range.start,
+                lsn_range,
+            )?;
+            for l in run.iter() {
+                for x in l.iter() {
+                    let (key, lsn, value) = x?;
+                    writer.put_value(key, lsn, value)?;
+                }
+            }
+            let new_layer = writer.finish(key_range.end)?;
+
+            num_removed += run.len() - 1;
+            self.replace_historic_layers(vec![new_layer], run)?;
+        }
+
+        Ok(num_removed)
+    }
+
+    ///
+    /// Install the new layers produced by compaction in the layer map, in
+    /// place of 'old_layers', and schedule the uploads and deletions.
+    ///
+    fn replace_historic_layers(
+        &self,
+        new_layers: Vec<DeltaLayer>,
+        old_layers: Vec<Arc<dyn Layer>>,
+    ) -> Result<()> {
         // Sync layers
         if !new_layers.is_empty() {
             let mut layer_paths: Vec<PathBuf> = new_layers.iter().map(|l| l.path()).collect();
@@ -1777,19 +4675,27 @@ impl LayeredTimeline {
 
             // Fsync all the layer files and directory using multiple threads to
             // minimize latency.
-            par_fsync::par_fsync(&layer_paths)?;
+            par_fsync::par_fsync(&layer_paths, self.conf.max_fsync_parallelism)?;
 
             layer_paths.pop().unwrap();
         }
 
+        let created: Vec<LayerDescriptor> =
+            new_layers.iter().map(|l| LayerDescriptor::of(l)).collect();
+        let removed: Vec<LayerDescriptor> = old_layers
+            .iter()
+            .map(|l| LayerDescriptor::of(l.as_ref()))
+            .collect();
+
         let mut layers = self.layers.write().unwrap();
         let mut new_layer_paths = HashSet::with_capacity(new_layers.len());
         for l in new_layers {
             let new_delta_path = l.path();
 
             // update the timeline's physical size
-            self.current_physical_size_gauge
-                .add(new_delta_path.metadata()?.len());
+            let sz = new_delta_path.metadata()?.len();
+            self.current_physical_size_gauge.add(sz);
+            self.delta_layer_size_histo.observe(sz as f64);
 
             new_layer_paths.insert(new_delta_path);
             layers.insert_historic(Arc::new(l));
@@ -1797,17 +4703,33 @@ impl LayeredTimeline {
 
         // Now that we have reshuffled the data to set of new delta layers, we can
         // delete the old ones
-        let mut layer_paths_do_delete = HashSet::with_capacity(deltas_to_compact.len());
-        drop(all_keys_iter);
-        for l in deltas_to_compact {
+        let mut layer_paths_do_delete = HashSet::with_capacity(old_layers.len());
+        let mut doomed_layers = self.doomed_layers.lock().unwrap();
+        for l in old_layers {
             if let Some(path) = l.local_path() {
-                self.current_physical_size_gauge.sub(path.metadata()?.len());
                 layer_paths_do_delete.insert(path);
             }
-            l.delete()?;
-            layers.remove_historic(l);
+            layers.remove_historic(Arc::clone(&l));
+            doomed_layers.push(l);
         }
+        drop(doomed_layers);
         drop(layers);
+        self.delete_unused_layers(DOOMED_LAYER_WAIT)?;
+
+        let observer = self.maintenance_observer.get();
+        if !created.is_empty() {
+            observer.on_layers_created(
+                ZTenantTimelineId::new(self.tenant_id, self.timeline_id),
+                &created,
+            );
+        }
+        if !removed.is_empty() {
+            observer.on_layers_removed(
+                ZTenantTimelineId::new(self.tenant_id, self.timeline_id),
+                &removed,
+                LayerRemovalCause::Compaction,
+            );
+        }
 
         if self.upload_layers.load(atomic::Ordering::Relaxed) {
             storage_sync::schedule_layer_upload(
@@ -1906,27 +4828,288 @@ impl LayeredTimeline {
     }
 
     ///
-    /// Garbage collect layer files on a timeline that are no longer needed.
+    /// Snapshot of what GC retains on this timeline, for the HTTP API.
     ///
-    /// Currently, we don't make any attempt at removing unneeded page versions
-    /// within a layer file. We can only remove the whole file if it's fully
-    /// obsolete.
+    /// The locks are taken in the same order as in gc().
     ///
-    pub fn gc(&self) -> Result<GcResult> {
-        let mut result: GcResult = Default::default();
-        let now = SystemTime::now();
-
-        fail_point!("before-timeline-gc");
-
-        let _layer_removal_cs = self.layer_removal_cs.lock().unwrap();
-
+    pub fn gc_info_snapshot(&self) -> GcInfoSnapshot {
         let gc_info = self.gc_info.read().unwrap();
+        let latest_gc_cutoff_lsn = *self.get_latest_gc_cutoff_lsn();
 
-        let horizon_cutoff = min(gc_info.horizon_cutoff, self.get_disk_consistent_lsn());
-        let pitr_cutoff = gc_info.pitr_cutoff;
-        let retain_lsns = &gc_info.retain_lsns;
+        GcInfoSnapshot {
+            retain_lsns: gc_info
+                .retain_lsns
+                .iter()
+                .map(|&lsn| RetainLsn {
+                    lsn,
+                    source: RetainLsnSource::ChildBranch,
+                })
+                .collect(),
+            horizon_cutoff: gc_info.horizon_cutoff,
+            pitr_cutoff: gc_info.pitr_cutoff,
+            latest_gc_cutoff_lsn,
+        }
+    }
 
-        let new_gc_cutoff = Lsn::min(horizon_cutoff, pitr_cutoff);
+    ///
+    /// Async version of get_physical_size_non_incremental(), for callers on
+    /// an async runtime.
+    ///
+    /// The timeline directory is scanned on the blocking thread pool, in
+    /// chunks of PHYSICAL_SIZE_CHUNK_SIZE files, so that a timeline with lots
+    /// of layer files occupies neither a runtime worker nor a blocking thread
+    /// for long. The files are not scanned from a consistent snapshot of the
+    /// directory either way, so the result is the same as from the sync version.
+    ///
+    pub async fn get_physical_size_non_incremental_async(&self) -> Result<u64> {
+        self.physical_size_in_chunks(PHYSICAL_SIZE_CHUNK_SIZE).await
+    }
+
+    async fn physical_size_in_chunks(&self, chunk_size: usize) -> Result<u64> {
+        let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
+        let mut read_dir = tokio::task::spawn_blocking(move || fs::read_dir(timeline_path))
+            .await
+            .context("failed to start reading the timeline directory")??;
+
+        let mut total_physical_size = 0;
+        loop {
+            let (rest, chunk_physical_size, done) = tokio::task::spawn_blocking(move || {
+                let mut chunk_physical_size = 0;
+                for _ in 0..chunk_size {
+                    match read_dir.next() {
+                        Some(direntry) => chunk_physical_size += layer_file_size(&direntry?)?,
+                        None => return Ok((read_dir, chunk_physical_size, true)),
+                    }
+                }
+                Ok::<_, anyhow::Error>((read_dir, chunk_physical_size, false))
+            })
+            .await
+            .context("failed to read the timeline directory")??;
+
+            total_physical_size += chunk_physical_size;
+            if done {
+                return Ok(total_physical_size);
+            }
+            read_dir = rest;
+        }
+    }
+
+    ///
+    /// Recompute the physical size from the sizes of the layer files, and
+    /// correct the incrementally maintained size by the difference.
+    ///
+    /// Returns the drift that was corrected, i.e. the actual size minus the
+    /// tracked size. The drift is also recorded in a gauge, and logged if nonzero.
+    ///
+    pub fn reconcile_physical_size(&self) -> Result<i64> {
+        // Layer files are deleted under 'layer_removal_cs', so none of the
+        // files we look at goes away during the scan.
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+
+        // Layer files are created under one of the two locks, with the gauge
+        // adjusted before releasing it. Hold 'layer_flush_lock' only to pick
+        // the files to scan and the matching tracked size. Files flushed
+        // during the scan are accounted for in the gauge by the flush, and
+        // left out of the comparison.
+        let (paths, tracked_size) = {
+            let _layer_flush_lock = self.layer_flush_lock.lock();
+            let layers = self.layers.snapshot();
+            let doomed_layers = self.doomed_layers.lock().unwrap();
+            let paths: HashSet<PathBuf> = layers
+                .iter_historic_layers()
+                .chain(doomed_layers.iter())
+                .filter_map(|l| l.local_path())
+                .collect();
+            (paths, self.current_physical_size_gauge.get())
+        };
+
+        let mut actual_size = 0;
+        for path in paths {
+            match path.metadata() {
+                Ok(metadata) => actual_size += metadata.len(),
+                // A missing layer file doesn't take any space
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let drift = actual_size as i64 - tracked_size as i64;
+
+        if drift != 0 {
+            warn!(
+                "physical size of timeline {} drifted by {} bytes: tracked {}, actual {}",
+                self.timeline_id, drift, tracked_size, actual_size
+            );
+            if drift > 0 {
+                self.current_physical_size_gauge.add(drift as u64);
+            } else {
+                self.current_physical_size_gauge.sub(-drift as u64);
+            }
+        }
+        self.physical_size_drift_gauge.set(drift);
+
+        Ok(drift)
+    }
+
+    ///
+    /// Recompute the logical size at the last record LSN, and compare it with
+    /// the incrementally maintained size. If 'correct' is true, the tracked
+    /// size is fixed.
+    ///
+    /// Returns the drift, i.e. the recomputed size minus the tracked size. The
+    /// drift is also recorded in a gauge, and logged if nonzero. Returns None
+    /// without checking anything if the last check was less than
+    /// LOGICAL_SIZE_CHECK_INTERVAL ago, or if the logical size has not been
+    /// initialized yet (see init_logical_size()).
+    ///
+    pub fn verify_logical_size(&self, correct: bool) -> Result<Option<i64>> {
+        // Like in init_logical_size(), 0 means not initialized
+        if self.current_logical_size.load(AtomicOrdering::Acquire) == 0 {
+            return Ok(None);
+        }
+        {
+            let mut last_check = self.last_logical_size_check.lock().unwrap();
+            if matches!(*last_check, Some(t) if t.elapsed() < LOGICAL_SIZE_CHECK_INTERVAL) {
+                return Ok(None);
+            }
+            *last_check = Some(Instant::now());
+        }
+
+        // The tracked size is updated along with the last record LSN, under
+        // 'write_lock'. Hold it to read a matching pair.
+        let (lsn, tracked_size) = {
+            let _write_guard = self.write_lock.lock().unwrap();
+            (
+                self.get_last_record_lsn(),
+                self.current_logical_size.load(AtomicOrdering::Acquire),
+            )
+        };
+        let actual_size = self.get_current_logical_size_non_incremental(lsn)?;
+        let drift = actual_size as i64 - tracked_size as i64;
+
+        if drift != 0 {
+            warn!(
+                "logical size of timeline {} at {} drifted by {} bytes: tracked {}, recomputed {}",
+                self.timeline_id, lsn, drift, tracked_size, actual_size
+            );
+            if correct {
+                // Keep the changes made since 'lsn'
+                self.current_logical_size
+                    .fetch_add(drift as isize, AtomicOrdering::SeqCst);
+            }
+        }
+        self.logical_size_drift_gauge.set(drift);
+
+        Ok(Some(drift))
+    }
+
+    ///
+    /// Report the maintenance locks that have been held for longer than
+    /// 'maintenance_stall_threshold', e.g. by a flush or compaction thread
+    /// that hangs on I/O.
+    ///
+    /// Returns the number of new stalls found. A stall is reported only once,
+    /// no matter how long the lock stays held.
+    ///
+    pub fn check_maintenance_stalls(&self) -> usize {
+        let threshold = self.conf.maintenance_stall_threshold;
+        let mut num_stalls = 0;
+        for lock in [&self.layer_flush_lock, &self.layer_removal_cs] {
+            if let Some(stall) = lock.check_stall(threshold) {
+                error!(
+                    "thread {} has held {} of timeline {} of tenant {} for {:?}",
+                    stall.thread,
+                    lock.name(),
+                    self.timeline_id,
+                    self.tenant_id,
+                    stall.held_for
+                );
+                num_stalls += 1;
+            }
+        }
+        num_stalls
+    }
+
+    ///
+    /// Delete the files of layers that were removed from the layer map, once
+    /// they are no longer referenced by any layer map snapshot. Waits up to
+    /// 'wait' for the readers that still use them.
+    ///
+    /// The layers are no longer in the map, so no new snapshot can pick them
+    /// up. Don't hold the layer map write lock while calling this, or the
+    /// readers we wait for can't make progress. Layers that are still in use
+    /// after 'wait' are kept for the next call; if the pageserver crashes
+    /// before that, load_layer_map() sets their files aside. The caller must
+    /// hold 'layer_removal_cs'.
+    ///
+    fn delete_unused_layers(&self, wait: Duration) -> Result<()> {
+        let deadline = Instant::now() + wait;
+        loop {
+            self.delete_unused_layers_once()?;
+            if self.doomed_layers.lock().unwrap().is_empty() || Instant::now() >= deadline {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn delete_unused_layers_once(&self) -> Result<()> {
+        let mut doomed_layers = self.doomed_layers.lock().unwrap();
+        let mut result = Ok(());
+        doomed_layers.retain(|l| {
+            // If no one else holds a reference to the layer, no snapshot can
+            // contain it anymore, and no new snapshot will.
+            if result.is_err() || Arc::strong_count(l) > 1 {
+                return true;
+            }
+            let size = l
+                .local_path()
+                .map(|path| path.metadata().map(|m| m.len()))
+                .transpose();
+            match size.and_then(|size| l.delete().map(|()| size)) {
+                Ok(size) => {
+                    if let Some(size) = size {
+                        self.current_physical_size_gauge.sub(size);
+                    }
+                    false
+                }
+                Err(e) => {
+                    result = Err(e);
+                    true
+                }
+            }
+        });
+        result
+    }
+
+    ///
+    /// Garbage collect layer files on a timeline that are no longer needed.
+    ///
+    /// Currently, we don't make any attempt at removing unneeded page versions
+    /// within a layer file. We can only remove the whole file if it's fully
+    /// obsolete.
+    ///
+    pub fn gc(&self) -> Result<GcResult> {
+        let mut result: GcResult = Default::default();
+        let now = SystemTime::now();
+
+        fail_point!("before-timeline-gc");
+
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+
+        let gc_info = self.gc_info.read().unwrap();
+
+        let horizon_cutoff = min(gc_info.horizon_cutoff, self.get_disk_consistent_lsn());
+        let pitr_cutoff = gc_info.pitr_cutoff;
+        let retain_lsns = &gc_info.retain_lsns;
+
+        let mut new_gc_cutoff = Lsn::min(horizon_cutoff, pitr_cutoff);
+
+        // Keep the follower LSN readable. Hold the lock until the new cutoff
+        // is set, so that the follower can't be moved below it meanwhile.
+        let follower_lsn = self.follower_lsn.lock().unwrap();
+        if let Some(follower_lsn) = *follower_lsn {
+            new_gc_cutoff = min(new_gc_cutoff, follower_lsn);
+        }
 
         // Nothing to GC. Return early.
         let latest_gc_cutoff = *self.get_latest_gc_cutoff_lsn();
@@ -1935,6 +5118,7 @@ impl LayeredTimeline {
                 "Nothing to GC for timeline {}: new_gc_cutoff_lsn {new_gc_cutoff}, latest_gc_cutoff_lsn {latest_gc_cutoff}",
                 self.timeline_id
             );
+            self.gc_runs_nothing_to_do_counter.inc();
             return Ok(result);
         }
 
@@ -1943,6 +5127,11 @@ impl LayeredTimeline {
         // We need to ensure that no one branches at a point before latest_gc_cutoff_lsn.
         // See branch_timeline() for details.
         *self.latest_gc_cutoff_lsn.write().unwrap() = new_gc_cutoff;
+        drop(follower_lsn);
+        self.prev_record_lsns
+            .lock()
+            .unwrap()
+            .remove_older_than(new_gc_cutoff)?;
 
         info!("GC starting");
 
@@ -1959,7 +5148,7 @@ impl LayeredTimeline {
         // 4. newer on-disk image layers cover the layer's whole key range
         //
         let mut layers = self.layers.write().unwrap();
-        'outer: for l in layers.iter_historic_layers() {
+        for l in layers.iter_historic_layers() {
             // This layer is in the process of being flushed to disk.
             // It will be swapped out of the layer map, replaced with
             // on-disk layers containing the same data.
@@ -1972,75 +5161,16 @@ impl LayeredTimeline {
 
             result.layers_total += 1;
 
-            // 1. Is it newer than GC horizon cutoff point?
-            if l.get_lsn_range().end > horizon_cutoff {
-                debug!(
-                    "keeping {} because it's newer than horizon_cutoff {}",
-                    l.filename().display(),
-                    horizon_cutoff
-                );
-                result.layers_needed_by_cutoff += 1;
-                continue 'outer;
-            }
-
-            // 2. It is newer than PiTR cutoff point?
-            if l.get_lsn_range().end > pitr_cutoff {
-                debug!(
-                    "keeping {} because it's newer than pitr_cutoff {}",
-                    l.filename().display(),
-                    pitr_cutoff
-                );
-                result.layers_needed_by_pitr += 1;
-                continue 'outer;
-            }
-
-            // 3. Is it needed by a child branch?
-            // NOTE With that we would keep data that
-            // might be referenced by child branches forever.
-            // We can track this in child timeline GC and delete parent layers when
-            // they are no longer needed. This might be complicated with long inheritance chains.
-            for retain_lsn in retain_lsns {
-                // start_lsn is inclusive
-                if &l.get_lsn_range().start <= retain_lsn {
-                    debug!(
-                        "keeping {} because it's still might be referenced by child branch forked at {} is_dropped: xx is_incremental: {}",
-                        l.filename().display(),
-                        retain_lsn,
-                        l.is_incremental(),
-                    );
-                    result.layers_needed_by_branches += 1;
-                    continue 'outer;
-                }
-            }
-
-            // 4. Is there a later on-disk layer for this relation?
-            //
-            // The end-LSN is exclusive, while disk_consistent_lsn is
-            // inclusive. For example, if disk_consistent_lsn is 100, it is
-            // OK for a delta layer to have end LSN 101, but if the end LSN
-            // is 102, then it might not have been fully flushed to disk
-            // before crash.
-            //
-            // For example, imagine that the following layers exist:
-            //
-            // 1000      - image (A)
-            // 1000-2000 - delta (B)
-            // 2000      - image (C)
-            // 2000-3000 - delta (D)
-            // 3000      - image (E)
-            //
-            // If GC horizon is at 2500, we can remove layers A and B, but
-            // we cannot remove C, even though it's older than 2500, because
-            // the delta layer 2000-3000 depends on it.
-            if !layers
-                .image_layer_exists(&l.get_key_range(), &(l.get_lsn_range().end..new_gc_cutoff))?
+            if let Some(reason) =
+                gc_keep_reason(&layers, l.as_ref(), horizon_cutoff, &gc_info, new_gc_cutoff)?
             {
-                debug!(
-                    "keeping {} because it is the latest layer",
-                    l.filename().display()
-                );
-                result.layers_not_updated += 1;
-                continue 'outer;
+                match reason {
+                    GcKeepReason::HorizonCutoff => result.layers_needed_by_cutoff += 1,
+                    GcKeepReason::PitrCutoff => result.layers_needed_by_pitr += 1,
+                    GcKeepReason::Branches => result.layers_needed_by_branches += 1,
+                    GcKeepReason::NotUpdated => result.layers_not_updated += 1,
+                }
+                continue;
             }
 
             // We didn't find any reason to keep this file, so remove it.
@@ -2056,15 +5186,34 @@ impl LayeredTimeline {
         // (couldn't do this in the loop above, because you cannot modify a collection
         // while iterating it. BTreeMap::retain() would be another option)
         let mut layer_paths_to_delete = HashSet::with_capacity(layers_to_remove.len());
+        let mut bytes_removed = 0;
+        let mut removed = Vec::with_capacity(layers_to_remove.len());
+        let mut doomed_layers = self.doomed_layers.lock().unwrap();
         for doomed_layer in layers_to_remove {
+            removed.push(LayerDescriptor::of(doomed_layer.as_ref()));
             if let Some(path) = doomed_layer.local_path() {
-                self.current_physical_size_gauge.sub(path.metadata()?.len());
+                // The file might be deleted later, if it's still in use, but
+                // count it as removed by this run.
+                bytes_removed += path.metadata().map(|m| m.len()).unwrap_or(0);
                 layer_paths_to_delete.insert(path);
             }
-            doomed_layer.delete()?;
-            layers.remove_historic(doomed_layer);
+            layers.remove_historic(Arc::clone(&doomed_layer));
+            doomed_layers.push(doomed_layer);
             result.layers_removed += 1;
         }
+        drop(doomed_layers);
+        drop(layers);
+        self.delete_unused_layers(DOOMED_LAYER_WAIT)?;
+
+        if !removed.is_empty() {
+            self.maintenance_observer.get().on_layers_removed(
+                ZTenantTimelineId::new(self.tenant_id, self.timeline_id),
+                &removed,
+                LayerRemovalCause::Gc {
+                    cutoff: new_gc_cutoff,
+                },
+            );
+        }
 
         if self.upload_layers.load(atomic::Ordering::Relaxed) {
             storage_sync::schedule_layer_delete(
@@ -2074,197 +5223,5369 @@ impl LayeredTimeline {
             );
         }
 
+        self.gc_layers_removed_counter.inc_by(result.layers_removed);
+        self.gc_bytes_removed_counter.inc_by(bytes_removed);
+        if result.layers_removed > 0 {
+            self.gc_runs_reclaimed_counter.inc();
+        } else {
+            self.gc_runs_nothing_to_do_counter.inc();
+        }
+
         result.elapsed = now.elapsed()?;
         Ok(result)
     }
 
     ///
-    /// Reconstruct a value, using the given base image and WAL records in 'data'.
+    /// Estimate how many bytes gc() would reclaim if it ran now, with the
+    /// current 'gc_info', without removing anything.
     ///
-    fn reconstruct_value(
-        &self,
-        key: Key,
-        request_lsn: Lsn,
-        mut data: ValueReconstructState,
-    ) -> Result<Bytes> {
-        // Perform WAL redo if needed
-        data.records.reverse();
-
-        // If we have a page image, and no WAL, we're all set
-        if data.records.is_empty() {
-            if let Some((img_lsn, img)) = &data.img {
-                trace!(
-                    "found page image for key {} at {}, no WAL redo required",
-                    key,
-                    img_lsn
-                );
-                Ok(img.clone())
-            } else {
-                bail!("base image for {} at {} not found", key, request_lsn);
-            }
-        } else {
-            // We need to do WAL redo.
-            //
-            // If we don't have a base image, then the oldest WAL record better initialize
-            // the page
-            if data.img.is_none() && !data.records.first().unwrap().1.will_init() {
-                bail!(
-                    "Base image for {} at {} not found, but got {} WAL records",
-                    key,
-                    request_lsn,
-                    data.records.len()
-                );
-            } else {
-                let base_img = if let Some((_lsn, img)) = data.img {
-                    trace!(
-                        "found {} WAL records and a base image for {} at {}, performing WAL redo",
-                        data.records.len(),
-                        key,
-                        request_lsn
-                    );
-                    Some(img)
-                } else {
-                    trace!("found {} WAL records that will init the page for {} at {}, performing WAL redo", data.records.len(), key, request_lsn);
-                    None
-                };
-
-                let last_rec_lsn = data.records.last().unwrap().0;
-
-                let img =
-                    self.walredo_mgr
-                        .request_redo(key, request_lsn, base_img, data.records)?;
+    /// This is the total size of the local layer files that gc() would remove.
+    /// GC can't rewrite a layer to remove only some of the page versions in it,
+    /// so the space that [`Layer::estimate_reclaimable_bytes`] finds within the
+    /// layers that have to be kept is not included.
+    ///
+    pub fn estimate_gc_reclaim(&self) -> Result<u64> {
+        let gc_info = self.gc_info.read().unwrap();
 
-                if img.len() == page_cache::PAGE_SZ {
-                    let cache = page_cache::get();
-                    cache.memorize_materialized_page(
-                        self.tenant_id,
-                        self.timeline_id,
-                        key,
-                        last_rec_lsn,
-                        &img,
-                    );
-                }
+        let horizon_cutoff = min(gc_info.horizon_cutoff, self.get_disk_consistent_lsn());
+        let new_gc_cutoff = Lsn::min(horizon_cutoff, gc_info.pitr_cutoff);
+        if *self.get_latest_gc_cutoff_lsn() >= new_gc_cutoff {
+            return Ok(0);
+        }
 
-                Ok(img)
+        let layers = self.layers.read().unwrap();
+        let mut reclaimable = 0;
+        for l in layers.iter_historic_layers() {
+            if l.is_in_memory() {
+                continue;
+            }
+            if gc_keep_reason(&layers, l.as_ref(), horizon_cutoff, &gc_info, new_gc_cutoff)?
+                .is_some()
+            {
+                continue;
+            }
+            if let Some(path) = l.local_path() {
+                reclaimable += path.metadata().map(|m| m.len()).unwrap_or(0);
             }
         }
+        Ok(reclaimable)
     }
-}
-
-/// Helper function for get_reconstruct_data() to add the path of layers traversed
-/// to an error, as anyhow context information.
-fn layer_traversal_error(
-    msg: String,
-    path: Vec<(ValueReconstructResult, Lsn, Arc<dyn Layer>)>,
-) -> anyhow::Result<()> {
-    // We want the original 'msg' to be the outermost context. The outermost context
-    // is the most high-level information, which also gets propagated to the client.
-    let mut msg_iter = path
-        .iter()
-        .map(|(r, c, l)| {
-            format!(
-                "layer traversal: result {:?}, cont_lsn {}, layer: {}",
-                r,
-                c,
-                l.filename().display()
-            )
-        })
-        .chain(std::iter::once(msg));
-    // Construct initial message from the first traversed layer
-    let err = anyhow!(msg_iter.next().unwrap());
-
-    // Append all subsequent traversals, and the error message 'msg', as contexts.
-    Err(msg_iter.fold(err, |err, msg| err.context(msg)))
-}
-
-struct LayeredTimelineWriter<'a> {
-    tl: &'a LayeredTimeline,
-    _write_guard: MutexGuard<'a, ()>,
-}
-
-impl Deref for LayeredTimelineWriter<'_> {
-    type Target = dyn Timeline;
 
-    fn deref(&self) -> &Self::Target {
-        self.tl
-    }
-}
+    ///
+    /// Remove the local copy of a layer file, e.g. because it is known to be corrupt.
+    ///
+    /// Not supported yet: always fails, after checking that the layer exists and
+    /// is a historic one. Individual layers cannot be downloaded on demand, so
+    /// reads would fall through to an older layer and return stale data, and
+    /// the timeline cannot be downloaded again while it is loaded either.
+    ///
+    pub fn drop_local_layer(&self, filename: &str) -> Result<()> {
+        let layers = self.layers.read().unwrap();
 
-impl<'a> TimelineWriter<'_> for LayeredTimelineWriter<'a> {
-    fn put(&self, key: Key, lsn: Lsn, value: &Value) -> Result<()> {
-        self.tl.put_value(key, lsn, value)
-    }
+        let filename = Path::new(filename);
+        if layers
+            .open_layer
+            .iter()
+            .chain(layers.frozen_layers.iter())
+            .any(|l| l.filename() == filename)
+        {
+            bail!("cannot drop in-memory layer {}", filename.display());
+        }
+        ensure!(
+            layers
+                .iter_historic_layers()
+                .any(|l| l.filename() == filename && !l.is_in_memory()),
+            "layer {} not found",
+            filename.display()
+        );
 
-    fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
-        self.tl.put_tombstone(key_range, lsn)
+        bail!(
+            "cannot drop layer {}: layers cannot be downloaded on demand yet",
+            filename.display()
+        )
     }
 
     ///
-    /// Remember the (end of) last valid WAL record remembered in the timeline.
+    /// Cross-check the historic layers in the layer map against the layer
+    /// files in the timeline directory, and against the remote index.
     ///
-    fn finish_write(&self, new_lsn: Lsn) {
-        self.tl.finish_write(new_lsn);
-    }
-
-    fn update_current_logical_size(&self, delta: isize) {
-        self.tl
-            .current_logical_size
-            .fetch_add(delta, AtomicOrdering::SeqCst);
-    }
-}
-
-/// Add a suffix to a layer file's name: .{num}.old
-/// Uses the first available num (starts at 0)
-fn rename_to_backup(path: PathBuf) -> anyhow::Result<()> {
-    let filename = path
-        .file_name()
-        .ok_or_else(|| anyhow!("Path {} don't have a file name", path.display()))?
-        .to_string_lossy();
-    let mut new_path = path.clone();
+    /// A layer in the map without a local file fails reads, and so does one
+    /// without a remote copy once its local file is lost. Layers flushed since
+    /// the last upload, and remote layers not downloaded yet, are reported too.
+    ///
+    /// With 'repair', local layer files that are missing from the layer map are
+    /// added to it, unless they are newer than disk_consistent_lsn. Nothing else
+    /// is changed.
+    ///
+    pub fn reconcile_layers(&self, repair: bool) -> Result<ReconcileReport> {
+        // Layer files are created and deleted under these locks, so neither the
+        // directory nor the layer map changes during the scan.
+        let _layer_removal_cs = self.layer_removal_cs.lock();
+        let _layer_flush_lock = self.layer_flush_lock.lock();
 
-    for i in 0u32.. {
-        new_path.set_file_name(format!("{}.{}.old", filename, i));
-        if !new_path.exists() {
-            std::fs::rename(&path, &new_path)?;
-            return Ok(());
+        let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
+        let mut local_files = HashSet::new();
+        for direntry in fs::read_dir(&timeline_path)? {
+            let direntry = direntry?;
+            let fname = direntry.file_name();
+            let fname = fname.to_string_lossy();
+            if ImageFileName::parse_str(&fname).is_some()
+                || DeltaFileName::parse_str(&fname).is_some()
+            {
+                local_files.insert(direntry.path());
+            }
         }
-    }
 
-    bail!("couldn't find an unused backup number for {:?}", path)
+        let remote_files = self
+            .remote_index
+            .blocking_read()
+            .timeline_entry(&ZTenantTimelineId {
+                tenant_id: self.tenant_id,
+                timeline_id: self.timeline_id,
+            })
+            .map(|remote_timeline| remote_timeline.stored_files().clone());
+
+        let mut layers = self.layers.write().unwrap();
+        let map_files: HashSet<PathBuf> = layers
+            .iter_historic_layers()
+            .filter_map(|l| l.local_path())
+            .collect();
+
+        let mut report = ReconcileReport::default();
+        for path in &map_files {
+            if !local_files.contains(path) {
+                report.map_layers_missing_locally.push(path.clone());
+            }
+            if let Some(remote_files) = &remote_files {
+                if !remote_files.contains(path) {
+                    report.map_layers_missing_remotely.push(path.clone());
+                }
+            }
+        }
+        for path in remote_files.iter().flatten() {
+            if !map_files.contains(path) {
+                report.remote_layers_not_in_map.push(path.clone());
+            }
+        }
+
+        let disk_consistent_lsn = self.disk_consistent_lsn.load();
+        for path in local_files {
+            if map_files.contains(&path) {
+                continue;
+            }
+            report.local_files_not_in_map.push(path.clone());
+            if !repair {
+                continue;
+            }
+
+            let fname = path.file_name().unwrap_or_default().to_string_lossy();
+            let layer: Arc<dyn Layer> = if let Some(imgfilename) = ImageFileName::parse_str(&fname)
+            {
+                if is_future_layer(imgfilename.lsn + 1, disk_consistent_lsn) {
+                    continue;
+                }
+                Arc::new(ImageLayer::new(
+                    self.conf,
+                    self.timeline_id,
+                    self.tenant_id,
+                    &imgfilename,
+                ))
+            } else if let Some(deltafilename) = DeltaFileName::parse_str(&fname) {
+                if is_future_layer(deltafilename.lsn_range.end, disk_consistent_lsn) {
+                    continue;
+                }
+                Arc::new(DeltaLayer::new(
+                    self.conf,
+                    self.timeline_id,
+                    self.tenant_id,
+                    &deltafilename,
+                ))
+            } else {
+                continue;
+            };
+
+            let size = path.metadata()?.len();
+            if layer.is_incremental() {
+                self.delta_layer_size_histo.observe(size as f64);
+            } else {
+                self.image_layer_size_histo.observe(size as f64);
+            }
+            layers.insert_historic(layer);
+            self.has_local_layers.store(true, AtomicOrdering::Release);
+            self.current_physical_size_gauge.add(size);
+            info!(
+                "added orphaned layer file {} to the layer map",
+                path.display()
+            );
+            report.repaired.push(path);
+        }
+        drop(layers);
+
+        report.local_files_not_in_map.sort();
+        report.map_layers_missing_locally.sort();
+        report.map_layers_missing_remotely.sort();
+        report.remote_layers_not_in_map.sort();
+        report.repaired.sort();
+        Ok(report)
+    }
+
+    ///
+    /// Reconstruct a value, using the given base image and WAL records in 'data'.
+    ///
+    fn reconstruct_value(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        data: ValueReconstructState,
+    ) -> Result<Bytes> {
+        match self.prepare_reconstruct(key, request_lsn, data)? {
+            Reconstruct::Done(img) => Ok(img),
+            Reconstruct::NeedsRedo(req) => {
+                let last_rec_lsn = req.records.last().unwrap().0;
+                let img = self.request_redo_checked(req)?;
+                self.memorize_reconstructed_page(key, last_rec_lsn, &img);
+                Ok(img)
+            }
+        }
+    }
+
+    /// Like [`Self::request_redo_in_steps`], but fails right away if the WAL
+    /// redo of the key keeps failing, see [`WalRedoCircuitOpen`].
+    fn request_redo_checked(&self, req: RedoRequest) -> Result<Bytes> {
+        self.check_redo_circuit(&req)?;
+        let key = req.key;
+        let inputs = RedoInputs::of(&req);
+        let result = self.request_redo_in_steps(req);
+        self.record_redo_outcome(key, inputs, result.as_ref().map(|_| ()));
+        result
+    }
+
+    ///
+    /// Return [`WalRedoCircuitOpen`] if the WAL redo of 'req' has failed
+    /// 'walredo_failure_threshold' times in a row, within the cooldown. The
+    /// failures are forgotten when the cooldown has passed, or when the WAL
+    /// to replay has changed since.
+    ///
+    fn check_redo_circuit(&self, req: &RedoRequest) -> Result<()> {
+        let threshold = self.conf.walredo_failure_threshold;
+        if threshold == 0 {
+            return Ok(());
+        }
+        let cooldown = self.conf.walredo_failure_cooldown;
+        let mut redo_failures = self.redo_failures.lock().unwrap();
+        if let Some(failure) = redo_failures.get(&req.key) {
+            let elapsed = failure.last_failure.elapsed();
+            if failure.inputs != RedoInputs::of(req) || elapsed >= cooldown {
+                redo_failures.remove(&req.key);
+            } else if failure.count >= threshold {
+                return Err(WalRedoCircuitOpen {
+                    key: req.key,
+                    failures: failure.count,
+                    retry_in: cooldown - elapsed,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a failed WAL redo of 'key', or forget the earlier failures if
+    /// it succeeded. Only failures that would happen again with the same WAL
+    /// count: an I/O error or a timeout of the WAL redo process, or the
+    /// process getting killed, says nothing about the WAL of the key.
+    fn record_redo_outcome(
+        &self,
+        key: Key,
+        inputs: RedoInputs,
+        result: Result<(), &anyhow::Error>,
+    ) {
+        let threshold = self.conf.walredo_failure_threshold;
+        if threshold == 0 {
+            return;
+        }
+        if let Err(err) = result {
+            if !matches!(
+                err.downcast_ref::<WalRedoError>(),
+                Some(WalRedoError::InvalidRecord | WalRedoError::InvalidRequest)
+            ) {
+                return;
+            }
+        }
+        let cooldown = self.conf.walredo_failure_cooldown;
+        let mut redo_failures = self.redo_failures.lock().unwrap();
+        if result.is_ok() {
+            redo_failures.remove(&key);
+            return;
+        }
+
+        let now = Instant::now();
+        let count = match redo_failures.get(&key) {
+            Some(failure) if failure.inputs == inputs && now - failure.last_failure < cooldown => {
+                failure.count + 1
+            }
+            _ => 1,
+        };
+        if !redo_failures.contains_key(&key) && redo_failures.len() >= MAX_TRACKED_REDO_FAILURES {
+            let oldest = redo_failures
+                .iter()
+                .min_by_key(|(_, failure)| failure.last_failure)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                redo_failures.remove(&oldest);
+            }
+        }
+        redo_failures.insert(
+            key,
+            RedoFailure {
+                inputs,
+                count,
+                last_failure: now,
+            },
+        );
+
+        if count == threshold {
+            warn!(
+                "WAL redo of key {} failed {} times in a row, failing reads of it for {:?}",
+                key, count, cooldown
+            );
+            self.walredo_circuit_opened_counter.inc();
+        }
+    }
+
+    /// Does 'req' need to be split by [`Self::request_redo_in_steps`]?
+    fn exceeds_walredo_max_records_size(&self, req: &RedoRequest) -> bool {
+        let max_records_size = self.get_walredo_max_records_size();
+        let mut records_size = 0;
+        for (_lsn, rec) in &req.records {
+            // Let request_redo_in_steps() report the error, if any
+            records_size = records_size.saturating_add(rec.serialized_size().unwrap_or(u64::MAX));
+            if records_size > max_records_size {
+                return true;
+            }
+        }
+        false
+    }
+
+    ///
+    /// Perform the WAL redo in 'req'. If the records add up to more than
+    /// 'walredo_max_records_size', they are sent to the WAL redo process in
+    /// several requests, each one replaying a part of the records on top of the
+    /// page produced by the previous one. That bounds the memory that the WAL
+    /// redo process needs, no matter how long the chain of records is.
+    ///
+    fn request_redo_in_steps(&self, req: RedoRequest) -> Result<Bytes> {
+        let max_records_size = self.get_walredo_max_records_size();
+        let RedoRequest {
+            key,
+            lsn,
+            mut base_img,
+            mut records,
+        } = req;
+
+        loop {
+            // Take as many records as fit in the limit, but at least one
+            let mut split_at = records.len();
+            let mut records_size = 0;
+            for (i, (_lsn, rec)) in records.iter().enumerate() {
+                records_size += rec.serialized_size()?;
+                if records_size > max_records_size && i > 0 {
+                    split_at = i;
+                    break;
+                }
+            }
+
+            if split_at == records.len() {
+                return Ok(self.walredo_mgr.request_redo(key, lsn, base_img, records)?);
+            }
+
+            let rest = records.split_off(split_at);
+            let step_lsn = records.last().unwrap().0;
+            trace!(
+                "replaying {} of {} WAL records for {} up to {}",
+                records.len(),
+                records.len() + rest.len(),
+                key,
+                step_lsn
+            );
+            base_img = Some(
+                self.walredo_mgr
+                    .request_redo(key, step_lsn, base_img, records)?,
+            );
+            records = rest;
+        }
+    }
+
+    ///
+    /// Check that the collected data is enough to reconstruct the value, and
+    /// return the value if no WAL redo is needed.
+    ///
+    fn prepare_reconstruct(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        mut data: ValueReconstructState,
+    ) -> Result<Reconstruct> {
+        // Perform WAL redo if needed
+        data.records.reverse();
+
+        // If we have a page image, and no WAL, we're all set
+        if data.records.is_empty() {
+            if let Some((img_lsn, img)) = data.img {
+                trace!(
+                    "found page image for key {} at {}, no WAL redo required",
+                    key,
+                    img_lsn
+                );
+                Ok(Reconstruct::Done(img))
+            } else {
+                bail!("base image for {} at {} not found", key, request_lsn);
+            }
+        } else {
+            // We need to do WAL redo.
+            //
+            // If we don't have a base image, then the oldest WAL record better initialize
+            // the page
+            if data.img.is_none() && !data.records.first().unwrap().1.will_init() {
+                bail!(
+                    "Base image for {} at {} not found, but got {} WAL records: {}",
+                    key,
+                    request_lsn,
+                    data.records.len(),
+                    RecordChain::of(None, &data.records)
+                );
+            } else {
+                let base_img = if let Some((_lsn, img)) = data.img {
+                    trace!(
+                        "found {} WAL records and a base image for {} at {}, performing WAL redo",
+                        data.records.len(),
+                        key,
+                        request_lsn
+                    );
+                    Some(img)
+                } else {
+                    trace!("found {} WAL records that will init the page for {} at {}, performing WAL redo", data.records.len(), key, request_lsn);
+                    None
+                };
+
+                Ok(Reconstruct::NeedsRedo(RedoRequest {
+                    key,
+                    lsn: request_lsn,
+                    base_img,
+                    records: data.records,
+                }))
+            }
+        }
+    }
+
+    /// Remember a page reconstructed with WAL redo in the page cache.
+    fn memorize_reconstructed_page(&self, key: Key, last_rec_lsn: Lsn, img: &Bytes) {
+        if img.len() == page_cache::PAGE_SZ && self.get_materialized_cache_enabled() {
+            let cache = page_cache::get();
+            cache.memorize_materialized_page(
+                self.tenant_id,
+                self.timeline_id,
+                key,
+                last_rec_lsn,
+                img,
+            );
+        }
+    }
 }
 
-/// Save timeline metadata to file
-pub fn save_metadata(
-    conf: &'static PageServerConf,
-    timelineid: ZTimelineId,
-    tenantid: ZTenantId,
-    data: &TimelineMetadata,
-    first_save: bool,
-) -> Result<()> {
-    let _enter = info_span!("saving metadata").entered();
-    let path = metadata_path(conf, timelineid, tenantid);
-    // use OpenOptions to ensure file presence is consistent with first_save
-    let mut file = VirtualFile::open_with_options(
-        &path,
-        OpenOptions::new().write(true).create_new(first_save),
-    )?;
+/// Result of [`LayeredTimeline::prepare_reconstruct`]
+enum Reconstruct {
+    /// The value, no WAL redo needed
+    Done(Bytes),
+    NeedsRedo(RedoRequest),
+}
 
-    let metadata_bytes = data.to_bytes().context("Failed to get metadata bytes")?;
+impl Drop for LayeredTimeline {
+    fn drop(&mut self) {
+        // No one can take a snapshot of the layer map anymore, so any layers
+        // that are still waiting to be deleted are unused now.
+        for l in self.doomed_layers.get_mut().unwrap().drain(..) {
+            if let Err(e) = l.delete() {
+                warn!("could not delete layer {}: {:#}", l.filename().display(), e);
+            }
+        }
+    }
+}
 
-    if file.write(&metadata_bytes)? != metadata_bytes.len() {
-        bail!("Could not write all the metadata bytes in a single call");
+/// Pick up to 'sample_size' keys from the keyspace, at evenly spaced intervals.
+fn sample_keys(keyspace: &KeySpace, sample_size: usize) -> Vec<Key> {
+    let total_size: u64 = keyspace
+        .ranges
+        .iter()
+        .map(|range| key_range_size(range) as u64)
+        .sum();
+    if total_size == 0 || sample_size == 0 {
+        return Vec::new();
     }
-    file.sync_all()?;
+    let stride = max(1, total_size / sample_size as u64);
+
+    let mut keys = Vec::with_capacity(sample_size);
+    // Offset of the next key to sample, relative to the start of the current range
+    let mut offset = 0;
+    for range in &keyspace.ranges {
+        let range_size = key_range_size(range) as u64;
+        while offset < range_size {
+            let key = range.start.add(offset as u32);
+            if key >= range.end || keys.len() >= sample_size {
+                break;
+            }
+            keys.push(key);
+            offset += stride;
+        }
+        if keys.len() >= sample_size {
+            break;
+        }
+        offset = offset.saturating_sub(range_size);
+    }
+    keys
+}
 
-    // fsync the parent directory to ensure the directory entry is durable
-    if first_save {
-        let timeline_dir = File::open(
-            &path
-                .parent()
-                .expect("Metadata should always have a parent dir"),
-        )?;
-        timeline_dir.sync_all()?;
+/// Helper function for get_internal() and get_reconstruct_data() to give up
+/// on a read once its deadline has passed.
+fn check_deadline(deadline: Option<Instant>, key: Key, lsn: Lsn) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(GetTimeoutError { key, lsn }.into()),
+        _ => Ok(()),
     }
+}
 
-    Ok(())
+///
+/// Helper function for get_reconstruct_data() to read from one layer.
+///
+/// The read is timed in the 'pageserver_layer_read_seconds' histogram. Errors
+/// are annotated with the layer that failed, e.g. because the file is corrupt.
+///
+fn read_layer(
+    layer: &dyn Layer,
+    key: Key,
+    lsn_range: Range<Lsn>,
+    reconstruct_state: &mut ValueReconstructState,
+) -> Result<ValueReconstructResult> {
+    let (kind, residency) = if layer.is_in_memory() {
+        ("in_memory", "resident")
+    } else {
+        let kind = if layer.is_incremental() {
+            "delta"
+        } else {
+            "image"
+        };
+        // Historic layers without a local file are read from the remote storage
+        let residency = if layer.local_path().is_some() {
+            "resident"
+        } else {
+            "remote"
+        };
+        (kind, residency)
+    };
+    LAYER_READ_TIME
+        .with_label_values(&[kind, residency])
+        .observe_closure_duration(|| {
+            layer.get_value_reconstruct_data(key, lsn_range.clone(), reconstruct_state)
+        })
+        .with_context(|| {
+            format!(
+                "failed to read key {} at LSN range {}..{} from layer {}",
+                key,
+                lsn_range.start,
+                lsn_range.end,
+                layer.filename().display()
+            )
+        })
+}
+
+///
+/// The layers that get_reconstruct_data() traversed, for the error message if
+/// the read fails. Only the last 'capacity' steps are kept, so that a very
+/// deep traversal doesn't hold on to an unbounded number of layers. The older
+/// steps are only counted.
+///
+struct TraversalPath {
+    steps: VecDeque<(ValueReconstructResult, Lsn, Arc<dyn Layer>)>,
+    capacity: usize,
+    omitted: usize,
+}
+
+impl TraversalPath {
+    fn new(capacity: usize) -> Self {
+        TraversalPath {
+            steps: VecDeque::with_capacity(capacity.min(16)),
+            capacity,
+            omitted: 0,
+        }
+    }
+
+    fn push(&mut self, step: (ValueReconstructResult, Lsn, Arc<dyn Layer>)) {
+        if self.capacity == 0 {
+            self.omitted += 1;
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+            self.omitted += 1;
+        }
+        self.steps.push_back(step);
+    }
+}
+
+/// Helper function for get_reconstruct_data() to add the path of layers traversed
+/// to an error, as anyhow context information.
+
+fn layer_traversal_error<M>(msg: M, path: TraversalPath) -> anyhow::Result<()>
+where
+    M: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+{
+    // We want the original 'msg' to be the outermost context. The outermost context
+    // is the most high-level information, which also gets propagated to the client.
+    let omitted =
+        (path.omitted > 0).then(|| format!("... and {} earlier layers omitted", path.omitted));
+    let mut traversal_iter = omitted
+        .into_iter()
+        .chain(path.steps.iter().map(|(r, c, l)| {
+            format!(
+                "layer traversal: result {:?}, cont_lsn {}, layer: {}",
+                r,
+                c,
+                l.filename().display()
+            )
+        }));
+
+    // Construct initial message from the first traversed layer, or the note
+    // about the omitted ones, and append all subsequent traversals, and the
+    // error message 'msg', as contexts. 'msg' is kept as is, so that callers
+    // can downcast to it.
+    let err = match traversal_iter.next() {
+        Some(first) => traversal_iter
+            .fold(anyhow!(first), |err, msg| err.context(msg))
+            .context(msg),
+        None => anyhow::Error::msg(msg),
+    };
+    Err(err)
+}
+
+struct LayeredTimelineWriter<'a> {
+    tl: &'a LayeredTimeline,
+    _write_guard: MutexGuard<'a, ()>,
+    /// Writes staged since begin_batch(), if a batch is in progress
+    staged: RefCell<Option<Vec<StagedWrite>>>,
+}
+
+enum StagedWrite {
+    Put(Key, Lsn, Value),
+    Delete(Range<Key>, Lsn),
+}
+
+impl Deref for LayeredTimelineWriter<'_> {
+    type Target = dyn Timeline;
+
+    fn deref(&self) -> &Self::Target {
+        self.tl
+    }
+}
+
+impl<'a> TimelineWriter<'_> for LayeredTimelineWriter<'a> {
+    fn put(&self, key: Key, lsn: Lsn, value: &Value) -> Result<()> {
+        if let Some(staged) = self.staged.borrow_mut().as_mut() {
+            staged.push(StagedWrite::Put(key, lsn, value.clone()));
+            return Ok(());
+        }
+        self.tl.check_ingest_disk_space()?;
+        self.tl.put_value(key, lsn, value)
+    }
+
+    fn put_batch(&self, values: &[(Key, Lsn, Value)]) -> Result<()> {
+        if let Some(staged) = self.staged.borrow_mut().as_mut() {
+            staged.extend(
+                values
+                    .iter()
+                    .map(|(key, lsn, value)| StagedWrite::Put(*key, *lsn, value.clone())),
+            );
+            return Ok(());
+        }
+        self.tl.check_ingest_disk_space()?;
+        self.tl.put_values(values)
+    }
+
+    fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
+        if let Some(staged) = self.staged.borrow_mut().as_mut() {
+            staged.push(StagedWrite::Delete(key_range, lsn));
+            return Ok(());
+        }
+        self.tl.check_ingest_disk_space()?;
+        self.tl.put_tombstone(key_range, lsn)
+    }
+
+    ///
+    /// Remember the (end of) last valid WAL record remembered in the timeline.
+    ///
+    fn finish_write(&self, new_lsn: Lsn) -> anyhow::Result<()> {
+        ensure!(
+            self.staged.borrow().is_none(),
+            "finish_write called in the middle of a batch, use commit_batch instead"
+        );
+        self.tl.finish_write(new_lsn)
+    }
+
+    fn begin_batch(&self) -> Result<()> {
+        let mut staged = self.staged.borrow_mut();
+        ensure!(staged.is_none(), "a batch is already in progress");
+        *staged = Some(Vec::new());
+        Ok(())
+    }
+
+    ///
+    /// The staged writes are applied while holding 'write_lock', before the
+    /// last record LSN is advanced, so readers never see only some of them.
+    /// The free disk space is checked once, before any of them is applied.
+    /// Consecutive puts are inserted together with put_values(), so the open
+    /// layer is only looked up once for each run of them.
+    ///
+    fn commit_batch(&self, new_lsn: Lsn) -> anyhow::Result<()> {
+        let staged = self
+            .staged
+            .borrow_mut()
+            .take()
+            .context("commit_batch called without begin_batch")?;
+        self.tl.check_finish_write_lsn(new_lsn)?;
+        self.tl.check_ingest_disk_space()?;
+
+        let mut puts = Vec::new();
+        for write in staged {
+            match write {
+                StagedWrite::Put(key, lsn, value) => puts.push((key, lsn, value)),
+                StagedWrite::Delete(key_range, lsn) => {
+                    self.tl.put_values(&puts)?;
+                    puts.clear();
+                    self.tl.put_tombstone(key_range, lsn)?;
+                }
+            }
+        }
+        self.tl.put_values(&puts)?;
+        self.tl.finish_write(new_lsn)
+    }
+
+    fn update_current_logical_size(&self, delta: isize) {
+        self.tl
+            .current_logical_size
+            .fetch_add(delta, AtomicOrdering::SeqCst);
+    }
+}
+
+///
+/// Does a layer with the given end LSN hold data that is not covered by
+/// 'disk_consistent_lsn'? Such a layer was written after the metadata file was
+/// last updated, and might not have been fully flushed to disk before a crash.
+///
+/// The end LSN is exclusive, like in [`Layer::get_lsn_range`], while
+/// disk_consistent_lsn is inclusive. For example, if disk_consistent_lsn is
+/// 100, it is OK for a delta layer to have end LSN 101, but if the end LSN is
+/// 102, it is a future layer. An image layer at LSN 100 has end LSN 101 and is
+/// fine, but one at LSN 101 is not.
+///
+fn is_future_layer(layer_end_lsn: Lsn, disk_consistent_lsn: Lsn) -> bool {
+    layer_end_lsn > disk_consistent_lsn + 1
+}
+
+///
+/// Find the delta layers whose contents are all in other layers, produced by
+/// a compaction that had them as input.
+///
+/// The LSN ranges of layers only overlap if one of them was produced by
+/// compacting the other, together with its neighbours. A delta layer is
+/// therefore superseded if every key in it is covered by layers that contain
+/// its whole LSN range, and either more LSNs, or the same LSNs and more keys.
+/// Those layers hold all the versions of the key in the LSN range.
+///
+fn superseded_delta_layers(layers: &LayerMap) -> Vec<Arc<dyn Layer>> {
+    let deltas: Vec<&Arc<dyn Layer>> = layers
+        .iter_historic_layers()
+        .filter(|l| l.is_incremental())
+        .collect();
+    let contains = |a: &Arc<dyn Layer>, b: &Arc<dyn Layer>| {
+        let (a_lsns, b_lsns) = (a.get_lsn_range(), b.get_lsn_range());
+        let (a_keys, b_keys) = (a.get_key_range(), b.get_key_range());
+        let contains_lsns = a_lsns.start <= b_lsns.start && b_lsns.end <= a_lsns.end;
+        let contains_keys = a_keys.start <= b_keys.start && b_keys.end <= a_keys.end;
+        contains_lsns && (a_lsns != b_lsns || (contains_keys && a_keys != b_keys))
+    };
+
+    let mut superseded = Vec::new();
+    for l in deltas.iter() {
+        let covering: Vec<Range<Key>> = deltas
+            .iter()
+            .filter(|other| contains(other, l))
+            .map(|other| other.get_key_range())
+            .collect();
+        if covering.is_empty() {
+            continue;
+        }
+        let key_range = l.get_key_range();
+        let is_superseded = covering
+            .iter()
+            .any(|r| r.start <= key_range.start && key_range.end <= r.end)
+            || l.key_iter()
+                .all(|(key, _, _)| covering.iter().any(|r| r.contains(&key)));
+        if is_superseded {
+            superseded.push(Arc::clone(l));
+        }
+    }
+    superseded
+}
+
+/// Reason for GC to keep a historic layer, see [`gc_keep_reason`].
+enum GcKeepReason {
+    HorizonCutoff,
+    PitrCutoff,
+    Branches,
+    NotUpdated,
+}
+
+///
+/// Check whether GC needs to keep a historic layer, given the cutoffs of the
+/// GC run. Returns None if the layer can be removed.
+///
+/// 'horizon_cutoff' is the cutoff from 'gc_info', clamped to
+/// disk_consistent_lsn, and 'new_gc_cutoff' the GC cutoff LSN of the run.
+///
+fn gc_keep_reason(
+    layers: &LayerMap,
+    l: &dyn Layer,
+    horizon_cutoff: Lsn,
+    gc_info: &GcInfo,
+    new_gc_cutoff: Lsn,
+) -> Result<Option<GcKeepReason>> {
+    // 1. Is it newer than GC horizon cutoff point?
+    if l.get_lsn_range().end > horizon_cutoff {
+        debug!(
+            "keeping {} because it's newer than horizon_cutoff {}",
+            l.filename().display(),
+            horizon_cutoff
+        );
+        return Ok(Some(GcKeepReason::HorizonCutoff));
+    }
+
+    // 2. It is newer than PiTR cutoff point?
+    if l.get_lsn_range().end > gc_info.pitr_cutoff {
+        debug!(
+            "keeping {} because it's newer than pitr_cutoff {}",
+            l.filename().display(),
+            gc_info.pitr_cutoff
+        );
+        return Ok(Some(GcKeepReason::PitrCutoff));
+    }
+
+    // 3. Is it needed by a child branch?
+    // NOTE With that we would keep data that
+    // might be referenced by child branches forever.
+    // We can track this in child timeline GC and delete parent layers when
+    // they are no longer needed. This might be complicated with long inheritance chains.
+    for retain_lsn in &gc_info.retain_lsns {
+        // start_lsn is inclusive
+        if &l.get_lsn_range().start <= retain_lsn {
+            debug!(
+                "keeping {} because it's still might be referenced by child branch forked at {} is_dropped: xx is_incremental: {}",
+                l.filename().display(),
+                retain_lsn,
+                l.is_incremental(),
+            );
+            return Ok(Some(GcKeepReason::Branches));
+        }
+    }
+
+    // 4. Is there a later on-disk layer for this relation?
+    //
+    // The end-LSN is exclusive, while disk_consistent_lsn is
+    // inclusive. For example, if disk_consistent_lsn is 100, it is
+    // OK for a delta layer to have end LSN 101, but if the end LSN
+    // is 102, then it might not have been fully flushed to disk
+    // before crash.
+    //
+    // For example, imagine that the following layers exist:
+    //
+    // 1000      - image (A)
+    // 1000-2000 - delta (B)
+    // 2000      - image (C)
+    // 2000-3000 - delta (D)
+    // 3000      - image (E)
+    //
+    // If GC horizon is at 2500, we can remove layers A and B, but
+    // we cannot remove C, even though it's older than 2500, because
+    // the delta layer 2000-3000 depends on it.
+    if !layers.image_layer_exists(&l.get_key_range(), &(l.get_lsn_range().end..new_gc_cutoff))? {
+        debug!(
+            "keeping {} because it is the latest layer",
+            l.filename().display()
+        );
+        return Ok(Some(GcKeepReason::NotUpdated));
+    }
+
+    Ok(None)
+}
+
+/// Move a file out of the way, by adding a suffix to its name: .{num}.old
+/// Uses the num after the highest one in use (starts at 0), so the copies
+/// are numbered from oldest to newest. The reason is recorded in a sidecar
+/// file next to it, named .{num}.reason.old.
+///
+/// The new name is reserved by creating it exclusively before the rename, so
+/// concurrent calls for the same file name never pick the same num.
+///
+/// At most 'max_copies' quarantined copies of the file name are kept, the
+/// oldest ones are deleted. The new copy is always kept.
+///
+/// Returns the new path of the file.
+pub fn quarantine_file(path: &Path, reason: &str, max_copies: usize) -> Result<PathBuf> {
+    let filename = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Path {} don't have a file name", path.display()))?
+        .to_string_lossy();
+    let mut new_path = path.to_path_buf();
+
+    let first_num = quarantined_copies(path, &filename)?
+        .last()
+        .map_or(0, |num| num + 1);
+    for i in first_num.. {
+        new_path.set_file_name(format!("{}.{}.old", filename, i));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&new_path)
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to reserve quarantine path {}", new_path.display())
+                })
+            }
+        }
+
+        if let Err(e) = fs::rename(path, &new_path) {
+            // Release the reserved name, the file was not moved
+            let _ = fs::remove_file(&new_path);
+            return Err(e).with_context(|| {
+                format!(
+                    "failed to rename {} to {}",
+                    path.display(),
+                    new_path.display()
+                )
+            });
+        }
+
+        let reason_path = path.with_file_name(format!("{}.{}.reason.old", filename, i));
+        fs::write(&reason_path, format!("{reason}\n")).with_context(|| {
+            format!(
+                "failed to record quarantine reason in {}",
+                reason_path.display()
+            )
+        })?;
+
+        warn!(
+            "quarantined {} as {}: {}",
+            path.display(),
+            new_path.display(),
+            reason
+        );
+
+        let copies = quarantined_copies(path, &filename)?;
+        let num_to_delete = copies.len().saturating_sub(max_copies.max(1));
+        for num in &copies[..num_to_delete] {
+            for old_name in [
+                format!("{}.{}.old", filename, num),
+                format!("{}.{}.reason.old", filename, num),
+            ] {
+                let old_path = path.with_file_name(old_name);
+                match fs::remove_file(&old_path) {
+                    Ok(()) => {}
+                    // Deleted by a concurrent call already
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("failed to delete {}", old_path.display()))
+                    }
+                }
+            }
+        }
+        if num_to_delete > 0 {
+            info!(
+                "deleted {} oldest quarantined copies of {}",
+                num_to_delete,
+                path.display()
+            );
+        }
+        return Ok(new_path);
+    }
+
+    bail!("couldn't find an unused backup number for {:?}", path)
+}
+
+/// The nums of the quarantined copies of 'path', see quarantine_file(), in
+/// ascending order.
+fn quarantined_copies(path: &Path, filename: &str) -> Result<Vec<u32>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", filename);
+
+    let mut nums = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(num) = name
+            .to_string_lossy()
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".old"))
+            .and_then(|num| num.parse::<u32>().ok())
+        {
+            nums.push(num);
+        }
+    }
+    nums.sort_unstable();
+    Ok(nums)
+}
+
+///
+/// Reconstruct the metadata of a timeline from the names of its layer files,
+/// for when the metadata file is lost or corrupt.
+///
+/// The layers are expected to cover a contiguous range of LSNs, starting from
+/// the oldest layer. 'disk_consistent_lsn' is set to the end of that range, so
+/// everything below it is present. If there is a gap, the layers above it are
+/// not used, and are quarantined as future layers when the timeline is loaded.
+/// There's no data below the start of the oldest layer, so that becomes the
+/// GC cutoff.
+///
+/// Keys are checked as far as the file names allow. Compaction moves the
+/// versions of a key with many versions into layers of their own, which cover
+/// consecutive LSN ranges up to the end of the compacted range. A gap between
+/// them means that a layer is lost, and recovery is refused. A lost layer with
+/// a range of keys can't be told apart from keys that had no changes, so it is
+/// not detected.
+///
+/// The ancestor of a branch is not recorded in the layer files. The layers of
+/// a branch start after the initdb LSN, which all the timelines of a tenant
+/// share, so recovery is refused if the layers start after the initdb LSN of
+/// another timeline of the tenant. That's also the case for a timeline whose
+/// oldest layers were removed by GC. A timeline without other timelines in the
+/// tenant is not a branch, and the start of its oldest layer becomes its initdb
+/// LSN.
+///
+pub fn recover_metadata_from_layers(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tenantid: ZTenantId,
+) -> Result<TimelineMetadata> {
+    let timeline_path = conf.timeline_path(&timelineid, &tenantid);
+
+    let mut lsn_ranges = Vec::new();
+    let mut deltas = Vec::new();
+    for direntry in fs::read_dir(&timeline_path)? {
+        let fname = direntry?.file_name();
+        let fname = fname.to_string_lossy();
+
+        if let Some(imgfilename) = ImageFileName::parse_str(&fname) {
+            lsn_ranges.push(imgfilename.lsn..imgfilename.lsn + 1);
+        } else if let Some(deltafilename) = DeltaFileName::parse_str(&fname) {
+            lsn_ranges.push(deltafilename.lsn_range.clone());
+            deltas.push(deltafilename);
+        }
+    }
+    lsn_ranges.sort_by_key(|lsn_range| lsn_range.start);
+
+    let (first, rest) = lsn_ranges.split_first().ok_or_else(|| {
+        anyhow!(
+            "no layer files to recover metadata from in {}",
+            timeline_path.display()
+        )
+    })?;
+    let start_lsn = first.start;
+    let mut end_lsn = first.end;
+    for lsn_range in rest {
+        if lsn_range.start > end_lsn {
+            warn!(
+                "layer files of timeline {} have no data between {} and {}, ignoring the layers after it",
+                timelineid, end_lsn, lsn_range.start
+            );
+            break;
+        }
+        end_lsn = max(end_lsn, lsn_range.end);
+    }
+    check_single_key_layers(&deltas)
+        .with_context(|| format!("layer files of timeline {} are incomplete", timelineid))?;
+
+    let disk_consistent_lsn = Lsn(end_lsn.0 - 1);
+    ensure!(
+        disk_consistent_lsn.is_aligned(),
+        "layer files of timeline {} end at unaligned LSN {}",
+        timelineid,
+        disk_consistent_lsn
+    );
+
+    let initdb_lsn = match tenant_initdb_lsn(conf, timelineid, tenantid)? {
+        Some(initdb_lsn) => {
+            ensure!(
+                start_lsn <= initdb_lsn,
+                "layer files of timeline {} start at {}, after the initdb LSN {}. It might be a branch, refusing to recover it without its ancestor",
+                timelineid,
+                start_lsn,
+                initdb_lsn
+            );
+            initdb_lsn
+        }
+        None => start_lsn,
+    };
+
+    Ok(TimelineMetadata::new(
+        disk_consistent_lsn,
+        None,
+        None,
+        Lsn(0),
+        start_lsn,
+        initdb_lsn,
+    ))
+}
+
+///
+/// Check that the layers holding the versions of a single key, which are
+/// narrower in LSNs than the layers they were compacted with, have no gaps
+/// between them, and reach the end of the compacted LSN range.
+///
+fn check_single_key_layers(deltas: &[DeltaFileName]) -> Result<()> {
+    let mut chains: BTreeMap<(Key, Lsn, Lsn), Vec<Range<Lsn>>> = BTreeMap::new();
+    for delta in deltas {
+        if delta.key_range.end != delta.key_range.start.next() {
+            continue;
+        }
+        // The other layers created by the same compaction have the full LSN range
+        let compacted_range = deltas
+            .iter()
+            .filter(|other| other.key_range != (Key::MIN..Key::MAX))
+            .map(|other| &other.lsn_range)
+            .filter(|range| {
+                range.start <= delta.lsn_range.start
+                    && delta.lsn_range.end <= range.end
+                    && **range != delta.lsn_range
+            })
+            .min_by_key(|range| range.end.0 - range.start.0);
+        if let Some(compacted_range) = compacted_range {
+            chains
+                .entry((
+                    delta.key_range.start,
+                    compacted_range.start,
+                    compacted_range.end,
+                ))
+                .or_default()
+                .push(delta.lsn_range.clone());
+        }
+    }
+
+    for ((key, _, compacted_end), mut lsn_ranges) in chains {
+        lsn_ranges.sort_by_key(|lsn_range| lsn_range.start);
+        for pair in lsn_ranges.windows(2) {
+            ensure!(
+                pair[0].end == pair[1].start,
+                "no layer has key {} between {} and {}",
+                key,
+                pair[0].end,
+                pair[1].start
+            );
+        }
+        let last_end = lsn_ranges.last().unwrap().end;
+        ensure!(
+            last_end == compacted_end,
+            "no layer has key {} between {} and {}",
+            key,
+            last_end,
+            compacted_end
+        );
+    }
+    Ok(())
+}
+
+///
+/// Find the initdb LSN of the tenant from the metadata of its other timelines.
+/// Returns None if the tenant has no other timelines.
+///
+fn tenant_initdb_lsn(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tenantid: ZTenantId,
+) -> Result<Option<Lsn>> {
+    let mut other_timelines = 0;
+    for direntry in fs::read_dir(conf.timelines_path(&tenantid))? {
+        let fname = direntry?.file_name();
+        let other_id = match fname.to_string_lossy().parse::<ZTimelineId>() {
+            Ok(other_id) if other_id != timelineid => other_id,
+            _ => continue,
+        };
+        other_timelines += 1;
+
+        let path = metadata_path(conf, other_id, tenantid);
+        match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| TimelineMetadata::from_bytes(&bytes))
+        {
+            Ok(metadata) => return Ok(Some(metadata.initdb_lsn())),
+            Err(e) => warn!("failed to read metadata of timeline {}: {:#}", other_id, e),
+        }
+    }
+    ensure!(
+        other_timelines == 0,
+        "none of the other {} timelines of tenant {} has readable metadata, can't tell if timeline {} is a branch",
+        other_timelines,
+        tenantid,
+        timelineid
+    );
+    Ok(None)
+}
+
+/// Save timeline metadata to file
+pub fn save_metadata(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tenantid: ZTenantId,
+    data: &TimelineMetadata,
+    first_save: bool,
+) -> Result<()> {
+    let _enter = info_span!("saving metadata").entered();
+    let path = metadata_path(conf, timelineid, tenantid);
+    // use OpenOptions to ensure file presence is consistent with first_save
+    let mut file = VirtualFile::open_with_options(
+        &path,
+        OpenOptions::new().write(true).create_new(first_save),
+    )?;
+
+    let metadata_bytes = data.to_bytes().context("Failed to get metadata bytes")?;
+
+    if file.write(&metadata_bytes)? != metadata_bytes.len() {
+        bail!("Could not write all the metadata bytes in a single call");
+    }
+    file.sync_all()?;
+
+    // fsync the parent directory to ensure the directory entry is durable
+    if first_save {
+        let timeline_dir = File::open(
+            &path
+                .parent()
+                .expect("Metadata should always have a parent dir"),
+        )?;
+        timeline_dir.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgdatadir_mapping::{create_test_timeline, ZERO_PAGE_SERVED};
+    use crate::reltag::SlruKind;
+    use crate::repository::repo_harness::*;
+    use crate::repository::Repository;
+    use crate::storage_sync::index::RemoteTimeline;
+    use crate::walredo::WalRedoError;
+    use postgres_ffi::pg_constants;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+
+    #[test]
+    fn flush_coalesces_metadata_updates() -> Result<()> {
+        let harness = RepoHarness::create("flush_coalesces_metadata_updates")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let save_metadata_histo = STORAGE_TIME.get_metric_with_label_values(&[
+            "save metadata",
+            &harness.tenant_id.to_string(),
+            &TIMELINE_ID.to_string(),
+        ])?;
+
+        // Queue up a few frozen layers
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0);
+        for _ in 0..3 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.freeze_inmem_layer(false);
+        }
+        assert_eq!(tline.layers.read().unwrap().frozen_layers.len(), 3);
+
+        let saves_before = save_metadata_histo.get_sample_count();
+        tline.flush_frozen_layers(true)?;
+        assert_eq!(save_metadata_histo.get_sample_count(), saves_before + 1);
+
+        assert!(tline.layers.read().unwrap().frozen_layers.is_empty());
+        assert_eq!(tline.get_disk_consistent_lsn(), lsn);
+        let metadata = TimelineMetadata::from_bytes(&fs::read(metadata_path(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+        ))?)?;
+        assert_eq!(metadata.disk_consistent_lsn(), lsn);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn physical_size_non_incremental_async() -> Result<()> {
+        let repo = RepoHarness::create("physical_size_non_incremental_async")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // A few layer files, next to the metadata file that isn't counted
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        let expected = tline.get_physical_size_non_incremental()?;
+        assert!(expected > 0);
+        assert_eq!(
+            tline.get_physical_size_non_incremental_async().await?,
+            expected
+        );
+        // Also when the files are spread over several chunks
+        for chunk_size in [1, 2, 3] {
+            assert_eq!(tline.physical_size_in_chunks(chunk_size).await?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_physical_size_fixes_drift() -> Result<()> {
+        let repo = RepoHarness::create("reconcile_physical_size_fixes_drift")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+
+        let actual_size = tline.get_physical_size_non_incremental()?;
+        assert!(actual_size > 0);
+        assert_eq!(tline.reconcile_physical_size()?, 0);
+        assert_eq!(tline.physical_size_drift_gauge.get(), 0);
+
+        // Pretend that some code path forgot to account for a removed file
+        tline.current_physical_size_gauge.add(1000);
+        assert_eq!(tline.reconcile_physical_size()?, -1000);
+        assert_eq!(tline.get_physical_size(), actual_size);
+        assert_eq!(tline.physical_size_drift_gauge.get(), -1000);
+
+        // ... and for a created one
+        tline.current_physical_size_gauge.sub(10);
+        assert_eq!(tline.reconcile_physical_size()?, 10);
+        assert_eq!(tline.get_physical_size(), actual_size);
+
+        assert_eq!(tline.reconcile_physical_size()?, 0);
+        assert_eq!(tline.physical_size_drift_gauge.get(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_logical_size_detects_drift() -> Result<()> {
+        let repo = RepoHarness::create("verify_logical_size_detects_drift")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 1,
+            relnode: 1000,
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_rel_creation(rel, 0)?;
+        m.put_rel_extend(rel, 3)?;
+        m.commit()?;
+        let actual_size = 3 * pg_constants::BLCKSZ as usize;
+        assert_eq!(tline.get_current_logical_size(), actual_size);
+        assert_eq!(tline.verify_logical_size(false)?, Some(0));
+
+        // Checks are throttled
+        assert_eq!(tline.verify_logical_size(false)?, None);
+        let allow_next_check = || *tline.last_logical_size_check.lock().unwrap() = None;
+
+        // Pretend that a WAL record's size change was counted twice
+        tline
+            .current_logical_size
+            .fetch_add(pg_constants::BLCKSZ as isize, AtomicOrdering::SeqCst);
+        allow_next_check();
+        let drift = -(pg_constants::BLCKSZ as i64);
+        assert_eq!(tline.verify_logical_size(false)?, Some(drift));
+        assert_eq!(tline.logical_size_drift_gauge.get(), drift);
+        assert_eq!(
+            tline.get_current_logical_size(),
+            actual_size + pg_constants::BLCKSZ as usize
+        );
+
+        // Fix it
+        allow_next_check();
+        assert_eq!(tline.verify_logical_size(true)?, Some(drift));
+        assert_eq!(tline.get_current_logical_size(), actual_size);
+        allow_next_check();
+        assert_eq!(tline.verify_logical_size(false)?, Some(0));
+        assert_eq!(tline.logical_size_drift_gauge.get(), 0);
+
+        Ok(())
+    }
+
+    /// Compact a few L0 layers with the given 'compaction_target_file_size',
+    /// and return the number of resulting L1 layers.
+    fn compact_with_target_file_size(
+        test_name: &'static str,
+        target_file_size: Option<u64>,
+    ) -> Result<usize> {
+        let mut harness = RepoHarness::create(test_name)?;
+        harness.tenant_conf.compaction_threshold = 2;
+        harness.tenant_conf.compaction_target_file_size = target_file_size;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        if target_file_size.is_none() {
+            assert_eq!(
+                tline.get_compaction_target_file_size(),
+                tline.get_checkpoint_distance()
+            );
+        }
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0x10);
+        for _ in 0..2 {
+            let writer = tline.writer();
+            for blknum in 0..1000 {
+                test_key.field6 = blknum;
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        assert_eq!(tline.layers.read().unwrap().get_level0_deltas()?.len(), 2);
+
+        tline.compact_level0(
+            &tline.get_compaction_target_file_sizes(),
+            &CancellationToken::default(),
+        )?;
+
+        let layers = tline.layers.read().unwrap();
+        assert!(layers.get_level0_deltas()?.is_empty());
+        Ok(layers
+            .iter_historic_layers()
+            .filter(|l| l.is_incremental())
+            .count())
+    }
+
+    #[test]
+    fn compaction_target_file_size() -> Result<()> {
+        let default_layers =
+            compact_with_target_file_size("compaction_target_file_size_default", None)?;
+        let large_layers = compact_with_target_file_size(
+            "compaction_target_file_size_large",
+            Some(1024 * 1024 * 1024),
+        )?;
+        let small_layers =
+            compact_with_target_file_size("compaction_target_file_size_small", Some(8192))?;
+
+        assert_eq!(default_layers, 1);
+        assert_eq!(large_layers, 1);
+        assert!(small_layers > 1, "got {small_layers} layers");
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_max_input_layers() -> Result<()> {
+        let mut harness = RepoHarness::create("compaction_max_input_layers")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        harness.tenant_conf.compaction_max_input_layers = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let lsns = [Lsn(0x10), Lsn(0x20), Lsn(0x30), Lsn(0x40), Lsn(0x50)];
+        for lsn in lsns {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        let level0_start_lsns = || -> Result<Vec<Lsn>> {
+            let mut lsns: Vec<Lsn> = tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()?
+                .iter()
+                .map(|l| l.get_lsn_range().start)
+                .collect();
+            lsns.sort();
+            Ok(lsns)
+        };
+        assert_eq!(level0_start_lsns()?.len(), 5);
+
+        // Each pass compacts the two oldest level 0 deltas
+        let compact = || {
+            tline.compact_level0(
+                &tline.get_compaction_target_file_sizes(),
+                &CancellationToken::default(),
+            )
+        };
+        compact()?;
+        assert_eq!(level0_start_lsns()?, vec![Lsn(0x21), Lsn(0x31), Lsn(0x41)]);
+        compact()?;
+        assert_eq!(level0_start_lsns()?, vec![Lsn(0x41)]);
+        // A single delta is below the compaction threshold
+        compact()?;
+        assert_eq!(level0_start_lsns()?, vec![Lsn(0x41)]);
+
+        for lsn in lsns {
+            assert_eq!(
+                tline.get(test_key, lsn)?,
+                TEST_IMG(&format!("foo at {lsn}"))
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Compact an append-heavy workload, where each flush writes new keys,
+    /// with the given strategy. Returns the number of resulting layers that
+    /// cover the latest LSN.
+    fn compact_appends_with_strategy(
+        test_name: &'static str,
+        strategy: CompactionStrategy,
+    ) -> Result<usize> {
+        let mut harness = RepoHarness::create(test_name)?;
+        harness.tenant_conf.compaction_threshold = 8;
+        harness.tenant_conf.compaction_strategy = strategy;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0x10);
+        for flush in 0..8 {
+            let writer = tline.writer();
+            for i in 0..100 {
+                test_key.field6 = flush * 100 + i;
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", test_key.field6, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        let last_lsn = Lsn(lsn.0 - 0x10);
+
+        tline.compact_level0(
+            &CompactionTargetSizes::uniform(16 * 1024),
+            &CancellationToken::default(),
+        )?;
+        assert!(tline.layers.read().unwrap().get_level0_deltas()?.is_empty());
+
+        let mut lsn = Lsn(0x10);
+        for flush in 0..8 {
+            for i in 0..100 {
+                test_key.field6 = flush * 100 + i;
+                assert_eq!(
+                    tline.get(test_key, last_lsn)?,
+                    TEST_IMG(&format!("{} at {}", test_key.field6, lsn))
+                );
+            }
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+
+        let layers = tline.layers.read().unwrap();
+        Ok(layers
+            .iter_historic_layers()
+            .filter(|l| l.is_incremental() && l.get_lsn_range().contains(&last_lsn))
+            .count())
+    }
+
+    #[test]
+    fn compaction_strategy_lsn_window() -> Result<()> {
+        let key_split = compact_appends_with_strategy(
+            "compaction_strategy_key_split",
+            CompactionStrategy::KeySplit,
+        )?;
+        let lsn_window = compact_appends_with_strategy(
+            "compaction_strategy_lsn_window",
+            CompactionStrategy::LsnWindow,
+        )?;
+
+        // The KeySplit layers all span the whole LSN range, while only the
+        // last window covers the latest LSN.
+        assert_eq!(lsn_window, 1);
+        assert!(key_split > lsn_window, "got {key_split} KeySplit layers");
+
+        Ok(())
+    }
+
+    #[test]
+    fn traversal_path_is_capped() -> Result<()> {
+        let harness = RepoHarness::create_with_conf("traversal_path_is_capped", |conf| {
+            conf.max_traversal_path_len = 3
+        })?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // A chain of WAL records without any page image, one per layer. The
+        // read goes through all the layers before it finds out that the key
+        // can't be reconstructed.
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for i in 1..=10 {
+            let lsn = Lsn(i * 0x10);
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::WalRecord(ZenithWalRecord::Postgres {
+                    will_init: false,
+                    rec: Bytes::from_static(b"record"),
+                }),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        let err = tline.get(test_key, Lsn(0xa0)).unwrap_err();
+        assert!(err.downcast_ref::<KeyNotFoundError>().is_some());
+        let err = format!("{err:#}");
+        assert_eq!(err.matches("layer traversal:").count(), 3, "{err}");
+        assert!(err.contains("... and 7 earlier layers omitted"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn record_chain_in_reconstruct_error() -> Result<()> {
+        let repo = RepoHarness::create("record_chain_in_reconstruct_error")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let record = |will_init| ZenithWalRecord::Postgres {
+            will_init,
+            rec: Bytes::from_static(b"record"),
+        };
+
+        // A chain whose oldest record doesn't initialize the page. The
+        // collected records are newest first.
+        let data = ValueReconstructState {
+            records: vec![
+                (Lsn(0x30), record(false)),
+                (Lsn(0x20), record(true)),
+                (Lsn(0x10), record(false)),
+            ],
+            img: None,
+        };
+        let err = format!(
+            "{:#}",
+            tline
+                .reconstruct_value(test_key, Lsn(0x30), data)
+                .unwrap_err()
+        );
+        assert!(
+            err.contains(
+                "got 3 WAL records: no base image, records: \
+                 0/10 (will_init: false) 0/20 (will_init: true) 0/30 (will_init: false)"
+            ),
+            "{err}"
+        );
+
+        // The debug method lists the chain that a read would use
+        let writer = tline.writer();
+        for (lsn, will_init) in [(Lsn(0x10), true), (Lsn(0x20), false), (Lsn(0x30), false)] {
+            writer.put(test_key, lsn, &Value::WalRecord(record(will_init)))?;
+            writer.finish_write(lsn)?;
+        }
+        drop(writer);
+        assert_eq!(
+            tline.record_chain(test_key, Lsn(0x30))?,
+            RecordChain {
+                base_image_lsn: None,
+                records: vec![(Lsn(0x10), true), (Lsn(0x20), false), (Lsn(0x30), false)],
+            }
+        );
+        assert_eq!(
+            tline.record_chain(test_key, Lsn(0x20))?.records,
+            vec![(Lsn(0x10), true), (Lsn(0x20), false)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_debt() -> Result<()> {
+        let repo = RepoHarness::create("compaction_debt")?.load();
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let make_l0_deltas = |timeline_id, count| -> Result<Arc<LayeredTimeline>> {
+            let tline = repo.create_empty_timeline(timeline_id, Lsn(0))?;
+            for i in 1..=count {
+                let lsn = Lsn(i * 0x10);
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn)?;
+                drop(writer);
+                tline.checkpoint(CheckpointConfig::Flush)?;
+            }
+            Ok(tline)
+        };
+        let busy = make_l0_deltas(TIMELINE_ID, 6)?;
+        let quiet = make_l0_deltas(NEW_TIMELINE_ID, 2)?;
+
+        let busy_debt = busy.compaction_debt()?;
+        let quiet_debt = quiet.compaction_debt()?;
+        assert_eq!(busy_debt.l0_count, 6);
+        assert_eq!(quiet_debt.l0_count, 2);
+        assert!(busy_debt.l0_bytes > quiet_debt.l0_bytes);
+        // Not compacted yet, so there's no partitioning to check
+        assert_eq!(busy_debt.partitions_needing_images, 0);
+
+        // Computing the debt doesn't compact anything
+        assert_eq!(busy.compaction_debt()?, busy_debt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn follower_reads_are_stable() -> Result<()> {
+        let repo = RepoHarness::create("follower_reads_are_stable")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put = |lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            Ok(())
+        };
+
+        put(Lsn(0x10))?;
+        assert!(tline.get_at_follower(test_key).is_err());
+        tline.advance_follower(Lsn(0x10))?;
+
+        // New writes don't change what followers see
+        put(Lsn(0x20))?;
+        put(Lsn(0x30))?;
+        assert_eq!(tline.get_at_follower(test_key)?, TEST_IMG("foo at 0/10"));
+
+        tline.advance_follower(Lsn(0x20))?;
+        assert_eq!(tline.get_at_follower(test_key)?, TEST_IMG("foo at 0/20"));
+
+        // Never backwards, and never past the tip
+        assert!(tline.advance_follower(Lsn(0x10)).is_err());
+        assert!(tline.advance_follower(Lsn(0x40)).is_err());
+        assert_eq!(tline.get_follower_lsn(), Some(Lsn(0x20)));
+
+        // GC doesn't remove the data at the follower LSN
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        tline.update_gc_info(Vec::new(), Lsn(0x30), Duration::ZERO)?;
+        tline.gc()?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x20));
+        assert_eq!(tline.get_at_follower(test_key)?, TEST_IMG("foo at 0/20"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_extended_but_unwritten_block() -> Result<()> {
+        let repo = RepoHarness::create("read_extended_but_unwritten_block")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 1,
+            relnode: 1000,
+        };
+        let zero_page = Bytes::from(vec![0u8; pg_constants::BLCKSZ as usize]);
+
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_rel_creation(rel, 1)?;
+        m.put_rel_page_image(rel, 0, TEST_IMG("foo blk 0 at 0/10"))?;
+        m.commit()?;
+
+        // Extend the relation without writing the new blocks
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_extend(rel, 3)?;
+        m.commit()?;
+
+        let served_before = ZERO_PAGE_SERVED.get();
+        assert_eq!(tline.get_rel_page_at_lsn(rel, 2, Lsn(0x20))?, zero_page);
+        assert!(ZERO_PAGE_SERVED.get() > served_before);
+
+        // Same from the layer files
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let served_before = ZERO_PAGE_SERVED.get();
+        assert_eq!(tline.get_rel_page_at_lsn(rel, 2, Lsn(0x20))?, zero_page);
+        assert!(ZERO_PAGE_SERVED.get() > served_before);
+        assert_eq!(
+            tline.get_rel_page_at_lsn(rel, 0, Lsn(0x20))?,
+            TEST_IMG("foo blk 0 at 0/10")
+        );
+
+        // A WAL record with no base image to apply it to is still an error
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_wal_record(
+            rel,
+            1,
+            ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"record"),
+            },
+        )?;
+        m.commit()?;
+        let err = tline.get_rel_page_at_lsn(rel, 1, Lsn(0x30)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KeyNotFoundError>().unwrap().num_records,
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn put_batch() -> Result<()> {
+        let repo = RepoHarness::create("put_batch")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let key = |blknum: u32| Key::from_hex(&format!("0122222222333333334444444455{blknum:08X}"));
+
+        let writer = tline.writer();
+        writer.put(key(0)?, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+
+        // A batch with an LSN at or below the last record LSN is rejected
+        // as a whole
+        let batch = vec![
+            (key(1)?, Lsn(0x20), Value::Image(TEST_IMG("bar at 0x20"))),
+            (key(2)?, Lsn(0x10), Value::Image(TEST_IMG("baz at 0x10"))),
+        ];
+        assert!(writer.put_batch(&batch).is_err());
+        writer.finish_write(Lsn(0x20))?;
+        assert!(tline.get(key(1)?, Lsn(0x20)).is_err());
+        assert!(tline.get(key(2)?, Lsn(0x20)).is_err());
+
+        // Several versions of several keys
+        let mut batch = Vec::new();
+        for blknum in 0..10 {
+            for lsn in [Lsn(0x30), Lsn(0x40)] {
+                let img = TEST_IMG(&format!("blk {blknum} at {lsn}"));
+                batch.push((key(blknum)?, lsn, Value::Image(img)));
+            }
+        }
+        writer.put_batch(&batch)?;
+        writer.put_batch(&[])?;
+        writer.finish_write(Lsn(0x40))?;
+
+        // Staged in a batch, like separate puts
+        writer.begin_batch()?;
+        writer.put_batch(&[(key(0)?, Lsn(0x50), Value::Image(TEST_IMG("foo at 0x50")))])?;
+        writer.commit_batch(Lsn(0x50))?;
+        drop(writer);
+
+        for blknum in 0..10 {
+            for lsn in [Lsn(0x30), Lsn(0x40)] {
+                assert_eq!(
+                    tline.get(key(blknum)?, lsn)?,
+                    TEST_IMG(&format!("blk {blknum} at {lsn}"))
+                );
+            }
+        }
+        assert_eq!(tline.get(key(0)?, Lsn(0x50))?, TEST_IMG("foo at 0x50"));
+
+        // Same from the layer files
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert_eq!(tline.get(key(9)?, Lsn(0x40))?, TEST_IMG("blk 9 at 0/40"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn orphaned_ephemeral_files() -> Result<()> {
+        let harness = RepoHarness::create("orphaned_ephemeral_files")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let start = SystemTime::now();
+        for (file_id, size) in [(1, 8192), (2, 100), (17, 3 * 8192)] {
+            fs::write(
+                timeline_path.join(format!("ephemeral-{file_id}")),
+                vec![0u8; size],
+            )?;
+        }
+        // Not an ephemeral file
+        fs::write(timeline_path.join("ephemeral-foo"), b"foo")?;
+
+        let orphaned = tline.load_layer_map(Lsn(0))?;
+        assert_eq!(orphaned.num_files, 3);
+        assert_eq!(orphaned.total_bytes, 4 * 8192 + 100);
+        let (oldest, newest) = (orphaned.oldest.unwrap(), orphaned.newest.unwrap());
+        assert!(oldest <= newest);
+        assert!(start <= oldest + Duration::from_secs(1), "{orphaned:?}");
+        assert!(orphaned.to_string().starts_with("3 files, 32868 bytes"));
+
+        let mut files = fs::read_dir(&timeline_path)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.retain(|f| f.starts_with("ephemeral"));
+        assert_eq!(files, vec!["ephemeral-foo"]);
+
+        // Nothing left to remove
+        assert_eq!(
+            tline.load_layer_map(Lsn(0))?,
+            OrphanedEphemeralFiles::default()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_to_lsn() -> Result<()> {
+        let harness = RepoHarness::create("flush_to_lsn")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let on_disk_lsn = || -> Result<Lsn> {
+            let metadata = TimelineMetadata::from_bytes(&fs::read(metadata_path(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+            ))?)?;
+            Ok(metadata.disk_consistent_lsn())
+        };
+
+        let writer = tline.writer();
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let img = TEST_IMG(&format!("foo at {lsn}"));
+            writer.put(test_key, lsn, &Value::Image(img))?;
+            writer.finish_write(lsn)?;
+        }
+        drop(writer);
+
+        // Flushing to 0x20 also flushes 0x30, which is in the same open layer
+        tline.flush_to_lsn(Lsn(0x20))?;
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x30));
+        assert_eq!(on_disk_lsn()?, Lsn(0x30));
+        assert!(tline.layers.read().unwrap().open_layer.is_none());
+
+        // Writes after the flushed LSN stay in memory
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x40), &Value::Image(TEST_IMG("foo at 0/40")))?;
+        writer.finish_write(Lsn(0x40))?;
+        drop(writer);
+        tline.flush_to_lsn(Lsn(0x30))?;
+        tline.flush_to_lsn(Lsn(0x20))?;
+        assert_eq!(on_disk_lsn()?, Lsn(0x30));
+        assert!(tline.layers.read().unwrap().open_layer.is_some());
+
+        // WAL that didn't modify any pages
+        tline.writer().finish_write(Lsn(0x50))?;
+        tline.flush_to_lsn(Lsn(0x50))?;
+        assert_eq!(on_disk_lsn()?, Lsn(0x50));
+        assert_eq!(tline.get(test_key, Lsn(0x50))?, TEST_IMG("foo at 0/40"));
+
+        assert!(tline.flush_to_lsn(Lsn(0x60)).is_err());
+
+        // Flush while another thread keeps writing
+        let tline_clone = Arc::clone(&tline);
+        let writer_thread = std::thread::spawn(move || -> Result<()> {
+            for i in 0..100 {
+                let lsn = Lsn(0x100 + i * 0x10);
+                let writer = tline_clone.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn)?;
+            }
+            Ok(())
+        });
+        for _ in 0..10 {
+            let lsn = tline.get_last_record_lsn();
+            tline.flush_to_lsn(lsn)?;
+            assert!(on_disk_lsn()? >= lsn);
+        }
+        writer_thread.join().unwrap()?;
+        tline.flush_to_lsn(Lsn(0x100 + 99 * 0x10))?;
+        assert_eq!(on_disk_lsn()?, Lsn(0x100 + 99 * 0x10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_rate_limit() -> Result<()> {
+        use crate::layered_repository::LayeredRepository;
+        use crate::storage_sync::index::RemoteIndex;
+
+        let mut harness = RepoHarness::create("get_rate_limit")?;
+        harness.tenant_conf.get_requests_per_second = 10;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // A well-behaved tenant with the same limit
+        let other_tenant_id = ZTenantId::generate();
+        fs::create_dir_all(harness.conf.timelines_path(&other_tenant_id))?;
+        let other_repo = LayeredRepository::new(
+            harness.conf,
+            TenantConfOpt::from(harness.tenant_conf),
+            Arc::new(TestRedoManager),
+            other_tenant_id,
+            RemoteIndex::default(),
+            false,
+        );
+        let other_tline = other_repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for tl in [&tline, &other_tline] {
+            let writer = tl.writer();
+            writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(0x10))?;
+        }
+
+        // Plain get() calls, like the ones of WAL ingestion or basebackup,
+        // are not limited
+        for _ in 0..20 {
+            tline.get(test_key, Lsn(0x10))?;
+        }
+        assert_eq!(tline.get_rate_limiter.num_throttled(), 0);
+
+        // A burst of twice the limit of GetPage requests. The first 10
+        // requests are allowed, most of the rest are refused.
+        let mut num_throttled = 0;
+        for _ in 0..20 {
+            match tline.check_get_page_rate_limit() {
+                Ok(rate_limits) => {
+                    let value = tline.get(test_key, Lsn(0x10))?;
+                    assert_eq!(value, TEST_IMG("foo"));
+                    tline.charge_get_page_bytes(rate_limits, value.len());
+                }
+                Err(RateLimited { tenant_id, .. }) => {
+                    assert_eq!(tenant_id, tline.tenant_id);
+                    num_throttled += 1;
+                }
+            }
+        }
+        assert!(
+            num_throttled >= 5,
+            "only {} requests throttled",
+            num_throttled
+        );
+        assert_eq!(tline.get_rate_limiter.num_throttled(), num_throttled);
+
+        for _ in 0..5 {
+            other_tline.check_get_page_rate_limit()?;
+        }
+        assert_eq!(other_tline.get_rate_limiter.num_throttled(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn effective_tenant_conf() -> Result<()> {
+        let harness = RepoHarness::create_with_conf("effective_tenant_conf", |conf| {
+            conf.default_tenant_conf.checkpoint_distance = 12345
+        })?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // Override a single setting, and leave everything else unset
+        *tline.tenant_conf.write().unwrap() = TenantConfOpt {
+            compaction_threshold: Some(7),
+            ..TenantConfOpt::default()
+        };
+
+        let effective = tline.effective_tenant_conf();
+        assert_eq!(effective.compaction_threshold, 7);
+        assert_eq!(tline.get_compaction_threshold(), 7);
+        assert_eq!(effective.checkpoint_distance, 12345);
+        assert_eq!(tline.get_checkpoint_distance(), 12345);
+        assert_eq!(
+            effective,
+            TenantConf {
+                compaction_threshold: 7,
+                ..harness.conf.default_tenant_conf
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prev_record_lsn_at_flush_points() -> Result<()> {
+        let harness = RepoHarness::create("prev_record_lsn_at_flush_points")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put = |lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)
+        };
+
+        // The record before the first one is not known
+        put(Lsn(0x10))?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x10)), None);
+
+        put(Lsn(0x20))?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        put(Lsn(0x30))?;
+        put(Lsn(0x40))?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        put(Lsn(0x50))?;
+
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x20)), Some(Lsn(0x10)));
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x40)), Some(Lsn(0x30)));
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x50)), Some(Lsn(0x40)));
+        // Not a flush point
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x30)), None);
+
+        // Simulate a crash in the middle of appending an entry
+        drop(tline);
+        drop(repo);
+        let path = harness
+            .timeline_path(&TIMELINE_ID)
+            .join(PREV_RECORD_LSNS_FILE_NAME);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"torn")?;
+
+        // The flushed points are remembered across a restart, the unflushed
+        // record at 0x50 is gone
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert_eq!(fs::metadata(&path)?.len(), 32);
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x20)), Some(Lsn(0x10)));
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x40)), Some(Lsn(0x30)));
+        assert_eq!(tline.prev_record_lsn_at(Lsn(0x50)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_separates_key_categories() -> Result<()> {
+        compact_key_categories_with_strategy(
+            "compaction_separates_key_categories",
+            CompactionStrategy::KeySplit,
+        )?;
+        compact_key_categories_with_strategy(
+            "compaction_separates_key_categories_lsn_window",
+            CompactionStrategy::LsnWindow,
+        )
+    }
+
+    /// Compact layers with keys of every category with the given strategy,
+    /// and check that each resulting layer holds a single category.
+    fn compact_key_categories_with_strategy(
+        test_name: &'static str,
+        strategy: CompactionStrategy,
+    ) -> Result<()> {
+        let mut harness = RepoHarness::create(test_name)?;
+        harness.tenant_conf.compaction_threshold = 2;
+        harness.tenant_conf.compaction_strategy = strategy;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // One key range of each category: relation, SLRU and metadata
+        let base_keys = [
+            Key::from_hex("002222222233333333444444445500000000")?,
+            Key::from_hex("012222222233333333444444445500000000")?,
+            Key::from_hex("032222222233333333444444445500000000")?,
+        ];
+        let mut lsn = Lsn(0x10);
+        for _ in 0..2 {
+            let writer = tline.writer();
+            for base_key in base_keys {
+                let mut test_key = base_key;
+                for blknum in 0..100 {
+                    test_key.field6 = blknum;
+                    writer.put(
+                        test_key,
+                        lsn,
+                        &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                    )?;
+                }
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+
+        // Everything would fit in one layer, but each category gets its own
+        tline.compact_level0(
+            &CompactionTargetSizes::uniform(1024 * 1024 * 1024),
+            &CancellationToken::default(),
+        )?;
+        let layers = tline.layers.read().unwrap();
+        let mut num_deltas = 0;
+        for l in layers.iter_historic_layers().filter(|l| l.is_incremental()) {
+            let key_range = l.get_key_range();
+            let categories: Vec<KeyCategory> = base_keys
+                .iter()
+                .filter(|key| key_range.contains(key))
+                .map(|key| KeyCategory::of(*key))
+                .collect();
+            assert_eq!(
+                categories.len(),
+                1,
+                "layer {key_range:?} spans {categories:?}"
+            );
+            num_deltas += 1;
+        }
+        assert_eq!(num_deltas, 3);
+        drop(layers);
+
+        for base_key in base_keys {
+            let mut test_key = base_key;
+            test_key.field6 = 50;
+            assert_eq!(
+                tline.get(test_key, Lsn(0x20))?,
+                TEST_IMG(&format!("{} at {}", 50, Lsn(0x20)))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_small_layers() -> Result<()> {
+        let mut harness = RepoHarness::create("coalesce_small_layers")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0x10);
+        for _ in 0..2 {
+            let writer = tline.writer();
+            for blknum in 0..1000 {
+                test_key.field6 = blknum;
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+
+        // Compacting with a small target file size leaves many small layers
+        // next to each other
+        tline.compact_level0(
+            &CompactionTargetSizes::uniform(8192),
+            &CancellationToken::default(),
+        )?;
+        let num_deltas = || {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .iter_historic_layers()
+                .filter(|l| l.is_incremental())
+                .count()
+        };
+        let num_small_layers = num_deltas();
+        assert!(num_small_layers > 1, "got {num_small_layers} layers");
+
+        // Nothing to coalesce if the target is as small as the layers
+        assert_eq!(
+            tline.coalesce_small_layers(&CompactionTargetSizes::uniform(8192))?,
+            0
+        );
+        assert_eq!(num_deltas(), num_small_layers);
+
+        assert_eq!(
+            tline.coalesce_small_layers(&CompactionTargetSizes::uniform(1024 * 1024 * 1024))?,
+            num_small_layers - 1
+        );
+        assert_eq!(num_deltas(), 1);
+
+        for blknum in [0, 500, 999] {
+            test_key.field6 = blknum;
+            assert_eq!(
+                tline.get(test_key, Lsn(0x20))?,
+                TEST_IMG(&format!("{} at {}", blknum, Lsn(0x20)))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_compaction_leaves_inputs() -> Result<()> {
+        let mut harness = RepoHarness::create("cancelled_compaction_leaves_inputs")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0x10);
+        for _ in 0..2 {
+            let writer = tline.writer();
+            for blknum in 0..1000 {
+                test_key.field6 = blknum;
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        let num_level0 = || {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()
+                .unwrap()
+                .len()
+        };
+        assert_eq!(num_level0(), 2);
+
+        // Cancel after the first output layer has been written, if failpoints
+        // are compiled in. Otherwise cancel before the compaction starts.
+        let cancel = Arc::new(CancellationToken::default());
+        if fail::has_failpoints() {
+            let cancel = Arc::clone(&cancel);
+            fail::cfg_callback("compact-level0-after-output-layer", move || cancel.cancel())
+                .unwrap();
+        } else {
+            cancel.cancel();
+        }
+        let res = tline.compact_level0(&CompactionTargetSizes::uniform(8192), &cancel);
+        fail::remove("compact-level0-after-output-layer");
+        assert!(res.unwrap_err().is::<CompactionCancelled>());
+
+        // The inputs are still there, and no output layers were left behind
+        assert_eq!(num_level0(), 2);
+        assert_eq!(
+            tline.layers.read().unwrap().iter_historic_layers().count(),
+            2
+        );
+        assert!(tline
+            .reconcile_layers(false)?
+            .local_files_not_in_map
+            .is_empty());
+        assert!(tline
+            .create_image_layers(
+                &KeyPartitioning {
+                    parts: vec![KeySpace {
+                        ranges: vec![test_key..test_key.next()]
+                    }]
+                },
+                Lsn(0x20),
+                true,
+                &cancel,
+            )?
+            .is_empty());
+
+        // The next compaction starts over
+        tline.compact_level0(
+            &CompactionTargetSizes::uniform(8192),
+            &CancellationToken::default(),
+        )?;
+        assert_eq!(num_level0(), 0);
+        for blknum in [0, 500, 999] {
+            test_key.field6 = blknum;
+            assert_eq!(
+                tline.get(test_key, Lsn(0x20))?,
+                TEST_IMG(&format!("{} at {}", blknum, Lsn(0x20)))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn hot_key_range_gets_image_layer_first() -> Result<()> {
+        let repo = RepoHarness::create("hot_key_range_gets_image_layer_first")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let hot_key = Key::from_hex("000000067F000032BE000040000000000000")?;
+        let cold_key = Key::from_hex("000000067F000032BE000040000100000000")?;
+
+        // Both keys get the same number of deltas, below the threshold
+        let threshold = tline.get_image_creation_threshold();
+        let num_deltas = (threshold + 1) / 2;
+        assert!(num_deltas < threshold);
+        let mut lsn = Lsn(0);
+        for _ in 0..num_deltas {
+            lsn = Lsn(lsn.0 + 0x10);
+            let writer = tline.writer();
+            for key in [hot_key, cold_key] {
+                writer.put(
+                    key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{key} at {lsn}"))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        let hot_partition = KeySpace {
+            ranges: vec![hot_key..hot_key.next()],
+        };
+        let cold_partition = KeySpace {
+            ranges: vec![cold_key..cold_key.next()],
+        };
+        assert!(!tline.time_for_new_image_layer(&hot_partition, lsn)?);
+        assert!(!tline.time_for_new_image_layer(&cold_partition, lsn)?);
+
+        for _ in 0..1000 {
+            tline.get(hot_key, lsn)?;
+        }
+        tline.get(cold_key, lsn)?;
+
+        let hottest = tline.hot_key_ranges(1);
+        assert!(hottest[0].0.contains(&hot_key));
+
+        assert!(tline.time_for_new_image_layer(&hot_partition, lsn)?);
+        assert!(!tline.time_for_new_image_layer(&cold_partition, lsn)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn layer_created_at() -> Result<()> {
+        let repo = RepoHarness::create("layer_created_at")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        write_blocks_and_flush(&tline, 10, Lsn(0x10))?;
+        let after = SystemTime::now() + Duration::from_secs(1);
+
+        let layers = tline.layers.read().unwrap();
+        let layer = layers.iter_historic_layers().next().unwrap();
+        let created_at = layer.created_at().unwrap();
+        assert!(before <= created_at && created_at <= after);
+        drop(layers);
+
+        // In-memory layers are not on disk, and don't have a creation time
+        let writer = tline.writer();
+        writer.put(
+            Key::from_hex("012222222233333333444444445500000000")?,
+            Lsn(0x20),
+            &Value::Image(TEST_IMG("foo at 0x20")),
+        )?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+        let open_layer = tline.layers.read().unwrap().open_layer.clone().unwrap();
+        assert!(open_layer.created_at().is_none());
+
+        Ok(())
+    }
+
+    /// WAL redo manager that returns full pages, so that they are eligible for
+    /// the materialized page cache, and counts how many times it was called.
+    #[derive(Default)]
+    struct CountingRedoManager {
+        requests: AtomicUsize,
+    }
+
+    impl WalRedoManager for CountingRedoManager {
+        fn request_redo(
+            &self,
+            _key: Key,
+            _lsn: Lsn,
+            _base_img: Option<Bytes>,
+            _records: Vec<(Lsn, ZenithWalRecord)>,
+        ) -> Result<Bytes, WalRedoError> {
+            self.requests.fetch_add(1, AtomicOrdering::SeqCst);
+            Ok(Bytes::from(vec![0xAB; page_cache::PAGE_SZ]))
+        }
+    }
+
+    fn count_redo_requests(materialized_cache_enabled: bool) -> Result<usize> {
+        // Exclusive, so that other tests don't evict the page from the small
+        // page cache used in tests.
+        let mut harness = RepoHarness::create_exclusive(if materialized_cache_enabled {
+            "materialized_cache_enabled"
+        } else {
+            "materialized_cache_disabled"
+        })?;
+        harness.tenant_conf.materialized_cache_enabled = materialized_cache_enabled;
+        let redo_mgr = Arc::new(CountingRedoManager::default());
+        let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(
+            test_key,
+            Lsn(0x10),
+            &Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init: true,
+                rec: Bytes::from_static(b"init record"),
+            }),
+        )?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        for _ in 0..3 {
+            assert_eq!(tline.get(test_key, Lsn(0x10))?.len(), page_cache::PAGE_SZ);
+        }
+
+        let cached = page_cache::get().lookup_materialized_page(
+            tline.tenant_id,
+            tline.timeline_id,
+            &test_key,
+            Lsn(0x10),
+        );
+        assert_eq!(cached.is_some(), materialized_cache_enabled);
+
+        Ok(redo_mgr.requests.load(AtomicOrdering::SeqCst))
+    }
+
+    #[test]
+    fn get_multi_bypasses_materialized_cache() -> Result<()> {
+        // Exclusive, so that other tests don't evict the pages from the small
+        // page cache used in tests.
+        let mut harness = RepoHarness::create_exclusive("get_multi_bypasses_materialized_cache")?;
+        harness.tenant_conf.materialized_cache_enabled = true;
+        let redo_mgr = Arc::new(CountingRedoManager::default());
+        let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let keys: Vec<Key> = (0..2)
+            .map(|i| Key::from_hex(&format!("01222222223333333344444444550000000{i}")))
+            .collect::<Result<_>>()?;
+        let writer = tline.writer();
+        for key in &keys {
+            writer.put(
+                *key,
+                Lsn(0x10),
+                &Value::WalRecord(ZenithWalRecord::Postgres {
+                    will_init: true,
+                    rec: Bytes::from_static(b"init record"),
+                }),
+            )?;
+        }
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        let redo_requests = || redo_mgr.requests.load(AtomicOrdering::SeqCst);
+        let is_cached = |key: &Key| {
+            page_cache::get()
+                .lookup_materialized_page(tline.tenant_id, tline.timeline_id, key, Lsn(0x10))
+                .is_some()
+        };
+
+        // A scan doesn't populate the cache by default
+        for result in tline.get_multi(&keys[0..1], Lsn(0x10), false) {
+            assert_eq!(result?.len(), page_cache::PAGE_SZ);
+        }
+        assert_eq!(redo_requests(), 1);
+        assert!(!is_cached(&keys[0]));
+
+        // A single-key read populates the cache, but a scan doesn't use it
+        tline.get(keys[0], Lsn(0x10))?;
+        assert!(is_cached(&keys[0]));
+        assert_eq!(redo_requests(), 2);
+        tline.get_multi(&keys[0..1], Lsn(0x10), false);
+        assert_eq!(redo_requests(), 3);
+
+        // When asked to, the scan populates the cache
+        tline.get_multi(&keys[1..2], Lsn(0x10), true);
+        assert_eq!(redo_requests(), 4);
+        assert!(is_cached(&keys[1]));
+        tline.get(keys[1], Lsn(0x10))?;
+        assert_eq!(redo_requests(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ancestor_hops_metric() -> Result<()> {
+        let repo = RepoHarness::create("ancestor_hops_metric")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        let grandchild_id = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x10)))?;
+        repo.branch_timeline(NEW_TIMELINE_ID, grandchild_id, Some(Lsn(0x10)))?;
+        let grandchild = repo.get_timeline_load(grandchild_id)?;
+
+        assert_eq!(
+            grandchild.get(test_key, Lsn(0x10))?,
+            TEST_IMG("foo at 0/10")
+        );
+        assert_eq!(grandchild.ancestor_hops_histo.get_sample_count(), 1);
+        assert_eq!(grandchild.ancestor_hops_histo.get_sample_sum(), 2.0);
+
+        // A read served by the timeline itself doesn't cross any
+        tline.get(test_key, Lsn(0x10))?;
+        assert_eq!(tline.ancestor_hops_histo.get_sample_count(), 1);
+        assert_eq!(tline.ancestor_hops_histo.get_sample_sum(), 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_latest_image_skips_redo() -> Result<()> {
+        let mut harness = RepoHarness::create("get_latest_image_skips_redo")?;
+        harness.tenant_conf.materialized_cache_enabled = false;
+        let redo_mgr = Arc::new(CountingRedoManager::default());
+        let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // Only in-memory and delta layers so far
+        assert!(tline.get_latest_image(test_key)?.is_none());
+
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let partitioning = KeyPartitioning {
+            parts: vec![KeySpace {
+                ranges: vec![test_key..test_key.next()],
+            }],
+        };
+        tline.create_image_layers(
+            &partitioning,
+            Lsn(0x10),
+            true,
+            &CancellationToken::default(),
+        )?;
+
+        let writer = tline.writer();
+        writer.put(
+            test_key,
+            Lsn(0x20),
+            &Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"update record"),
+            }),
+        )?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+
+        let expected = Some((Lsn(0x10), TEST_IMG("foo at 0/10")));
+        assert_eq!(tline.get_latest_image(test_key)?, expected);
+
+        // A branch without images of its own returns the ancestor's image
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x20)))?;
+        let new_tline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        assert_eq!(new_tline.get_latest_image(test_key)?, expected);
+
+        assert_eq!(redo_mgr.requests.load(AtomicOrdering::SeqCst), 0);
+
+        // The latest version needs WAL redo
+        assert_eq!(tline.get(test_key, Lsn(0x20))?.len(), page_cache::PAGE_SZ);
+        assert_eq!(redo_mgr.requests.load(AtomicOrdering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_history_across_branch() -> Result<()> {
+        let repo = RepoHarness::create("key_history_across_branch")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let wal_record = |will_init: bool| {
+            Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init,
+                rec: Bytes::from_static(b"record"),
+            })
+        };
+        // The first two versions are flushed to a delta layer, the third one
+        // stays in memory, after the branch point.
+        for (lsn, value) in [
+            (Lsn(0x10), Value::Image(TEST_IMG("foo at 0/10"))),
+            (Lsn(0x20), wal_record(false)),
+            (Lsn(0x30), wal_record(true)),
+        ] {
+            let writer = tline.writer();
+            writer.put(test_key, lsn, &value)?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            if lsn == Lsn(0x20) {
+                tline.checkpoint(CheckpointConfig::Flush)?;
+            }
+        }
+
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x20)))?;
+        let new_tline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        let writer = new_tline.writer();
+        writer.put(test_key, Lsn(0x40), &wal_record(false))?;
+        writer.finish_write(Lsn(0x40))?;
+        drop(writer);
+
+        assert_eq!(
+            tline.key_history(test_key, Lsn(0)..Lsn::MAX)?,
+            vec![
+                (Lsn(0x10), ValueKind::Image, TIMELINE_ID),
+                (
+                    Lsn(0x20),
+                    ValueKind::WalRecord { will_init: false },
+                    TIMELINE_ID
+                ),
+                (
+                    Lsn(0x30),
+                    ValueKind::WalRecord { will_init: true },
+                    TIMELINE_ID
+                ),
+            ]
+        );
+        // The branch inherits the versions up to the branch point
+        assert_eq!(
+            new_tline.key_history(test_key, Lsn(0)..Lsn::MAX)?,
+            vec![
+                (Lsn(0x10), ValueKind::Image, TIMELINE_ID),
+                (
+                    Lsn(0x20),
+                    ValueKind::WalRecord { will_init: false },
+                    TIMELINE_ID
+                ),
+                (
+                    Lsn(0x40),
+                    ValueKind::WalRecord { will_init: false },
+                    NEW_TIMELINE_ID
+                ),
+            ]
+        );
+        assert_eq!(
+            new_tline.key_history(test_key, Lsn(0x11)..Lsn(0x40))?,
+            vec![(
+                Lsn(0x20),
+                ValueKind::WalRecord { will_init: false },
+                TIMELINE_ID
+            )]
+        );
+        // Other keys have no history
+        assert_eq!(
+            new_tline.key_history(test_key.next(), Lsn(0)..Lsn::MAX)?,
+            vec![]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn will_init_record_skips_image_layer() -> Result<()> {
+        let repo = RepoHarness::create("will_init_record_skips_image_layer")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let key_a = Key::from_hex("012222222233333333444444445500000000")?;
+        let key_b = key_a.next();
+        let wal_record = |will_init: bool| {
+            Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init,
+                rec: Bytes::from_static(b"record"),
+            })
+        };
+
+        // Images of both keys at 0x10 go into an image layer
+        let writer = tline.writer();
+        writer.put(key_a, Lsn(0x10), &Value::Image(TEST_IMG("a at 0/10")))?;
+        writer.put(key_b, Lsn(0x10), &Value::Image(TEST_IMG("b at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let partitioning = KeyPartitioning {
+            parts: vec![KeySpace {
+                ranges: vec![key_a..key_b.next()],
+            }],
+        };
+        tline.create_image_layers(
+            &partitioning,
+            Lsn(0x10),
+            true,
+            &CancellationToken::default(),
+        )?;
+
+        // Key A is then initialized by a WAL record, key B is only updated
+        let writer = tline.writer();
+        writer.put(key_a, Lsn(0x20), &wal_record(true))?;
+        writer.put(key_b, Lsn(0x20), &wal_record(false))?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        // Remove the image layer file, so that any read from it fails
+        let image_layer_path = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .find(|l| !l.is_incremental())
+            .and_then(|l| l.local_path())
+            .expect("image layer should exist");
+        fs::remove_file(image_layer_path)?;
+
+        assert_eq!(
+            tline.get(key_a, Lsn(0x20))?,
+            TEST_IMG(&format!(
+                "redo for {key_a} to get to 0/20, with no base image and 1 records"
+            ))
+        );
+        // Without a record that initializes the page, the base image is needed
+        assert!(tline.get(key_b, Lsn(0x20)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn materialized_cache_can_be_disabled() -> Result<()> {
+        // With the cache, the page is reconstructed once and then served from the cache
+        assert_eq!(count_redo_requests(true)?, 1);
+        // Without it, every read reconstructs the page again
+        assert_eq!(count_redo_requests(false)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn maintenance_observer_events() -> Result<()> {
+        use crate::layered_repository::maintenance_observer::MaintenanceObserver;
+
+        #[derive(Default)]
+        struct RecordingObserver {
+            events: Mutex<Vec<String>>,
+        }
+        impl RecordingObserver {
+            fn record(&self, event: &str, layers: &[LayerDescriptor]) {
+                let lsn_ranges = layers
+                    .iter()
+                    .map(|l| format!("{}-{}", l.lsn_range.start, l.lsn_range.end))
+                    .join(" ");
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("{event} {lsn_ranges}"));
+            }
+        }
+        impl MaintenanceObserver for RecordingObserver {
+            fn on_layers_created(&self, timeline: ZTenantTimelineId, layers: &[LayerDescriptor]) {
+                assert_eq!(timeline.timeline_id, TIMELINE_ID);
+                self.record("created", layers);
+            }
+            fn on_layers_removed(
+                &self,
+                timeline: ZTenantTimelineId,
+                layers: &[LayerDescriptor],
+                cause: LayerRemovalCause,
+            ) {
+                assert_eq!(timeline.timeline_id, TIMELINE_ID);
+                self.record(&format!("removed by {cause:?}"), layers);
+            }
+            fn on_image_created(
+                &self,
+                timeline: ZTenantTimelineId,
+                layer: &LayerDescriptor,
+                lsn: Lsn,
+            ) {
+                assert_eq!(timeline.timeline_id, TIMELINE_ID);
+                assert!(!layer.is_incremental);
+                self.record(&format!("image at {lsn}"), std::slice::from_ref(layer));
+            }
+        }
+
+        let mut harness = RepoHarness::create("maintenance_observer_events")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let observer = Arc::new(RecordingObserver::default());
+        repo.set_maintenance_observer(observer.clone());
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let format_range = |r: &Range<Lsn>| format!("{}-{}", r.start, r.end);
+        let mut level0_ranges = Vec::new();
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30), Lsn(0x40)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+
+            if lsn == Lsn(0x20) {
+                // Compact the first two layers into an L1 layer
+                level0_ranges = tline
+                    .layers
+                    .read()
+                    .unwrap()
+                    .get_level0_deltas()?
+                    .iter()
+                    .map(|l| l.get_lsn_range())
+                    .sorted_by_key(|r| r.start)
+                    .collect();
+                assert_eq!(level0_ranges.len(), 2);
+                tline.compact_level0(
+                    &CompactionTargetSizes::uniform(1024 * 1024),
+                    &CancellationToken::default(),
+                )?;
+            } else if lsn == Lsn(0x30) {
+                // An image layer at 0x30 makes the L1 layer obsolete
+                let partitioning = KeyPartitioning {
+                    parts: vec![KeySpace {
+                        ranges: vec![test_key..test_key.next()],
+                    }],
+                };
+                tline.create_image_layers(
+                    &partitioning,
+                    lsn,
+                    true,
+                    &CancellationToken::default(),
+                )?;
+            }
+        }
+        tline.update_gc_info(Vec::new(), Lsn(0x40), Duration::ZERO)?;
+        tline.gc()?;
+
+        let l1_range = format_range(&(level0_ranges[0].start..level0_ranges[1].end));
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                format!("created {l1_range}"),
+                format!(
+                    "removed by Compaction {} {}",
+                    format_range(&level0_ranges[0]),
+                    format_range(&level0_ranges[1])
+                ),
+                format!("image at 0/30 {}", format_range(&(Lsn(0x30)..Lsn(0x31)))),
+                format!(
+                    "removed by Gc {{ cutoff: {} }} {l1_range}",
+                    *tline.get_latest_gc_cutoff_lsn()
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn gc_metrics() -> Result<()> {
+        let mut harness = RepoHarness::create("gc_metrics")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30), Lsn(0x40)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+
+            if lsn == Lsn(0x20) {
+                // Turn the first two layers into an L1 layer ending at 0x21
+                tline.compact_level0(
+                    &CompactionTargetSizes::uniform(1024 * 1024),
+                    &CancellationToken::default(),
+                )?;
+            } else if lsn == Lsn(0x30) {
+                // An image layer at 0x30 makes the L1 layer obsolete
+                let partitioning = KeyPartitioning {
+                    parts: vec![KeySpace {
+                        ranges: vec![test_key..test_key.next()],
+                    }],
+                };
+                tline.create_image_layers(
+                    &partitioning,
+                    lsn,
+                    true,
+                    &CancellationToken::default(),
+                )?;
+            }
+        }
+
+        tline.update_gc_info(Vec::new(), Lsn(0x40), Duration::ZERO)?;
+        let result = tline.gc()?;
+        assert_eq!(result.layers_removed, 1);
+        assert_eq!(tline.gc_layers_removed_counter.get(), 1);
+        assert!(tline.gc_bytes_removed_counter.get() > 0);
+        assert_eq!(tline.gc_runs_reclaimed_counter.get(), 1);
+        assert_eq!(tline.gc_runs_nothing_to_do_counter.get(), 0);
+
+        // The GC cutoff hasn't moved, so there's nothing to do
+        let bytes_removed = tline.gc_bytes_removed_counter.get();
+        let result = tline.gc()?;
+        assert_eq!(result.layers_removed, 0);
+        assert_eq!(tline.gc_layers_removed_counter.get(), 1);
+        assert_eq!(tline.gc_bytes_removed_counter.get(), bytes_removed);
+        assert_eq!(tline.gc_runs_reclaimed_counter.get(), 1);
+        assert_eq!(tline.gc_runs_nothing_to_do_counter.get(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn layer_size_metrics() -> Result<()> {
+        let repo = RepoHarness::create("layer_size_metrics")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        assert_eq!(tline.delta_layer_size_histo.get_sample_count(), 2);
+        assert_eq!(tline.image_layer_size_histo.get_sample_count(), 0);
+
+        // Compaction adds an L1 layer, the removed L0 layers stay counted
+        tline.compact_level0(
+            &CompactionTargetSizes::uniform(1024 * 1024),
+            &CancellationToken::default(),
+        )?;
+        assert_eq!(tline.delta_layer_size_histo.get_sample_count(), 3);
+
+        let partitioning = KeyPartitioning {
+            parts: vec![KeySpace {
+                ranges: vec![test_key..test_key.next()],
+            }],
+        };
+        tline.create_image_layers(
+            &partitioning,
+            Lsn(0x20),
+            true,
+            &CancellationToken::default(),
+        )?;
+        assert_eq!(tline.image_layer_size_histo.get_sample_count(), 1);
+        assert!(tline.image_layer_size_histo.get_sample_sum() > 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_gc_reclaim() -> Result<()> {
+        let mut harness = RepoHarness::create("estimate_gc_reclaim")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30), Lsn(0x40)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+
+            if lsn == Lsn(0x20) {
+                tline.compact_level0(
+                    &CompactionTargetSizes::uniform(1024 * 1024),
+                    &CancellationToken::default(),
+                )?;
+            } else if lsn == Lsn(0x30) {
+                // An image layer at 0x30 makes the L1 layer below it obsolete
+                let partitioning = KeyPartitioning {
+                    parts: vec![KeySpace {
+                        ranges: vec![test_key..test_key.next()],
+                    }],
+                };
+                tline.create_image_layers(
+                    &partitioning,
+                    lsn,
+                    true,
+                    &CancellationToken::default(),
+                )?;
+            }
+        }
+
+        // Nothing can be collected before the GC cutoff is set
+        assert_eq!(tline.estimate_gc_reclaim()?, 0);
+
+        tline.update_gc_info(Vec::new(), Lsn(0x40), Duration::ZERO)?;
+        let layer_files = || -> Result<Vec<PathBuf>> {
+            let mut files = fs::read_dir(harness.timeline_path(&TIMELINE_ID))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let before = layer_files()?;
+        let estimate = tline.estimate_gc_reclaim()?;
+        assert!(estimate > 0);
+        // The estimate doesn't remove anything
+        assert_eq!(layer_files()?, before);
+
+        let bytes_removed = tline.gc_bytes_removed_counter.get();
+        let result = tline.gc()?;
+        assert_eq!(result.layers_removed, 1);
+        assert_eq!(
+            tline.gc_bytes_removed_counter.get() - bytes_removed,
+            estimate
+        );
+
+        // After the GC, there's nothing left to reclaim
+        assert_eq!(tline.estimate_gc_reclaim()?, 0);
+
+        Ok(())
+    }
+
+    /// WAL redo manager that "replays" records by appending their contents to
+    /// the page, and remembers the number of records in each request.
+    #[derive(Default)]
+    struct AppendingRedoManager {
+        request_lens: Mutex<Vec<usize>>,
+    }
+
+    impl WalRedoManager for AppendingRedoManager {
+        fn request_redo(
+            &self,
+            _key: Key,
+            _lsn: Lsn,
+            base_img: Option<Bytes>,
+            records: Vec<(Lsn, ZenithWalRecord)>,
+        ) -> Result<Bytes, WalRedoError> {
+            self.request_lens.lock().unwrap().push(records.len());
+            let mut page = base_img.map(|img| img.to_vec()).unwrap_or_default();
+            for (_lsn, rec) in records {
+                match rec {
+                    ZenithWalRecord::Postgres { will_init, rec } => {
+                        if will_init {
+                            page.clear();
+                        }
+                        page.extend_from_slice(&rec);
+                    }
+                    _ => return Err(WalRedoError::InvalidRecord),
+                }
+            }
+            Ok(Bytes::from(page))
+        }
+    }
+
+    fn redo_with_max_records_size(max_records_size: u64) -> Result<(Bytes, Vec<usize>)> {
+        let mut harness =
+            RepoHarness::create(&format!("walredo_max_records_size_{max_records_size}"))?;
+        harness.tenant_conf.walredo_max_records_size = max_records_size;
+        let redo_mgr = Arc::new(AppendingRedoManager::default());
+        let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        for i in 1..=5 {
+            writer.put(
+                test_key,
+                Lsn(i * 0x10),
+                &Value::WalRecord(ZenithWalRecord::Postgres {
+                    will_init: i == 1,
+                    rec: Bytes::from(format!("record {i};")),
+                }),
+            )?;
+        }
+        writer.finish_write(Lsn(0x50))?;
+        drop(writer);
+
+        let img = tline.get(test_key, Lsn(0x50))?;
+        let request_lens = redo_mgr.request_lens.lock().unwrap().clone();
+        Ok((img, request_lens))
+    }
+
+    #[test]
+    fn walredo_max_records_size() -> Result<()> {
+        let record_size = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"record 1;"),
+        }
+        .serialized_size()?;
+
+        let (img, request_lens) = redo_with_max_records_size(u64::MAX)?;
+        assert_eq!(img, "record 1;record 2;record 3;record 4;record 5;");
+        assert_eq!(request_lens, [5]);
+
+        // Two records fit in one request, so the five records are replayed in three steps
+        let (split_img, request_lens) = redo_with_max_records_size(2 * record_size)?;
+        assert_eq!(split_img, img);
+        assert_eq!(request_lens, [2, 2, 1]);
+
+        // A record bigger than the limit is still replayed, one at a time
+        let (split_img, request_lens) = redo_with_max_records_size(1)?;
+        assert_eq!(split_img, img);
+        assert_eq!(request_lens, [1, 1, 1, 1, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn inmemory_layer_creation_is_counted() -> Result<()> {
+        let repo = RepoHarness::create("inmemory_layer_creation_is_counted")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        assert_eq!(tline.inmemory_layers_created_counter.get(), 0);
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let mut lsn = Lsn(0);
+        for i in 1..=3 {
+            // Several writes go to the same layer, until it's frozen
+            for _ in 0..2 {
+                lsn = Lsn(lsn.0 + 0x10);
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn)?;
+            }
+            assert_eq!(tline.inmemory_layers_created_counter.get(), i);
+            let start_lsn = tline
+                .layers
+                .read()
+                .unwrap()
+                .open_layer
+                .as_ref()
+                .unwrap()
+                .get_lsn_range()
+                .start;
+            assert_eq!(tline.open_layer_start_lsn_gauge.get(), start_lsn.0 as i64);
+
+            tline.freeze_inmem_layer(false);
+        }
+        assert_eq!(tline.inmemory_layers_created_counter.get(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_lsn_is_in_scope() -> Result<()> {
+        let repo = RepoHarness::create("check_lsn_is_in_scope")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0x20))?;
+        *tline.latest_gc_cutoff_lsn.write().unwrap() = Lsn(0x40);
+        let latest_gc_cutoff_lsn = tline.get_latest_gc_cutoff_lsn();
+
+        // Before initdb
+        let err = tline
+            .check_lsn_is_in_scope(Lsn(0x10), &latest_gc_cutoff_lsn)
+            .unwrap_err();
+        let err = err.downcast_ref::<LsnBeforeInitdb>().unwrap();
+        assert_eq!(err.requested, Lsn(0x10));
+        assert_eq!(err.initdb_lsn, Lsn(0x20));
+
+        // Between initdb and the GC cutoff
+        for lsn in [Lsn(0x20), Lsn(0x30)] {
+            let err = tline
+                .check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)
+                .unwrap_err();
+            assert!(err.downcast_ref::<LsnBeforeInitdb>().is_none());
+            assert!(err
+                .to_string()
+                .contains("is earlier than latest GC horizon"));
+        }
+
+        // At and after the GC cutoff
+        tline.check_lsn_is_in_scope(Lsn(0x40), &latest_gc_cutoff_lsn)?;
+        tline.check_lsn_is_in_scope(Lsn(0x50), &latest_gc_cutoff_lsn)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn effective_gc_floor() -> Result<()> {
+        let repo = RepoHarness::create("effective_gc_floor")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0x20))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x30), Lsn(0x40), Lsn(0x50), Lsn(0x60)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        // Nothing has been garbage collected, so the floor is at initdb
+        assert_eq!(tline.effective_gc_floor(), Lsn(0x20));
+
+        // A branch point, and new cutoffs, don't move the floor by themselves
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x40)))?;
+        tline.update_gc_info(vec![Lsn(0x40)], Lsn(0x50), Duration::ZERO)?;
+        assert_eq!(tline.effective_gc_floor(), Lsn(0x20));
+
+        // GC raises the floor to its cutoff, past the branch point
+        tline.gc()?;
+        let floor = tline.effective_gc_floor();
+        assert_eq!(floor, Lsn(0x50));
+        let latest_gc_cutoff_lsn = tline.get_latest_gc_cutoff_lsn();
+        tline.check_lsn_is_in_scope(floor, &latest_gc_cutoff_lsn)?;
+        assert!(tline
+            .check_lsn_is_in_scope(Lsn(0x40), &latest_gc_cutoff_lsn)
+            .is_err());
+        drop(latest_gc_cutoff_lsn);
+
+        // A GC with older cutoffs doesn't lower it
+        tline.update_gc_info(Vec::new(), Lsn(0x30), Duration::ZERO)?;
+        tline.gc()?;
+        assert_eq!(tline.effective_gc_floor(), Lsn(0x50));
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_flush_lsn() -> Result<()> {
+        let repo = RepoHarness::create("wait_flush_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // The record has been processed, but not flushed yet
+        tline.wait_lsn(Lsn(0x10))?;
+        assert!(tline
+            .wait_flush_lsn(Lsn(0x10), Duration::from_millis(100))
+            .is_err());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = {
+            let tline = Arc::clone(&tline);
+            std::thread::spawn(move || {
+                tx.send(tline.wait_flush_lsn(Lsn(0x10), Duration::from_secs(60)))
+                    .unwrap()
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        rx.recv_timeout(Duration::from_secs(60))??;
+        waiter.join().unwrap();
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x10));
+
+        // Already flushed, returns immediately
+        tline.wait_flush_lsn(Lsn(0x10), Duration::ZERO)?;
+
+        Ok(())
+    }
+
+    /// Write a new version of blocks 0..'num_blocks' at 'lsn', and flush them to
+    /// an L0 layer.
+    fn write_blocks_and_flush(tline: &LayeredTimeline, num_blocks: u32, lsn: Lsn) -> Result<()> {
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        for blknum in 0..num_blocks {
+            test_key.field6 = blknum;
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+            )?;
+        }
+        writer.finish_write(lsn)?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)
+    }
+
+    #[test]
+    fn snapshot_keeps_compacted_layers() -> Result<()> {
+        let mut harness = RepoHarness::create("snapshot_keeps_compacted_layers")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        write_blocks_and_flush(&tline, 100, Lsn(0x10))?;
+        write_blocks_and_flush(&tline, 100, Lsn(0x20))?;
+
+        let snapshot = tline.layers.snapshot();
+        let old_paths: Vec<PathBuf> = snapshot
+            .get_level0_deltas()?
+            .iter()
+            .map(|l| l.local_path().unwrap())
+            .collect();
+        assert_eq!(old_paths.len(), 2);
+
+        tline.compact_level0(
+            &tline.get_compaction_target_file_sizes(),
+            &CancellationToken::default(),
+        )?;
+
+        // The current layer map no longer has the L0 layers, but the snapshot
+        // still does, and their files must stay around as long as it's in use.
+        assert!(tline.layers.read().unwrap().get_level0_deltas()?.is_empty());
+        assert_eq!(snapshot.get_level0_deltas()?.len(), 2);
+        assert!(old_paths.iter().all(|path| path.exists()));
+
+        drop(snapshot);
+        tline.delete_unused_layers(Duration::ZERO)?;
+        assert!(old_paths.iter().all(|path| !path.exists()));
+        assert!(tline.doomed_layers.lock().unwrap().is_empty());
+        assert_eq!(
+            tline.get_physical_size(),
+            tline.get_physical_size_non_incremental()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_in_snapshot() -> Result<()> {
+        let repo = RepoHarness::create("get_in_snapshot")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put = |lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+
+        put(Lsn(0x10))?;
+        let snapshot = tline.layers.snapshot();
+        put(Lsn(0x20))?;
+
+        // The layer with the newer version was added after the snapshot
+        assert_eq!(
+            tline.get_in_snapshot(test_key, Lsn(0x20), &snapshot)?,
+            TEST_IMG("foo at 0/10")
+        );
+        assert_eq!(tline.get(test_key, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_reads_during_compaction() -> Result<()> {
+        const NUM_BLOCKS: u32 = 100;
+        const NUM_READERS: usize = 4;
+        const NUM_ROUNDS: u64 = 20;
+
+        let mut harness = RepoHarness::create("concurrent_reads_during_compaction")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        write_blocks_and_flush(&tline, NUM_BLOCKS, Lsn(0x10))?;
+        let written_lsn = AtomicU64::new(0x10);
+        let done = AtomicBool::new(false);
+
+        crossbeam_utils::thread::scope(|s| -> Result<()> {
+            let mut readers = Vec::new();
+            for i in 0..NUM_READERS {
+                let tline = &tline;
+                let written_lsn = &written_lsn;
+                let done = &done;
+                readers.push(s.spawn(move |_| -> Result<()> {
+                    let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+                    let mut blknum = i as u32;
+                    while !done.load(AtomicOrdering::Relaxed) {
+                        let lsn = Lsn(written_lsn.load(AtomicOrdering::Acquire));
+                        blknum = (blknum + 7) % NUM_BLOCKS;
+                        test_key.field6 = blknum;
+                        assert_eq!(
+                            tline.get(test_key, lsn)?,
+                            TEST_IMG(&format!("{} at {}", blknum, lsn))
+                        );
+                    }
+                    Ok(())
+                }));
+            }
+
+            for round in 2..=NUM_ROUNDS {
+                let lsn = Lsn(round * 0x10);
+                write_blocks_and_flush(&tline, NUM_BLOCKS, lsn)?;
+                written_lsn.store(lsn.0, AtomicOrdering::Release);
+                tline.compact_level0(
+                    &tline.get_compaction_target_file_sizes(),
+                    &CancellationToken::default(),
+                )?;
+            }
+            done.store(true, AtomicOrdering::Relaxed);
+
+            for reader in readers {
+                reader.join().unwrap()?;
+            }
+            Ok(())
+        })
+        .unwrap()?;
+
+        // With the readers gone, all the replaced layers can be deleted.
+        tline.delete_unused_layers(Duration::ZERO)?;
+        assert!(tline.doomed_layers.lock().unwrap().is_empty());
+        assert_eq!(
+            tline.get_physical_size(),
+            tline.get_physical_size_non_incremental()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn superseded_layers_are_quarantined() -> Result<()> {
+        let mut harness = RepoHarness::create("superseded_layers_are_quarantined")?;
+        harness.tenant_conf.compaction_threshold = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        write_blocks_and_flush(&tline, 100, Lsn(0x10))?;
+        write_blocks_and_flush(&tline, 100, Lsn(0x20))?;
+        let old_paths: Vec<PathBuf> = tline
+            .layers
+            .read()
+            .unwrap()
+            .get_level0_deltas()?
+            .iter()
+            .map(|l| l.local_path().unwrap())
+            .collect();
+        let old_contents: Vec<Vec<u8>> = old_paths
+            .iter()
+            .map(fs::read)
+            .collect::<std::io::Result<_>>()?;
+        tline.compact_level0(
+            &tline.get_compaction_target_file_sizes(),
+            &CancellationToken::default(),
+        )?;
+        assert!(old_paths.iter().all(|path| !path.exists()));
+
+        // Simulate a crash after the compacted layers were installed, but
+        // before the old ones were deleted
+        drop(tline);
+        drop(repo);
+        for (path, contents) in old_paths.iter().zip(old_contents) {
+            fs::write(path, contents)?;
+        }
+
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert!(tline.layers.read().unwrap().get_level0_deltas()?.is_empty());
+        for path in &old_paths {
+            assert!(!path.exists());
+            let filename = path.file_name().unwrap().to_string_lossy();
+            assert!(path.with_file_name(format!("{filename}.0.old")).exists());
+        }
+        assert_eq!(
+            tline.get_physical_size(),
+            tline.get_physical_size_non_incremental()?
+        );
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        test_key.field6 = 50;
+        assert_eq!(
+            tline.get(test_key, Lsn(0x20))?,
+            TEST_IMG(&format!("{} at {}", 50, Lsn(0x20)))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_relation_data() -> Result<()> {
+        let repo = RepoHarness::create("drop_relation_data")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let rel = |relnode| RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 1,
+            relnode,
+        };
+        let block_key = |relnode, blknum| Key {
+            field6: blknum,
+            ..rel_key_range(rel(relnode)).start
+        };
+
+        for (i, lsn) in [Lsn(0x10), Lsn(0x20)].into_iter().enumerate() {
+            let writer = tline.writer();
+            for relnode in [1000, 1001] {
+                writer.put(
+                    block_key(relnode, i as u32),
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{relnode} at {lsn}"))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+        }
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        tline.drop_relation_data(rel(1000), Lsn(0x30))?;
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x30));
+
+        // Gone at and after the drop, but still there before it
+        assert!(tline.get(block_key(1000, 0), Lsn(0x30)).is_err());
+        assert!(tline.get(block_key(1000, 1), Lsn(0x30)).is_err());
+        assert_eq!(
+            tline.get(block_key(1000, 1), Lsn(0x20))?,
+            TEST_IMG("1000 at 0/20")
+        );
+
+        let writer = tline.writer();
+        writer.put(
+            block_key(1001, 0),
+            Lsn(0x40),
+            &Value::Image(TEST_IMG("1001 at 0/40")),
+        )?;
+        writer.finish_write(Lsn(0x40))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert!(tline.get(block_key(1000, 0), Lsn(0x40)).is_err());
+
+        // The other relation is not affected
+        assert_eq!(
+            tline.get(block_key(1001, 0), Lsn(0x40))?,
+            TEST_IMG("1001 at 0/40")
+        );
+        assert_eq!(
+            tline.get(block_key(1001, 1), Lsn(0x40))?,
+            TEST_IMG("1001 at 0/20")
+        );
+
+        // Dropping at an LSN that's not newer than the last record LSN fails
+        assert!(tline.drop_relation_data(rel(1001), Lsn(0x40)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn image_coverage_is_cached() -> Result<()> {
+        let repo = RepoHarness::create("image_coverage_is_cached")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put_and_flush = |lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+        let partition = KeySpace {
+            ranges: vec![test_key..test_key.next()],
+        };
+        let cached_coverage = || {
+            let cache = tline.image_coverage_cache.lock().unwrap();
+            let (_, coverage) = cache.entries.get(&partition.ranges[0]).unwrap();
+            Arc::clone(coverage)
+        };
+
+        put_and_flush(Lsn(0x10))?;
+        tline.time_for_new_image_layer(&partition, Lsn(0x20))?;
+        let first = cached_coverage();
+
+        // A second pass with the layer map unchanged reuses the result, even
+        // at a later LSN, as there are no layers after either LSN
+        tline.time_for_new_image_layer(&partition, Lsn(0x30))?;
+        assert!(Arc::ptr_eq(&first, &cached_coverage()));
+
+        // The result depends on the layer at LSN 0x10
+        tline.time_for_new_image_layer(&partition, Lsn(0x10))?;
+        assert!(!Arc::ptr_eq(&first, &cached_coverage()));
+
+        // Modifying the layer map invalidates the cache
+        tline.time_for_new_image_layer(&partition, Lsn(0x30))?;
+        let before_flush = cached_coverage();
+        put_and_flush(Lsn(0x40))?;
+        tline.time_for_new_image_layer(&partition, Lsn(0x30))?;
+        assert!(!Arc::ptr_eq(&before_flush, &cached_coverage()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_json() -> Result<()> {
+        let repo = RepoHarness::create("dump_json")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let key_a = Key::from_hex("012222222233333333444444445500000001")?;
+        let key_b = Key::from_hex("012222222233333333444444445500000002")?;
+        let writer = tline.writer();
+        writer.put(key_b, Lsn(0x10), &Value::Image(TEST_IMG("foo")))?;
+        writer.put(key_a, Lsn(0x10), &Value::Image(TEST_IMG("bar")))?;
+        writer.put(
+            key_a,
+            Lsn(0x20),
+            &Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"rec"),
+            }),
+        )?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+
+        let dump = tline.dump_json()?;
+        assert_eq!(dump["last_record_lsn"], "0/20");
+        assert_eq!(dump["layer_map"]["historic_layers"], json!([]));
+
+        let open_layer = &dump["layer_map"]["open_layer"];
+        assert!(open_layer["start_lsn"].is_string());
+        assert!(open_layer["end_lsn"].is_null());
+
+        // Sorted by key
+        let keys = open_layer["keys"].as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0]["key"], key_a.to_string());
+        assert_eq!(keys[1]["key"], key_b.to_string());
+
+        let versions = keys[0]["versions"].as_array().unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0]["lsn"], "0/10");
+        assert_eq!(versions[0]["has_image"], true);
+        assert_eq!(versions[0]["has_record"], false);
+        assert_eq!(versions[1]["lsn"], "0/20");
+        assert_eq!(versions[1]["has_image"], false);
+        assert_eq!(versions[1]["has_record"], true);
+        assert_eq!(versions[1]["will_init"], false);
+
+        // After a flush, the layer shows up as a historic layer
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let dump = tline.dump_json()?;
+        assert!(dump["layer_map"]["open_layer"].is_null());
+        let historic_layers = dump["layer_map"]["historic_layers"].as_array().unwrap();
+        assert_eq!(historic_layers.len(), 1);
+        assert_eq!(historic_layers[0]["lsn_end"], "0/21");
+        assert_eq!(historic_layers[0]["is_incremental"], true);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_bounded_staleness() -> Result<()> {
+        let repo = RepoHarness::create("get_bounded_staleness")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+        }
+
+        // Available LSNs are read exactly
+        assert_eq!(
+            tline.get_bounded_staleness(test_key, Lsn(0x10), Lsn(0x100))?,
+            (TEST_IMG("foo at 0/10"), Lsn(0x10))
+        );
+
+        // A future LSN within the staleness bound is served at the last
+        // record LSN, without waiting for the WAL to arrive
+        let start = Instant::now();
+        assert_eq!(
+            tline.get_bounded_staleness(test_key, Lsn(0x80), Lsn(0x100))?,
+            (TEST_IMG("foo at 0/20"), Lsn(0x20))
+        );
+        assert!(start.elapsed() < repo.conf.wait_lsn_timeout);
+
+        // Never older than the GC cutoff
+        *tline.latest_gc_cutoff_lsn.write().unwrap() = Lsn(0x30);
+        let err = tline
+            .get_bounded_staleness(test_key, Lsn(0x40), Lsn(0x100))
+            .unwrap_err();
+        assert!(err.downcast_ref::<LsnGarbageCollected>().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_at_timestamp() -> Result<()> {
+        let repo = RepoHarness::create("get_at_timestamp")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 1,
+            relnode: 1000,
+        };
+        let block_key = rel_key_range(rel).start;
+        let commit_time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        // A CLOG page, with the timestamp of the latest commit on it at the end
+        let clog_page = |secs| {
+            let mut page = vec![0u8; pg_constants::BLCKSZ as usize];
+            page.extend_from_slice(&to_pg_timestamp(commit_time(secs)).to_be_bytes());
+            Bytes::from(page)
+        };
+
+        let read_before_commits = || tline.get_at_timestamp(block_key, commit_time(1_600_000_500));
+        let err = read_before_commits().unwrap_err();
+        assert!(
+            err.to_string().contains("no commit timestamps available"),
+            "{err}"
+        );
+
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_slru_segment_creation(SlruKind::Clog, 0, 1)?;
+        m.put_slru_page_image(SlruKind::Clog, 0, 0, clog_page(1_600_001_000))?;
+        m.put_rel_creation(rel, 1)?;
+        m.put_rel_page_image(rel, 0, TEST_IMG("foo at 0/10"))?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_slru_page_image(SlruKind::Clog, 0, 0, clog_page(1_600_002_000))?;
+        m.put_rel_page_image(rel, 0, TEST_IMG("foo at 0/20"))?;
+        m.commit()?;
+
+        // Between the two commits, we get the value before the second one
+        let (value, lsn) = tline.get_at_timestamp(block_key, commit_time(1_600_001_500))?;
+        assert_eq!(value, TEST_IMG("foo at 0/10"));
+        assert!(Lsn(0x10) <= lsn && lsn < Lsn(0x20), "{lsn}");
+
+        // After the last commit
+        let err = tline
+            .get_at_timestamp(block_key, commit_time(1_600_003_000))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is in the future of available WAL"),
+            "{err}"
+        );
+
+        // Before the first commit
+        let err = read_before_commits().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is before the oldest available commit"),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn future_layers() {
+        let disk_consistent_lsn = Lsn(0x100);
+
+        // Delta layers, with an exclusive end LSN
+        for (end_lsn, is_future) in [
+            (Lsn(0xff), false),
+            (Lsn(0x100), false),
+            (Lsn(0x101), false),
+            (Lsn(0x102), true),
+            (Lsn(0x200), true),
+        ] {
+            assert_eq!(
+                is_future_layer(end_lsn, disk_consistent_lsn),
+                is_future,
+                "delta layer ending at {end_lsn}"
+            );
+        }
+
+        // Image layers, at an inclusive LSN
+        for (lsn, is_future) in [
+            (Lsn(0xff), false),
+            (Lsn(0x100), false),
+            (Lsn(0x101), true),
+            (Lsn(0x200), true),
+        ] {
+            assert_eq!(
+                is_future_layer(lsn + 1, disk_consistent_lsn),
+                is_future,
+                "image layer at {lsn}"
+            );
+        }
+
+        // Nothing has been flushed yet
+        assert!(!is_future_layer(Lsn(1), Lsn(0)));
+        assert!(is_future_layer(Lsn(2), Lsn(0)));
+    }
+
+    #[test]
+    fn pause_ingest() -> Result<()> {
+        let repo = RepoHarness::create("pause_ingest")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put = |lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)
+        };
+
+        put(Lsn(0x10))?;
+
+        tline.pause_ingest();
+        assert!(tline.is_ingest_paused());
+        let err = put(Lsn(0x20)).unwrap_err();
+        assert!(err.downcast_ref::<IngestPaused>().is_some(), "{err:#}");
+        let writer = tline.writer();
+        assert!(writer
+            .delete(test_key..test_key.next(), Lsn(0x20))
+            .unwrap_err()
+            .downcast_ref::<IngestPaused>()
+            .is_some());
+        drop(writer);
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x10));
+
+        // Reads and checkpoints still work
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        // After resuming, ingestion continues where it left off
+        tline.resume_ingest();
+        assert!(!tline.is_ingest_paused());
+        put(Lsn(0x20))?;
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x20));
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+        assert_eq!(tline.get(test_key, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn durability_latency() -> Result<()> {
+        let repo = RepoHarness::create("durability_latency")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // Pretend that the WAL was received two seconds ago
+        let received_at = SystemTime::now() - Duration::from_secs(2);
+        let received = |lsn| WalReceiverInfo {
+            wal_source_connstr: "test".to_string(),
+            last_received_msg_lsn: lsn,
+            last_received_msg_ts: received_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_micros(),
+        };
+        tline.record_received_wal(received(Lsn(0x10)));
+        // Too soon after the previous sample
+        tline.record_received_wal(received(Lsn(0x10)));
+        assert_eq!(tline.durability_samples.lock().unwrap().len(), 1);
+        assert_eq!(tline.durability_latency_histo.get_sample_count(), 0);
+
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert_eq!(tline.durability_latency_histo.get_sample_count(), 1);
+        let latency = tline.durability_latency_histo.get_sample_sum();
+        assert!((2.0..60.0).contains(&latency), "latency {latency}");
+        assert!(tline.durability_samples.lock().unwrap().is_empty());
+
+        // WAL that is durable already is not sampled
+        tline.record_received_wal(received(Lsn(0x10)));
+        assert!(tline.durability_samples.lock().unwrap().is_empty());
+        assert_eq!(
+            tline
+                .last_received_wal
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|info| info.last_received_msg_lsn),
+            Some(Lsn(0x10))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_timeline() -> Result<()> {
+        let harness = RepoHarness::create_with_conf("truncate_timeline", |conf| {
+            conf.wait_lsn_timeout = Duration::from_millis(10)
+        })?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put = |lsn: Lsn, what: &str| -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("{what} at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)
+        };
+
+        // The first three versions go to one delta layer, which has to be
+        // rewritten. The last one stays in memory.
+        put(Lsn(0x10), "foo")?;
+        put(Lsn(0x20), "foo")?;
+        put(Lsn(0x30), "foo")?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        put(Lsn(0x40), "foo")?;
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x10)))?;
+
+        // Refused without confirmation, while ingesting, and when it would
+        // cut off the branch point of a child
+        assert!(repo
+            .truncate_timeline(TIMELINE_ID, Lsn(0x20), false)
+            .is_err());
+        assert!(repo
+            .truncate_timeline(TIMELINE_ID, Lsn(0x20), true)
+            .is_err());
+        tline.pause_ingest();
+        assert!(repo.truncate_timeline(TIMELINE_ID, Lsn(0x8), true).is_err());
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x40));
+
+        repo.truncate_timeline(TIMELINE_ID, Lsn(0x20), true)?;
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x20));
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x20));
+        // Reads after the truncation point wait for WAL that isn't coming
+        assert!(tline.wait_lsn(Lsn(0x30)).is_err());
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+        assert_eq!(tline.get(test_key, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+        assert_eq!(
+            tline.key_history(test_key, Lsn(0)..Lsn::MAX)?,
+            vec![
+                (Lsn(0x10), ValueKind::Image, TIMELINE_ID),
+                (Lsn(0x20), ValueKind::Image, TIMELINE_ID),
+            ]
+        );
+
+        // Ingestion continues from the truncation point
+        tline.resume_ingest();
+        put(Lsn(0x30), "bar")?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert_eq!(tline.get(test_key, Lsn(0x30))?, TEST_IMG("bar at 0/30"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn layer_reads_are_timed() -> Result<()> {
+        let repo = RepoHarness::create("layer_reads_are_timed")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let reads = |kind: &str| {
+            LAYER_READ_TIME
+                .with_label_values(&[kind, "resident"])
+                .get_sample_count()
+        };
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // Other tests read layers concurrently, so only check that the count
+        // of the layer kind that was read went up.
+        let check_read = |kind: &str| -> Result<()> {
+            let before = reads(kind);
+            assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+            assert!(reads(kind) > before, "no {kind} layer read recorded");
+            Ok(())
+        };
+        check_read("in_memory")?;
+
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        check_read("delta")?;
+
+        let partitioning = KeyPartitioning {
+            parts: vec![KeySpace {
+                ranges: vec![test_key..test_key.next()],
+            }],
+        };
+        tline.create_image_layers(
+            &partitioning,
+            Lsn(0x10),
+            true,
+            &CancellationToken::default(),
+        )?;
+        check_read("image")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn layer_read_error_names_layer() -> Result<()> {
+        let repo = RepoHarness::create("layer_read_error_names_layer")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let values = [
+            (Lsn(0x10), Value::Image(TEST_IMG("foo at 0/10"))),
+            (
+                Lsn(0x20),
+                Value::WalRecord(ZenithWalRecord::Postgres {
+                    will_init: false,
+                    rec: Bytes::from_static(b"record at 0/20"),
+                }),
+            ),
+        ];
+        for (lsn, value) in values {
+            let writer = tline.writer();
+            writer.put(test_key, lsn, &value)?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        let mut layers: Vec<_> = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .cloned()
+            .collect();
+        layers.sort_by_key(|l| l.get_lsn_range().start);
+        assert_eq!(layers.len(), 2);
+
+        // Damage the older layer, which holds the image
+        OpenOptions::new()
+            .write(true)
+            .open(layers[0].local_path().unwrap())?
+            .set_len(0)?;
+
+        let err = tline.get(test_key, Lsn(0x20)).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(
+            msg.contains(&format!("from layer {}", layers[0].filename().display())),
+            "{msg}"
+        );
+        assert!(
+            msg.contains(&format!("failed to read key {test_key}")),
+            "{msg}"
+        );
+        assert!(
+            !msg.contains(&layers[1].filename().display().to_string()),
+            "{msg}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn staged_batch() -> Result<()> {
+        let repo = RepoHarness::create("staged_batch")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let key1 = Key::from_hex("012222222233333333444444445500000001")?;
+        let key2 = Key::from_hex("012222222233333333444444445500000002")?;
+
+        let writer = tline.writer();
+        writer.put(key1, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // Stage some writes, and "crash" before committing them
+        let writer = tline.writer();
+        writer.begin_batch()?;
+        writer.put(key1, Lsn(0x20), &Value::Image(TEST_IMG("foo at 0x20")))?;
+        writer.put(key2, Lsn(0x20), &Value::Image(TEST_IMG("bar at 0x20")))?;
+        assert!(writer.finish_write(Lsn(0x20)).is_err());
+        drop(writer);
+
+        // None of the staged writes made it to the timeline
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x10));
+        assert_eq!(tline.get(key1, Lsn(0x20))?, TEST_IMG("foo at 0x10"));
+        assert!(tline.get(key2, Lsn(0x20)).is_err());
+
+        // A batch that doesn't advance the LSN is rejected as a whole
+        let writer = tline.writer();
+        writer.begin_batch()?;
+        writer.put(key2, Lsn(0x10), &Value::Image(TEST_IMG("bar at 0x10")))?;
+        assert!(writer.commit_batch(Lsn(0x10)).is_err());
+        assert!(tline.get(key2, Lsn(0x10)).is_err());
+
+        // Committed writes become visible together
+        writer.begin_batch()?;
+        assert!(writer.begin_batch().is_err());
+        writer.put(key1, Lsn(0x30), &Value::Image(TEST_IMG("foo at 0x30")))?;
+        writer.put(key2, Lsn(0x30), &Value::Image(TEST_IMG("bar at 0x30")))?;
+        writer.commit_batch(Lsn(0x30))?;
+        assert!(writer.commit_batch(Lsn(0x40)).is_err());
+        drop(writer);
+
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x30));
+        assert_eq!(tline.get(key1, Lsn(0x30))?, TEST_IMG("foo at 0x30"));
+        assert_eq!(tline.get(key2, Lsn(0x30))?, TEST_IMG("bar at 0x30"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn synchronous_flush() -> Result<()> {
+        let mut harness = RepoHarness::create_with_conf("synchronous_flush", |conf| {
+            conf.synchronous_flush = true
+        })?;
+        // Freeze the open layer on every check
+        harness.tenant_conf.checkpoint_distance = 1;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for (i, lsn) in [Lsn(0x10), Lsn(0x20), Lsn(0x30)].into_iter().enumerate() {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+
+            // The layer is frozen and flushed before this returns
+            tline.check_checkpoint_distance()?;
+            let layers = tline.layers.read().unwrap();
+            assert!(layers.open_layer.is_none());
+            assert!(layers.frozen_layers.is_empty());
+            assert_eq!(layers.iter_historic_layers().count(), i + 1);
+            drop(layers);
+            assert_eq!(tline.get_disk_consistent_lsn(), lsn);
+
+            assert_eq!(
+                tline.get(test_key, lsn)?,
+                TEST_IMG(&format!("foo at {lsn}"))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_max_entries() -> Result<()> {
+        let mut harness = RepoHarness::create_with_conf("checkpoint_max_entries", |conf| {
+            conf.synchronous_flush = true
+        })?;
+        harness.tenant_conf.checkpoint_max_entries = 100;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // Many distinct keys, with very little data each
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        let put_keys = |test_key: &mut Key, num_keys: u32, lsn: Lsn| -> Result<()> {
+            let writer = tline.writer();
+            for _ in 0..num_keys {
+                writer.put(*test_key, lsn, &Value::Image(Bytes::from_static(b"x")))?;
+                *test_key = test_key.next();
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.check_checkpoint_distance()
+        };
+
+        // At the limit, far below the checkpoint distance
+        put_keys(&mut test_key, 100, Lsn(0x10))?;
+        let open_layer = tline.layers.read().unwrap().open_layer.clone().unwrap();
+        assert_eq!(open_layer.num_entries(), 100);
+        assert!(open_layer.size()? < tline.get_checkpoint_distance());
+
+        // Going over the limit freezes and flushes the layer
+        put_keys(&mut test_key, 1, Lsn(0x20))?;
+        assert_eq!(open_layer.num_entries(), 101);
+        let layers = tline.layers.read().unwrap();
+        assert!(layers.open_layer.is_none());
+        assert_eq!(layers.iter_historic_layers().count(), 1);
+        drop(layers);
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x20));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_empty_branch_from_ancestor() -> Result<()> {
+        let repo = RepoHarness::create("read_empty_branch_from_ancestor")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let key_a = Key::from_hex("012222222233333333444444445500000000")?;
+        let key_b = Key::from_hex("012222222233333333444444445500000001")?;
+        let writer = tline.writer();
+        writer.put(key_a, Lsn(0x10), &Value::Image(TEST_IMG("a at 0/10")))?;
+        writer.put(key_b, Lsn(0x10), &Value::Image(TEST_IMG("b at 0/10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert!(tline.has_local_layers());
+
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x10)))?;
+        let newtline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+
+        // A fresh branch has no layers, reads are served by the ancestor
+        assert!(!newtline.has_local_layers());
+        assert_eq!(newtline.get(key_a, Lsn(0x10))?, TEST_IMG("a at 0/10"));
+        let missing = Key::from_hex("012222222233333333444444445500000002")?;
+        assert!(newtline.get(missing, Lsn(0x10)).is_err());
+        assert!(!newtline.has_local_layers());
+        assert!(newtline.layers.snapshot().open_layer.is_none());
+
+        // After the first write, the branch's own layers are searched first
+        let writer = newtline.writer();
+        writer.put(key_a, Lsn(0x20), &Value::Image(TEST_IMG("a at 0/20")))?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+        assert!(newtline.has_local_layers());
+        assert_eq!(newtline.get(key_a, Lsn(0x20))?, TEST_IMG("a at 0/20"));
+        assert_eq!(newtline.get(key_b, Lsn(0x20))?, TEST_IMG("b at 0/10"));
+        assert_eq!(tline.get(key_a, Lsn(0x10))?, TEST_IMG("a at 0/10"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn gc_info_snapshot_shows_branch_point() -> Result<()> {
+        let repo = RepoHarness::create("gc_info_snapshot_shows_branch_point")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+        }
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Some(Lsn(0x20)))?;
+
+        // Nothing is known before GC has run
+        let snapshot = tline.gc_info_snapshot();
+        assert!(snapshot.retain_lsns.is_empty());
+        assert_eq!(snapshot.horizon_cutoff, Lsn(0));
+
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+
+        let snapshot = tline.gc_info_snapshot();
+        assert_eq!(
+            snapshot.retain_lsns,
+            vec![RetainLsn {
+                lsn: Lsn(0x20),
+                source: RetainLsnSource::ChildBranch,
+            }]
+        );
+        assert_eq!(snapshot.horizon_cutoff, Lsn(0x20));
+        assert_eq!(snapshot.pitr_cutoff, Lsn(0x20));
+        assert_eq!(
+            snapshot.latest_gc_cutoff_lsn,
+            *tline.get_latest_gc_cutoff_lsn()
+        );
+
+        assert_eq!(
+            serde_json::to_value(&snapshot)?["retain_lsns"],
+            json!([{ "lsn": "0/20", "source": "child_branch" }])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_check() -> Result<()> {
+        let harness = RepoHarness::create("test_self_check")?;
+        let tline = create_test_timeline(harness.load(), TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_control_file(TEST_IMG("control file"))?;
+        m.put_checkpoint(TEST_IMG("checkpoint"))?;
+        m.commit()?;
+
+        let report = tline.self_check(100);
+        assert!(report.keys_checked > 0);
+        assert_eq!(report.successes, report.keys_checked);
+        assert_eq!(report.failures, 0);
+
+        // Flush the data to a layer file, and remove it behind the timeline's back
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        for direntry in fs::read_dir(harness.timeline_path(&TIMELINE_ID))? {
+            let path = direntry?.path();
+            if path.file_name().unwrap() != METADATA_FILE_NAME {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let report = tline.self_check(100);
+        assert_eq!(report.successes, 0);
+        assert!(report.failures > 0);
+        assert!(!report.first_failures.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_local_layer() -> Result<()> {
+        let harness = RepoHarness::create("test_drop_local_layer")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        let (filename, path) = {
+            let layers = tline.layers.read().unwrap();
+            let layer = layers.iter_historic_layers().next().unwrap();
+            (
+                layer.filename().display().to_string(),
+                layer.local_path().unwrap(),
+            )
+        };
+        let size_before = tline.get_physical_size();
+
+        // Unknown layers cannot be dropped
+        assert!(tline.drop_local_layer("no-such-layer").is_err());
+
+        // Neither can layers that are present in the remote storage, as they
+        // could not be downloaded back on demand
+        let mut remote_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(0),
+            None,
+            None,
+            Lsn(0),
+            Lsn(0),
+            Lsn(0),
+        ));
+        remote_timeline.add_timeline_layers([path.clone()]);
+        futures::executor::block_on(repo.get_remote_index().write()).add_timeline_entry(
+            ZTenantTimelineId {
+                tenant_id: harness.tenant_id,
+                timeline_id: TIMELINE_ID,
+            },
+            remote_timeline,
+        );
+        assert!(tline.drop_local_layer(&filename).is_err());
+
+        // The layer is left untouched
+        assert!(path.exists());
+        assert_eq!(tline.get_physical_size(), size_before);
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_quarantine() -> Result<()> {
+        const NUM_THREADS: usize = 8;
+        const NUM_ROUNDS: usize = 10;
+
+        let dir = RepoHarness::create("test_concurrent_quarantine")?.timeline_path(&TIMELINE_ID);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("layer");
+
+        let mut new_paths = Vec::new();
+        for round in 0..NUM_ROUNDS {
+            fs::write(&path, format!("round {round}"))?;
+
+            let barrier = std::sync::Barrier::new(NUM_THREADS);
+            let results = crossbeam_utils::thread::scope(|s| {
+                let handles = (0..NUM_THREADS)
+                    .map(|_| {
+                        s.spawn(|_| {
+                            barrier.wait();
+                            quarantine_file(&path, "test", usize::MAX)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap();
+
+            // Exactly one of the threads gets to move the file
+            assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+            assert!(!path.exists());
+            new_paths.push(results.into_iter().find_map(Result::ok).unwrap());
+        }
+
+        // Every round's file got a distinct name, and nothing was overwritten
+        // or left behind by the threads that lost the race. The numbering
+        // follows the order of the rounds, even if a thread that lost the race
+        // left a gap in it.
+        let mut contents = Vec::new();
+        for new_path in &new_paths {
+            contents.push(fs::read_to_string(new_path)?);
+            assert_eq!(
+                fs::read_to_string(new_path.with_extension("reason.old"))?,
+                "test\n"
+            );
+        }
+        let expected = (0..NUM_ROUNDS)
+            .map(|round| format!("round {round}"))
+            .collect::<Vec<_>>();
+        assert_eq!(contents, expected);
+        let nums = new_paths
+            .iter()
+            .map(|p| {
+                let name = p.file_name().unwrap().to_string_lossy().into_owned();
+                name.split('.').nth(1).unwrap().parse::<u32>().unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(nums.windows(2).all(|w| w[0] < w[1]), "{nums:?}");
+        assert_eq!(fs::read_dir(&dir)?.count(), 2 * NUM_ROUNDS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_with_deadline() -> Result<()> {
+        let repo = RepoHarness::create("test_get_with_deadline")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // Build up a few layers of history for the key
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0);
+        for _ in 0..10 {
+            lsn = Lsn(lsn.0 + 0x10);
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        // A deadline that has already passed must not return a value
+        let err = tline
+            .get_with_deadline(test_key, lsn, Instant::now())
+            .unwrap_err();
+        assert!(err.downcast_ref::<GetTimeoutError>().is_some());
+
+        // With plenty of time, the read succeeds
+        assert_eq!(
+            tline.get_with_deadline(test_key, lsn, Instant::now() + Duration::from_secs(60))?,
+            TEST_IMG(&format!("foo at {lsn}"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_ancestor_depth() -> Result<()> {
+        let harness = RepoHarness::create_with_conf("test_max_ancestor_depth", |conf| {
+            conf.max_ancestor_depth = 3
+        })?;
+        let repo = harness.load();
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        // Build a chain of branches, each one branched from the previous one
+        let mut chain = vec![TIMELINE_ID];
+        let mut lsn = Lsn(0x10);
+        for _ in 0..4 {
+            let new_tline_id = ZTimelineId::generate();
+            repo.branch_timeline(*chain.last().unwrap(), new_tline_id, Some(lsn))?;
+            let new_tline = repo.get_timeline_load(new_tline_id)?;
+            lsn = Lsn(lsn.0 + 0x10);
+            let writer = new_tline.writer();
+            writer.put(
+                test_key.next(),
+                lsn,
+                &Value::Image(TEST_IMG(&format!("bar at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            chain.push(new_tline_id);
+        }
+
+        // Three levels of ancestors is fine
+        let tline = repo.get_timeline_load(chain[3])?;
+        assert_eq!(tline.get(test_key, Lsn(0x40))?, TEST_IMG("foo at 0x10"));
+
+        // Four is too many, and the error names the whole chain
+        let tline = repo.get_timeline_load(chain[4])?;
+        let err = format!("{:#}", tline.get(test_key, lsn).unwrap_err());
+        assert!(err.contains("max ancestor depth of 3"), "{err}");
+        for tline_id in chain.iter().rev() {
+            assert!(err.contains(&tline_id.to_string()), "{err}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_if_beneficial() -> Result<()> {
+        let harness = RepoHarness::create("test_compact_if_beneficial")?;
+        let tline = create_test_timeline(harness.load(), TIMELINE_ID)?;
+        let compaction_threshold = harness.tenant_conf.compaction_threshold;
+
+        // Create one level 0 delta layer less than what's needed for compaction
+        let mut lsn = Lsn(0x10);
+        for _ in 0..compaction_threshold - 1 {
+            let mut m = tline.begin_modification(lsn);
+            m.put_control_file(TEST_IMG(&format!("control file at {lsn}")))?;
+            m.put_checkpoint(TEST_IMG(&format!("checkpoint at {lsn}")))?;
+            m.commit()?;
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        let num_level0_deltas = || {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()
+                .unwrap()
+                .len()
+        };
+        assert_eq!(num_level0_deltas(), compaction_threshold - 1);
+
+        assert!(!tline.compact_if_beneficial()?);
+        assert_eq!(num_level0_deltas(), compaction_threshold - 1);
+
+        // One more reaches the threshold
+        let mut m = tline.begin_modification(lsn);
+        m.put_control_file(TEST_IMG(&format!("control file at {lsn}")))?;
+        m.put_checkpoint(TEST_IMG(&format!("checkpoint at {lsn}")))?;
+        m.commit()?;
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert_eq!(num_level0_deltas(), compaction_threshold);
+
+        assert!(tline.compact_if_beneficial()?);
+        assert!(num_level0_deltas() < compaction_threshold);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_if_beneficial_min_benefit() -> Result<()> {
+        let mut harness = RepoHarness::create("test_compact_if_beneficial_min_benefit")?;
+        harness.tenant_conf.compaction_threshold = 3;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // The same two keys in each of the 3 level 0 delta layers. Merging
+        // them saves 2 layer visits per key, 4 in total.
+        let keys = [
+            Key::from_hex("112222222233333333444444445500000001")?,
+            Key::from_hex("112222222233333333444444445500000002")?,
+        ];
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let writer = tline.writer();
+            for key in keys {
+                writer.put(
+                    key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", key.field6, lsn))),
+                )?;
+            }
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        let num_level0_deltas = || {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()
+                .unwrap()
+                .len()
+        };
+        assert_eq!(num_level0_deltas(), 3);
+
+        let set_min_benefit = |min_benefit| {
+            repo.update_tenant_config(TenantConfOpt {
+                compaction_min_benefit: Some(min_benefit),
+                ..TenantConfOpt::default()
+            })
+        };
+
+        // Just below the benefit threshold
+        set_min_benefit(5)?;
+        assert!(!tline.compact_if_beneficial()?);
+        assert_eq!(num_level0_deltas(), 3);
+
+        // At the threshold
+        set_min_benefit(4)?;
+        assert!(tline.compact_if_beneficial()?);
+        assert_eq!(num_level0_deltas(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_leftover_temp_layer_file() -> Result<()> {
+        let harness = RepoHarness::create("test_leftover_temp_layer_file")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let num_layers = tline.layers.read().unwrap().iter_historic_layers().count();
+        drop(tline);
+        drop(repo);
+
+        // Simulate a crash in the middle of writing a layer file: the data
+        // is left behind under the writer's temporary name.
+        let temp_path = harness.timeline_path(&TIMELINE_ID).join(format!(
+            "{}-XXX__{:016X}-{:016X}.abcdefgh.temp",
+            test_key, 0x11, 0x20
+        ));
+        fs::write(&temp_path, b"partial layer")?;
+
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert!(!temp_path.exists());
+        assert_eq!(
+            tline.layers.read().unwrap().iter_historic_layers().count(),
+            num_layers
+        );
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_keys() -> Result<()> {
+        let repo = RepoHarness::create("test_missing_keys")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let key_at = |blknum: u32| Key {
+            field6: blknum,
+            ..test_key
+        };
+
+        // Write blocks 0-2 and 5-6, leaving a hole at 3-4
+        let writer = tline.writer();
+        for blknum in [0, 1, 2, 5, 6] {
+            writer.put(
+                key_at(blknum),
+                Lsn(0x10),
+                &Value::Image(TEST_IMG(&format!("{blknum} at 0x10"))),
+            )?;
+        }
+        writer.finish_write(Lsn(0x10))?;
+        drop(writer);
+
+        let expected = vec![key_at(3)..key_at(5), key_at(7)..key_at(8)];
+        let missing = tline.missing_keys(key_at(0)..key_at(8), Lsn(0x10))?;
+        assert_eq!(missing.ranges, expected);
+
+        // Same result when the data comes from layer files
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        let missing = tline.missing_keys(key_at(0)..key_at(8), Lsn(0x10))?;
+        assert_eq!(missing.ranges, expected);
+
+        // Nothing is missing in the written ranges
+        assert!(tline
+            .missing_keys(key_at(0)..key_at(3), Lsn(0x10))?
+            .ranges
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_below_gc_cutoff() -> Result<()> {
+        let repo = RepoHarness::create("test_get_below_gc_cutoff")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.put(test_key, Lsn(0x20), &Value::Image(TEST_IMG("foo at 0x20")))?;
+        writer.finish_write(Lsn(0x20))?;
+        drop(writer);
+
+        // Populate the materialized page cache, too
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+
+        *tline.latest_gc_cutoff_lsn.write().unwrap() = Lsn(0x20);
+
+        let err = tline.get(test_key, Lsn(0x10)).unwrap_err();
+        let err = err
+            .downcast_ref::<LsnGarbageCollected>()
+            .expect("unexpected error");
+        assert_eq!(err.requested, Lsn(0x10));
+        assert_eq!(err.gc_cutoff, Lsn(0x20));
+
+        assert_eq!(tline.get(test_key, Lsn(0x20))?, TEST_IMG("foo at 0x20"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_max_copies() -> Result<()> {
+        const MAX_COPIES: usize = 3;
+
+        let dir = RepoHarness::create("test_quarantine_max_copies")?.timeline_path(&TIMELINE_ID);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("layer");
+
+        for round in 0..20 {
+            fs::write(&path, format!("round {round}"))?;
+            quarantine_file(&path, "test", MAX_COPIES)?;
+
+            let num_copies = (round + 1).min(MAX_COPIES);
+            assert_eq!(fs::read_dir(&dir)?.count(), 2 * num_copies);
+        }
+
+        // The newest copies are kept
+        for round in 17..20 {
+            assert_eq!(
+                fs::read_to_string(dir.join(format!("layer.{round}.old")))?,
+                format!("round {round}")
+            );
+            assert!(dir.join(format!("layer.{round}.reason.old")).exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_layers() -> Result<()> {
+        let harness = RepoHarness::create("test_reconcile_layers")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        for lsn in [Lsn(0x10), Lsn(0x20)] {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn)?;
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        let mut paths = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .map(|l| l.local_path().unwrap())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths.len(), 2);
+
+        // Without a remote index entry, only the local files are checked
+        let report = tline.reconcile_layers(false)?;
+        assert!(report.local_files_not_in_map.is_empty());
+        assert!(report.map_layers_missing_locally.is_empty());
+        assert!(report.map_layers_missing_remotely.is_empty());
+        assert!(report.remote_layers_not_in_map.is_empty());
+
+        // The remote storage has the first layer, and one the map doesn't know of
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_only = timeline_path.join(
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000000000001-0000000000000002",
+        );
+        let mut remote_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(0),
+            None,
+            None,
+            Lsn(0),
+            Lsn(0),
+            Lsn(0),
+        ));
+        remote_timeline.add_timeline_layers([paths[0].clone(), remote_only.clone()]);
+        futures::executor::block_on(repo.get_remote_index().write()).add_timeline_entry(
+            ZTenantTimelineId {
+                tenant_id: harness.tenant_id,
+                timeline_id: TIMELINE_ID,
+            },
+            remote_timeline,
+        );
+        let report = tline.reconcile_layers(false)?;
+        assert_eq!(report.map_layers_missing_remotely, vec![paths[1].clone()]);
+        assert_eq!(report.remote_layers_not_in_map, vec![remote_only]);
+
+        // A layer file that is not in the layer map
+        {
+            let mut layers = tline.layers.write().unwrap();
+            let layer = layers
+                .iter_historic_layers()
+                .find(|l| l.local_path().as_ref() == Some(&paths[1]))
+                .map(Arc::clone)
+                .unwrap();
+            layers.remove_historic(layer);
+        }
+        let report = tline.reconcile_layers(false)?;
+        assert_eq!(report.local_files_not_in_map, vec![paths[1].clone()]);
+        assert!(report.map_layers_missing_remotely.is_empty());
+        assert!(report.repaired.is_empty());
+
+        // ... is added back by the repair
+        let report = tline.reconcile_layers(true)?;
+        assert_eq!(report.repaired, vec![paths[1].clone()]);
+        let report = tline.reconcile_layers(false)?;
+        assert!(report.local_files_not_in_map.is_empty());
+        assert_eq!(report.map_layers_missing_remotely, vec![paths[1].clone()]);
+        assert_eq!(
+            tline.get(test_key, Lsn(0x20))?,
+            TEST_IMG(&format!("foo at {}", Lsn(0x20)))
+        );
+
+        // A layer in the map whose file is gone
+        fs::remove_file(&paths[0])?;
+        let report = tline.reconcile_layers(true)?;
+        assert_eq!(report.map_layers_missing_locally, vec![paths[0].clone()]);
+        assert!(report.repaired.is_empty());
+
+        Ok(())
+    }
+
+    // Batched WAL redo in get_multi()
+    mod wal_redo_batching {
+        use super::*;
+
+        /// WAL redo manager that records the sizes of the batches it gets, and
+        /// fails to reconstruct one key.
+        struct BatchingRedoManager {
+            batch_sizes: Mutex<Vec<usize>>,
+            bad_key: Key,
+        }
+
+        impl WalRedoManager for BatchingRedoManager {
+            fn request_redo(
+                &self,
+                key: Key,
+                lsn: Lsn,
+                _base_img: Option<Bytes>,
+                records: Vec<(Lsn, ZenithWalRecord)>,
+            ) -> Result<Bytes, WalRedoError> {
+                if key == self.bad_key {
+                    return Err(WalRedoError::InvalidRecord);
+                }
+                Ok(TEST_IMG(&format!(
+                    "{key} at {lsn} with {} records",
+                    records.len()
+                )))
+            }
+
+            fn request_redo_batch(
+                &self,
+                requests: Vec<RedoRequest>,
+            ) -> Vec<Result<Bytes, WalRedoError>> {
+                self.batch_sizes.lock().unwrap().push(requests.len());
+                requests
+                    .into_iter()
+                    .map(|req| self.request_redo(req.key, req.lsn, req.base_img, req.records))
+                    .collect()
+            }
+        }
+
+        #[test]
+        fn get_multi_batches_wal_redo() -> Result<()> {
+            let harness = RepoHarness::create_with_conf("get_multi_batches_wal_redo", |conf| {
+                conf.wal_redo_batch_size = 2
+            })?;
+
+            let keys: Vec<Key> = (0..7)
+                .map(|i| Key::from_hex(&format!("01222222223333333344444444550000000{i}")))
+                .collect::<Result<_>>()?;
+            let redo_mgr = Arc::new(BatchingRedoManager {
+                batch_sizes: Mutex::new(Vec::new()),
+                bad_key: keys[3],
+            });
+            let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            // Keys 0-4 need WAL redo, key 5 has an image, and key 6 doesn't exist
+            let writer = tline.writer();
+            for key in &keys[0..5] {
+                writer.put(
+                    *key,
+                    Lsn(0x10),
+                    &Value::WalRecord(ZenithWalRecord::Postgres {
+                        will_init: true,
+                        rec: Bytes::from_static(b"init record"),
+                    }),
+                )?;
+            }
+            writer.put(keys[5], Lsn(0x10), &Value::Image(TEST_IMG("image")))?;
+            writer.finish_write(Lsn(0x10))?;
+            drop(writer);
+
+            let results = tline.get_multi(&keys, Lsn(0x10), true);
+            assert_eq!(results.len(), keys.len());
+            for (i, (key, result)) in keys.iter().zip(&results).enumerate() {
+                match i {
+                    3 => assert!(result.is_err(), "redo of key {i} should fail"),
+                    5 => assert_eq!(result.as_ref().unwrap(), &TEST_IMG("image")),
+                    6 => assert!(result.is_err(), "key {i} should not be found"),
+                    _ => assert_eq!(
+                        result.as_ref().unwrap(),
+                        &TEST_IMG(&format!("{key} at 0/10 with 1 records"))
+                    ),
+                }
+                // The same as reading the keys one by one
+                match tline.get(*key, Lsn(0x10)) {
+                    Ok(value) => assert_eq!(result.as_ref().unwrap(), &value),
+                    Err(_) => assert!(result.is_err()),
+                }
+            }
+
+            // The 5 keys that needed WAL redo were sent in batches of 2
+            assert_eq!(*redo_mgr.batch_sizes.lock().unwrap(), vec![2, 2, 1]);
+
+            Ok(())
+        }
+    }
+
+    // Reporting of flushes and compactions that hold their locks for too long
+    mod maintenance_stalls {
+        use super::*;
+
+        #[test]
+        fn held_maintenance_lock_is_reported() -> Result<()> {
+            let harness =
+                RepoHarness::create_with_conf("held_maintenance_lock_is_reported", |conf| {
+                    conf.maintenance_stall_threshold = Duration::from_millis(10)
+                })?;
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            assert_eq!(tline.check_maintenance_stalls(), 0);
+
+            // Pretend to be a compaction that got stuck
+            let guard = tline.layer_removal_cs.lock();
+            assert_eq!(tline.check_maintenance_stalls(), 0);
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(tline.check_maintenance_stalls(), 1);
+            assert_eq!(
+                repo.check_maintenance_stalls(),
+                0,
+                "a stall is reported once"
+            );
+
+            drop(guard);
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(tline.check_maintenance_stalls(), 0);
+
+            Ok(())
+        }
+    }
+
+    // Refusing to write layers and ingest WAL when the disk is almost full
+    mod low_disk_space {
+        use super::*;
+
+        #[test]
+        fn low_disk_space_prevents_layer_writes() -> Result<()> {
+            let harness =
+                RepoHarness::create_with_conf("low_disk_space_prevents_layer_writes", |conf| {
+                    conf.min_free_disk_space = 1024 * 1024
+                })?;
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+            let put = |lsn: Lsn| -> Result<()> {
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn)
+            };
+            let layer_files = || -> Result<Vec<String>> {
+                let mut names = Vec::new();
+                for entry in fs::read_dir(harness.timeline_path(&TIMELINE_ID))? {
+                    let name = entry?.file_name().to_string_lossy().to_string();
+                    if name != METADATA_FILE_NAME
+                        && name != PREV_RECORD_LSNS_FILE_NAME
+                        && !is_ephemeral_file(&name)
+                    {
+                        names.push(name);
+                    }
+                }
+                Ok(names)
+            };
+
+            put(Lsn(0x10))?;
+
+            // The flush is refused before anything is written
+            disk_space::mock_available_space(Some(1000));
+            let err = tline.checkpoint(CheckpointConfig::Flush).unwrap_err();
+            let low = err
+                .downcast_ref::<DiskSpaceLow>()
+                .unwrap_or_else(|| panic!("{err:#}"));
+            assert_eq!((low.available, low.reserve), (1000, 1024 * 1024));
+            assert!(layer_files()?.is_empty());
+            assert_eq!(
+                tline.layers.read().unwrap().iter_historic_layers().count(),
+                0
+            );
+
+            // Ingestion is held back until there's room again
+            assert!(tline.is_disk_space_low());
+            let err = put(Lsn(0x20)).unwrap_err();
+            assert!(err.downcast_ref::<DiskSpaceLow>().is_some(), "{err:#}");
+            assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+
+            disk_space::mock_available_space(Some(1024 * 1024));
+            assert!(!tline.is_disk_space_low());
+            put(Lsn(0x20))?;
+            tline.checkpoint(CheckpointConfig::Flush)?;
+            assert!(!layer_files()?.is_empty());
+            assert_eq!(tline.get(test_key, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+
+            disk_space::mock_available_space(None);
+            Ok(())
+        }
+
+        #[test]
+        fn low_disk_space_refuses_whole_batch() -> Result<()> {
+            let harness =
+                RepoHarness::create_with_conf("low_disk_space_refuses_whole_batch", |conf| {
+                    conf.min_free_disk_space = 1024 * 1024
+                })?;
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let test_keys = [
+                Key::from_hex("012222222233333333444444445500000001")?,
+                Key::from_hex("012222222233333333444444445500000002")?,
+            ];
+            let commit_batch = |lsn: Lsn| -> Result<()> {
+                let writer = tline.writer();
+                writer.begin_batch()?;
+                for key in test_keys {
+                    writer.put(key, lsn, &Value::Image(TEST_IMG(&format!("foo at {lsn}"))))?;
+                }
+                writer.commit_batch(lsn)
+            };
+            commit_batch(Lsn(0x10))?;
+
+            // A failed flush finds out that the disk is almost full
+            disk_space::mock_available_space(Some(1000));
+            assert!(tline.checkpoint(CheckpointConfig::Flush).is_err());
+            assert!(tline.is_disk_space_low());
+
+            // None of the staged writes is applied
+            let err = commit_batch(Lsn(0x20)).unwrap_err();
+            assert!(err.downcast_ref::<DiskSpaceLow>().is_some(), "{err:#}");
+            assert_eq!(tline.get_last_record_lsn(), Lsn(0x10));
+            for key in test_keys {
+                assert_eq!(tline.get(key, Lsn(0x10))?, TEST_IMG("foo at 0/10"));
+            }
+
+            disk_space::mock_available_space(Some(1024 * 1024));
+            commit_batch(Lsn(0x20))?;
+            for key in test_keys {
+                assert_eq!(tline.get(key, Lsn(0x20))?, TEST_IMG("foo at 0/20"));
+            }
+
+            disk_space::mock_available_space(None);
+            Ok(())
+        }
+    }
+
+    // Refusing WAL redo for keys that keep failing it
+    mod wal_redo_circuit_breaker {
+        use super::*;
+
+        /// WAL redo manager that fails every request, and counts them. The
+        /// failures are I/O errors of the redo process if 'transient' is set,
+        /// invalid records otherwise.
+        #[derive(Default)]
+        struct FailingRedoManager {
+            requests: AtomicUsize,
+            transient: bool,
+        }
+
+        impl WalRedoManager for FailingRedoManager {
+            fn request_redo(
+                &self,
+                _key: Key,
+                _lsn: Lsn,
+                _base_img: Option<Bytes>,
+                _records: Vec<(Lsn, ZenithWalRecord)>,
+            ) -> Result<Bytes, WalRedoError> {
+                self.requests.fetch_add(1, AtomicOrdering::SeqCst);
+                if self.transient {
+                    Err(WalRedoError::IoError(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "WAL redo process exited",
+                    )))
+                } else {
+                    Err(WalRedoError::InvalidRecord)
+                }
+            }
+        }
+
+        #[test]
+        fn walredo_circuit_breaker() -> Result<()> {
+            let harness = RepoHarness::create_with_conf("walredo_circuit_breaker", |conf| {
+                conf.walredo_failure_threshold = 2;
+                conf.walredo_failure_cooldown = Duration::from_secs(3600);
+            })?;
+            let redo_mgr = Arc::new(FailingRedoManager::default());
+            let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+            let put = |lsn: Lsn| -> Result<()> {
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    &Value::WalRecord(ZenithWalRecord::Postgres {
+                        will_init: true,
+                        rec: Bytes::from_static(b"bad record"),
+                    }),
+                )?;
+                writer.finish_write(lsn)
+            };
+            let is_circuit_open =
+                |err: &anyhow::Error| err.downcast_ref::<WalRedoCircuitOpen>().is_some();
+            let requests = || redo_mgr.requests.load(AtomicOrdering::SeqCst);
+
+            put(Lsn(0x10))?;
+            for _ in 0..2 {
+                let err = tline.get(test_key, Lsn(0x10)).unwrap_err();
+                assert!(!is_circuit_open(&err), "{err:#}");
+            }
+            assert_eq!(requests(), 2);
+            assert_eq!(tline.walredo_circuit_opened_counter.get(), 1);
+
+            // The circuit is open, the WAL redo is not attempted anymore
+            let err = tline.get(test_key, Lsn(0x10)).unwrap_err();
+            assert!(is_circuit_open(&err), "{err:#}");
+            let err = tline
+                .get_multi(&[test_key], Lsn(0x10), false)
+                .pop()
+                .unwrap()
+                .unwrap_err();
+            assert!(is_circuit_open(&err), "{err:#}");
+            assert_eq!(requests(), 2);
+
+            // New WAL for the key resets the circuit
+            put(Lsn(0x20))?;
+            let err = tline.get(test_key, Lsn(0x20)).unwrap_err();
+            assert!(!is_circuit_open(&err), "{err:#}");
+            assert_eq!(requests(), 3);
+
+            Ok(())
+        }
+
+        #[test]
+        fn walredo_circuit_breaker_ignores_io_errors() -> Result<()> {
+            let harness = RepoHarness::create_with_conf(
+                "walredo_circuit_breaker_ignores_io_errors",
+                |conf| {
+                    conf.walredo_failure_threshold = 2;
+                    conf.walredo_failure_cooldown = Duration::from_secs(3600);
+                },
+            )?;
+            let redo_mgr = Arc::new(FailingRedoManager {
+                transient: true,
+                ..Default::default()
+            });
+            let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let test_key = Key::from_hex("012222222233333333444444445500000000")?;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                Lsn(0x10),
+                &Value::WalRecord(ZenithWalRecord::Postgres {
+                    will_init: true,
+                    rec: Bytes::from_static(b"record"),
+                }),
+            )?;
+            writer.finish_write(Lsn(0x10))?;
+            drop(writer);
+
+            // A crashed redo process is not the record's fault, every request is retried
+            for _ in 0..3 {
+                let err = tline.get(test_key, Lsn(0x10)).unwrap_err();
+                assert!(
+                    err.downcast_ref::<WalRedoCircuitOpen>().is_none(),
+                    "{err:#}"
+                );
+            }
+            assert_eq!(redo_mgr.requests.load(AtomicOrdering::SeqCst), 3);
+            assert_eq!(tline.walredo_circuit_opened_counter.get(), 0);
+
+            Ok(())
+        }
+    }
 }
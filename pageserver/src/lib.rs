@@ -2,6 +2,7 @@ pub mod basebackup;
 pub mod config;
 pub mod http;
 pub mod import_datadir;
+pub mod jitter;
 pub mod keyspace;
 pub mod layered_repository;
 pub mod page_cache;
@@ -408,6 +408,36 @@ impl PageCache {
         self.lock_for_read(&mut cache_key)
     }
 
+    /// Immediately drop all materialized page versions of the given timeline
+    /// newer than 'lsn'. Used when the timeline is truncated.
+    pub fn drop_materialized_pages_after(
+        &self,
+        drop_tenant_id: ZTenantId,
+        drop_timeline_id: ZTimelineId,
+        drop_after_lsn: Lsn,
+    ) {
+        for slot_idx in 0..self.slots.len() {
+            let slot = &self.slots[slot_idx];
+
+            let mut inner = slot.inner.write().unwrap();
+            if let Some(key) = &inner.key {
+                match key {
+                    CacheKey::MaterializedPage { hash_key, lsn }
+                        if hash_key.tenant_id == drop_tenant_id
+                            && hash_key.timeline_id == drop_timeline_id
+                            && *lsn > drop_after_lsn =>
+                    {
+                        // remove mapping for old buffer
+                        self.remove_mapping(key);
+                        inner.key = None;
+                        inner.dirty = false;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
     /// Immediately drop all buffers belonging to given file, without writeback
     pub fn drop_buffers_for_immutable(&self, drop_file_id: u64) {
         for slot_idx in 0..self.slots.len() {
@@ -18,6 +18,7 @@ use std::net::TcpListener;
 use std::str;
 use std::str::FromStr;
 use std::sync::{Arc, RwLockReadGuard};
+use std::thread;
 use tracing::*;
 use utils::{
     auth::{self, Claims, JwtAuth, Scope},
@@ -39,6 +40,7 @@ use crate::tenant_mgr;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::CheckpointConfig;
+use crate::TimelineImpl;
 use metrics::{register_histogram_vec, HistogramVec};
 use postgres_ffi::xlog_utils::to_pg_timestamp;
 
@@ -690,8 +692,12 @@ impl PageServerHandler {
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_rel_exists", rel = %req.rel, req_lsn = %req.lsn).entered();
 
-        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &timeline.get_latest_gc_cutoff_lsn(),
+        )?;
 
         let exists = timeline.get_rel_exists(req.rel, lsn)?;
 
@@ -706,8 +712,12 @@ impl PageServerHandler {
         req: &PagestreamNblocksRequest,
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_nblocks", rel = %req.rel, req_lsn = %req.lsn).entered();
-        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &timeline.get_latest_gc_cutoff_lsn(),
+        )?;
 
         let n_blocks = timeline.get_rel_size(req.rel, lsn)?;
 
@@ -722,8 +732,12 @@ impl PageServerHandler {
         req: &PagestreamDbSizeRequest,
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_db_size", dbnode = %req.dbnode, req_lsn = %req.lsn).entered();
-        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &timeline.get_latest_gc_cutoff_lsn(),
+        )?;
 
         let total_blocks =
             timeline.get_db_size(pg_constants::DEFAULTTABLESPACE_OID, req.dbnode, lsn)?;
@@ -735,15 +749,36 @@ impl PageServerHandler {
         }))
     }
 
-    fn handle_get_page_at_lsn_request<T: DatadirTimeline>(
+    fn handle_get_page_at_lsn_request(
         &self,
-        timeline: &T,
+        timeline: &TimelineImpl,
         req: &PagestreamGetPageRequest,
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_page", rel = %req.rel, blkno = &req.blkno, req_lsn = %req.lsn)
             .entered();
-        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+
+        // Each GetPage request takes one token from the tenant's rate limit.
+        // While the tenant is over it, hold back the request. The compute
+        // waits for the response, which slows it down to the rate allowed.
+        let rate_limits = loop {
+            match timeline.check_get_page_rate_limit() {
+                Ok(rate_limits) => break rate_limits,
+                Err(rate_limited) => {
+                    ensure!(
+                        !thread_mgr::is_shutdown_requested(),
+                        "shutdown requested while the request was rate limited"
+                    );
+                    thread::sleep(rate_limited.retry_after);
+                }
+            }
+        };
+
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &timeline.get_latest_gc_cutoff_lsn(),
+        )?;
         /*
         // Add a 1s delay to some requests. The delayed causes the requests to
         // hit the race condition from github issue #1047 more easily.
@@ -753,6 +788,7 @@ impl PageServerHandler {
         }
         */
         let page = timeline.get_rel_page_at_lsn(req.rel, req.blkno, lsn)?;
+        timeline.charge_get_page_bytes(rate_limits, page.len());
 
         Ok(PagestreamBeMessage::GetPage(PagestreamGetPageResponse {
             page,
@@ -775,10 +811,9 @@ impl PageServerHandler {
         // check that the timeline exists
         let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
             .context("Cannot load local timeline")?;
-        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
         if let Some(lsn) = lsn {
             timeline
-                .check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)
+                .check_lsn_is_in_scope(lsn, &timeline.get_latest_gc_cutoff_lsn())
                 .context("invalid basebackup lsn")?;
         }
 
@@ -1042,16 +1077,32 @@ impl postgres_backend::Handler for PageServerHandler {
             ensure!(params.len() == 1, "invalid param number for config command");
             let tenantid = ZTenantId::from_str(params[0])?;
             let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
+            let maintenance_window = repo.get_maintenance_window().map(|w| w.to_string());
+            let rate_limits = repo.get_rate_limits();
             pgb.write_message_noflush(&BeMessage::RowDescription(&[
                 RowDescriptor::int8_col(b"checkpoint_distance"),
                 RowDescriptor::int8_col(b"checkpoint_timeout"),
+                RowDescriptor::int8_col(b"checkpoint_max_entries"),
                 RowDescriptor::int8_col(b"compaction_target_size"),
+                RowDescriptor::int8_col(b"compaction_target_file_size"),
+                RowDescriptor::int8_col(b"compaction_target_file_size_relation"),
+                RowDescriptor::int8_col(b"compaction_target_file_size_slru"),
+                RowDescriptor::int8_col(b"compaction_target_file_size_metadata"),
                 RowDescriptor::int8_col(b"compaction_period"),
                 RowDescriptor::int8_col(b"compaction_threshold"),
                 RowDescriptor::int8_col(b"gc_horizon"),
                 RowDescriptor::int8_col(b"gc_period"),
                 RowDescriptor::int8_col(b"image_creation_threshold"),
                 RowDescriptor::int8_col(b"pitr_interval"),
+                RowDescriptor::text_col(b"materialized_cache_enabled"),
+                RowDescriptor::text_col(b"maintenance_window"),
+                RowDescriptor::int8_col(b"walredo_max_records_size"),
+                RowDescriptor::int8_col(b"compaction_concurrency"),
+                RowDescriptor::int8_col(b"compaction_max_input_layers"),
+                RowDescriptor::text_col(b"compaction_strategy"),
+                RowDescriptor::int8_col(b"compaction_min_benefit"),
+                RowDescriptor::int8_col(b"get_requests_per_second"),
+                RowDescriptor::int8_col(b"get_bytes_per_second"),
             ]))?
             .write_message_noflush(&BeMessage::DataRow(&[
                 Some(repo.get_checkpoint_distance().to_string().as_bytes()),
@@ -1061,7 +1112,28 @@ impl postgres_backend::Handler for PageServerHandler {
                         .to_string()
                         .as_bytes(),
                 ),
+                Some(repo.get_checkpoint_max_entries().to_string().as_bytes()),
                 Some(repo.get_compaction_target_size().to_string().as_bytes()),
+                Some(
+                    repo.get_compaction_target_file_size()
+                        .to_string()
+                        .as_bytes(),
+                ),
+                Some(
+                    repo.get_compaction_target_file_size_relation()
+                        .to_string()
+                        .as_bytes(),
+                ),
+                Some(
+                    repo.get_compaction_target_file_size_slru()
+                        .to_string()
+                        .as_bytes(),
+                ),
+                Some(
+                    repo.get_compaction_target_file_size_metadata()
+                        .to_string()
+                        .as_bytes(),
+                ),
                 Some(
                     repo.get_compaction_period()
                         .as_secs()
@@ -1073,6 +1145,19 @@ impl postgres_backend::Handler for PageServerHandler {
                 Some(repo.get_gc_period().as_secs().to_string().as_bytes()),
                 Some(repo.get_image_creation_threshold().to_string().as_bytes()),
                 Some(repo.get_pitr_interval().as_secs().to_string().as_bytes()),
+                Some(repo.get_materialized_cache_enabled().to_string().as_bytes()),
+                maintenance_window.as_deref().map(str::as_bytes),
+                Some(repo.get_walredo_max_records_size().to_string().as_bytes()),
+                Some(repo.get_compaction_concurrency().to_string().as_bytes()),
+                Some(
+                    repo.get_compaction_max_input_layers()
+                        .to_string()
+                        .as_bytes(),
+                ),
+                Some(repo.get_compaction_strategy().to_string().as_bytes()),
+                Some(repo.get_compaction_min_benefit().to_string().as_bytes()),
+                Some(rate_limits.requests_per_second.to_string().as_bytes()),
+                Some(rate_limits.bytes_per_second.to_string().as_bytes()),
             ]))?
             .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("do_gc ") {
@@ -1138,7 +1223,7 @@ impl postgres_backend::Handler for PageServerHandler {
             let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
             let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
                 .context("Couldn't load timeline")?;
-            timeline.compact()?;
+            timeline.compact_forced()?;
 
             pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
                 .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
@@ -6,13 +6,16 @@
 //! walingest.rs handles a few things like implicit relation creation and extension.
 //! Clarify that)
 //!
-use crate::keyspace::{KeySpace, KeySpaceAccum};
+use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum};
+use crate::layered_repository::KeyNotFoundError;
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::Timeline;
 use crate::repository::*;
 use crate::walrecord::ZenithWalRecord;
 use anyhow::{bail, ensure, Result};
 use bytes::{Buf, Bytes};
+use metrics::{register_int_counter, IntCounter};
+use once_cell::sync::Lazy;
 use postgres_ffi::xlog_utils::TimestampTz;
 use postgres_ffi::{pg_constants, Oid, TransactionId};
 use serde::{Deserialize, Serialize};
@@ -24,6 +27,19 @@ use utils::{bin_ser::BeSer, lsn::Lsn};
 /// Block number within a relation or SLRU. This matches PostgreSQL's BlockNumber type.
 pub type BlockNumber = u32;
 
+/// If more parts of the key space than this have changed since the previous
+/// partitioning, collect_keyspace_incremental() collects the whole key space
+/// instead.
+const MAX_INCREMENTAL_KEYSPACE_SECTIONS: usize = 1000;
+
+pub static ZERO_PAGE_SERVED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_zero_page_served_total",
+        "Number of all-zeros pages returned for blocks that a relation was extended over, but that were never written",
+    )
+    .expect("failed to define a metric")
+});
+
 #[derive(Debug)]
 pub enum LsnForTimestamp {
     Present(Lsn),
@@ -104,7 +120,25 @@ pub trait DatadirTimeline: Timeline {
         }
 
         let key = rel_block_to_key(tag, blknum);
-        self.get(key, lsn)
+        match self.get(key, lsn) {
+            // The relation was extended over the block, but no version of the
+            // page was ever stored. Like PostgreSQL, read it as all-zeros.
+            // If some WAL records were found but no base image to apply them
+            // to, the page is corrupt, so that's still an error.
+            Err(err)
+                if err
+                    .downcast_ref::<KeyNotFoundError>()
+                    .map_or(false, |e| e.num_records == 0) =>
+            {
+                debug!(
+                    "block {} of {} was never written at {}, size is {}: returning all-zeros page",
+                    blknum, tag, lsn, nblocks
+                );
+                ZERO_PAGE_SERVED.inc();
+                Ok(ZERO_PAGE.clone())
+            }
+            res => res,
+        }
     }
 
     // Get size of a database in blocks
@@ -222,8 +256,7 @@ pub trait DatadirTimeline: Timeline {
     /// "in flight" at that point in time.
     ///
     fn find_lsn_for_timestamp(&self, search_timestamp: TimestampTz) -> Result<LsnForTimestamp> {
-        let gc_cutoff_lsn_guard = self.get_latest_gc_cutoff_lsn();
-        let min_lsn = *gc_cutoff_lsn_guard;
+        let min_lsn = *self.get_latest_gc_cutoff_lsn();
         let max_lsn = self.get_last_record_lsn();
 
         // LSNs are always 8-byte aligned. low/mid/high represent the
@@ -389,76 +422,99 @@ pub trait DatadirTimeline: Timeline {
     /// Get a KeySpace that covers all the Keys that are in use at the given LSN.
     /// Anything that's not listed maybe removed from the underlying storage (from
     /// that LSN forwards).
+    ///
+    /// Relations that have no data in any layer, according to
+    /// covered_key_ranges(), are skipped without reading their size.
     fn collect_keyspace(&self, lsn: Lsn) -> Result<KeySpace> {
-        // Iterate through key ranges, greedily packing them into partitions
-        let mut result = KeySpaceAccum::new();
-
-        // The dbdir metadata always exists
-        result.add_key(DBDIR_KEY);
-
-        // Fetch list of database dirs and iterate them
-        let buf = self.get(DBDIR_KEY, lsn)?;
-        let dbdir = DbDirectory::des(&buf)?;
+        let coverage = self.covered_key_ranges();
+        collect_keyspace_with_coverage(self, lsn, coverage.as_deref())
+    }
 
-        let mut dbs: Vec<(Oid, Oid)> = dbdir.dbdirs.keys().cloned().collect();
-        dbs.sort_unstable();
-        for (spcnode, dbnode) in dbs {
-            result.add_key(relmap_file_key(spcnode, dbnode));
-            result.add_key(rel_dir_to_key(spcnode, dbnode));
-
-            let mut rels: Vec<RelTag> = self
-                .list_rels(spcnode, dbnode, lsn)?
-                .iter()
-                .cloned()
-                .collect();
-            rels.sort_unstable();
-            for rel in rels {
-                let relsize_key = rel_size_to_key(rel);
-                let mut buf = self.get(relsize_key, lsn)?;
-                let relsize = buf.get_u32_le();
+    ///
+    /// Like collect_keyspace(), but reuses the key space of the previous
+    /// partitioning, and only collects again the parts of the key space that
+    /// were modified after it was made, according to changed_keys(). Collects
+    /// the whole key space if there is no previous partitioning, or if too
+    /// much has changed since.
+    ///
+    fn collect_keyspace_incremental(
+        &self,
+        lsn: Lsn,
+        prev: &(KeyPartitioning, Lsn),
+    ) -> Result<KeySpace> {
+        let (prev_partitioning, prev_lsn) = prev;
+        if *prev_lsn == Lsn(0) {
+            return self.collect_keyspace(lsn);
+        }
+        let changed_keys = match self.changed_keys(*prev_lsn) {
+            Some(changed_keys) => changed_keys,
+            None => return self.collect_keyspace(lsn),
+        };
 
-                result.add_range(rel_block_to_key(rel, 0)..rel_block_to_key(rel, relsize));
-                result.add_key(relsize_key);
+        let mut sections = HashSet::new();
+        for key in changed_keys {
+            match KeySpaceSection::affected_by(key) {
+                Some(KeySpaceSection::All) => return self.collect_keyspace(lsn),
+                Some(section) => {
+                    sections.insert(section);
+                }
+                None => {}
             }
         }
+        if sections.len() > MAX_INCREMENTAL_KEYSPACE_SECTIONS {
+            debug!(
+                "{} parts of the key space changed since {}, collecting all of it",
+                sections.len(),
+                prev_lsn
+            );
+            return self.collect_keyspace(lsn);
+        }
 
-        // Iterate SLRUs next
-        for kind in [
-            SlruKind::Clog,
-            SlruKind::MultiXactMembers,
-            SlruKind::MultiXactOffsets,
-        ] {
-            let slrudir_key = slru_dir_to_key(kind);
-            result.add_key(slrudir_key);
-            let buf = self.get(slrudir_key, lsn)?;
-            let dir = SlruSegmentDirectory::des(&buf)?;
-            let mut segments: Vec<u32> = dir.segments.iter().cloned().collect();
-            segments.sort_unstable();
-            for segno in segments {
-                let segsize_key = slru_segment_size_to_key(kind, segno);
-                let mut buf = self.get(segsize_key, lsn)?;
-                let segsize = buf.get_u32_le();
-
-                result.add_range(
-                    slru_block_to_key(kind, segno, 0)..slru_block_to_key(kind, segno, segsize),
-                );
-                result.add_key(segsize_key);
+        // Relations are collected with their database, if that changed too.
+        // That leaves sections that don't overlap.
+        let changed_dbs: HashSet<(Oid, Oid)> = sections
+            .iter()
+            .filter_map(|section| match section {
+                KeySpaceSection::Database(spcnode, dbnode) => Some((*spcnode, *dbnode)),
+                _ => None,
+            })
+            .collect();
+        let mut sections: Vec<KeySpaceSection> = sections
+            .into_iter()
+            .filter(|section| match section {
+                KeySpaceSection::Relation(rel) => !changed_dbs.contains(&(rel.spcnode, rel.dbnode)),
+                _ => true,
+            })
+            .collect();
+        sections.sort_unstable_by_key(|section| section.key_range().start);
+
+        let changed = KeySpace {
+            ranges: sections.iter().map(KeySpaceSection::key_range).collect(),
+        };
+        let coverage = self.covered_key_ranges();
+        let coverage = coverage.as_deref();
+        let mut ranges = prev_partitioning.keyspace().subtract(&changed).ranges;
+        for section in sections {
+            let mut accum = KeySpaceAccum::new();
+            match section {
+                KeySpaceSection::All => unreachable!("handled above"),
+                KeySpaceSection::Database(spcnode, dbnode) => {
+                    collect_db_keyspace(self, spcnode, dbnode, lsn, coverage, &mut accum)?
+                }
+                KeySpaceSection::Relation(rel) => {
+                    collect_rel_keyspace(self, rel, lsn, coverage, &mut accum)?
+                }
+                KeySpaceSection::Slru(kind) => collect_slru_keyspace(self, kind, lsn, &mut accum)?,
+                KeySpaceSection::TwoPhase => collect_twophase_keyspace(self, lsn, &mut accum)?,
             }
+            ranges.extend(accum.to_keyspace().ranges);
         }
 
-        // Then pg_twophase
-        result.add_key(TWOPHASEDIR_KEY);
-        let buf = self.get(TWOPHASEDIR_KEY, lsn)?;
-        let twophase_dir = TwoPhaseDirectory::des(&buf)?;
-        let mut xids: Vec<TransactionId> = twophase_dir.xids.iter().cloned().collect();
-        xids.sort_unstable();
-        for xid in xids {
-            result.add_key(twophase_file_key(xid));
+        ranges.sort_unstable_by_key(|range| range.start);
+        let mut result = KeySpaceAccum::new();
+        for range in ranges {
+            result.add_range(range);
         }
-
-        result.add_key(CONTROLFILE_KEY);
-        result.add_key(CHECKPOINT_KEY);
-
         Ok(result.to_keyspace())
     }
 
@@ -473,6 +529,159 @@ pub trait DatadirTimeline: Timeline {
 
     /// Remove cached relation size
     fn remove_cached_rel_size(&self, tag: &RelTag);
+
+    /// Remember that 'key', which defines the shape of the key space, was
+    /// modified at 'lsn'.
+    fn record_keyspace_change(&self, key: Key, lsn: Lsn);
+
+    /// Keys recorded with record_keyspace_change() after 'lsn'. Returns None
+    /// if they are not known that far back.
+    fn changed_keys(&self, lsn: Lsn) -> Option<Vec<Key>>;
+
+    /// Sorted, non-overlapping key ranges outside of which the timeline and
+    /// its ancestors have no data at any LSN. The ranges are coarse: they
+    /// can include keys that have no data. Returns None if not known.
+    fn covered_key_ranges(&self) -> Option<Vec<Range<Key>>>;
+}
+
+//
+// Helper functions for collect_keyspace() and collect_keyspace_incremental()
+//
+
+///
+/// Collect the key space like collect_keyspace(), with the given key
+/// coverage. With 'coverage' None, the size of every relation is read.
+///
+pub fn collect_keyspace_with_coverage<T: DatadirTimeline + ?Sized>(
+    tline: &T,
+    lsn: Lsn,
+    coverage: Option<&[Range<Key>]>,
+) -> Result<KeySpace> {
+    // Iterate through key ranges, greedily packing them into partitions
+    let mut result = KeySpaceAccum::new();
+
+    // The dbdir metadata always exists
+    result.add_key(DBDIR_KEY);
+
+    // Fetch list of database dirs and iterate them
+    let buf = tline.get(DBDIR_KEY, lsn)?;
+    let dbdir = DbDirectory::des(&buf)?;
+
+    let mut dbs: Vec<(Oid, Oid)> = dbdir.dbdirs.keys().cloned().collect();
+    dbs.sort_unstable();
+    for (spcnode, dbnode) in dbs {
+        collect_db_keyspace(tline, spcnode, dbnode, lsn, coverage, &mut result)?;
+    }
+
+    // Iterate SLRUs next
+    for kind in [
+        SlruKind::Clog,
+        SlruKind::MultiXactMembers,
+        SlruKind::MultiXactOffsets,
+    ] {
+        collect_slru_keyspace(tline, kind, lsn, &mut result)?;
+    }
+
+    // Then pg_twophase
+    collect_twophase_keyspace(tline, lsn, &mut result)?;
+
+    result.add_key(CONTROLFILE_KEY);
+    result.add_key(CHECKPOINT_KEY);
+
+    Ok(result.to_keyspace())
+}
+
+/// Does 'range' overlap any of the sorted, non-overlapping 'coverage' ranges?
+fn is_covered(coverage: &[Range<Key>], range: &Range<Key>) -> bool {
+    let idx = coverage.partition_point(|covered| covered.end <= range.start);
+    coverage
+        .get(idx)
+        .map_or(false, |covered| covered.start < range.end)
+}
+
+fn collect_db_keyspace<T: DatadirTimeline + ?Sized>(
+    tline: &T,
+    spcnode: Oid,
+    dbnode: Oid,
+    lsn: Lsn,
+    coverage: Option<&[Range<Key>]>,
+    result: &mut KeySpaceAccum,
+) -> Result<()> {
+    result.add_key(relmap_file_key(spcnode, dbnode));
+    result.add_key(rel_dir_to_key(spcnode, dbnode));
+
+    let mut rels: Vec<RelTag> = tline
+        .list_rels(spcnode, dbnode, lsn)?
+        .iter()
+        .cloned()
+        .collect();
+    rels.sort_unstable();
+    for rel in rels {
+        collect_rel_keyspace(tline, rel, lsn, coverage, result)?;
+    }
+    Ok(())
+}
+
+fn collect_rel_keyspace<T: DatadirTimeline + ?Sized>(
+    tline: &T,
+    rel: RelTag,
+    lsn: Lsn,
+    coverage: Option<&[Range<Key>]>,
+    result: &mut KeySpaceAccum,
+) -> Result<()> {
+    if let Some(coverage) = coverage {
+        if !is_covered(coverage, &rel_key_range(rel)) {
+            trace!("relation {rel} has no data in any layer, skipping it");
+            return Ok(());
+        }
+    }
+    let relsize_key = rel_size_to_key(rel);
+    let mut buf = tline.get(relsize_key, lsn)?;
+    let relsize = buf.get_u32_le();
+
+    result.add_range(rel_block_to_key(rel, 0)..rel_block_to_key(rel, relsize));
+    result.add_key(relsize_key);
+    Ok(())
+}
+
+fn collect_slru_keyspace<T: DatadirTimeline + ?Sized>(
+    tline: &T,
+    kind: SlruKind,
+    lsn: Lsn,
+    result: &mut KeySpaceAccum,
+) -> Result<()> {
+    let slrudir_key = slru_dir_to_key(kind);
+    result.add_key(slrudir_key);
+    let buf = tline.get(slrudir_key, lsn)?;
+    let dir = SlruSegmentDirectory::des(&buf)?;
+    let mut segments: Vec<u32> = dir.segments.iter().cloned().collect();
+    segments.sort_unstable();
+    for segno in segments {
+        let segsize_key = slru_segment_size_to_key(kind, segno);
+        let mut buf = tline.get(segsize_key, lsn)?;
+        let segsize = buf.get_u32_le();
+
+        result
+            .add_range(slru_block_to_key(kind, segno, 0)..slru_block_to_key(kind, segno, segsize));
+        result.add_key(segsize_key);
+    }
+    Ok(())
+}
+
+fn collect_twophase_keyspace<T: DatadirTimeline + ?Sized>(
+    tline: &T,
+    lsn: Lsn,
+    result: &mut KeySpaceAccum,
+) -> Result<()> {
+    result.add_key(TWOPHASEDIR_KEY);
+    let buf = tline.get(TWOPHASEDIR_KEY, lsn)?;
+    let twophase_dir = TwoPhaseDirectory::des(&buf)?;
+    let mut xids: Vec<TransactionId> = twophase_dir.xids.iter().cloned().collect();
+    xids.sort_unstable();
+    for xid in xids {
+        result.add_key(twophase_file_key(xid));
+    }
+    Ok(())
 }
 
 /// DatadirModification represents an operation to ingest an atomic set of
@@ -930,14 +1139,19 @@ impl<'a, T: DatadirTimeline> DatadirModification<'a, T> {
         let pending_nblocks = self.pending_nblocks;
         self.pending_nblocks = 0;
 
+        // Stage the writes, so that they become visible all at once
+        writer.begin_batch()?;
         for (key, value) in self.pending_updates.drain() {
+            if KeySpaceSection::affected_by(key).is_some() {
+                self.tline.record_keyspace_change(key, lsn);
+            }
             writer.put(key, lsn, &value)?;
         }
         for key_range in self.pending_deletions.drain(..) {
             writer.delete(key_range, lsn)?;
         }
 
-        writer.finish_write(lsn);
+        writer.commit_batch(lsn)?;
 
         if pending_nblocks != 0 {
             writer.update_current_logical_size(pending_nblocks * pg_constants::BLCKSZ as isize);
@@ -1165,7 +1379,8 @@ fn rel_size_to_key(rel: RelTag) -> Key {
     }
 }
 
-fn rel_key_range(rel: RelTag) -> Range<Key> {
+/// The range of keys that holds all the blocks, and the size, of relation fork 'rel'.
+pub fn rel_key_range(rel: RelTag) -> Range<Key> {
     Key {
         field1: 0x00,
         field2: rel.spcnode,
@@ -1315,6 +1530,108 @@ const CHECKPOINT_KEY: Key = Key {
     field6: 1,
 };
 
+///
+/// Kinds of keys with different access patterns, which compaction keeps in
+/// separate layers.
+///
+/// The categories follow the sections of the key space above. The relation
+/// sizes and directories are stored next to the relation data, and belong to
+/// the Relation category with it.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCategory {
+    /// Section 00: relation data and metadata
+    Relation,
+    /// Section 01: SLRU segments
+    Slru,
+    /// Everything else: two-phase files, control file and checkpoint
+    Metadata,
+}
+
+impl KeyCategory {
+    pub const ALL: [KeyCategory; 3] = [
+        KeyCategory::Relation,
+        KeyCategory::Slru,
+        KeyCategory::Metadata,
+    ];
+
+    pub fn of(key: Key) -> KeyCategory {
+        match key.field1 {
+            0x00 => KeyCategory::Relation,
+            0x01 => KeyCategory::Slru,
+            _ => KeyCategory::Metadata,
+        }
+    }
+}
+
+///
+/// A part of the key space that collect_keyspace_incremental() can collect
+/// separately from the rest. Each part is defined by a few metadata keys, see
+/// KeySpaceSection::affected_by().
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum KeySpaceSection {
+    /// The list of databases changed, so everything needs to be collected
+    All,
+    /// The list of relations in a database
+    Database(Oid, Oid),
+    /// The size of a relation
+    Relation(RelTag),
+    /// The list or the sizes of the segments of an SLRU
+    Slru(SlruKind),
+    /// The list of two-phase files
+    TwoPhase,
+}
+
+impl KeySpaceSection {
+    /// Which part of the key space does a modification of 'key' affect, if any?
+    fn affected_by(key: Key) -> Option<Self> {
+        match key.field1 {
+            0x00 if key == DBDIR_KEY => Some(KeySpaceSection::All),
+            0x00 if key == rel_dir_to_key(key.field2, key.field3) => {
+                Some(KeySpaceSection::Database(key.field2, key.field3))
+            }
+            0x00 if key.field4 != 0 && key.field6 == 0xffffffff => {
+                Some(KeySpaceSection::Relation(RelTag {
+                    spcnode: key.field2,
+                    dbnode: key.field3,
+                    relnode: key.field4,
+                    forknum: key.field5,
+                }))
+            }
+            // SlruDir or SlruSegSize
+            0x01 if key.field3 == 0 || key.field6 == 0xffffffff => {
+                let kind = match key.field2 {
+                    0x00 => SlruKind::Clog,
+                    0x01 => SlruKind::MultiXactMembers,
+                    0x02 => SlruKind::MultiXactOffsets,
+                    _ => return None,
+                };
+                Some(KeySpaceSection::Slru(kind))
+            }
+            0x02 if key == TWOPHASEDIR_KEY => Some(KeySpaceSection::TwoPhase),
+            _ => None,
+        }
+    }
+
+    /// The range of keys that this part of the key space can contain.
+    fn key_range(&self) -> Range<Key> {
+        match self {
+            KeySpaceSection::All => Key::MIN..Key::MAX,
+            KeySpaceSection::Database(spcnode, dbnode) => dbdir_key_range(*spcnode, *dbnode),
+            KeySpaceSection::Relation(rel) => rel_key_range(*rel),
+            KeySpaceSection::Slru(kind) => {
+                let start = slru_dir_to_key(*kind);
+                start..Key {
+                    field2: start.field2 + 1,
+                    ..start
+                }
+            }
+            KeySpaceSection::TwoPhase => TWOPHASEDIR_KEY..CONTROLFILE_KEY,
+        }
+    }
+}
+
 // Reverse mappings for a few Keys.
 // These are needed by WAL redo manager.
 
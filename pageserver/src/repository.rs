@@ -318,6 +318,16 @@ pub trait Timeline: Send + Sync {
     ///
     fn wait_lsn(&self, lsn: Lsn) -> Result<()>;
 
+    ///
+    /// Wait until all WAL up to this LSN has been processed and stored durably
+    /// on local disk, i.e. until disk_consistent_lsn reaches it. Unlike
+    /// wait_lsn(), this doesn't make the data flushed, see checkpoint().
+    ///
+    /// Fails if 'timeout' expires first, or if the thread is requested to
+    /// shut down while waiting.
+    ///
+    fn wait_flush_lsn(&self, lsn: Lsn, timeout: Duration) -> Result<()>;
+
     /// Lock and get timeline's GC cuttof
     fn get_latest_gc_cutoff_lsn(&self) -> RwLockReadGuard<Lsn>;
 
@@ -391,6 +401,13 @@ pub trait TimelineWriter<'a> {
     /// current end-of-file.
     fn put(&self, key: Key, lsn: Lsn, value: &Value) -> Result<()>;
 
+    /// Put several page versions at once, like put() for each of them.
+    ///
+    /// This is cheaper than separate put() calls, because the layer to write
+    /// to is looked up only once. All the LSNs must be greater than the
+    /// current last record LSN, or nothing is written.
+    fn put_batch(&self, values: &[(Key, Lsn, Value)]) -> Result<()>;
+
     fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()>;
 
     /// Track the end of the latest digested WAL record.
@@ -400,7 +417,25 @@ pub trait TimelineWriter<'a> {
     /// 'lsn' must be aligned. This wakes up any wait_lsn() callers waiting for
     /// the 'lsn' or anything older. The previous last record LSN is stored alongside
     /// the latest and can be read.
-    fn finish_write(&self, lsn: Lsn);
+    ///
+    /// 'lsn' must be greater than the current last record LSN. Returns an
+    /// error otherwise, without changing anything.
+    fn finish_write(&self, lsn: Lsn) -> Result<()>;
+
+    /// Start a batch of writes that are applied atomically.
+    ///
+    /// The puts and deletes until the next commit_batch() are only staged in
+    /// the writer. If the writer is dropped before the batch is committed, e.g.
+    /// because the WAL receiver failed in the middle of a transaction, the
+    /// staged writes are discarded and none of them ever becomes visible.
+    fn begin_batch(&self) -> Result<()>;
+
+    /// Apply the writes staged since begin_batch(), and advance the last record
+    /// LSN to 'lsn', like finish_write().
+    ///
+    /// 'lsn' is checked before anything is applied, so if it doesn't advance the
+    /// last record LSN, the whole batch is rejected.
+    fn commit_batch(&self, lsn: Lsn) -> Result<()>;
 
     fn update_current_logical_size(&self, delta: isize);
 }
@@ -446,7 +481,14 @@ pub mod repo_harness {
             Self {
                 checkpoint_distance: Some(tenant_conf.checkpoint_distance),
                 checkpoint_timeout: Some(tenant_conf.checkpoint_timeout),
+                checkpoint_max_entries: Some(tenant_conf.checkpoint_max_entries),
                 compaction_target_size: Some(tenant_conf.compaction_target_size),
+                compaction_target_file_size: tenant_conf.compaction_target_file_size,
+                compaction_target_file_size_relation: tenant_conf
+                    .compaction_target_file_size_relation,
+                compaction_target_file_size_slru: tenant_conf.compaction_target_file_size_slru,
+                compaction_target_file_size_metadata: tenant_conf
+                    .compaction_target_file_size_metadata,
                 compaction_period: Some(tenant_conf.compaction_period),
                 compaction_threshold: Some(tenant_conf.compaction_threshold),
                 gc_horizon: Some(tenant_conf.gc_horizon),
@@ -456,6 +498,15 @@ pub mod repo_harness {
                 walreceiver_connect_timeout: Some(tenant_conf.walreceiver_connect_timeout),
                 lagging_wal_timeout: Some(tenant_conf.lagging_wal_timeout),
                 max_lsn_wal_lag: Some(tenant_conf.max_lsn_wal_lag),
+                materialized_cache_enabled: Some(tenant_conf.materialized_cache_enabled),
+                maintenance_window: tenant_conf.maintenance_window,
+                walredo_max_records_size: Some(tenant_conf.walredo_max_records_size),
+                compaction_concurrency: Some(tenant_conf.compaction_concurrency),
+                compaction_max_input_layers: Some(tenant_conf.compaction_max_input_layers),
+                compaction_strategy: Some(tenant_conf.compaction_strategy),
+                compaction_min_benefit: Some(tenant_conf.compaction_min_benefit),
+                get_requests_per_second: Some(tenant_conf.get_requests_per_second),
+                get_bytes_per_second: Some(tenant_conf.get_bytes_per_second),
             }
         }
     }
@@ -473,12 +524,34 @@ pub mod repo_harness {
 
     impl<'a> RepoHarness<'a> {
         pub fn create(test_name: &'static str) -> Result<Self> {
-            Self::create_internal(test_name, false)
+            Self::create_internal(test_name, false, ZTenantId::generate())
         }
         pub fn create_exclusive(test_name: &'static str) -> Result<Self> {
-            Self::create_internal(test_name, true)
+            Self::create_internal(test_name, true, ZTenantId::generate())
+        }
+        /// Like create(), for a given tenant, e.g. to load layer files that
+        /// were written by another harness.
+        pub fn create_with_tenant_id(
+            test_name: &'static str,
+            tenant_id: ZTenantId,
+        ) -> Result<Self> {
+            Self::create_internal(test_name, false, tenant_id)
         }
-        fn create_internal(test_name: &'static str, exclusive: bool) -> Result<Self> {
+        /// Like create(), with the page server config changed by 'update_conf'
+        /// from the test defaults.
+        pub fn create_with_conf(
+            test_name: &'static str,
+            update_conf: impl FnOnce(&mut PageServerConf),
+        ) -> Result<Self> {
+            let mut harness = Self::create(test_name)?;
+            harness.update_conf(update_conf);
+            Ok(harness)
+        }
+        fn create_internal(
+            test_name: &'static str,
+            exclusive: bool,
+            tenant_id: ZTenantId,
+        ) -> Result<Self> {
             let lock_guard = if exclusive {
                 (None, Some(LOCK.write().unwrap()))
             } else {
@@ -496,7 +569,6 @@ pub mod repo_harness {
 
             let tenant_conf = TenantConf::dummy_conf();
 
-            let tenant_id = ZTenantId::generate();
             fs::create_dir_all(conf.tenant_path(&tenant_id))?;
             fs::create_dir_all(conf.timelines_path(&tenant_id))?;
 
@@ -508,13 +580,26 @@ pub mod repo_harness {
             })
         }
 
+        /// Change the page server config, for the repositories loaded after this.
+        pub fn update_conf(&mut self, update_conf: impl FnOnce(&mut PageServerConf)) {
+            let mut conf = self.conf.clone();
+            update_conf(&mut conf);
+            // Like in create_internal(), leaking the config is OK in a test.
+            self.conf = Box::leak(Box::new(conf));
+        }
+
         pub fn load(&self) -> RepositoryImpl {
             self.try_load().expect("failed to load test repo")
         }
 
         pub fn try_load(&self) -> Result<RepositoryImpl> {
-            let walredo_mgr = Arc::new(TestRedoManager);
+            self.try_load_with_redo_manager(Arc::new(TestRedoManager))
+        }
 
+        pub fn try_load_with_redo_manager(
+            &self,
+            walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
+        ) -> Result<RepositoryImpl> {
             let repo = LayeredRepository::new(
                 self.conf,
                 TenantConfOpt::from(self.tenant_conf),
@@ -600,12 +685,12 @@ mod tests {
 
         let writer = tline.writer();
         writer.put(*TEST_KEY, Lsn(0x10), &Value::Image(TEST_IMG("foo at 0x10")))?;
-        writer.finish_write(Lsn(0x10));
+        writer.finish_write(Lsn(0x10))?;
         drop(writer);
 
         let writer = tline.writer();
         writer.put(*TEST_KEY, Lsn(0x20), &Value::Image(TEST_IMG("foo at 0x20")))?;
-        writer.finish_write(Lsn(0x20));
+        writer.finish_write(Lsn(0x20))?;
         drop(writer);
 
         assert_eq!(tline.get(*TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
@@ -615,6 +700,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn finish_write_rejects_backward_lsn() -> Result<()> {
+        let repo = RepoHarness::create("finish_write_rejects_backward_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let writer = tline.writer();
+        writer.put(*TEST_KEY, Lsn(0x20), &Value::Image(TEST_IMG("foo at 0x20")))?;
+        writer.finish_write(Lsn(0x20))?;
+
+        let err = writer
+            .finish_write(Lsn(0x10))
+            .expect_err("moving last_record_lsn backwards should fail");
+        let msg = err.to_string();
+        assert!(msg.contains("0/10") && msg.contains("0/20"), "{msg}");
+        writer
+            .finish_write(Lsn(0x20))
+            .expect_err("advancing last_record_lsn to the same LSN should fail");
+        drop(writer);
+
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x20));
+        assert_eq!(tline.get(*TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x20"));
+
+        Ok(())
+    }
+
     #[test]
     fn no_duplicate_timelines() -> Result<()> {
         let repo = RepoHarness::create("no_duplicate_timelines")?.load();
@@ -653,12 +763,12 @@ mod tests {
         // Insert a value on the timeline
         writer.put(TEST_KEY_A, Lsn(0x20), &test_value("foo at 0x20"))?;
         writer.put(TEST_KEY_B, Lsn(0x20), &test_value("foobar at 0x20"))?;
-        writer.finish_write(Lsn(0x20));
+        writer.finish_write(Lsn(0x20))?;
 
         writer.put(TEST_KEY_A, Lsn(0x30), &test_value("foo at 0x30"))?;
-        writer.finish_write(Lsn(0x30));
+        writer.finish_write(Lsn(0x30))?;
         writer.put(TEST_KEY_A, Lsn(0x40), &test_value("foo at 0x40"))?;
-        writer.finish_write(Lsn(0x40));
+        writer.finish_write(Lsn(0x40))?;
 
         //assert_current_logical_size(&tline, Lsn(0x40));
 
@@ -669,7 +779,7 @@ mod tests {
             .expect("Should have a local timeline");
         let new_writer = newtline.writer();
         new_writer.put(TEST_KEY_A, Lsn(0x40), &test_value("bar at 0x40"))?;
-        new_writer.finish_write(Lsn(0x40));
+        new_writer.finish_write(Lsn(0x40))?;
 
         // Check page contents on both branches
         assert_eq!(
@@ -701,14 +811,14 @@ mod tests {
                 lsn,
                 &Value::Image(TEST_IMG(&format!("foo at {}", lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
             lsn += 0x10;
             writer.put(
                 *TEST_KEY,
                 lsn,
                 &Value::Image(TEST_IMG(&format!("foo at {}", lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
             lsn += 0x10;
         }
         tline.checkpoint(CheckpointConfig::Forced)?;
@@ -719,14 +829,14 @@ mod tests {
                 lsn,
                 &Value::Image(TEST_IMG(&format!("foo at {}", lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
             lsn += 0x10;
             writer.put(
                 *TEST_KEY,
                 lsn,
                 &Value::Image(TEST_IMG(&format!("foo at {}", lsn))),
             )?;
-            writer.finish_write(lsn);
+            writer.finish_write(lsn)?;
         }
         tline.checkpoint(CheckpointConfig::Forced)
     }
@@ -774,7 +884,7 @@ mod tests {
                     .source()
                     .unwrap()
                     .to_string()
-                    .contains("is earlier than latest GC horizon"));
+                    .contains("is earlier than the initdb LSN"));
             }
         }
 
@@ -175,7 +175,9 @@ use crate::{
     exponential_backoff,
     layered_repository::{
         ephemeral_file::is_ephemeral_file,
+        load_metadata,
         metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME},
+        PREV_RECORD_LSNS_FILE_NAME,
     },
     storage_sync::{self, index::RemoteIndex},
     tenant_mgr::attach_downloaded_tenants,
@@ -363,7 +365,7 @@ fn collect_timelines_for_tenant(
         match timelines_dir_entry {
             Ok(timelines_dir_entry) => {
                 let timeline_path = timelines_dir_entry.path();
-                match collect_timeline_files(&timeline_path) {
+                match collect_timeline_files(config, tenant_id, &timeline_path) {
                     Ok((timeline_id, metadata, timeline_files)) => {
                         timelines.insert(
                             ZTenantTimelineId {
@@ -393,10 +395,11 @@ fn collect_timelines_for_tenant(
 // discover timeline files and extract timeline metadata
 //  NOTE: ephemeral files are excluded from the list
 fn collect_timeline_files(
+    config: &'static PageServerConf,
+    tenant_id: ZTenantId,
     timeline_dir: &Path,
 ) -> anyhow::Result<(ZTimelineId, TimelineMetadata, HashSet<PathBuf>)> {
     let mut timeline_files = HashSet::new();
-    let mut timeline_metadata_path = None;
 
     let timeline_id = timeline_dir
         .file_name()
@@ -409,8 +412,11 @@ fn collect_timeline_files(
     for entry in timeline_dir_entries {
         let entry_path = entry.context("Failed to list timeline dir entry")?.path();
         if entry_path.is_file() {
-            if entry_path.file_name().and_then(OsStr::to_str) == Some(METADATA_FILE_NAME) {
-                timeline_metadata_path = Some(entry_path);
+            let file_name = entry_path.file_name().and_then(OsStr::to_str);
+            if file_name == Some(METADATA_FILE_NAME)
+                || file_name == Some(PREV_RECORD_LSNS_FILE_NAME)
+            {
+                continue;
             } else if is_ephemeral_file(&entry_path.file_name().unwrap().to_string_lossy()) {
                 debug!("skipping ephemeral file {}", entry_path.display());
                 continue;
@@ -445,14 +451,7 @@ fn collect_timeline_files(
     //   be aware of that and retry attach if awaits_download for timeline switched from true to false
     //   but timelinne didn't appear locally.
     //   Check what happens with remote index in that case.
-    let timeline_metadata_path = match timeline_metadata_path {
-        Some(path) => path,
-        None => bail!("No metadata file found in the timeline directory"),
-    };
-    let metadata = TimelineMetadata::from_bytes(
-        &std::fs::read(&timeline_metadata_path).context("Failed to read timeline metadata file")?,
-    )
-    .context("Failed to parse timeline metadata file bytes")?;
+    let metadata = load_metadata(config, timeline_id, tenant_id)?;
 
     Ok((timeline_id, metadata, timeline_files))
 }
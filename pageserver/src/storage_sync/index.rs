@@ -125,6 +125,12 @@ impl RemoteIndex {
     pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<'_, RemoteTimelineIndex> {
         self.0.write().await
     }
+
+    /// Synchronous counterpart of [`RemoteIndex::read`], for the blocking pageserver threads.
+    /// Panics if called from within an async runtime context.
+    pub fn blocking_read(&self) -> tokio::sync::RwLockReadGuard<'_, RemoteTimelineIndex> {
+        self.0.blocking_read()
+    }
 }
 
 impl Clone for RemoteIndex {
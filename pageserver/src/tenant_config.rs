@@ -9,10 +9,13 @@
 //! may lead to a data loss.
 //!
 use crate::config::PageServerConf;
+use anyhow::{bail, ensure, Context};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::num::NonZeroU64;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use utils::zid::ZTenantId;
 
 pub const TENANT_CONFIG_NAME: &str = "config";
@@ -24,6 +27,7 @@ pub mod defaults {
     // This parameter actually determines L0 layer file size.
     pub const DEFAULT_CHECKPOINT_DISTANCE: u64 = 256 * 1024 * 1024;
     pub const DEFAULT_CHECKPOINT_TIMEOUT: &str = "10 m";
+    pub const DEFAULT_CHECKPOINT_MAX_ENTRIES: usize = 10_000_000;
 
     // Target file size, when creating image and delta layers.
     // This parameter determines L1 layer file size.
@@ -39,6 +43,14 @@ pub mod defaults {
     pub const DEFAULT_WALRECEIVER_CONNECT_TIMEOUT: &str = "2 seconds";
     pub const DEFAULT_WALRECEIVER_LAGGING_WAL_TIMEOUT: &str = "3 seconds";
     pub const DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG: u64 = 10 * 1024 * 1024;
+    pub const DEFAULT_MATERIALIZED_CACHE_ENABLED: bool = true;
+    pub const DEFAULT_WALREDO_MAX_RECORDS_SIZE: u64 = 64 * 1024 * 1024;
+    pub const DEFAULT_COMPACTION_CONCURRENCY: usize = 4;
+    pub const DEFAULT_COMPACTION_MAX_INPUT_LAYERS: usize = 0;
+    pub const DEFAULT_COMPACTION_STRATEGY: &str = "KeySplit";
+    pub const DEFAULT_COMPACTION_MIN_BENEFIT: u64 = 0;
+    pub const DEFAULT_GET_REQUESTS_PER_SECOND: u64 = 0;
+    pub const DEFAULT_GET_BYTES_PER_SECOND: u64 = 0;
 }
 
 /// Per-tenant configuration options
@@ -52,9 +64,24 @@ pub struct TenantConf {
     // Inmemory layer is also flushed at least once in checkpoint_timeout to
     // eventually upload WAL after activity is stopped.
     pub checkpoint_timeout: Duration,
+    // Inmemory layer is also flushed when it holds more page versions than
+    // this. Bounds the memory used by the layer's index, when the WAL touches
+    // many distinct keys with little data each.
+    pub checkpoint_max_entries: usize,
     // Target file size, when creating image and delta layers.
     // This parameter determines L1 layer file size.
     pub compaction_target_size: u64,
+    // Target file size of the L1 delta layers created by compacting L0 layers.
+    // If not set, checkpoint_distance is used, which is what L0 layer files are
+    // sized by.
+    pub compaction_target_file_size: Option<u64>,
+    // Target file sizes of the L1 delta layers holding relation data, SLRU
+    // data, and other metadata respectively. Compaction doesn't mix keys of
+    // different categories in one layer. If not set, compaction_target_file_size
+    // is used.
+    pub compaction_target_file_size_relation: Option<u64>,
+    pub compaction_target_file_size_slru: Option<u64>,
+    pub compaction_target_file_size_metadata: Option<u64>,
     // How often to check if there's compaction work to be done.
     #[serde(with = "humantime_serde")]
     pub compaction_period: Duration,
@@ -87,6 +114,36 @@ pub struct TenantConf {
     /// A lagging safekeeper will be changed after `lagging_wal_timeout` time elapses since the last WAL update,
     /// to avoid eager reconnects.
     pub max_lsn_wal_lag: NonZeroU64,
+    /// Whether pages reconstructed by WAL redo are stored in, and looked up from,
+    /// the materialized page cache. Scan-heavy tenants that rarely read the same
+    /// page twice can turn it off, to leave the shared cache to other tenants.
+    pub materialized_cache_enabled: bool,
+    /// If set, background compaction and GC only run within this daily time
+    /// window. Flushing of in-memory layers is not affected.
+    pub maintenance_window: Option<MaintenanceWindow>,
+    /// Maximum total size of the WAL records sent to the WAL redo process in
+    /// one request. Longer chains of records are replayed in several steps,
+    /// which bounds the memory used by the WAL redo process.
+    pub walredo_max_records_size: u64,
+    /// Max number of timelines of the tenant that run compaction at the same
+    /// time. Timelines that find the limit reached skip their compaction round.
+    pub compaction_concurrency: usize,
+    /// Max number of level 0 delta layers merged in one compaction pass. A
+    /// longer backlog is compacted in several passes, which bounds the time
+    /// and memory of each one. 0 means no limit.
+    pub compaction_max_input_layers: usize,
+    /// How level 0 delta layers are merged by compaction, see
+    /// [`CompactionStrategy`].
+    pub compaction_strategy: CompactionStrategy,
+    /// Min number of layer visits that merging the level 0 delta layers must
+    /// save for compact_if_beneficial() to run compaction. 0 means compaction
+    /// runs whenever there are compaction_threshold level 0 delta layers.
+    pub compaction_min_benefit: u64,
+    /// Max rate of the GetPage requests served to the tenant's compute nodes,
+    /// and of the bytes they return. Requests over the limit are held back by
+    /// the page service. 0 means no limit.
+    pub get_requests_per_second: u64,
+    pub get_bytes_per_second: u64,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -95,7 +152,12 @@ pub struct TenantConf {
 pub struct TenantConfOpt {
     pub checkpoint_distance: Option<u64>,
     pub checkpoint_timeout: Option<Duration>,
+    pub checkpoint_max_entries: Option<usize>,
     pub compaction_target_size: Option<u64>,
+    pub compaction_target_file_size: Option<u64>,
+    pub compaction_target_file_size_relation: Option<u64>,
+    pub compaction_target_file_size_slru: Option<u64>,
+    pub compaction_target_file_size_metadata: Option<u64>,
     #[serde(with = "humantime_serde")]
     pub compaction_period: Option<Duration>,
     pub compaction_threshold: Option<usize>,
@@ -110,6 +172,163 @@ pub struct TenantConfOpt {
     #[serde(with = "humantime_serde")]
     pub lagging_wal_timeout: Option<Duration>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub materialized_cache_enabled: Option<bool>,
+    pub maintenance_window: Option<MaintenanceWindow>,
+    pub walredo_max_records_size: Option<u64>,
+    pub compaction_concurrency: Option<usize>,
+    pub compaction_max_input_layers: Option<usize>,
+    pub compaction_strategy: Option<CompactionStrategy>,
+    pub compaction_min_benefit: Option<u64>,
+    pub get_requests_per_second: Option<u64>,
+    pub get_bytes_per_second: Option<u64>,
+}
+
+/// A daily time window in UTC, written as "HH:MM-HH:MM", e.g. "22:00-06:00".
+/// The start is inclusive and the end is exclusive. If the end is before the
+/// start, the window spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MaintenanceWindow {
+    // Minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+impl MaintenanceWindow {
+    /// Is 'time' within the window?
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let minute = (secs / 60 % MINUTES_PER_DAY as u64) as u16;
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            self.start <= minute || minute < self.end
+        }
+    }
+}
+
+fn parse_time_of_day(s: &str) -> anyhow::Result<u16> {
+    let (hours, minutes) = s
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("invalid time '{s}', expected HH:MM"))?;
+    let hours: u16 = hours
+        .parse()
+        .with_context(|| format!("invalid hours in '{s}'"))?;
+    let minutes: u16 = minutes
+        .parse()
+        .with_context(|| format!("invalid minutes in '{s}'"))?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes != 0) {
+        bail!("time '{s}' is out of range");
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .with_context(|| format!("invalid time window '{s}', expected HH:MM-HH:MM"))?;
+        let start = parse_time_of_day(start)?;
+        let end = parse_time_of_day(end)?;
+        ensure!(start != end, "time window '{s}' is empty");
+        // "00:00-24:00" becomes start == end, which covers the whole day
+        Ok(MaintenanceWindow {
+            start: start % MINUTES_PER_DAY,
+            end: end % MINUTES_PER_DAY,
+        })
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Print the end of a whole-day window as 24:00, so that it parses back
+        let end = if self.start == self.end {
+            self.end + MINUTES_PER_DAY
+        } else {
+            self.end
+        };
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            end / 60,
+            end % 60
+        )
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MaintenanceWindow> for String {
+    fn from(window: MaintenanceWindow) -> String {
+        window.to_string()
+    }
+}
+
+/// How compaction merges level 0 delta layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CompactionStrategy {
+    /// Merge all the input layers, and split the result by key range into
+    /// layers of the target file size. Each output layer covers the whole LSN
+    /// range of the input.
+    KeySplit,
+    /// Merge runs of consecutive input layers into layers of about the target
+    /// file size. Each output layer covers the whole key range of its input,
+    /// and only the LSN range of the layers it was made of. Suits append-heavy
+    /// workloads, where recent WAL touches keys that weren't written before,
+    /// so that reads of recent data only have to look at the newest layers.
+    LsnWindow,
+}
+
+impl FromStr for CompactionStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "KeySplit" => Ok(CompactionStrategy::KeySplit),
+            "LsnWindow" => Ok(CompactionStrategy::LsnWindow),
+            _ => bail!("invalid compaction strategy '{s}', expected KeySplit or LsnWindow"),
+        }
+    }
+}
+
+impl fmt::Display for CompactionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompactionStrategy::KeySplit => "KeySplit",
+            CompactionStrategy::LsnWindow => "LsnWindow",
+        })
+    }
+}
+
+impl TryFrom<String> for CompactionStrategy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CompactionStrategy> for String {
+    fn from(strategy: CompactionStrategy) -> String {
+        strategy.to_string()
+    }
 }
 
 impl TenantConfOpt {
@@ -121,9 +340,24 @@ impl TenantConfOpt {
             checkpoint_timeout: self
                 .checkpoint_timeout
                 .unwrap_or(global_conf.checkpoint_timeout),
+            checkpoint_max_entries: self
+                .checkpoint_max_entries
+                .unwrap_or(global_conf.checkpoint_max_entries),
             compaction_target_size: self
                 .compaction_target_size
                 .unwrap_or(global_conf.compaction_target_size),
+            compaction_target_file_size: self
+                .compaction_target_file_size
+                .or(global_conf.compaction_target_file_size),
+            compaction_target_file_size_relation: self
+                .compaction_target_file_size_relation
+                .or(global_conf.compaction_target_file_size_relation),
+            compaction_target_file_size_slru: self
+                .compaction_target_file_size_slru
+                .or(global_conf.compaction_target_file_size_slru),
+            compaction_target_file_size_metadata: self
+                .compaction_target_file_size_metadata
+                .or(global_conf.compaction_target_file_size_metadata),
             compaction_period: self
                 .compaction_period
                 .unwrap_or(global_conf.compaction_period),
@@ -143,6 +377,31 @@ impl TenantConfOpt {
                 .lagging_wal_timeout
                 .unwrap_or(global_conf.lagging_wal_timeout),
             max_lsn_wal_lag: self.max_lsn_wal_lag.unwrap_or(global_conf.max_lsn_wal_lag),
+            materialized_cache_enabled: self
+                .materialized_cache_enabled
+                .unwrap_or(global_conf.materialized_cache_enabled),
+            maintenance_window: self.maintenance_window.or(global_conf.maintenance_window),
+            walredo_max_records_size: self
+                .walredo_max_records_size
+                .unwrap_or(global_conf.walredo_max_records_size),
+            compaction_concurrency: self
+                .compaction_concurrency
+                .unwrap_or(global_conf.compaction_concurrency),
+            compaction_max_input_layers: self
+                .compaction_max_input_layers
+                .unwrap_or(global_conf.compaction_max_input_layers),
+            compaction_strategy: self
+                .compaction_strategy
+                .unwrap_or(global_conf.compaction_strategy),
+            compaction_min_benefit: self
+                .compaction_min_benefit
+                .unwrap_or(global_conf.compaction_min_benefit),
+            get_requests_per_second: self
+                .get_requests_per_second
+                .unwrap_or(global_conf.get_requests_per_second),
+            get_bytes_per_second: self
+                .get_bytes_per_second
+                .unwrap_or(global_conf.get_bytes_per_second),
         }
     }
 
@@ -153,9 +412,28 @@ impl TenantConfOpt {
         if let Some(checkpoint_timeout) = other.checkpoint_timeout {
             self.checkpoint_timeout = Some(checkpoint_timeout);
         }
+        if let Some(checkpoint_max_entries) = other.checkpoint_max_entries {
+            self.checkpoint_max_entries = Some(checkpoint_max_entries);
+        }
         if let Some(compaction_target_size) = other.compaction_target_size {
             self.compaction_target_size = Some(compaction_target_size);
         }
+        if let Some(compaction_target_file_size) = other.compaction_target_file_size {
+            self.compaction_target_file_size = Some(compaction_target_file_size);
+        }
+        if let Some(compaction_target_file_size_relation) =
+            other.compaction_target_file_size_relation
+        {
+            self.compaction_target_file_size_relation = Some(compaction_target_file_size_relation);
+        }
+        if let Some(compaction_target_file_size_slru) = other.compaction_target_file_size_slru {
+            self.compaction_target_file_size_slru = Some(compaction_target_file_size_slru);
+        }
+        if let Some(compaction_target_file_size_metadata) =
+            other.compaction_target_file_size_metadata
+        {
+            self.compaction_target_file_size_metadata = Some(compaction_target_file_size_metadata);
+        }
         if let Some(compaction_period) = other.compaction_period {
             self.compaction_period = Some(compaction_period);
         }
@@ -183,6 +461,33 @@ impl TenantConfOpt {
         if let Some(max_lsn_wal_lag) = other.max_lsn_wal_lag {
             self.max_lsn_wal_lag = Some(max_lsn_wal_lag);
         }
+        if let Some(materialized_cache_enabled) = other.materialized_cache_enabled {
+            self.materialized_cache_enabled = Some(materialized_cache_enabled);
+        }
+        if let Some(maintenance_window) = other.maintenance_window {
+            self.maintenance_window = Some(maintenance_window);
+        }
+        if let Some(walredo_max_records_size) = other.walredo_max_records_size {
+            self.walredo_max_records_size = Some(walredo_max_records_size);
+        }
+        if let Some(compaction_concurrency) = other.compaction_concurrency {
+            self.compaction_concurrency = Some(compaction_concurrency);
+        }
+        if let Some(compaction_max_input_layers) = other.compaction_max_input_layers {
+            self.compaction_max_input_layers = Some(compaction_max_input_layers);
+        }
+        if let Some(compaction_strategy) = other.compaction_strategy {
+            self.compaction_strategy = Some(compaction_strategy);
+        }
+        if let Some(compaction_min_benefit) = other.compaction_min_benefit {
+            self.compaction_min_benefit = Some(compaction_min_benefit);
+        }
+        if let Some(get_requests_per_second) = other.get_requests_per_second {
+            self.get_requests_per_second = Some(get_requests_per_second);
+        }
+        if let Some(get_bytes_per_second) = other.get_bytes_per_second {
+            self.get_bytes_per_second = Some(get_bytes_per_second);
+        }
     }
 }
 
@@ -194,7 +499,12 @@ impl TenantConf {
             checkpoint_distance: DEFAULT_CHECKPOINT_DISTANCE,
             checkpoint_timeout: humantime::parse_duration(DEFAULT_CHECKPOINT_TIMEOUT)
                 .expect("cannot parse default checkpoint timeout"),
+            checkpoint_max_entries: DEFAULT_CHECKPOINT_MAX_ENTRIES,
             compaction_target_size: DEFAULT_COMPACTION_TARGET_SIZE,
+            compaction_target_file_size: None,
+            compaction_target_file_size_relation: None,
+            compaction_target_file_size_slru: None,
+            compaction_target_file_size_metadata: None,
             compaction_period: humantime::parse_duration(DEFAULT_COMPACTION_PERIOD)
                 .expect("cannot parse default compaction period"),
             compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
@@ -212,6 +522,17 @@ impl TenantConf {
                 .expect("cannot parse default walreceiver lagging wal timeout"),
             max_lsn_wal_lag: NonZeroU64::new(DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .expect("cannot parse default max walreceiver Lsn wal lag"),
+            materialized_cache_enabled: DEFAULT_MATERIALIZED_CACHE_ENABLED,
+            maintenance_window: None,
+            walredo_max_records_size: DEFAULT_WALREDO_MAX_RECORDS_SIZE,
+            compaction_concurrency: DEFAULT_COMPACTION_CONCURRENCY,
+            compaction_max_input_layers: DEFAULT_COMPACTION_MAX_INPUT_LAYERS,
+            compaction_strategy: DEFAULT_COMPACTION_STRATEGY
+                .parse()
+                .expect("cannot parse default compaction strategy"),
+            compaction_min_benefit: DEFAULT_COMPACTION_MIN_BENEFIT,
+            get_requests_per_second: DEFAULT_GET_REQUESTS_PER_SECOND,
+            get_bytes_per_second: DEFAULT_GET_BYTES_PER_SECOND,
         }
     }
 
@@ -226,7 +547,12 @@ impl TenantConf {
         TenantConf {
             checkpoint_distance: defaults::DEFAULT_CHECKPOINT_DISTANCE,
             checkpoint_timeout: Duration::from_secs(600),
+            checkpoint_max_entries: defaults::DEFAULT_CHECKPOINT_MAX_ENTRIES,
             compaction_target_size: 4 * 1024 * 1024,
+            compaction_target_file_size: None,
+            compaction_target_file_size_relation: None,
+            compaction_target_file_size_slru: None,
+            compaction_target_file_size_metadata: None,
             compaction_period: Duration::from_secs(10),
             compaction_threshold: defaults::DEFAULT_COMPACTION_THRESHOLD,
             gc_horizon: defaults::DEFAULT_GC_HORIZON,
@@ -243,6 +569,75 @@ impl TenantConf {
             .unwrap(),
             max_lsn_wal_lag: NonZeroU64::new(defaults::DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .unwrap(),
+            materialized_cache_enabled: defaults::DEFAULT_MATERIALIZED_CACHE_ENABLED,
+            maintenance_window: None,
+            walredo_max_records_size: defaults::DEFAULT_WALREDO_MAX_RECORDS_SIZE,
+            compaction_concurrency: defaults::DEFAULT_COMPACTION_CONCURRENCY,
+            compaction_max_input_layers: defaults::DEFAULT_COMPACTION_MAX_INPUT_LAYERS,
+            compaction_strategy: CompactionStrategy::KeySplit,
+            compaction_min_benefit: defaults::DEFAULT_COMPACTION_MIN_BENEFIT,
+            get_requests_per_second: defaults::DEFAULT_GET_REQUESTS_PER_SECOND,
+            get_bytes_per_second: defaults::DEFAULT_GET_BYTES_PER_SECOND,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_maintenance_window() {
+        let window: MaintenanceWindow = "01:30-04:00".parse().unwrap();
+        assert_eq!(window.to_string(), "01:30-04:00");
+        let at = |minutes: u64| UNIX_EPOCH + Duration::from_secs(minutes * 60);
+        assert!(!window.contains(at(89)));
+        assert!(window.contains(at(90)));
+        assert!(window.contains(at(239)));
+        assert!(!window.contains(at(240)));
+
+        let whole_day: MaintenanceWindow = "00:00-24:00".parse().unwrap();
+        assert_eq!(whole_day.to_string(), "00:00-24:00");
+        assert!(whole_day.contains(at(0)));
+        assert!(whole_day.contains(at(1439)));
+
+        for invalid in [
+            "",
+            "01:00",
+            "01:00-01:00",
+            "25:00-01:00",
+            "01:60-02:00",
+            "a:b-c:d",
+        ] {
+            assert!(
+                invalid.parse::<MaintenanceWindow>().is_err(),
+                "'{invalid}' should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn maintenance_window_serde_roundtrip() {
+        let conf = TenantConfOpt {
+            maintenance_window: Some("22:00-06:00".parse().unwrap()),
+            ..TenantConfOpt::default()
+        };
+        let serialized = toml_edit::easy::to_string(&conf).unwrap();
+        assert!(serialized.contains("maintenance_window = \"22:00-06:00\""));
+        let deserialized: TenantConfOpt = toml_edit::easy::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.maintenance_window, conf.maintenance_window);
+    }
+
+    #[test]
+    fn compaction_strategy_serde_roundtrip() {
+        let conf = TenantConfOpt {
+            compaction_strategy: Some(CompactionStrategy::LsnWindow),
+            ..TenantConfOpt::default()
+        };
+        let serialized = toml_edit::easy::to_string(&conf).unwrap();
+        assert!(serialized.contains("compaction_strategy = \"LsnWindow\""));
+        let deserialized: TenantConfOpt = toml_edit::easy::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.compaction_strategy, conf.compaction_strategy);
+        assert!("Lsnwindow".parse::<CompactionStrategy>().is_err());
+    }
+}
@@ -3,7 +3,7 @@
 
 use crate::config::PageServerConf;
 use crate::http::models::TenantInfo;
-use crate::layered_repository::{load_metadata, LayeredRepository};
+use crate::layered_repository::{load_metadata, InMemoryLayerStat, LayeredRepository};
 use crate::repository::Repository;
 use crate::storage_sync::index::{RemoteIndex, RemoteTimelineIndex};
 use crate::storage_sync::{self, LocalTimelineInitStatus, SyncStartupData};
@@ -236,6 +236,7 @@ pub fn shutdown_all_tenants() {
         match tenant.state {
             TenantState::Active | TenantState::Idle | TenantState::Stopping => {
                 tenant.state = TenantState::Stopping;
+                tenant.repo.cancel_compactions();
                 tenantids.push(*tenantid)
             }
             TenantState::Broken => {}
@@ -436,6 +437,10 @@ pub fn delete_timeline(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> anyhow
 
 pub fn detach_tenant(conf: &'static PageServerConf, tenant_id: ZTenantId) -> anyhow::Result<()> {
     set_tenant_state(tenant_id, TenantState::Stopping)?;
+    // Compaction runs on the tenant task worker threads, not on threads of the tenant
+    if let Ok(repo) = get_repository_for_tenant(tenant_id) {
+        repo.cancel_compactions();
+    }
     // shutdown the tenant and timeline threads: gc, compaction, page service threads)
     thread_mgr::shutdown_threads(None, Some(tenant_id), None);
 
@@ -504,6 +509,38 @@ fn load_local_timeline(
     Ok(inmem_timeline)
 }
 
+///
+/// Report stuck flush, compaction and GC threads of all tenants. Called
+/// periodically by the stall watchdog task.
+///
+pub fn check_maintenance_stalls() -> usize {
+    let repos = tenants_state::read_tenants()
+        .values()
+        .map(|tenant| Arc::clone(&tenant.repo))
+        .collect::<Vec<_>>();
+    repos
+        .iter()
+        .map(|repo| repo.check_maintenance_stalls())
+        .sum()
+}
+
+///
+/// Memory used by the in-memory layers of all loaded timelines, largest
+/// first. For triaging memory pressure.
+///
+pub fn inmemory_layer_report() -> Vec<InMemoryLayerStat> {
+    let repos = tenants_state::read_tenants()
+        .values()
+        .map(|tenant| Arc::clone(&tenant.repo))
+        .collect::<Vec<_>>();
+    let mut report = repos
+        .iter()
+        .flat_map(|repo| repo.inmemory_layer_report())
+        .collect::<Vec<_>>();
+    report.sort_by_key(|stat| std::cmp::Reverse(stat.total_bytes()));
+    report
+}
+
 ///
 /// Get list of tenants, for the mgmt API
 ///
@@ -3,7 +3,7 @@
 
 use std::collections::HashMap;
 use std::ops::ControlFlow;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use crate::repository::Repository;
 use crate::tenant_mgr::TenantState;
@@ -50,9 +50,13 @@ async fn compaction_loop(tenantid: ZTenantId, mut cancel: watch::Receiver<()>) {
                 Err(_) => return Ok(ControlFlow::Break(())),
             };
 
-            // Run compaction
-            let compaction_period = repo.get_compaction_period();
-            repo.compaction_iteration()?;
+            // Run compaction, unless we're outside the maintenance window
+            let compaction_period = repo.get_jittered_compaction_period();
+            if repo.should_run_background_maintenance(SystemTime::now()) {
+                repo.compaction_iteration()?;
+            } else {
+                debug!("outside of maintenance window, skipping compaction");
+            }
             Ok(ControlFlow::Continue(compaction_period))
         })
         .await;
@@ -87,6 +91,35 @@ async fn compaction_loop(tenantid: ZTenantId, mut cancel: watch::Receiver<()>) {
     );
 }
 
+/// How often the stall watchdog checks the maintenance locks of all timelines
+const STALL_WATCHDOG_PERIOD: Duration = Duration::from_secs(10);
+
+///
+/// Stall watchdog's main loop, see [`tenant_mgr::check_maintenance_stalls`].
+///
+/// Runs independently of the compaction and GC loops, so that it keeps
+/// checking when one of them is stuck.
+///
+async fn stall_watchdog_loop(mut cancel: watch::Receiver<()>) {
+    loop {
+        trace!("waking up");
+
+        let result = tokio::task::spawn_blocking(tenant_mgr::check_maintenance_stalls).await;
+        if let Err(e) = result {
+            error!("Stall watchdog join error: {}", e);
+        }
+
+        tokio::select! {
+            _ = cancel.changed() => {
+                trace!("received cancellation request");
+                break;
+            },
+            _ = tokio::time::sleep(STALL_WATCHDOG_PERIOD) => {},
+        }
+    }
+    trace!("stall watchdog loop stopped");
+}
+
 static START_GC_LOOP: OnceCell<mpsc::Sender<ZTenantId>> = OnceCell::new();
 static START_COMPACTION_LOOP: OnceCell<mpsc::Sender<ZTenantId>> = OnceCell::new();
 
@@ -139,6 +172,7 @@ pub fn init_tenant_task_pool() -> anyhow::Result<()> {
     // TODO this is getting repetitive
     let mut gc_loops = HashMap::<ZTenantId, watch::Sender<()>>::new();
     let mut compaction_loops = HashMap::<ZTenantId, watch::Sender<()>>::new();
+    let (watchdog_cancel, watchdog_cancel_recv) = watch::channel(());
 
     thread_mgr::spawn(
         ThreadKind::TenantTaskManager,
@@ -149,6 +183,11 @@ pub fn init_tenant_task_pool() -> anyhow::Result<()> {
         move || {
             runtime.block_on(async move {
                 let mut futures = FuturesUnordered::new();
+                futures.push(tokio::spawn(
+                    stall_watchdog_loop(watchdog_cancel_recv)
+                        .instrument(info_span!("stall watchdog")),
+                ));
+                TENANT_TASK_EVENTS.with_label_values(&["start"]).inc();
                 loop {
                     tokio::select! {
                         _ = thread_mgr::shutdown_watcher() => {
@@ -159,6 +198,7 @@ pub fn init_tenant_task_pool() -> anyhow::Result<()> {
                             for (_, cancel) in compaction_loops.drain() {
                                 cancel.send(()).ok();
                             }
+                            watchdog_cancel.send(()).ok();
 
                             // Exit after all tasks finish
                             while let Some(result) = futures.next().await {
@@ -249,10 +289,12 @@ async fn gc_loop(tenantid: ZTenantId, mut cancel: watch::Receiver<()>) {
                 Err(_) => return Ok(ControlFlow::Break(())),
             };
 
-            // Run gc
+            // Run gc, unless we're outside the maintenance window
             let gc_period = repo.get_gc_period();
             let gc_horizon = repo.get_gc_horizon();
-            if gc_horizon > 0 {
+            if !repo.should_run_background_maintenance(SystemTime::now()) {
+                debug!("outside of maintenance window, skipping gc");
+            } else if gc_horizon > 0 {
                 repo.gc_iteration(None, gc_horizon, repo.get_pitr_interval(), false)?;
             }
 
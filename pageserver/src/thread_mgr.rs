@@ -355,6 +355,34 @@ pub fn is_shutdown_requested() -> bool {
     })
 }
 
+///
+/// Asks a long-running operation to stop early. For operations that run on
+/// threads not associated with the tenant they work on, like compaction on the
+/// tenant task worker threads, which shutdown_threads() can't reach.
+///
+/// The operation also counts as cancelled if the current thread has been
+/// requested to shut down.
+///
+#[derive(Debug, Default)]
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || CURRENT_THREAD.with(|ct| {
+                ct.borrow()
+                    .as_ref()
+                    .map_or(false, |ct| ct.shutdown_requested.load(Ordering::Relaxed))
+            })
+    }
+}
+
 /// Needed to register threads that were not spawned through spawn function.
 /// For example tokio blocking threads. This function is expected to be used
 /// in tandem with `deregister`.
@@ -273,6 +273,33 @@ impl VirtualFile {
         self.with_file("fsync", |file| file.sync_all())?
     }
 
+    /// Hint to the OS that the given range of the file will be read soon, so
+    /// that it can start reading it ahead. This doesn't change the result of
+    /// any reads. It's a no-op on platforms without posix_fadvise().
+    pub fn prefetch(&self, offset: u64, len: u64) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        {
+            use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+            use std::os::unix::io::AsRawFd;
+
+            self.with_file("prefetch", |file| {
+                posix_fadvise(
+                    file.as_raw_fd(),
+                    offset as i64,
+                    len as i64,
+                    PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+                )
+                .map(|_| ())
+                .map_err(Error::from)
+            })?
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (offset, len);
+            Ok(())
+        }
+    }
+
     /// Helper function that looks up the underlying File for this VirtualFile,
     /// opening it and evicting some other File if necessary. It calls 'func'
     /// with the physical File.
@@ -1033,6 +1033,7 @@ mod tests {
     use crate::pgdatadir_mapping::create_test_timeline;
     use crate::repository::repo_harness::*;
     use crate::repository::Timeline;
+    use crate::CheckpointConfig;
     use postgres_ffi::pg_constants;
 
     /// Arbitrary relation tag, for testing.
@@ -1059,6 +1060,127 @@ mod tests {
         Ok(walingest)
     }
 
+    #[test]
+    fn test_incremental_keyspace() -> Result<()> {
+        let repo = RepoHarness::create("test_incremental_keyspace")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        init_walingest_test(&*tline)?;
+
+        // Small partitions, so that the changes below move partition boundaries
+        let partition_size = 4 * pg_constants::BLCKSZ as u64;
+        let rel = |relnode| RelTag {
+            relnode,
+            ..TESTREL_A
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for relnode in 1000..1010 {
+            m.put_rel_creation(rel(relnode), 10)?;
+        }
+        m.commit()?;
+        let mut prev = (
+            tline.collect_keyspace(Lsn(0x20))?.partition(partition_size),
+            Lsn(0x20),
+        );
+
+        // Check that the incremental collection matches a full one
+        let mut check = |lsn: Lsn| -> Result<()> {
+            let full = tline.collect_keyspace(lsn)?;
+            let incremental = tline.collect_keyspace_incremental(lsn, &prev)?;
+            assert_eq!(incremental, full, "key space differs at {}", lsn);
+
+            let partitioning = incremental.partition(partition_size);
+            assert_eq!(partitioning, full.partition(partition_size));
+            prev = (partitioning, lsn);
+            Ok(())
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_extend(rel(1000), 20)?;
+        m.commit()?;
+        check(Lsn(0x30))?;
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_rel_truncation(rel(1001), 2)?;
+        m.commit()?;
+        check(Lsn(0x40))?;
+
+        let mut m = tline.begin_modification(Lsn(0x50));
+        m.put_rel_creation(rel(2000), 5)?;
+        m.put_rel_extend(rel(1002), 11)?;
+        m.commit()?;
+        check(Lsn(0x50))?;
+
+        let mut m = tline.begin_modification(Lsn(0x60));
+        m.put_rel_drop(rel(1003))?;
+        m.commit()?;
+        check(Lsn(0x60))?;
+
+        let mut m = tline.begin_modification(Lsn(0x70));
+        m.put_slru_segment_creation(SlruKind::Clog, 0, 3)?;
+        m.commit()?;
+        check(Lsn(0x70))?;
+
+        // A new database, which requires collecting everything
+        let mut m = tline.begin_modification(Lsn(0x80));
+        m.put_relmap_file(0, 222, Bytes::from(""))?;
+        m.commit()?;
+        check(Lsn(0x80))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyspace_coverage() -> Result<()> {
+        let repo = RepoHarness::create("test_keyspace_coverage")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&*tline)?;
+        let rel = |relnode| RelTag {
+            relnode,
+            ..TESTREL_A
+        };
+
+        // Some relations in on-disk layers, and some only in memory
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for relnode in 1000..1005 {
+            walingest.put_rel_creation(&mut m, rel(relnode))?;
+            for blknum in 0..3 {
+                walingest.put_rel_page_image(
+                    &mut m,
+                    rel(relnode),
+                    blknum,
+                    TEST_IMG(&format!("{relnode} blk {blknum}")),
+                )?;
+            }
+        }
+        m.commit()?;
+        tline.checkpoint(CheckpointConfig::Forced)?;
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        walingest.put_rel_creation(&mut m, rel(2000))?;
+        walingest.put_rel_page_image(&mut m, rel(2000), 0, TEST_IMG("2000 blk 0"))?;
+        m.commit()?;
+
+        let coverage = tline.covered_key_ranges().unwrap();
+        for relnode in [1000, 1004, 2000] {
+            let key = rel_key_range(rel(relnode)).start;
+            assert!(coverage.iter().any(|range| range.contains(&key)));
+        }
+        let key = rel_key_range(rel(3000)).start;
+        assert!(!coverage.iter().any(|range| range.contains(&key)));
+
+        for lsn in [Lsn(0x20), Lsn(0x30)] {
+            assert_eq!(
+                tline.collect_keyspace(lsn)?,
+                collect_keyspace_with_coverage(&*tline, lsn, None)?,
+                "key space differs at {}",
+                lsn
+            );
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_relsize() -> Result<()> {
         let repo = RepoHarness::create("test_relsize")?.load();
@@ -30,6 +30,10 @@ use crate::{
 use postgres_ffi::waldecoder::WalStreamDecoder;
 use utils::{lsn::Lsn, pq_proto::ReplicationFeedback, zid::ZTenantTimelineId};
 
+/// How long to wait before disconnecting, when WAL ingestion is paused on the
+/// timeline. The connection manager reconnects right after that.
+const INGEST_PAUSED_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Status of the connection.
 #[derive(Debug, Clone)]
 pub struct WalConnectionStatus {
@@ -209,6 +213,24 @@ pub async fn handle_walreceiver_connection(
 
         let status_update = match replication_message {
             ReplicationMessage::XLogData(xlog_data) => {
+                let paused_reason = if timeline.is_ingest_paused() {
+                    Some("WAL ingestion is paused")
+                } else if timeline.is_disk_space_low() {
+                    Some("disk space is low")
+                } else {
+                    None
+                };
+                if let Some(reason) = paused_reason {
+                    // Don't consume any WAL. Wait a while and disconnect; the next
+                    // connection streams the WAL again from the last record LSN.
+                    info!("{reason} at {last_rec_lsn}, disconnecting");
+                    select! {
+                        _ = cancellation.changed() => {}
+                        _ = time::sleep(INGEST_PAUSED_RETRY_INTERVAL) => {}
+                    }
+                    return Ok(());
+                }
+
                 // Pass the WAL data to the decoder, and see if we can decode
                 // more records as a result.
                 let data = xlog_data.data();
@@ -304,7 +326,7 @@ pub async fn handle_walreceiver_connection(
                     .expect("Received message time should be before UNIX EPOCH!")
                     .as_micros(),
             };
-            *timeline.last_received_wal.lock().unwrap() = Some(last_received_wal);
+            timeline.record_received_wal(last_received_wal);
 
             // Send zenith feedback message.
             // Regular standby_status_update fields are put into this message.
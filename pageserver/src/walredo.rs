@@ -80,6 +80,27 @@ pub trait WalRedoManager: Send + Sync {
         base_img: Option<Bytes>,
         records: Vec<(Lsn, ZenithWalRecord)>,
     ) -> Result<Bytes, WalRedoError>;
+
+    /// Apply WAL records for several pages.
+    ///
+    /// Returns the results in the same order as the requests. A failure to
+    /// reconstruct one page doesn't fail the others. The default
+    /// implementation handles the requests one by one.
+    fn request_redo_batch(&self, requests: Vec<RedoRequest>) -> Vec<Result<Bytes, WalRedoError>> {
+        requests
+            .into_iter()
+            .map(|req| self.request_redo(req.key, req.lsn, req.base_img, req.records))
+            .collect()
+    }
+}
+
+/// The arguments of one [`WalRedoManager::request_redo`] call, for
+/// [`WalRedoManager::request_redo_batch`].
+pub struct RedoRequest {
+    pub key: Key,
+    pub lsn: Lsn,
+    pub base_img: Option<Bytes>,
+    pub records: Vec<(Lsn, ZenithWalRecord)>,
 }
 
 ///
@@ -229,6 +250,63 @@ impl WalRedoManager for PostgresRedoManager {
             )
         }
     }
+
+    ///
+    /// Requests that consist of Postgres WAL records only are sent to the WAL
+    /// redo process together, and the pages are read back in one round trip.
+    /// The others are handled one by one, like in request_redo().
+    ///
+    fn request_redo_batch(&self, requests: Vec<RedoRequest>) -> Vec<Result<Bytes, WalRedoError>> {
+        let mut results: Vec<Option<Result<Bytes, WalRedoError>>> =
+            requests.iter().map(|_| None).collect();
+
+        let mut batch = Vec::new();
+        for (i, req) in requests.into_iter().enumerate() {
+            let postgres_only = !req.records.is_empty()
+                && !req.records.iter().any(|(_, rec)| can_apply_in_zenith(rec));
+            match key_to_rel_block(req.key) {
+                Ok((rel, blknum)) if postgres_only => {
+                    batch.push((i, BufferTag { rel, blknum }, req));
+                }
+                _ => {
+                    results[i] =
+                        Some(self.request_redo(req.key, req.lsn, req.base_img, req.records));
+                }
+            }
+        }
+
+        if batch.len() > 1 {
+            // The timeout is meant for reconstructing one page, so allow as
+            // much for each page in the batch.
+            let timeout = self.conf.wal_redo_timeout * batch.len() as u32;
+            match self.apply_batch_postgres_multi(&batch, timeout) {
+                Ok(images) => {
+                    for ((i, _, _), img) in batch.iter().zip(images) {
+                        results[*i] = Some(Ok(img));
+                    }
+                    batch.clear();
+                }
+                Err(err) => {
+                    // A bad record in one request fails the whole round trip.
+                    // Retry the requests one by one, so that only that request
+                    // fails.
+                    warn!(
+                        "batched WAL redo of {} pages failed, retrying them one by one: {}",
+                        batch.len(),
+                        err
+                    );
+                }
+            }
+        }
+        for (i, _, req) in batch {
+            results[i] = Some(self.request_redo(req.key, req.lsn, req.base_img, req.records));
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every request has a result"))
+            .collect()
+    }
 }
 
 impl PostgresRedoManager {
@@ -245,18 +323,14 @@ impl PostgresRedoManager {
     }
 
     ///
-    /// Process one request for WAL redo using wal-redo postgres
+    /// Run 'f' with the WAL redo process, launching it if it's not running yet.
+    /// If 'f' fails, the process is killed, and the next request will launch
+    /// a new one.
     ///
-    fn apply_batch_postgres(
+    fn with_redo_process<T>(
         &self,
-        key: Key,
-        lsn: Lsn,
-        base_img: Option<Bytes>,
-        records: &[(Lsn, ZenithWalRecord)],
-        wal_redo_timeout: Duration,
-    ) -> Result<Bytes, WalRedoError> {
-        let (rel, blknum) = key_to_rel_block(key).or(Err(WalRedoError::InvalidRecord))?;
-
+        f: impl FnOnce(&mut PostgresRedoProcess) -> Result<T, Error>,
+    ) -> Result<T, WalRedoError> {
         let start_time = Instant::now();
 
         let mut process_guard = self.process.lock().unwrap();
@@ -271,36 +345,84 @@ impl PostgresRedoManager {
 
         WAL_REDO_WAIT_TIME.observe(lock_time.duration_since(start_time).as_secs_f64());
 
+        let result = f(process).map_err(WalRedoError::IoError);
+
+        WAL_REDO_TIME.observe(lock_time.elapsed().as_secs_f64());
+
+        // If something went wrong, don't try to reuse the process. Kill it, and
+        // next request will launch a new one.
+        if result.is_err() {
+            let process = process_guard.take().unwrap();
+            process.kill();
+        }
+        result
+    }
+
+    ///
+    /// Process one request for WAL redo using wal-redo postgres
+    ///
+    fn apply_batch_postgres(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        base_img: Option<Bytes>,
+        records: &[(Lsn, ZenithWalRecord)],
+        wal_redo_timeout: Duration,
+    ) -> Result<Bytes, WalRedoError> {
+        let (rel, blknum) = key_to_rel_block(key).or(Err(WalRedoError::InvalidRecord))?;
+
+        let start_time = Instant::now();
+
         // Relational WAL records are applied using wal-redo-postgres
         let buf_tag = BufferTag { rel, blknum };
-        let result = process
-            .apply_wal_records(buf_tag, base_img, records, wal_redo_timeout)
-            .map_err(WalRedoError::IoError);
+        let result = self.with_redo_process(|process| {
+            process.apply_wal_records(buf_tag, base_img, records, wal_redo_timeout)
+        });
 
-        let end_time = Instant::now();
-        let duration = end_time.duration_since(lock_time);
-        WAL_REDO_TIME.observe(duration.as_secs_f64());
         debug!(
             "postgres applied {} WAL records in {} us to reconstruct page image at LSN {}",
             records.len(),
-            duration.as_micros(),
+            start_time.elapsed().as_micros(),
             lsn
         );
-
-        // If something went wrong, don't try to reuse the process. Kill it, and
-        // next request will launch a new one.
         if result.is_err() {
             error!(
                 "error applying {} WAL records to reconstruct page image at LSN {}",
                 records.len(),
                 lsn
             );
-            let process = process_guard.take().unwrap();
-            process.kill();
         }
         result
     }
 
+    ///
+    /// Process several requests for WAL redo using wal-redo postgres, with
+    /// one round trip. The requests must only contain Postgres WAL records.
+    ///
+    fn apply_batch_postgres_multi(
+        &self,
+        requests: &[(usize, BufferTag, RedoRequest)],
+        wal_redo_timeout: Duration,
+    ) -> Result<Vec<Bytes>, WalRedoError> {
+        let start_time = Instant::now();
+
+        let result = self.with_redo_process(|process| {
+            process.apply_wal_records_multi(
+                requests
+                    .iter()
+                    .map(|(_, tag, req)| (*tag, req.base_img.as_ref(), req.records.as_slice())),
+                wal_redo_timeout,
+            )
+        });
+
+        debug!(
+            "postgres applied WAL records in {} us to reconstruct {} page images",
+            start_time.elapsed().as_micros(),
+            requests.len()
+        );
+        result
+    }
+
     ///
     /// Process a batch of WAL records using bespoken Zenith code.
     ///
@@ -724,32 +846,53 @@ impl PostgresRedoProcess {
         records: &[(Lsn, ZenithWalRecord)],
         wal_redo_timeout: Duration,
     ) -> Result<Bytes, std::io::Error> {
+        let mut pages = self.apply_wal_records_multi(
+            [(tag, base_img.as_ref(), records)].into_iter(),
+            wal_redo_timeout,
+        )?;
+        Ok(pages.pop().unwrap())
+    }
+
+    //
+    // Like apply_wal_records(), but for several pages. All the requests are
+    // sent to the process first, and then all the page images are read back.
+    // Returns the new page images in the same order as the requests.
+    //
+    fn apply_wal_records_multi<'a>(
+        &mut self,
+        requests: impl Iterator<Item = (BufferTag, Option<&'a Bytes>, &'a [(Lsn, ZenithWalRecord)])>,
+        wal_redo_timeout: Duration,
+    ) -> Result<Vec<Bytes>, std::io::Error> {
         // Serialize all the messages to send the WAL redo process first.
         //
         // This could be problematic if there are millions of records to replay,
         // but in practice the number of records is usually so small that it doesn't
         // matter, and it's better to keep this code simple.
         let mut writebuf: Vec<u8> = Vec::new();
-        build_begin_redo_for_block_msg(tag, &mut writebuf);
-        if let Some(img) = base_img {
-            build_push_page_msg(tag, &img, &mut writebuf);
-        }
-        for (lsn, rec) in records.iter() {
-            if let ZenithWalRecord::Postgres {
-                will_init: _,
-                rec: postgres_rec,
-            } = rec
-            {
-                build_apply_record_msg(*lsn, postgres_rec, &mut writebuf);
-            } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "tried to pass zenith wal record to postgres WAL redo",
-                ));
+        let mut num_pages = 0;
+        for (tag, base_img, records) in requests {
+            build_begin_redo_for_block_msg(tag, &mut writebuf);
+            if let Some(img) = base_img {
+                build_push_page_msg(tag, img, &mut writebuf);
+            }
+            for (lsn, rec) in records.iter() {
+                if let ZenithWalRecord::Postgres {
+                    will_init: _,
+                    rec: postgres_rec,
+                } = rec
+                {
+                    build_apply_record_msg(*lsn, postgres_rec, &mut writebuf);
+                } else {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "tried to pass zenith wal record to postgres WAL redo",
+                    ));
+                }
             }
+            build_get_page_msg(tag, &mut writebuf);
+            WAL_REDO_RECORD_COUNTER.inc_by(records.len() as u64);
+            num_pages += 1;
         }
-        build_get_page_msg(tag, &mut writebuf);
-        WAL_REDO_RECORD_COUNTER.inc_by(records.len() as u64);
 
         // The input is now in 'writebuf'. Do a blind write first, writing as much as
         // we can, before calling poll(). That skips one call to poll() if the stdin is
@@ -757,9 +900,10 @@ impl PostgresRedoProcess {
         // process is idle.
         let mut nwrite = self.stdin.write(&writebuf)?;
 
-        // We expect the WAL redo process to respond with an 8k page image. We read it
-        // into this buffer.
-        let mut resultbuf = vec![0; pg_constants::BLCKSZ.into()];
+        // We expect the WAL redo process to respond with an 8k page image for each
+        // request. We read them into this buffer.
+        let page_size: usize = pg_constants::BLCKSZ.into();
+        let mut resultbuf = vec![0; num_pages * page_size];
         let mut nresult: usize = 0; // # of bytes read into 'resultbuf' so far
 
         // Prepare for calling poll()
@@ -772,7 +916,7 @@ impl PostgresRedoProcess {
         // We do three things simultaneously: send the old base image and WAL records to
         // the child process's stdin, read the result from child's stdout, and forward any logging
         // information that the child writes to its stderr to the page server's log.
-        while nresult < pg_constants::BLCKSZ.into() {
+        while nresult < resultbuf.len() {
             // If we have more data to write, wake up if 'stdin' becomes writeable or
             // we have data to read. Otherwise only wake up if there's data to read.
             let nfds = if nwrite < writebuf.len() { 3 } else { 2 };
@@ -838,7 +982,10 @@ impl PostgresRedoProcess {
             }
         }
 
-        Ok(Bytes::from(resultbuf))
+        let resultbuf = Bytes::from(resultbuf);
+        Ok((0..num_pages)
+            .map(|i| resultbuf.slice(i * page_size..(i + 1) * page_size))
+            .collect())
     }
 }
 
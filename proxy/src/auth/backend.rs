@@ -16,13 +16,32 @@ use crate::{
     waiters::{self, Waiter, Waiters},
 };
 
+use metrics::{register_histogram, register_int_gauge, Histogram, IntGauge};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 static CPLANE_WAITERS: Lazy<Waiters<mgmt::ComputeReady>> = Lazy::new(Default::default);
 
+static NUM_PENDING_AUTH_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "proxy_pending_auth_sessions",
+        "Number of auth sessions waiting for the cloud's reply."
+    )
+    .unwrap()
+});
+
+static AUTH_WAIT_TIME: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "proxy_auth_wait_seconds",
+        "Time auth sessions spent waiting for the cloud's reply."
+    )
+    .unwrap()
+});
+
 /// Give caller an opportunity to wait for the cloud's reply.
+/// The session counts as pending until `action` completes or is cancelled.
 pub async fn with_waiter<R, T, E>(
     psql_session_id: impl Into<String>,
     action: impl FnOnce(Waiter<'static, mgmt::ComputeReady>) -> R,
@@ -32,30 +51,134 @@ where
     E: From<waiters::RegisterError>,
 {
     let waiter = CPLANE_WAITERS.register(psql_session_id.into())?;
+
+    let registered_at = Instant::now();
+    NUM_PENDING_AUTH_SESSIONS.inc();
+    scopeguard::defer! {
+        NUM_PENDING_AUTH_SESSIONS.dec();
+        AUTH_WAIT_TIME.observe(registered_at.elapsed().as_secs_f64());
+    }
+
     action(waiter).await
 }
 
+/// List the sessions which are still waiting for the cloud's reply.
+pub fn pending_sessions() -> Vec<waiters::PendingWaiter> {
+    CPLANE_WAITERS.pending()
+}
+
 pub fn notify(psql_session_id: &str, msg: mgmt::ComputeReady) -> Result<(), waiters::NotifyError> {
     CPLANE_WAITERS.notify(psql_session_id, msg)
 }
 
 /// Compute node connection params provided by the cloud.
 /// Note how it implements serde traits, since we receive it over the wire.
+///
+/// The cloud either sends a single `host` & `port` pair or a list of
+/// `endpoints` (primary first, then standbys); both become [`Self::endpoints`].
+///
+/// Compute credentials are either a plaintext `password`, or a short-lived
+/// `token` which is forwarded to compute in place of the password.
+///
+/// The cloud may also tell us how long to wait for the compute node to accept
+/// the connection, in `connect_timeout_ms`; otherwise the proxy's default is used.
 #[derive(Serialize, Deserialize, Default)]
+#[serde(try_from = "DatabaseInfoRepr")]
 pub struct DatabaseInfo {
-    pub host: String,
-    pub port: u16,
+    /// Compute endpoints to try in order until one of them accepts the connection.
+    pub endpoints: Vec<ComputeEndpoint>,
     pub dbname: String,
     pub user: String,
     pub password: Option<String>,
+    pub token: Option<ComputeToken>,
+    pub connect_timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComputeEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Short-lived token (JWT) to authenticate with at compute.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ComputeToken {
+    pub jwt: String,
+    /// Expiration time, in seconds since the unix epoch.
+    /// We refuse to use the token after that.
+    pub expires_at: u64,
+}
+
+impl ComputeToken {
+    pub fn expiration_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires_at)
+    }
+}
+
+impl DatabaseInfo {
+    /// Expiration time of the compute credentials, if they expire at all.
+    pub fn expiration_time(&self) -> Option<SystemTime> {
+        self.token.as_ref().map(ComputeToken::expiration_time)
+    }
+
+    /// How long to wait for compute, if the cloud has specified it.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// Wire format of [`DatabaseInfo`] which accepts both the single-endpoint
+/// and the multi-endpoint shapes.
+#[derive(Deserialize)]
+struct DatabaseInfoRepr {
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    endpoints: Vec<ComputeEndpoint>,
+    dbname: String,
+    user: String,
+    password: Option<String>,
+    token: Option<ComputeToken>,
+    connect_timeout_ms: Option<u64>,
+}
+
+impl TryFrom<DatabaseInfoRepr> for DatabaseInfo {
+    type Error = &'static str;
+
+    fn try_from(repr: DatabaseInfoRepr) -> Result<Self, Self::Error> {
+        let endpoints = match (repr.host, repr.port, repr.endpoints) {
+            (Some(host), Some(port), endpoints) if endpoints.is_empty() => {
+                vec![ComputeEndpoint { host, port }]
+            }
+            (None, None, endpoints) => endpoints,
+            _ => return Err("expected either `host` and `port`, or `endpoints`"),
+        };
+        if endpoints.is_empty() {
+            return Err("expected at least one compute endpoint");
+        }
+
+        if repr.password.is_some() && repr.token.is_some() {
+            return Err("expected either `password` or `token`, not both");
+        }
+
+        Ok(Self {
+            endpoints,
+            dbname: repr.dbname,
+            user: repr.user,
+            password: repr.password,
+            token: repr.token,
+            connect_timeout_ms: repr.connect_timeout_ms,
+        })
+    }
 }
 
 // Manually implement debug to omit personal and sensitive info.
 impl std::fmt::Debug for DatabaseInfo {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         fmt.debug_struct("DatabaseInfo")
-            .field("host", &self.host)
-            .field("port", &self.port)
+            .field("endpoints", &self.endpoints)
+            .field("expiration_time", &self.expiration_time())
+            .field("connect_timeout", &self.connect_timeout())
             .finish()
     }
 }
@@ -64,13 +187,22 @@ impl From<DatabaseInfo> for tokio_postgres::Config {
     fn from(db_info: DatabaseInfo) -> Self {
         let mut config = tokio_postgres::Config::new();
 
-        config
-            .host(&db_info.host)
-            .port(db_info.port)
-            .dbname(&db_info.dbname)
-            .user(&db_info.user);
+        // Hosts and ports are matched by index, see `compute::NodeInfo`.
+        for endpoint in &db_info.endpoints {
+            config.host(&endpoint.host).port(endpoint.port);
+        }
+
+        config.dbname(&db_info.dbname).user(&db_info.user);
 
-        if let Some(password) = db_info.password {
+        // Picked up by `compute::NodeInfo` in place of the proxy's default.
+        if let Some(timeout) = db_info.connect_timeout() {
+            config.connect_timeout(timeout);
+        }
+
+        // Compute accepts the token in place of the password.
+        if let Some(token) = db_info.token {
+            config.password(token.jwt);
+        } else if let Some(password) = db_info.password {
             config.password(password);
         }
 
@@ -132,6 +264,7 @@ impl BackendType<ClientCredentials> {
     pub async fn authenticate(
         mut self,
         urls: &config::AuthUrls,
+        max_response_size: usize,
         client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
     ) -> super::Result<compute::NodeInfo> {
         use BackendType::*;
@@ -171,6 +304,7 @@ impl BackendType<ClientCredentials> {
                 return Ok(compute::NodeInfo {
                     reported_auth_ok: false,
                     config,
+                    expiration_time: None,
                 });
             }
         }
@@ -178,8 +312,9 @@ impl BackendType<ClientCredentials> {
         match self {
             LegacyConsole(creds) => {
                 legacy_console::handle_user(
-                    &urls.auth_endpoint,
+                    &urls.auth_endpoints(),
                     &urls.auth_link_uri,
+                    max_response_size,
                     &creds,
                     client,
                 )
@@ -232,4 +367,28 @@ mod tests {
             assert_eq!(value.map(Result::unwrap), value.transpose().unwrap());
         }
     }
+
+    #[tokio::test]
+    async fn test_pending_sessions_metrics() -> anyhow::Result<()> {
+        let session_id = "test_pending_sessions_metrics";
+        let pending_before = NUM_PENDING_AUTH_SESSIONS.get();
+        let waits_before = AUTH_WAIT_TIME.get_sample_count();
+
+        let db_info = with_waiter(session_id, |waiter| async {
+            assert_eq!(NUM_PENDING_AUTH_SESSIONS.get(), pending_before + 1);
+            assert!(pending_sessions().iter().any(|s| s.key == session_id));
+
+            notify(session_id, Ok(Default::default()))?;
+            assert!(!pending_sessions().iter().any(|s| s.key == session_id));
+
+            Ok::<_, anyhow::Error>(waiter.await?.map_err(anyhow::Error::msg)?)
+        })
+        .await?;
+        assert!(db_info.endpoints.is_empty());
+
+        assert_eq!(NUM_PENDING_AUTH_SESSIONS.get(), pending_before);
+        assert_eq!(AUTH_WAIT_TIME.get_sample_count(), waits_before + 1);
+
+        Ok(())
+    }
 }
@@ -231,6 +231,7 @@ where
     Ok(compute::NodeInfo {
         reported_auth_ok: false,
         config,
+        expiration_time: None,
     })
 }
 
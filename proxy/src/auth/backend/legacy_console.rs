@@ -26,6 +26,14 @@ pub enum LegacyAuthError {
     #[error("Console responded with a malformed JSON: {0}")]
     BadResponse(#[from] serde_json::Error),
 
+    /// The console's response body is larger than we're willing to read.
+    #[error("Console response exceeds the size limit of {0} bytes")]
+    ResponseTooLarge(usize),
+
+    /// There's no console endpoint to send the request to.
+    #[error("No console endpoint is configured")]
+    NoConsoleEndpoint,
+
     #[error(transparent)]
     Transport(#[from] reqwest::Error),
 
@@ -36,6 +44,19 @@ pub enum LegacyAuthError {
     WaiterWait(#[from] waiters::WaitError),
 }
 
+impl LegacyAuthError {
+    /// Is this a failure of the console itself, rather than an answer from it?
+    /// If so, the request can be retried with a fallback console endpoint.
+    fn should_fall_back(&self) -> bool {
+        use LegacyAuthError::*;
+        match self {
+            Transport(_) => true,
+            HttpStatus(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
 impl UserFacingError for LegacyAuthError {
     fn to_string_client(&self) -> String {
         use LegacyAuthError::*;
@@ -62,31 +83,92 @@ impl ClientCredentials {
     }
 }
 
-async fn authenticate_proxy_client(
+/// Read the response body, failing as soon as it grows beyond `max_size` bytes.
+/// This way a misbehaving console can't make us buffer an arbitrarily large body.
+async fn read_body_limited(
+    mut resp: reqwest::Response,
+    max_size: usize,
+) -> Result<Vec<u8>, LegacyAuthError> {
+    if matches!(resp.content_length(), Some(len) if len > max_size as u64) {
+        return Err(LegacyAuthError::ResponseTooLarge(max_size));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > max_size {
+            return Err(LegacyAuthError::ResponseTooLarge(max_size));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Send the authentication request to the first of `auth_endpoints`, moving on
+/// to the next one only if the console is unreachable or fails with a server
+/// error. An explicit answer from the console, including a 4xx status, is final.
+async fn request_auth_info(
+    auth_endpoints: &[&reqwest::Url],
+    max_response_size: usize,
+    query: &[(&str, &str)],
+) -> Result<ProxyAuthResponse, LegacyAuthError> {
+    let (last_endpoint, fallbacks) = auth_endpoints
+        .split_last()
+        .ok_or(LegacyAuthError::NoConsoleEndpoint)?;
+
+    for auth_endpoint in fallbacks {
+        match request_auth_info_from(auth_endpoint, max_response_size, query).await {
+            Err(e) if e.should_fall_back() => {
+                println!("cloud request to {auth_endpoint} failed, trying the next endpoint: {e}");
+            }
+            res => return res,
+        }
+    }
+
+    request_auth_info_from(last_endpoint, max_response_size, query).await
+}
+
+async fn request_auth_info_from(
     auth_endpoint: &reqwest::Url,
+    max_response_size: usize,
+    query: &[(&str, &str)],
+) -> Result<ProxyAuthResponse, LegacyAuthError> {
+    let mut url = auth_endpoint.clone();
+    url.query_pairs_mut().extend_pairs(query);
+
+    println!("cloud request: {}", url);
+    // TODO: leverage `reqwest::Client` to reuse connections
+    let resp = reqwest::get(url).await?;
+    if !resp.status().is_success() {
+        return Err(LegacyAuthError::HttpStatus(resp.status()));
+    }
+
+    let body = read_body_limited(resp, max_response_size).await?;
+    let auth_info = serde_json::from_slice(&body)?;
+    println!("got auth info: {:?}", auth_info);
+
+    Ok(auth_info)
+}
+
+async fn authenticate_proxy_client(
+    auth_endpoints: &[&reqwest::Url],
+    max_response_size: usize,
     creds: &ClientCredentials,
     md5_response: &str,
     salt: &[u8; 4],
     psql_session_id: &str,
 ) -> Result<DatabaseInfo, LegacyAuthError> {
-    let mut url = auth_endpoint.clone();
-    url.query_pairs_mut()
-        .append_pair("login", &creds.user)
-        .append_pair("database", &creds.dbname)
-        .append_pair("md5response", md5_response)
-        .append_pair("salt", &hex::encode(salt))
-        .append_pair("psql_session_id", psql_session_id);
+    let salt = hex::encode(salt);
+    let query = [
+        ("login", creds.user.as_str()),
+        ("database", creds.dbname.as_str()),
+        ("md5response", md5_response),
+        ("salt", salt.as_str()),
+        ("psql_session_id", psql_session_id),
+    ];
 
     super::with_waiter(psql_session_id, |waiter| async {
-        println!("cloud request: {}", url);
-        // TODO: leverage `reqwest::Client` to reuse connections
-        let resp = reqwest::get(url).await?;
-        if !resp.status().is_success() {
-            return Err(LegacyAuthError::HttpStatus(resp.status()));
-        }
-
-        let auth_info = serde_json::from_str(resp.text().await?.as_str())?;
-        println!("got auth info: {:?}", auth_info);
+        let auth_info = request_auth_info(auth_endpoints, max_response_size, &query).await?;
 
         use ProxyAuthResponse::*;
         let db_info = match auth_info {
@@ -101,7 +183,8 @@ async fn authenticate_proxy_client(
 }
 
 async fn handle_existing_user(
-    auth_endpoint: &reqwest::Url,
+    auth_endpoints: &[&reqwest::Url],
+    max_response_size: usize,
     client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
     creds: &ClientCredentials,
 ) -> auth::Result<compute::NodeInfo> {
@@ -119,7 +202,8 @@ async fn handle_existing_user(
     ))?;
 
     let db_info = authenticate_proxy_client(
-        auth_endpoint,
+        auth_endpoints,
+        max_response_size,
         creds,
         md5_response,
         &md5_salt,
@@ -129,18 +213,22 @@ async fn handle_existing_user(
 
     Ok(compute::NodeInfo {
         reported_auth_ok: false,
+        expiration_time: db_info.expiration_time(),
         config: db_info.into(),
     })
 }
 
+/// `auth_endpoints` are the console endpoints to authenticate existing users
+/// with, in the order of preference.
 pub async fn handle_user(
-    auth_endpoint: &reqwest::Url,
+    auth_endpoints: &[&reqwest::Url],
     auth_link_uri: &reqwest::Url,
+    max_response_size: usize,
     creds: &ClientCredentials,
     client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
 ) -> auth::Result<compute::NodeInfo> {
     if creds.is_existing_user() {
-        handle_existing_user(auth_endpoint, client, creds).await
+        handle_existing_user(auth_endpoints, max_response_size, client, creds).await
     } else {
         super::link::handle_user(auth_link_uri, client).await
     }
@@ -153,14 +241,22 @@ fn parse_password(bytes: &[u8]) -> Option<&str> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::backend::ComputeEndpoint;
     use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
     #[test]
     fn test_proxy_auth_response() {
         // Ready
         let auth: ProxyAuthResponse = serde_json::from_value(json!({
             "ready": true,
-            "conn_info": DatabaseInfo::default(),
+            "conn_info": {
+                "host": "localhost",
+                "port": 5432,
+                "dbname": "postgres",
+                "user": "john_doe",
+            },
         }))
         .unwrap();
         assert!(matches!(
@@ -186,6 +282,157 @@ mod tests {
         assert!(matches!(auth, ProxyAuthResponse::NotReady { .. }));
     }
 
+    #[tokio::test]
+    async fn no_console_endpoint() {
+        let res = request_auth_info(&[], 1024, &[]).await;
+        assert!(matches!(res, Err(LegacyAuthError::NoConsoleEndpoint)));
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_rejected() -> anyhow::Result<()> {
+        const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+        // Bail out eventually if the client keeps reading.
+        const MAX_BYTES_TO_SEND: usize = 1024 * 1024 * 1024;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // A console which replies with an endless chunked body.
+        let console = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = [0u8; 4096];
+            let _ = socket.read(&mut request).await?;
+
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await?;
+
+            let chunk = [b' '; 8192];
+            let mut bytes_sent = 0;
+            while bytes_sent < MAX_BYTES_TO_SEND {
+                let header = format!("{:x}\r\n", chunk.len());
+                let write = async {
+                    socket.write_all(header.as_bytes()).await?;
+                    socket.write_all(&chunk).await?;
+                    socket.write_all(b"\r\n").await
+                };
+                if write.await.is_err() {
+                    break;
+                }
+                bytes_sent += chunk.len();
+            }
+
+            Ok::<_, anyhow::Error>(bytes_sent)
+        });
+
+        let auth_endpoint = reqwest::Url::parse(&format!("http://{addr}/authenticate"))?;
+        let creds = ClientCredentials {
+            user: "john_doe@zenith".into(),
+            dbname: "postgres".into(),
+            project: None,
+        };
+
+        let res = authenticate_proxy_client(
+            &[&auth_endpoint],
+            MAX_RESPONSE_SIZE,
+            &creds,
+            "md5",
+            &[0; 4],
+            "oversized_response_is_rejected",
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(LegacyAuthError::ResponseTooLarge(MAX_RESPONSE_SIZE))
+        ));
+
+        // The proxy must have hung up long before the console ran out of data.
+        let bytes_sent = console.await??;
+        assert!(bytes_sent < MAX_BYTES_TO_SEND);
+
+        Ok(())
+    }
+
+    /// Start a console which answers a single request with `response`.
+    async fn console_stub(response: String) -> anyhow::Result<reqwest::Url> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = reqwest::Url::parse(&format!("http://{}/authenticate", listener.local_addr()?))?;
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = [0u8; 4096];
+            let _ = socket.read(&mut request).await?;
+            socket.write_all(response.as_bytes()).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+        Ok(url)
+    }
+
+    fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn console_fallback_on_failure() -> anyhow::Result<()> {
+        // Nothing listens on this port once the listener is dropped.
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            reqwest::Url::parse(&format!("http://{}/authenticate", listener.local_addr()?))?
+        };
+        let failing = console_stub(http_response("503 Service Unavailable", "")).await?;
+        let body = json!({
+            "ready": true,
+            "conn_info": {
+                "host": "localhost",
+                "port": 5432,
+                "dbname": "postgres",
+                "user": "john_doe",
+            },
+        });
+        let fallback = console_stub(http_response("200 OK", &body.to_string())).await?;
+
+        let auth_info = request_auth_info(
+            &[&down, &failing, &fallback],
+            64 * 1024,
+            &[("login", "john_doe")],
+        )
+        .await?;
+        assert!(matches!(auth_info, ProxyAuthResponse::Ready { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_console_fallback_on_client_error() -> anyhow::Result<()> {
+        let primary = console_stub(http_response("400 Bad Request", "")).await?;
+        let fallback_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let fallback = reqwest::Url::parse(&format!(
+            "http://{}/authenticate",
+            fallback_listener.local_addr()?
+        ))?;
+
+        let res =
+            request_auth_info(&[&primary, &fallback], 64 * 1024, &[("login", "john_doe")]).await;
+        assert!(matches!(
+            res,
+            Err(LegacyAuthError::HttpStatus(
+                reqwest::StatusCode::BAD_REQUEST
+            ))
+        ));
+
+        // The fallback console must not have been contacted.
+        let accepted = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            fallback_listener.accept(),
+        )
+        .await;
+        assert!(accepted.is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn parse_db_info() -> anyhow::Result<()> {
         let _: DatabaseInfo = serde_json::from_value(json!({
@@ -196,12 +443,137 @@ mod tests {
             "password": "password",
         }))?;
 
-        let _: DatabaseInfo = serde_json::from_value(json!({
+        let db_info: DatabaseInfo = serde_json::from_value(json!({
             "host": "localhost",
             "port": 5432,
             "dbname": "postgres",
             "user": "john_doe",
         }))?;
+        assert_eq!(
+            db_info.endpoints,
+            [ComputeEndpoint {
+                host: "localhost".into(),
+                port: 5432
+            }]
+        );
+        assert!(db_info.connect_timeout().is_none());
+
+        // The connect timeout is forwarded to the compute connection config.
+        let db_info: DatabaseInfo = serde_json::from_value(json!({
+            "host": "localhost",
+            "port": 5432,
+            "dbname": "postgres",
+            "user": "john_doe",
+            "connect_timeout_ms": 1500,
+        }))?;
+        let config = tokio_postgres::Config::from(db_info);
+        assert_eq!(
+            config.get_connect_timeout(),
+            Some(&std::time::Duration::from_millis(1500))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_db_info_token() -> anyhow::Result<()> {
+        let auth: ProxyAuthResponse = serde_json::from_value(json!({
+            "ready": true,
+            "conn_info": {
+                "host": "localhost",
+                "port": 5432,
+                "dbname": "postgres",
+                "user": "john_doe",
+                "token": { "jwt": "header.payload.signature", "expires_at": 1660000000 },
+            },
+        }))?;
+        let db_info = match auth {
+            ProxyAuthResponse::Ready { conn_info } => conn_info,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert!(db_info.password.is_none());
+        assert_eq!(
+            db_info.expiration_time(),
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1660000000))
+        );
+
+        // The token is forwarded to compute in place of the password.
+        let config = tokio_postgres::Config::from(db_info);
+        assert_eq!(
+            config.get_password(),
+            Some(&b"header.payload.signature"[..])
+        );
+
+        // The legacy password form doesn't expire.
+        let db_info: DatabaseInfo = serde_json::from_value(json!({
+            "host": "localhost",
+            "port": 5432,
+            "dbname": "postgres",
+            "user": "john_doe",
+            "password": "password",
+        }))?;
+        assert!(db_info.expiration_time().is_none());
+        let config = tokio_postgres::Config::from(db_info);
+        assert_eq!(config.get_password(), Some(&b"password"[..]));
+
+        // Only one kind of credentials at a time.
+        assert!(serde_json::from_value::<DatabaseInfo>(json!({
+            "host": "localhost",
+            "port": 5432,
+            "dbname": "postgres",
+            "user": "john_doe",
+            "password": "password",
+            "token": { "jwt": "header.payload.signature", "expires_at": 1660000000 },
+        }))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_db_info_endpoints() -> anyhow::Result<()> {
+        let db_info: DatabaseInfo = serde_json::from_value(json!({
+            "endpoints": [
+                { "host": "primary", "port": 5432 },
+                { "host": "standby", "port": 5433 },
+            ],
+            "dbname": "postgres",
+            "user": "john_doe",
+        }))?;
+        assert_eq!(
+            db_info.endpoints,
+            [
+                ComputeEndpoint {
+                    host: "primary".into(),
+                    port: 5432
+                },
+                ComputeEndpoint {
+                    host: "standby".into(),
+                    port: 5433
+                },
+            ]
+        );
+
+        // Our own serialization must round-trip.
+        let _: DatabaseInfo = serde_json::from_value(serde_json::to_value(&db_info)?)?;
+
+        // Either shape is fine, but not both at once.
+        assert!(serde_json::from_value::<DatabaseInfo>(json!({
+            "host": "primary",
+            "port": 5432,
+            "endpoints": [{ "host": "standby", "port": 5433 }],
+            "dbname": "postgres",
+            "user": "john_doe",
+        }))
+        .is_err());
+
+        // There must be somewhere to connect to.
+        assert!(serde_json::from_value::<DatabaseInfo>(json!({
+            "endpoints": [],
+            "dbname": "postgres",
+            "user": "john_doe",
+        }))
+        .is_err());
 
         Ok(())
     }
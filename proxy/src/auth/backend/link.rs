@@ -69,6 +69,7 @@ pub async fn handle_user(
 
     Ok(compute::NodeInfo {
         reported_auth_ok: true,
+        expiration_time: db_info.expiration_time(),
         config: db_info.into(),
     })
 }
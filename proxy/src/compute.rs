@@ -1,6 +1,10 @@
 use crate::{cancellation::CancelClosure, error::UserFacingError};
 use futures::TryFutureExt;
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio_postgres::NoTls;
@@ -17,6 +21,15 @@ pub enum ConnectionError {
 
     #[error("Failed to fetch compute node version")]
     FailedToFetchPgVersion,
+
+    /// The short-lived compute auth token expired before we could use it.
+    #[error("Compute node credentials have expired")]
+    CredentialsExpired,
+
+    /// The compute node didn't accept the connection or didn't complete
+    /// the startup handshake in time, e.g. because it's still starting up.
+    #[error("Timed out connecting to the compute node after {0:?}")]
+    Timeout(Duration),
 }
 
 impl UserFacingError for ConnectionError {
@@ -45,24 +58,41 @@ pub struct NodeInfo {
     pub reported_auth_ok: bool,
     /// Compute node connection params.
     pub config: tokio_postgres::Config,
+    /// The credentials in [`Self::config`] can't be used after this time.
+    pub expiration_time: Option<SystemTime>,
 }
 
 impl NodeInfo {
-    async fn connect_raw(&self) -> io::Result<(SocketAddr, TcpStream)> {
+    /// How long we wait for a single compute endpoint before trying the next one,
+    /// and then for the startup handshake: the one in [`Self::config`] if the
+    /// cloud has specified it, otherwise `default`.
+    fn connect_timeout(&self, default: Duration) -> Duration {
+        self.config
+            .get_connect_timeout()
+            .copied()
+            .unwrap_or(default)
+    }
+
+    async fn connect_raw(&self, timeout: Duration) -> io::Result<(SocketAddr, TcpStream)> {
         use tokio_postgres::config::Host;
 
-        let connect_once = |host, port| {
-            TcpStream::connect((host, port)).and_then(|socket| async {
+        let connect_once = |host, port| async move {
+            let connect = TcpStream::connect((host, port)).and_then(|socket| async {
                 let socket_addr = socket.peer_addr()?;
                 // This prevents load balancer from severing the connection.
                 socket2::SockRef::from(&socket).set_keepalive(true)?;
                 Ok((socket_addr, socket))
-            })
+            });
+
+            tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
         };
 
         // We can't reuse connection establishing logic from `tokio_postgres` here,
         // because it has no means for extracting the underlying socket which we
-        // require for our business.
+        // require for our business. Hosts are tried in order (primary first,
+        // then standbys), so that we fail over if the primary is unavailable.
         let mut connection_error = None;
         let ports = self.config.get_ports();
         for (i, host) in self.config.get_hosts().iter().enumerate() {
@@ -72,7 +102,6 @@ impl NodeInfo {
                 Host::Unix(_) => continue, // unix sockets are not welcome here
             };
 
-            // TODO: maybe we should add a timeout.
             match connect_once(host, *port).await {
                 Ok(socket) => return Ok(socket),
                 Err(err) => {
@@ -101,14 +130,29 @@ pub struct PostgresConnection {
 
 impl NodeInfo {
     /// Connect to a corresponding compute node.
-    pub async fn connect(&self) -> Result<(PostgresConnection, CancelClosure), ConnectionError> {
-        let (socket_addr, mut stream) = self
-            .connect_raw()
-            .await
-            .map_err(|_| ConnectionError::FailedToConnectToCompute)?;
+    /// `default_timeout` applies unless the cloud has provided a timeout.
+    pub async fn connect(
+        &self,
+        default_timeout: Duration,
+    ) -> Result<(PostgresConnection, CancelClosure), ConnectionError> {
+        if matches!(self.expiration_time, Some(t) if t <= SystemTime::now()) {
+            return Err(ConnectionError::CredentialsExpired);
+        }
+
+        let timeout = self.connect_timeout(default_timeout);
+        let (socket_addr, mut stream) = match self.connect_raw(timeout).await {
+            Ok(socket) => socket,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                return Err(ConnectionError::Timeout(timeout))
+            }
+            Err(_) => return Err(ConnectionError::FailedToConnectToCompute),
+        };
 
         // TODO: establish a secure connection to the DB
-        let (client, conn) = self.config.connect_raw(&mut stream, NoTls).await?;
+        let (client, conn) =
+            tokio::time::timeout(timeout, self.config.connect_raw(&mut stream, NoTls))
+                .await
+                .map_err(|_| ConnectionError::Timeout(timeout))??;
         let version = conn
             .parameter("server_version")
             .ok_or(ConnectionError::FailedToFetchPgVersion)?
@@ -121,3 +165,94 @@ impl NodeInfo {
         Ok((db, cancel_closure))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_falls_through_to_next_endpoint() -> anyhow::Result<()> {
+        // Nobody listens on the first endpoint once we drop the listener.
+        let unavailable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let available = TcpListener::bind("127.0.0.1:0").await?;
+
+        let mut config = ComputeConnCfg::new();
+        config
+            .host("127.0.0.1")
+            .port(unavailable.port())
+            .host("127.0.0.1")
+            .port(available.local_addr()?.port());
+
+        let node = NodeInfo {
+            reported_auth_ok: false,
+            config,
+            expiration_time: None,
+        };
+
+        let (socket_addr, _stream) = node.connect_raw(Duration::from_secs(2)).await?;
+        assert_eq!(socket_addr, available.local_addr()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_credentials_are_not_used() -> anyhow::Result<()> {
+        let compute = TcpListener::bind("127.0.0.1:0").await?;
+
+        let mut config = ComputeConnCfg::new();
+        config
+            .host("127.0.0.1")
+            .port(compute.local_addr()?.port())
+            .password("token");
+
+        let node = NodeInfo {
+            reported_auth_ok: false,
+            config,
+            expiration_time: Some(SystemTime::now() - Duration::from_secs(1)),
+        };
+        assert!(matches!(
+            node.connect(Duration::from_secs(2)).await,
+            Err(ConnectionError::CredentialsExpired)
+        ));
+
+        // We must have refused before connecting to compute.
+        let accepted = tokio::time::timeout(Duration::from_millis(100), compute.accept()).await;
+        assert!(accepted.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_times_out_on_unresponsive_compute() -> anyhow::Result<()> {
+        // The kernel completes the TCP handshake, but nobody ever
+        // answers the startup message.
+        let compute = TcpListener::bind("127.0.0.1:0").await?;
+
+        let mut config = ComputeConnCfg::new();
+        config
+            .host("127.0.0.1")
+            .port(compute.local_addr()?.port())
+            .user("john_doe")
+            .connect_timeout(Duration::from_millis(100));
+
+        let node = NodeInfo {
+            reported_auth_ok: false,
+            config,
+            expiration_time: None,
+        };
+
+        // The timeout from the config takes precedence over the default.
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            node.connect(Duration::from_secs(60)),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Ok(Err(ConnectionError::Timeout(t))) if t == Duration::from_millis(100)
+        ));
+
+        Ok(())
+    }
+}
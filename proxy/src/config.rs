@@ -1,6 +1,6 @@
 use crate::{auth, url::ApiUrl};
 use anyhow::{bail, ensure, Context};
-use std::{str::FromStr, sync::Arc};
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 impl FromStr for auth::BackendType<()> {
     type Err = anyhow::Error;
@@ -21,13 +21,31 @@ pub struct ProxyConfig {
     pub tls_config: Option<TlsConfig>,
     pub auth_backend: auth::BackendType<()>,
     pub auth_urls: AuthUrls,
+    /// Max size of a console response body we're willing to read, in bytes.
+    pub console_max_response_size: usize,
+    /// How long to wait for a compute node to accept a connection, unless
+    /// the cloud has provided a timeout in [`auth::DatabaseInfo`].
+    pub compute_connect_timeout: Duration,
 }
 
 pub struct AuthUrls {
     pub auth_endpoint: ApiUrl,
+    /// Endpoints to try in order if `auth_endpoint` is unreachable or fails
+    /// with a server error. Only used by the legacy console backend.
+    pub auth_endpoint_fallbacks: Vec<ApiUrl>,
     pub auth_link_uri: ApiUrl,
 }
 
+impl AuthUrls {
+    /// The console endpoints for authenticating users, in the order of preference.
+    pub fn auth_endpoints(&self) -> Vec<&reqwest::Url> {
+        std::iter::once(&self.auth_endpoint)
+            .chain(&self.auth_endpoint_fallbacks)
+            .map(|url| &**url)
+            .collect()
+    }
+}
+
 pub struct TlsConfig {
     pub config: Arc<rustls::ServerConfig>,
     pub common_name: Option<String>,
@@ -1,5 +1,7 @@
+use crate::auth;
 use anyhow::anyhow;
 use hyper::{Body, Request, Response, StatusCode};
+use serde_json::json;
 use std::net::TcpListener;
 use utils::http::{endpoint, error::ApiError, json::json_response, RouterBuilder, RouterService};
 
@@ -7,9 +9,26 @@ async fn status_handler(_: Request<Body>) -> Result<Response<Body>, ApiError> {
     json_response(StatusCode::OK, "")
 }
 
+/// List the auth sessions (link and console flows) waiting for the cloud's reply.
+async fn auth_sessions_handler(_: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let sessions: Vec<_> = auth::backend::pending_sessions()
+        .into_iter()
+        .map(|session| {
+            json!({
+                "psql_session_id": session.key,
+                "waiting_secs": session.age.as_secs_f64(),
+            })
+        })
+        .collect();
+
+    json_response(StatusCode::OK, sessions)
+}
+
 fn make_router() -> RouterBuilder<hyper::Body, ApiError> {
     let router = endpoint::make_router();
-    router.get("/v1/status", status_handler)
+    router
+        .get("/v1/status", status_handler)
+        .get("/v1/auth_sessions", auth_sessions_handler)
 }
 
 pub async fn thread_main(http_listener: TcpListener) -> anyhow::Result<()> {
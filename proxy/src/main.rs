@@ -23,7 +23,7 @@ use anyhow::{bail, Context};
 use clap::{App, Arg};
 use config::ProxyConfig;
 use futures::FutureExt;
-use std::{future::Future, net::SocketAddr};
+use std::{future::Future, net::SocketAddr, time::Duration};
 use tokio::{net::TcpListener, task::JoinError};
 use utils::project_git_version;
 
@@ -87,6 +87,27 @@ async fn main() -> anyhow::Result<()> {
                 .help("cloud API endpoint for authenticating users")
                 .default_value("http://localhost:3000/authenticate_proxy_request/"),
         )
+        .arg(
+            Arg::new("auth-endpoint-fallback")
+                .long("auth-endpoint-fallback")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("cloud API endpoint to use if the previous ones are unavailable, can be repeated"),
+        )
+        .arg(
+            Arg::new("console-max-response-size")
+                .long("console-max-response-size")
+                .takes_value(true)
+                .help("max size of a cloud API response body, in bytes")
+                .default_value("65536"),
+        )
+        .arg(
+            Arg::new("compute-connect-timeout-ms")
+                .long("compute-connect-timeout-ms")
+                .takes_value(true)
+                .help("how long to wait for a compute node to accept a connection, in milliseconds, unless the cloud API specifies it")
+                .default_value("2000"),
+        )
         .arg(
             Arg::new("tls-key")
                 .short('k')
@@ -120,6 +141,12 @@ async fn main() -> anyhow::Result<()> {
 
     let auth_urls = config::AuthUrls {
         auth_endpoint: arg_matches.value_of("auth-endpoint").unwrap().parse()?,
+        auth_endpoint_fallbacks: arg_matches
+            .values_of("auth-endpoint-fallback")
+            .into_iter()
+            .flatten()
+            .map(str::parse)
+            .collect::<anyhow::Result<_>>()?,
         auth_link_uri: arg_matches.value_of("uri").unwrap().parse()?,
     };
 
@@ -127,6 +154,18 @@ async fn main() -> anyhow::Result<()> {
         tls_config,
         auth_backend: arg_matches.value_of("auth-backend").unwrap().parse()?,
         auth_urls,
+        console_max_response_size: arg_matches
+            .value_of("console-max-response-size")
+            .unwrap()
+            .parse()
+            .context("failed to parse console-max-response-size")?,
+        compute_connect_timeout: Duration::from_millis(
+            arg_matches
+                .value_of("compute-connect-timeout-ms")
+                .unwrap()
+                .parse()
+                .context("failed to parse compute-connect-timeout-ms")?,
+        ),
     }));
 
     println!("Version: {GIT_VERSION}");
@@ -198,10 +198,19 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
         let Self { mut stream, creds } = self;
 
         // Authenticate and connect to a compute node.
-        let auth = creds.authenticate(&config.auth_urls, &mut stream).await;
+        let auth = creds
+            .authenticate(
+                &config.auth_urls,
+                config.console_max_response_size,
+                &mut stream,
+            )
+            .await;
         let node = async { auth }.or_else(|e| stream.throw_error(e)).await?;
 
-        let (db, cancel_closure) = node.connect().or_else(|e| stream.throw_error(e)).await?;
+        let (db, cancel_closure) = node
+            .connect(config.compute_connect_timeout)
+            .or_else(|e| stream.throw_error(e))
+            .await?;
         let cancel_key_data = session.enable_cancellation(cancel_closure);
 
         // Report authentication success if we haven't done this already.
@@ -3,6 +3,7 @@ use parking_lot::Mutex;
 use pin_project_lite::pin_project;
 use std::pin::Pin;
 use std::task;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::oneshot;
 
@@ -27,7 +28,20 @@ pub enum WaitError {
     Hangup,
 }
 
-pub struct Waiters<T>(pub(self) Mutex<HashMap<String, oneshot::Sender<T>>>);
+struct Entry<T> {
+    sender: oneshot::Sender<T>,
+    registered_at: Instant,
+}
+
+/// A snapshot of a registered waiter, for observability.
+#[derive(Debug, Clone)]
+pub struct PendingWaiter {
+    pub key: String,
+    /// How long the waiter has been waiting for a notification.
+    pub age: Duration,
+}
+
+pub struct Waiters<T>(pub(self) Mutex<HashMap<String, Entry<T>>>);
 
 impl<T> Default for Waiters<T> {
     fn default() -> Self {
@@ -38,10 +52,14 @@ impl<T> Default for Waiters<T> {
 impl<T> Waiters<T> {
     pub fn register(&self, key: String) -> Result<Waiter<T>, RegisterError> {
         let (tx, rx) = oneshot::channel();
+        let entry = Entry {
+            sender: tx,
+            registered_at: Instant::now(),
+        };
 
         self.0
             .lock()
-            .try_insert(key.clone(), tx)
+            .try_insert(key.clone(), entry)
             .map_err(|e| RegisterError::Occupied(e.entry.key().clone()))?;
 
         Ok(Waiter {
@@ -57,13 +75,39 @@ impl<T> Waiters<T> {
     where
         T: Send + Sync,
     {
-        let tx = self
+        let entry = self
             .0
             .lock()
             .remove(key)
             .ok_or_else(|| NotifyError::NotFound(key.to_string()))?;
 
-        tx.send(value).map_err(|_| NotifyError::Hangup)
+        entry.sender.send(value).map_err(|_| NotifyError::Hangup)
+    }
+
+    /// Number of waiters which haven't been notified yet.
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// List the waiters which haven't been notified yet, oldest first.
+    pub fn pending(&self) -> Vec<PendingWaiter> {
+        let now = Instant::now();
+        let mut pending: Vec<_> = self
+            .0
+            .lock()
+            .iter()
+            .map(|(key, entry)| PendingWaiter {
+                key: key.clone(),
+                age: now.saturating_duration_since(entry.registered_at),
+            })
+            .collect();
+
+        pending.sort_by(|a, b| b.age.cmp(&a.age));
+        pending
     }
 }
 
@@ -118,4 +162,29 @@ mod tests {
         waiter.await?;
         notifier.await?
     }
+
+    #[tokio::test]
+    async fn test_pending_waiters() -> anyhow::Result<()> {
+        let waiters = Waiters::<()>::default();
+        assert!(waiters.is_empty());
+
+        let first = waiters.register("first".to_owned())?;
+        let second = waiters.register("second".to_owned())?;
+
+        let pending = waiters.pending();
+        let mut keys: Vec<_> = pending.iter().map(|w| w.key.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["first", "second"]);
+        assert!(pending[0].age >= pending[1].age);
+
+        waiters.notify("first", ())?;
+        first.await?;
+        assert_eq!(waiters.len(), 1);
+
+        // Dropping an unnotified waiter deregisters it as well.
+        drop(second);
+        assert!(waiters.is_empty());
+
+        Ok(())
+    }
 }